@@ -0,0 +1,228 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use crate::config;
+use crate::tags::Selection;
+
+/// Gives each distinct value its own placeholder, the same one every time the value is seen.
+struct Placeholders {
+    format: fn(usize) -> String,
+    names: HashMap<String, String>,
+}
+
+impl Placeholders {
+    fn new(format: fn(usize) -> String) -> Self {
+        Self {
+            format,
+            names: HashMap::new(),
+        }
+    }
+
+    fn get(&mut self, value: &str) -> String {
+        let next = self.names.len() + 1;
+        let format = self.format;
+        self.names
+            .entry(value.to_owned())
+            .or_insert_with(|| format(next))
+            .clone()
+    }
+}
+
+/// The placeholders of one config, shared by its devices and the selections naming them.
+struct Names {
+    device_ids: Placeholders,
+    hosts: Placeholders,
+    registration_ids: Placeholders,
+    sites: Placeholders,
+    regions: Placeholders,
+    environments: Placeholders,
+    addresses: HashMap<IpAddr, IpAddr>,
+}
+
+impl Names {
+    fn new() -> Self {
+        Self {
+            device_ids: Placeholders::new(|n| format!("device-{}", n)),
+            hosts: Placeholders::new(|n| format!("host-{}.example.com", n)),
+            registration_ids: Placeholders::new(|n| format!("registration-{}", n)),
+            sites: Placeholders::new(|n| format!("site-{}", n)),
+            regions: Placeholders::new(|n| format!("region-{}", n)),
+            environments: Placeholders::new(|n| format!("environment-{}", n)),
+            addresses: HashMap::new(),
+        }
+    }
+
+    /// The selection with the names in it replaced. One that does not parse is kept, so the
+    /// anonymized config fails the same way.
+    fn selection(&mut self, selection: &str) -> String {
+        match selection.parse() {
+            Ok(Selection::Device(device_id)) => {
+                format!("device:{}", self.device_ids.get(&device_id))
+            }
+            Ok(Selection::Subtree(device_id)) => {
+                format!("subtree:{}", self.device_ids.get(&device_id))
+            }
+            Ok(Selection::Site(site)) => format!("site:{}", self.sites.get(&site)),
+            Ok(Selection::Region(region)) => format!("region:{}", self.regions.get(&region)),
+            Ok(Selection::Environment(environment)) => {
+                format!("environment:{}", self.environments.get(&environment))
+            }
+            Ok(Selection::All) | Ok(Selection::Layer(_)) | Err(_) => selection.to_owned(),
+        }
+    }
+}
+
+/// Copy of the config with its secrets redacted and the names of the infrastructure, device ids,
+/// hostnames, addresses, sites and hub, DPS and storage names, replaced with placeholders. A
+/// value gets the same placeholder everywhere, so the config still reproduces what it was shared
+/// for.
+pub fn anonymize(config: &config::Config) -> config::Config {
+    let mut config = config.redacted();
+    let mut names = Names::new();
+
+    config.iothub.iothub_name = "iothub-1".to_owned();
+    config.iothub.iothub_hostname = "iothub-1.azure-devices.net".to_owned();
+    if let Some(event_grid) = &mut config.iothub.event_grid {
+        event_grid.subscription_name = "subscription-1".to_owned();
+        event_grid.endpoint = "https://example.com/events".to_owned();
+    }
+    if let Some(dps) = &mut config.dps {
+        dps.dps_name = "dps-1".to_owned();
+        if let Some(id_scope) = &mut dps.id_scope {
+            *id_scope = "0ne00000000".to_owned();
+        }
+    }
+    if let Some(certificates) = &mut config.certificates {
+        certificates.root_ca_cert_path = "root_ca.cert.pem".to_owned();
+        certificates.root_ca_cert_key_path = "root_ca.key.pem".to_owned();
+        if let Some(secret) = &mut certificates.root_ca_cert_key_passphrase_secret {
+            *secret = "https://vault-1.vault.azure.net/secrets/root-ca-passphrase".to_owned();
+        }
+    }
+    if let Some(bootstrap) = &mut config.bootstrap {
+        bootstrap.storage_account = "storageaccount1".to_owned();
+    }
+    if let Some(proxy_jump) = config.ssh.as_mut().and_then(|s| s.proxy_jump.as_mut()) {
+        *proxy_jump = names.hosts.get(proxy_jump);
+    }
+
+    fn anonymize_device(device: &mut config::DeviceConfig, names: &mut Names) {
+        device.device_id = names.device_ids.get(&device.device_id);
+        if let Some(registration_id) = &mut device.registration_id {
+            *registration_id = names.registration_ids.get(registration_id);
+        }
+        for host in device
+            .hostname
+            .iter_mut()
+            .chain(device.ssh_proxy_jump.iter_mut())
+        {
+            *host = names.hosts.get(host);
+        }
+        if let Some(external_parent) = &mut device.external_parent {
+            external_parent.device_id = names.device_ids.get(&external_parent.device_id);
+            if let Some(hostname) = &mut external_parent.hostname {
+                *hostname = names.hosts.get(hostname);
+            }
+        }
+        if let Some(site) = &mut device.site {
+            *site = names.sites.get(site);
+        }
+        if let Some(region) = &mut device.region {
+            *region = names.regions.get(region);
+        }
+        if let Some(environment) = &mut device.environment {
+            *environment = names.environments.get(environment);
+        }
+        if let Some(ip_address) = &mut device.ip_address {
+            // Documentation ranges, in the order the addresses are seen
+            let addresses = &mut names.addresses;
+            let n = addresses.len() as u32 + 1;
+            *ip_address = *addresses.entry(*ip_address).or_insert(match ip_address {
+                IpAddr::V4(_) => {
+                    IpAddr::V4(Ipv4Addr::from(u32::from(Ipv4Addr::new(192, 0, 2, 0)) + n))
+                }
+                IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, n as u16)),
+            });
+        }
+        if let Some(key) = &mut device.tpm_endorsement_key {
+            *key = "<redacted>".to_owned();
+        }
+        for child in &mut device.children {
+            anonymize_device(child, names);
+        }
+    }
+    for root in &mut config.root_devices {
+        anonymize_device(root, &mut names);
+    }
+    // The windows are named after their sites
+    for window in &mut config.schedule {
+        window.name = names.sites.get(&window.name);
+        if let Some(devices) = &mut window.devices {
+            *devices = names.selection(devices);
+        }
+    }
+    config.namespace = None;
+
+    config
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_anonymize() {
+        let mut config = config::Config::read_config("src/test_files/cert_test.yaml")
+            .await
+            .unwrap();
+        let root = &mut config.root_devices[0];
+        root.hostname = Some("gw.plant.contoso.com".to_owned());
+        root.ip_address = Some("10.1.2.3".parse().unwrap());
+        root.children[0].ssh_proxy_jump = Some("gw.plant.contoso.com".to_owned());
+        root.site = Some("Contoso Plant".to_owned());
+        root.region = Some("contoso-west".to_owned());
+        root.environment = Some("contoso-prod".to_owned());
+        root.external_parent = Some(config::ExternalParent {
+            device_id: "contoso-core".to_owned(),
+            hostname: Some("core.contoso.com".to_owned()),
+        });
+        config.schedule = vec![config::MaintenanceWindow {
+            name: "Contoso Plant".to_owned(),
+            devices: Some(format!("subtree:{}", root.device_id)),
+            days: Vec::new(),
+            start: "22:00".to_owned(),
+            end: "04:00".to_owned(),
+            utc_offset: None,
+        }];
+
+        let anonymized = anonymize(&config);
+        let yaml = serde_yaml::to_string(&anonymized).unwrap();
+        assert!(!yaml.contains("contoso"));
+        assert!(!yaml.contains("10.1.2.3"));
+        assert!(!yaml.contains(&config.iothub.iothub_name));
+        assert!(!yaml.contains(&format!(":{}", config.root_devices[0].device_id)));
+
+        let root = &anonymized.root_devices[0];
+        assert_eq!(root.device_id, "device-1");
+        assert_eq!(root.hostname.as_deref(), Some("host-1.example.com"));
+        assert_eq!(root.ip_address, Some("192.0.2.1".parse().unwrap()));
+        assert_eq!(root.children[0].device_id, "device-2");
+        assert_eq!(
+            root.children[0].ssh_proxy_jump.as_deref(),
+            Some("host-1.example.com")
+        );
+        assert_eq!(root.site.as_deref(), Some("site-1"));
+        assert_eq!(root.region.as_deref(), Some("region-1"));
+        assert_eq!(root.environment.as_deref(), Some("environment-1"));
+        let external_parent = root.external_parent.as_ref().unwrap();
+        assert_eq!(external_parent.device_id, "device-2");
+        assert_eq!(
+            external_parent.hostname.as_deref(),
+            Some("host-2.example.com")
+        );
+
+        let window = &anonymized.schedule[0];
+        assert_eq!(window.name, "site-1");
+        assert_eq!(window.devices.as_deref(), Some("subtree:device-1"));
+    }
+}
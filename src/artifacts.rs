@@ -0,0 +1,224 @@
+use anyhow::{Context, Result};
+use tokio::fs;
+use walkdir::WalkDir;
+
+use crate::config;
+use crate::log::LogLevel;
+use crate::FileManager;
+
+/// Start of the stamp comment on the first line of generated files.
+const PREFIX: &str = "# Generated by iotedge_config ";
+
+/// Generated files that are stamped. JSON files and certs have no comments to put one in.
+const STAMPED_FILES: &[&str] = &[
+    "config.toml",
+    "install.sh",
+    "install.ps1",
+    "k8s.yaml",
+    "docker-compose.yml",
+];
+
+/// Tool metadata stamped on generated files, so outputs of different tool versions or configs
+/// can be told apart.
+#[derive(Debug, PartialEq)]
+pub struct Stamp {
+    pub tool_version: String,
+    pub config_hash: String,
+    /// In RFC 3339. Left out with --gitops, so the files only change with the config.
+    pub generated: Option<String>,
+}
+
+impl Stamp {
+    /// The stamp of files generated now, None if the config file's hash is not known.
+    pub fn new(config: &config::Config, file_manager: &FileManager) -> Option<Self> {
+        Some(Self {
+            tool_version: env!("CARGO_PKG_VERSION").to_owned(),
+            config_hash: config.source_hash.clone()?,
+            generated: if config.stamp_time {
+                Some(file_manager.now().to_rfc3339())
+            } else {
+                None
+            },
+        })
+    }
+
+    /// The comment line, with its newline.
+    pub fn line(&self) -> String {
+        match &self.generated {
+            Some(generated) => format!(
+                "{}{} from config sha256 {} at {}\n",
+                PREFIX, self.tool_version, self.config_hash, generated
+            ),
+            None => format!(
+                "{}{} from config sha256 {}\n",
+                PREFIX, self.tool_version, self.config_hash
+            ),
+        }
+    }
+
+    /// Reads the stamp from the first line of a generated file.
+    pub fn parse(contents: &str) -> Option<Self> {
+        let rest = contents.lines().next()?.strip_prefix(PREFIX)?;
+        let (tool_version, rest) = rest.split_once(" from config sha256 ")?;
+        let (config_hash, generated) = match rest.split_once(" at ") {
+            Some((config_hash, generated)) => (config_hash, Some(generated.to_owned())),
+            None => (rest, None),
+        };
+
+        Some(Self {
+            tool_version: tool_version.to_owned(),
+            config_hash: config_hash.to_owned(),
+            generated,
+        })
+    }
+}
+
+/// Puts the stamp on top of a generated file's contents.
+pub fn stamp(config: &config::Config, file_manager: &FileManager, contents: &str) -> String {
+    match Stamp::new(config, file_manager) {
+        Some(stamp) => stamp.line() + contents,
+        None => contents.to_owned(),
+    }
+}
+
+/// The contents of a generated file without its stamp, to compare files generated at different
+/// times.
+pub fn unstamped(contents: &str) -> &str {
+    match Stamp::parse(contents) {
+        Some(_) => contents.split_once('\n').map_or("", |(_, rest)| rest),
+        None => contents,
+    }
+}
+
+/// Prints the stamp of each generated file in the output folder, and warns about the files
+/// generated by another version of the tool or from another config than the current ones.
+pub async fn info(config: &config::Config, file_manager: &FileManager) -> Result<()> {
+    let base_path = file_manager.base_path();
+    let mut files = Vec::new();
+    for entry in WalkDir::new(base_path)
+        .sort_by(|a, b| a.file_name().cmp(b.file_name()))
+        .into_iter()
+        .filter_map(|e| e.ok())
+    {
+        let name = entry.file_name().to_string_lossy();
+        if !entry.file_type().is_file() || !STAMPED_FILES.contains(&name.as_ref()) {
+            continue;
+        }
+        let contents = fs::read_to_string(entry.path())
+            .await
+            .with_context(|| format!("Error reading {:?}", entry.path()))?;
+        let path = entry.path().strip_prefix(base_path).unwrap_or(entry.path());
+        files.push((path.to_string_lossy().into_owned(), Stamp::parse(&contents)));
+    }
+
+    let (listing, warnings) = report(
+        &files,
+        env!("CARGO_PKG_VERSION"),
+        config.source_hash.as_deref(),
+    );
+    print!("{}", listing);
+    for warning in warnings {
+        file_manager
+            .log(LogLevel::Warn, "artifacts", None, warning)
+            .await?;
+    }
+
+    Ok(())
+}
+
+/// The listing of the files' stamps, and the warnings about them.
+fn report(
+    files: &[(String, Option<Stamp>)],
+    tool_version: &str,
+    config_hash: Option<&str>,
+) -> (String, Vec<String>) {
+    let mut listing = String::new();
+    let mut warnings = Vec::new();
+    let mut unstamped = Vec::new();
+    for (path, stamp) in files {
+        let stamp = match stamp {
+            Some(stamp) => stamp,
+            None => {
+                listing.push_str(&format!("{}: no stamp\n", path));
+                unstamped.push(path.as_str());
+                continue;
+            }
+        };
+        listing.push_str(&format!(
+            "{}: iotedge_config {}, config sha256 {}, generated {}\n",
+            path,
+            stamp.tool_version,
+            stamp.config_hash,
+            stamp.generated.as_deref().unwrap_or("at an unknown time")
+        ));
+
+        let mut differs = Vec::new();
+        if stamp.tool_version != tool_version {
+            differs.push(format!("by iotedge_config {}", stamp.tool_version));
+        }
+        if config_hash.map_or(false, |h| h != stamp.config_hash) {
+            differs.push("from another config".to_owned());
+        }
+        if !differs.is_empty() {
+            warnings.push(format!(
+                "{} was generated {}, not by iotedge_config {} from the current config. Rerun the tool to regenerate it.",
+                path,
+                differs.join(" and "),
+                tool_version
+            ));
+        }
+    }
+    if !unstamped.is_empty() {
+        warnings.push(format!(
+            "{} files have no stamp. They were generated by an older version of iotedge_config, or the stamp was removed: {}",
+            unstamped.len(),
+            unstamped.join(", ")
+        ));
+    }
+
+    (listing, warnings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stamp() {
+        let stamp = Stamp {
+            tool_version: "0.1.0".to_owned(),
+            config_hash: "abc".to_owned(),
+            generated: Some("2021-06-01T12:00:00+00:00".to_owned()),
+        };
+        let contents = stamp.line() + "hostname = \"top\"\n";
+        assert_eq!(
+            contents,
+            "# Generated by iotedge_config 0.1.0 from config sha256 abc at 2021-06-01T12:00:00+00:00\nhostname = \"top\"\n"
+        );
+        assert_eq!(Stamp::parse(&contents).as_ref(), Some(&stamp));
+        assert_eq!(unstamped(&contents), "hostname = \"top\"\n");
+        // Stamps of earlier versions have no time
+        let old = Stamp::parse("# Generated by iotedge_config 0.0.9 from config sha256 def\n");
+        assert_eq!(old.as_ref().and_then(|s| s.generated.as_deref()), None);
+
+        let (listing, warnings) = report(
+            &[
+                ("A/config.toml".to_owned(), Some(stamp)),
+                ("A/install.sh".to_owned(), None),
+                ("B/config.toml".to_owned(), old),
+            ],
+            "0.1.0",
+            Some("abc"),
+        );
+        assert_eq!(
+            listing,
+            "A/config.toml: iotedge_config 0.1.0, config sha256 abc, generated 2021-06-01T12:00:00+00:00\nA/install.sh: no stamp\nB/config.toml: iotedge_config 0.0.9, config sha256 def, generated at an unknown time\n"
+        );
+        assert_eq!(warnings.len(), 2);
+        assert!(warnings[0].starts_with(
+            "B/config.toml was generated by iotedge_config 0.0.9 and from another config"
+        ));
+        assert!(warnings[1]
+            .ends_with("older version of iotedge_config, or the stamp was removed: A/install.sh"));
+    }
+}
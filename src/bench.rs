@@ -0,0 +1,257 @@
+use std::time::Duration;
+
+use anyhow::Result;
+use futures::StreamExt;
+use tokio::fs;
+use tokio::time::Instant;
+
+use crate::config;
+use crate::log::LogLevel;
+use crate::throttle;
+use crate::{
+    CertManager, FileManager, FlatenedDevice, IoTHubDeviceManager, MAX_CONCURRENT_DEVICES,
+};
+
+/// Top layer devices with nothing but an id, for the benchmark's config.
+pub fn synthetic_devices(count: usize) -> Vec<config::DeviceConfig> {
+    (1..=count)
+        .map(|i| config::DeviceConfig {
+            device_id: format!("device-{}", i),
+            ..Default::default()
+        })
+        .collect()
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Outcome {
+    Done,
+    Throttled,
+    Failed,
+}
+
+impl Outcome {
+    fn of<T>(result: &Result<T>) -> Self {
+        match result {
+            Ok(_) => Self::Done,
+            Err(e) => {
+                let error = format!("{:#}", e);
+                if throttle::is_throttled(&error) || error.contains("QuotaExceeded") {
+                    Self::Throttled
+                } else {
+                    Self::Failed
+                }
+            }
+        }
+    }
+}
+
+/// Throughput of one operation of the benchmark.
+#[derive(Debug, PartialEq)]
+struct Summary {
+    done: usize,
+    throttled: usize,
+    failed: usize,
+    per_second: f64,
+    /// Most operations done within one second.
+    peak_per_second: usize,
+    /// Operations done before the first throttled one, and when it finished.
+    first_throttle: Option<(usize, Duration)>,
+}
+
+/// Summarizes operations by when each one finished since the start.
+fn summarize(completions: &[(Duration, Outcome)], elapsed: Duration) -> Summary {
+    let count = |outcome| completions.iter().filter(|(_, o)| *o == outcome).count();
+    let done = count(Outcome::Done);
+
+    let mut per_second = std::collections::BTreeMap::new();
+    for (at, _) in completions.iter().filter(|(_, o)| *o == Outcome::Done) {
+        *per_second.entry(at.as_secs()).or_insert(0) += 1;
+    }
+    let first_throttle = completions
+        .iter()
+        .position(|(_, o)| *o == Outcome::Throttled)
+        .map(|i| {
+            let done_before = completions[..i]
+                .iter()
+                .filter(|(_, o)| *o == Outcome::Done)
+                .count();
+            (done_before, completions[i].0)
+        });
+
+    Summary {
+        done,
+        throttled: count(Outcome::Throttled),
+        failed: count(Outcome::Failed),
+        per_second: done as f64 / elapsed.as_secs_f64().max(0.001),
+        peak_per_second: per_second.values().copied().max().unwrap_or(0),
+        first_throttle,
+    }
+}
+
+/// Creates and deletes the synthetic devices of a benchmark config, measuring how fast the hub
+/// takes them with the config's auth method.
+pub struct BenchManager<'a> {
+    config: &'a config::Config,
+    file_manager: &'a FileManager,
+    cert_manager: &'a CertManager<'a>,
+    hub_manager: &'a IoTHubDeviceManager<'a>,
+}
+
+impl<'a> BenchManager<'a> {
+    pub fn new(
+        config: &'a config::Config,
+        file_manager: &'a FileManager,
+        cert_manager: &'a CertManager<'a>,
+        hub_manager: &'a IoTHubDeviceManager<'a>,
+    ) -> Self {
+        Self {
+            config,
+            file_manager,
+            cert_manager,
+            hub_manager,
+        }
+    }
+
+    /// Creates every device, then deletes them even if some failed, and prints the throughput
+    /// of both. Their output folders are removed afterwards.
+    pub async fn run(&self) -> Result<()> {
+        let devices = &self.config.root_devices;
+        self.file_manager
+            .log(
+                LogLevel::Info,
+                "bench",
+                None,
+                format!(
+                    "Benchmarking {} devices under namespace {} in hub {} with {:?} auth, {} at a time",
+                    devices.len(),
+                    self.config.namespace.as_deref().unwrap_or_default(),
+                    self.config.iothub.iothub_name,
+                    self.config.iothub.authentication_method,
+                    MAX_CONCURRENT_DEVICES
+                ),
+            )
+            .await?;
+
+        if self.config.iothub.authentication_method == config::IoTHubAuthMethod::X509Cert {
+            let start = Instant::now();
+            self.cert_manager.make_all_device_ca_certs().await?;
+            self.file_manager
+                .print(format!(
+                    "certs: made the certs of {} devices in {:.1}s",
+                    devices.len(),
+                    start.elapsed().as_secs_f64()
+                ))
+                .await?;
+        }
+
+        let start = Instant::now();
+        let mut completions = Vec::new();
+        self.hub_manager
+            .create_device_stream()
+            .for_each(|r| {
+                completions.push((start.elapsed(), Outcome::of(&r.result)));
+                futures::future::ready(())
+            })
+            .await;
+        let create = summarize(&completions, start.elapsed());
+        self.report("create", &create).await?;
+
+        let start = Instant::now();
+        let futures = FlatenedDevice::iter(devices).map(|d| async move {
+            let result = match self.hub_manager.delete_device(d.device).await {
+                Ok(true) => Ok(()),
+                Ok(false) => Err(anyhow::Error::msg("Not deleted")),
+                Err(e) => Err(e),
+            };
+            (start.elapsed(), Outcome::of(&result))
+        });
+        let completions: Vec<(Duration, Outcome)> = futures::stream::iter(futures)
+            .buffer_unordered(MAX_CONCURRENT_DEVICES)
+            .collect()
+            .await;
+        let delete = summarize(&completions, start.elapsed());
+        self.report("delete", &delete).await?;
+
+        for device in devices {
+            let folder = self.file_manager.base_path().join(&device.device_id);
+            if folder.exists() {
+                fs::remove_dir_all(&folder).await?;
+            }
+        }
+
+        if create.throttled + delete.throttled > 0 {
+            self.file_manager
+                .print("The hub throttled the benchmark. Keep real runs below the rate reached before the first throttled request, with --hub-tier and --hub-units.")
+                .await?;
+        }
+        if delete.done < devices.len() {
+            return Err(anyhow::Error::msg(format!(
+                "{} benchmark devices were not deleted. Delete them with gc --namespace {}.",
+                devices.len() - delete.done,
+                self.config.namespace.as_deref().unwrap_or_default()
+            )));
+        }
+
+        Ok(())
+    }
+
+    async fn report(&self, operation: &str, summary: &Summary) -> Result<()> {
+        let throttle = match summary.first_throttle {
+            Some((done, at)) => format!(
+                ", first throttled after {} devices at {:.1}s",
+                done,
+                at.as_secs_f64()
+            ),
+            None => String::new(),
+        };
+        self.file_manager
+            .print(format!(
+                "{}: {} devices at {:.1}/s, peak {}/s. {} throttled{}, {} failed.",
+                operation,
+                summary.done,
+                summary.per_second,
+                summary.peak_per_second,
+                summary.throttled,
+                throttle,
+                summary.failed
+            ))
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_summarize() {
+        let at = |ms| Duration::from_millis(ms);
+        let completions = vec![
+            (at(200), Outcome::Done),
+            (at(500), Outcome::Done),
+            (at(900), Outcome::Done),
+            (at(1200), Outcome::Throttled),
+            (at(1500), Outcome::Done),
+            (at(1800), Outcome::Failed),
+        ];
+
+        assert_eq!(
+            summarize(&completions, at(2000)),
+            Summary {
+                done: 4,
+                throttled: 1,
+                failed: 1,
+                per_second: 2.0,
+                peak_per_second: 3,
+                first_throttle: Some((3, at(1200))),
+            }
+        );
+        assert_eq!(
+            Outcome::of::<()>(&Err(anyhow::Error::msg(
+                "Failed to create device-1:\nThrottlingException: IotHub(429002)"
+            ))),
+            Outcome::Throttled
+        );
+        assert_eq!(synthetic_devices(3)[2].device_id, "device-3");
+    }
+}
@@ -0,0 +1,242 @@
+use std::path::Path;
+
+use anyhow::Result;
+use chrono::{Duration, Utc};
+use tokio::fs;
+
+use crate::commands::CommandRunner;
+use crate::config;
+use crate::log::LogLevel;
+use crate::remote::sh_quote;
+use crate::{quote_arg, CreatedDevice, FileManager};
+
+const DEFAULT_EXPIRY_HOURS: i64 = 24;
+
+/// Uploads each device's zip and a bootstrap script to blob storage, and writes a copy-pasteable
+/// command per device to bootstrap.md, for technicians who only get console access to the box.
+pub struct BootstrapManager<'a> {
+    config: &'a config::Config,
+    file_manager: &'a FileManager,
+    runner: &'a CommandRunner,
+}
+
+impl<'a> BootstrapManager<'a> {
+    pub fn new(
+        config: &'a config::Config,
+        file_manager: &'a FileManager,
+        runner: &'a CommandRunner,
+    ) -> Self {
+        Self {
+            config,
+            file_manager,
+            runner,
+        }
+    }
+
+    /// Needs the device zips, so it runs after they are made.
+    pub async fn publish_all(&self, devices: &[CreatedDevice<'_>]) -> Result<()> {
+        let bootstrap = match &self.config.bootstrap {
+            Some(bootstrap) => bootstrap,
+            None => return Ok(()),
+        };
+        self.file_manager
+            .log(
+                LogLevel::Info,
+                "bootstrap",
+                None,
+                format!(
+                    "Uploading {} device bundles to container {} of storage account {}.",
+                    devices.len(),
+                    bootstrap.container,
+                    bootstrap.storage_account
+                ),
+            )
+            .await?;
+
+        let mut readme = String::from(
+            "# Bootstrap commands\n\nRun the command of a device on its console to download and install its bundle.\n",
+        );
+        for device in devices {
+            let device_id = device.device.device_id.as_str();
+            let zip = FileManager::path_to_zip(self.file_manager.base_path().join(device_id));
+            let zip_url = self
+                .upload(bootstrap, &zip, &format!("{}.zip", device_id))
+                .await?;
+
+            let script_url = self
+                .upload_script(
+                    bootstrap,
+                    &format!("{}.bootstrap.sh", device_id),
+                    bootstrap_script(&zip_url, true),
+                )
+                .await?;
+            let eflow_script_url = self
+                .upload_script(
+                    bootstrap,
+                    &format!("{}.eflow.bootstrap.sh", device_id),
+                    bootstrap_script(&zip_url, false),
+                )
+                .await?;
+
+            let (sh, powershell) = one_liners(&script_url, &eflow_script_url);
+            readme.push_str(&format!(
+                "\n## {}\n\nLinux:\n\n```sh\n{}\n```\n\nWindows, for IoT Edge for Linux on Windows:\n\n```powershell\n{}\n```\n",
+                device_id, sh, powershell
+            ));
+        }
+
+        let expiry_hours = bootstrap.expiry_hours.unwrap_or(DEFAULT_EXPIRY_HOURS);
+        readme.push_str(&format!(
+            "\nThe links expire {} hours after they were made. Rerun iotedge_config to make new ones.\n",
+            expiry_hours
+        ));
+        fs::write(self.file_manager.base_path().join("bootstrap.md"), readme).await?;
+
+        Ok(())
+    }
+
+    /// Writes a bootstrap script to the output folder and uploads it.
+    async fn upload_script(
+        &self,
+        bootstrap: &config::Bootstrap,
+        name: &str,
+        script: String,
+    ) -> Result<String> {
+        let path = self.file_manager.base_path().join(name);
+        fs::write(&path, script).await?;
+
+        self.upload(bootstrap, &path, name).await
+    }
+
+    /// Uploads the file and returns a read-only SAS URL to it.
+    async fn upload(
+        &self,
+        bootstrap: &config::Bootstrap,
+        file: &Path,
+        name: &str,
+    ) -> Result<String> {
+        self.file_manager
+            .log(
+                LogLevel::Debug,
+                "bootstrap",
+                None,
+                format!("Uploading {:?} as {}", file, name),
+            )
+            .await?;
+
+        let file = file.to_string_lossy();
+        let command = self
+            .runner
+            .output(&[
+                "az storage blob upload",
+                "--account-name",
+                &bootstrap.storage_account,
+                "--container-name",
+                &bootstrap.container,
+                "--name",
+                name,
+                "--file",
+                &quote_arg(&file),
+                "--overwrite",
+                "--auth-mode",
+                "login",
+            ])
+            .await?;
+        if !command.status.success() {
+            return Err(anyhow::Error::msg(format!(
+                "Failed to upload {}:\n{}",
+                name,
+                String::from_utf8_lossy(&command.stderr)
+            )));
+        }
+
+        let expiry =
+            Utc::now() + Duration::hours(bootstrap.expiry_hours.unwrap_or(DEFAULT_EXPIRY_HOURS));
+        let expiry = expiry.format("%Y-%m-%dT%H:%MZ").to_string();
+        let command = self
+            .runner
+            .output(&[
+                "az storage blob generate-sas",
+                "--account-name",
+                &bootstrap.storage_account,
+                "--container-name",
+                &bootstrap.container,
+                "--name",
+                name,
+                "--permissions",
+                "r",
+                "--expiry",
+                &expiry,
+                "--auth-mode",
+                "login",
+                "--as-user",
+                "--full-uri",
+                "-o",
+                "tsv",
+            ])
+            .await?;
+        let url = String::from_utf8_lossy(&command.stdout).trim().to_owned();
+        if !command.status.success() || url.is_empty() {
+            return Err(anyhow::Error::msg(format!(
+                "Failed to make a SAS URL for {}:\n{}",
+                name,
+                String::from_utf8_lossy(&command.stderr)
+            )));
+        }
+
+        Ok(url)
+    }
+}
+
+/// Script the bootstrap command runs: downloads the device's zip to a temporary folder and runs
+/// its install.sh. Interactively, install.sh reads its prompts from the terminal, since stdin is
+/// the script. The EFLOW virtual machine has no terminal, so there it gets no input and hostnames
+/// have to be set in the config.
+fn bootstrap_script(zip_url: &str, interactive: bool) -> String {
+    format!(
+        "set -e\ndir=$(mktemp -d)\ncurl -fsSL {} -o \"$dir/bundle.zip\"\ncd \"$dir\"\nunzip -o bundle.zip > /dev/null\nbash ./install.sh < {}\n",
+        sh_quote(zip_url),
+        if interactive { "/dev/tty" } else { "/dev/null" }
+    )
+}
+
+/// The Linux command, and the PowerShell command that runs the non-interactive script in the
+/// EFLOW virtual machine.
+fn one_liners(script_url: &str, eflow_script_url: &str) -> (String, String) {
+    let sh = format!("curl -fsSL {} | sudo sh", sh_quote(script_url));
+    let eflow_sh = format!("curl -fsSL {} | sudo sh", sh_quote(eflow_script_url));
+    let powershell = format!("Invoke-EflowVmCommand \"{}\"", eflow_sh.replace('"', "`\""));
+
+    (sh, powershell)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_one_liners() {
+        let url = "https://account.blob.core.windows.net/bundles/top-layer.bootstrap.sh?sv=2020&sig=abc%3D";
+        let eflow_url = "https://account.blob.core.windows.net/bundles/top-layer.eflow.bootstrap.sh?sv=2020&sig=def%3D";
+        let (sh, powershell) = one_liners(url, eflow_url);
+
+        assert_eq!(sh, format!("curl -fsSL '{}' | sudo sh", url));
+        assert_eq!(
+            powershell,
+            format!(
+                "Invoke-EflowVmCommand \"curl -fsSL '{}' | sudo sh\"",
+                eflow_url
+            )
+        );
+        assert!(bootstrap_script(url, true).contains(&format!("curl -fsSL '{}' -o", url)));
+        assert!(bootstrap_script(url, true).contains("install.sh < /dev/tty"));
+    }
+
+    #[test]
+    fn test_eflow_script() {
+        // The EFLOW virtual machine has no terminal to read prompts from
+        let script = bootstrap_script("https://account.blob.core.windows.net/bundles/a.zip", false);
+        assert!(!script.contains("/dev/tty"));
+        assert!(script.contains("bash ./install.sh < /dev/null\n"));
+    }
+}
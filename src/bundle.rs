@@ -0,0 +1,210 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use tokio::fs;
+
+use crate::{diff, run, sha256_hex};
+
+/// Files that differ on every run without the bundles changing.
+fn is_volatile(path: &str) -> bool {
+    path == "state.json"
+        || path == "trace.log"
+        || path.starts_with("logs/")
+        || (path.starts_with("log_") && !path.contains('/'))
+}
+
+/// How a file differs between two output folders.
+#[derive(Debug, PartialEq)]
+enum Change {
+    Added,
+    Removed,
+    /// Text files, with the number of added and removed lines.
+    Edited {
+        added: usize,
+        removed: usize,
+        diff: String,
+    },
+    /// Certs are compared by fingerprint only.
+    Reissued {
+        old: String,
+        new: String,
+    },
+    /// Keys and other binary files.
+    Replaced,
+}
+
+/// Compares the files generated by two runs and summarizes what changed per device, for review
+/// before a fleet update. Logs and state.json are left out. Device folders are reported in the
+/// order of `device_ids`, the hierarchy's, and folders of other devices after them by name.
+pub async fn diff_bundles(
+    old: &Path,
+    new: &Path,
+    device_ids: &[&str],
+    patch: bool,
+) -> Result<String> {
+    for dir in &[old, new] {
+        if !dir.is_dir() {
+            return Err(anyhow::Error::msg(format!(
+                "{:?} is not an output folder",
+                dir
+            )));
+        }
+    }
+    let old_files = run::artifacts(old);
+    let new_files = run::artifacts(new);
+    let mut paths: Vec<&String> = old_files.iter().chain(new_files.iter()).collect();
+    paths.sort();
+    paths.dedup();
+
+    // By device folder, or "(output folder)" for files at the top
+    let mut changes: BTreeMap<&str, Vec<(&str, Change)>> = BTreeMap::new();
+    for path in paths {
+        if is_volatile(path) {
+            continue;
+        }
+        let group = match path.split_once('/') {
+            Some((folder, _)) => folder,
+            None => "(output folder)",
+        };
+        let entry = changes.entry(group).or_default();
+        let change = match (old_files.contains(path), new_files.contains(path)) {
+            (true, false) => Change::Removed,
+            (false, true) => Change::Added,
+            _ => match compare(&old.join(path), &new.join(path), path).await? {
+                Some(change) => change,
+                None => continue,
+            },
+        };
+        entry.push((path.as_str(), change));
+    }
+
+    let mut changes: Vec<(&str, Vec<(&str, Change)>)> = changes.into_iter().collect();
+    changes.sort_by_key(
+        |(group, _)| match device_ids.iter().position(|d| d == group) {
+            Some(i) => (1, i),
+            None if *group == "(output folder)" => (0, 0),
+            None => (2, 0),
+        },
+    );
+
+    let mut report = String::new();
+    let mut changed = 0;
+    for (group, files) in &changes {
+        if files.is_empty() {
+            report.push_str(&format!("{}: unchanged\n", group));
+            continue;
+        }
+        changed += 1;
+        report.push_str(&format!("{}:\n", group));
+        for (path, change) in files {
+            let line = match change {
+                Change::Added => format!("  + {}", path),
+                Change::Removed => format!("  - {}", path),
+                Change::Edited { added, removed, .. } => {
+                    format!("  ~ {} (+{} -{} lines)", path, added, removed)
+                }
+                Change::Reissued { old, new } => {
+                    format!("  ~ {} (reissued, fingerprint {} -> {})", path, old, new)
+                }
+                Change::Replaced => format!("  ~ {} (replaced)", path),
+            };
+            report.push_str(&line);
+            report.push('\n');
+            if let (true, Change::Edited { diff, .. }) = (patch, change) {
+                for line in diff.lines() {
+                    report.push_str(&format!("    {}\n", line));
+                }
+            }
+        }
+    }
+    report.push_str(&format!("{} of {} changed\n", changed, changes.len()));
+
+    Ok(report)
+}
+
+/// None if the file is the same in both folders.
+async fn compare(old: &Path, new: &Path, name: &str) -> Result<Option<Change>> {
+    let old_data = fs::read(old)
+        .await
+        .with_context(|| format!("Error reading {:?}", old))?;
+    let new_data = fs::read(new)
+        .await
+        .with_context(|| format!("Error reading {:?}", new))?;
+    if old_data == new_data {
+        return Ok(None);
+    }
+
+    let (old_text, new_text) = match (
+        std::str::from_utf8(&old_data),
+        std::str::from_utf8(&new_data),
+    ) {
+        (Ok(old_text), Ok(new_text)) => (old_text, new_text),
+        _ => return Ok(Some(Change::Replaced)),
+    };
+    if old_text.contains("PRIVATE KEY") || new_text.contains("PRIVATE KEY") {
+        return Ok(Some(Change::Replaced));
+    }
+    if old_text.contains("BEGIN CERTIFICATE") {
+        return Ok(Some(Change::Reissued {
+            old: fingerprint(&old_data),
+            new: fingerprint(&new_data),
+        }));
+    }
+
+    let diff = diff::unified_diff(
+        old_text,
+        new_text,
+        &format!("old/{}", name),
+        &format!("new/{}", name),
+    );
+    if diff.is_empty() {
+        // Only line endings differ
+        return Ok(None);
+    }
+    let count = |prefix: char| {
+        diff.lines()
+            .skip(2)
+            .filter(|l| l.starts_with(prefix))
+            .count()
+    };
+
+    Ok(Some(Change::Edited {
+        added: count('+'),
+        removed: count('-'),
+        diff,
+    }))
+}
+
+/// Start of the SHA-256 of a cert file, enough to tell certs apart in a report.
+fn fingerprint(data: &[u8]) -> String {
+    sha256_hex(data).chars().take(16).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_diff_bundles() {
+        let old = tempfile::tempdir().unwrap();
+        let new = tempfile::tempdir().unwrap();
+        for dir in &[old.path(), new.path()] {
+            std::fs::create_dir_all(dir.join("B")).unwrap();
+            std::fs::create_dir_all(dir.join("A")).unwrap();
+            std::fs::write(dir.join("A").join("install.sh"), "echo\n").unwrap();
+        }
+        std::fs::write(old.path().join("B").join("config.toml"), "a = 1\nb = 2\n").unwrap();
+        std::fs::write(new.path().join("B").join("config.toml"), "a = 1\nb = 3\n").unwrap();
+        std::fs::write(new.path().join("B").join("hosts"), "").unwrap();
+        std::fs::write(old.path().join("log_1.txt"), "old").unwrap();
+
+        let report = diff_bundles(old.path(), new.path(), &["B", "A"], false)
+            .await
+            .unwrap();
+        assert_eq!(
+            report,
+            "B:\n  ~ B/config.toml (+1 -1 lines)\n  + B/hosts\nA: unchanged\n1 of 2 changed\n"
+        );
+    }
+}
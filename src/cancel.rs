@@ -0,0 +1,79 @@
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use anyhow::Result;
+
+/// Asks long running operations to stop. Operations already running finish, and the ones not
+/// started yet fail with `Cancelled`, so a run stops at a point where its state is known.
+#[derive(Clone, Debug, Default)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Fails with `Cancelled` once the token is cancelled. Called before starting an operation.
+    pub fn check(&self) -> Result<()> {
+        if self.is_cancelled() {
+            Err(Cancelled.into())
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Cancels the token on the first ctrl-c. A second ctrl-c exits right away.
+    pub fn cancel_on_ctrl_c(&self) {
+        let token = self.clone();
+        tokio::spawn(async move {
+            if tokio::signal::ctrl_c().await.is_err() {
+                return;
+            }
+            eprintln!("Stopping after the operations in progress. Press ctrl-c again to exit now.");
+            token.cancel();
+
+            if tokio::signal::ctrl_c().await.is_ok() {
+                std::process::exit(130);
+            }
+        });
+    }
+}
+
+/// Error of operations that were not started because the run was cancelled.
+#[derive(Debug)]
+pub struct Cancelled;
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Cancelled")
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cancellation_token() {
+        let token = CancellationToken::new();
+        assert!(token.check().is_ok());
+
+        token.clone().cancel();
+        assert!(token.is_cancelled());
+        let error = token.check().unwrap_err();
+        assert!(error.downcast_ref::<Cancelled>().is_some());
+    }
+}
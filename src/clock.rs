@@ -0,0 +1,143 @@
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use tokio::process::Command;
+
+use crate::commands::CommandExt;
+
+/// Where the time written to logs, the issuance log, state.json and twin tags, and the start of
+/// cert validity, comes from. Token expiry always uses the real time, so tokens can be used.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> DateTime<Utc>;
+
+    /// Whether this is the current time, which openssl then picks for cert validity by itself.
+    fn is_real(&self) -> bool {
+        false
+    }
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+
+    fn is_real(&self) -> bool {
+        true
+    }
+}
+
+/// Always the same time, so generated files can be compared between runs.
+pub struct FixedClock(pub DateTime<Utc>);
+
+impl Clock for FixedClock {
+    fn now(&self) -> DateTime<Utc> {
+        self.0
+    }
+}
+
+/// Where cert serial numbers come from, a `SeededRng` with --seed. Secrets, such as the shares of
+/// a split root key, always come from `OpensslRng` instead.
+pub trait Rng: Send + Sync {
+    /// `bytes` random bytes as lowercase hex.
+    fn random_hex(&self, bytes: usize) -> BoxFuture<'_, Result<String>>;
+}
+
+/// `openssl rand`.
+pub struct OpensslRng {
+    pub openssl_path: Option<PathBuf>,
+}
+
+impl Rng for OpensslRng {
+    fn random_hex(&self, bytes: usize) -> BoxFuture<'_, Result<String>> {
+        async move {
+            let command = self
+                .openssl_path
+                .as_ref()
+                .map_or_else(|| Command::new("openssl"), Command::new)
+                .args(&["rand", "-hex", &bytes.to_string()])
+                .bounded_output()
+                .await?;
+            if !command.status.success() {
+                return Err(anyhow::Error::msg(format!(
+                    "Error making random bytes:\n{}",
+                    String::from_utf8_lossy(&command.stderr)
+                )));
+            }
+
+            Ok(String::from_utf8_lossy(&command.stdout).trim().to_owned())
+        }
+        .boxed()
+    }
+}
+
+/// `openssl::rand`, in-process, for --cert-backend native.
+pub struct NativeRng;
+
+impl Rng for NativeRng {
+    fn random_hex(&self, bytes: usize) -> BoxFuture<'_, Result<String>> {
+        let mut random = vec![0; bytes];
+        let hex = openssl::rand::rand_bytes(&mut random)
+            .map(|()| random.iter().map(|b| format!("{:02x}", b)).collect())
+            .map_err(anyhow::Error::from);
+
+        futures::future::ready(hex).boxed()
+    }
+}
+
+/// The same sequence for the same seed. Not for certs that leave a lab.
+pub struct SeededRng {
+    state: Mutex<u64>,
+}
+
+impl SeededRng {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            state: Mutex::new(seed),
+        }
+    }
+
+    /// splitmix64
+    fn next(&self) -> u64 {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        *state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = *state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+}
+
+impl Rng for SeededRng {
+    fn random_hex(&self, bytes: usize) -> BoxFuture<'_, Result<String>> {
+        let mut hex = String::with_capacity(bytes * 2);
+        while hex.len() < bytes * 2 {
+            hex.push_str(&format!("{:016x}", self.next()));
+        }
+        hex.truncate(bytes * 2);
+
+        futures::future::ready(Ok(hex)).boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_seeded_rng() {
+        let first = SeededRng::new(7);
+        let second = SeededRng::new(7);
+
+        let hex = first.random_hex(16).await.unwrap();
+        assert_eq!(hex.len(), 32);
+        assert!(hex.chars().all(|c| c.is_ascii_hexdigit()));
+        assert_eq!(hex, second.random_hex(16).await.unwrap());
+        assert_ne!(hex, first.random_hex(16).await.unwrap());
+    }
+}
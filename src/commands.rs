@@ -0,0 +1,674 @@
+use std::path::{Path, PathBuf};
+use std::process::{ExitStatus, Output, Stdio};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use futures::future::BoxFuture;
+use tokio::fs;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::process::{Child, Command};
+use tokio::sync::{Mutex, Semaphore};
+use tokio::time::Instant;
+
+use crate::hub_responses::{Capabilities, CreateResponse};
+use crate::hub_rest::HubRestClient;
+use crate::plan::Plan;
+use crate::telemetry;
+use crate::{quote_arg, run_command};
+
+/// Longest a child process may run. az calls against a throttled hub take seconds and openssl
+/// less than one, so this only stops processes that are stuck.
+pub const COMMAND_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+/// Bytes of a child process's stdout that are kept, enough for az to list every device of a
+/// large hub.
+const MAX_STDOUT: usize = 64 * 1024 * 1024;
+
+/// Bytes of a child process's stderr that are kept. The rest is read and dropped, so a process
+/// that keeps printing errors neither blocks on a full pipe nor fills memory.
+const MAX_STDERR: usize = 1024 * 1024;
+
+/// Arguments whose values change on every run, such as thumbprints of newly made certs, the
+/// creation time in twin tags or SAS expiry times. They are ignored when matching commands against
+/// a recording.
+const VOLATILE_ARGS: &[&str] = &[
+    "--primary-thumbprint",
+    "--secondary-thumbprint",
+    "--tags",
+    "--expiry",
+];
+
+/// Arguments whose values are secrets, left out of trace.log and recordings.
+const SECRET_ARGS: &[&str] = &[
+    "--login",
+    "--connection-string",
+    "--primary-key",
+    "--secondary-key",
+    "--key",
+    "--password",
+];
+
+/// Markers of secrets in arguments and responses, such as connection strings, SAS tokens and the
+/// device keys in identity JSON. The value after them is left out of trace.log and recordings.
+const SECRET_MARKERS: &[&str] = &[
+    "SharedAccessKey=",
+    "AccountKey=",
+    "sig=",
+    "\"primaryKey\": \"",
+    "\"secondaryKey\": \"",
+    "\"password\": \"",
+];
+
+/// Last words of the az commands that only read, the ones run with a read only login and by dry
+/// runs.
+const READ_COMMANDS: &[&str] = &["show", "list", "query"];
+
+/// Connection strings az iot commands authenticate with through `--login`, instead of the az
+/// session.
+#[derive(Clone, Debug, Default)]
+pub struct AzLogin {
+    pub hub: Option<String>,
+    pub dps: Option<String>,
+    /// Set by --read-only. az commands that would change anything are refused before they run.
+    pub read_only: bool,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum RunMode {
+    Live,
+    /// Runs commands and saves their output to the file.
+    Record(PathBuf),
+    /// Answers commands from a file saved by `Record` instead of running them.
+    Replay(PathBuf),
+    /// Adds every command to the plan, and only runs the az commands that read. The others are
+    /// answered as if they succeeded.
+    DryRun,
+}
+
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+struct Interaction {
+    command: Vec<String>,
+    status: i32,
+    stdout: String,
+    stderr: String,
+}
+
+/// Runs the az commands that talk to the hub. Can record their output to a fixture file and replay
+/// it later, so the whole pipeline can be run without hub access.
+pub struct CommandRunner {
+    mode: RunMode,
+    interactions: Mutex<Vec<Interaction>>,
+    /// trace.log, with -vv.
+    trace: Option<Mutex<fs::File>>,
+    /// Quoted connection strings.
+    login: AzLogin,
+    /// Set by --max-concurrency. Commands with `output` wait for a permit to run.
+    calls: Option<Semaphore>,
+    /// The operations of a dry run.
+    plan: Option<Plan>,
+    /// Set by --hub-api rest. Answers the device identity commands it knows instead of az.
+    hub_rest: Option<HubRestClient>,
+}
+
+impl CommandRunner {
+    /// With `trace`, every command and its raw output are appended to that file, with secrets
+    /// redacted. With `max_concurrency`, at most that many commands run at the same time. With
+    /// `hub_rest`, the commands it answers go to the hub's REST API instead of az.
+    pub async fn new(
+        mode: RunMode,
+        trace: Option<&Path>,
+        login: AzLogin,
+        max_concurrency: Option<usize>,
+        hub_rest: Option<HubRestClient>,
+    ) -> Result<Self> {
+        let interactions = match &mode {
+            RunMode::Replay(file) => {
+                let recording = fs::read(file)
+                    .await
+                    .with_context(|| format!("Error reading recording {:?}", file))?;
+                serde_json::from_slice(&recording)
+                    .with_context(|| format!("Error parsing recording {:?}", file))?
+            }
+            _ => Vec::new(),
+        };
+
+        let trace = match trace {
+            Some(path) => Some(Mutex::new(
+                fs::File::create(path)
+                    .await
+                    .with_context(|| format!("Error creating {:?}", path))?,
+            )),
+            None => None,
+        };
+
+        let plan = if mode == RunMode::DryRun {
+            Some(Plan::default())
+        } else {
+            None
+        };
+
+        Ok(Self {
+            mode,
+            interactions: Mutex::new(interactions),
+            trace,
+            login: AzLogin {
+                hub: login.hub.as_deref().map(quote_arg),
+                dps: login.dps.as_deref().map(quote_arg),
+                read_only: login.read_only,
+            },
+            calls: max_concurrency.map(Semaphore::new),
+            plan,
+            hub_rest,
+        })
+    }
+
+    /// The plan commands are added to instead of running them, with --dry-run.
+    pub fn plan(&self) -> Option<&Plan> {
+        self.plan.as_ref()
+    }
+
+    /// Adds `--login` to az iot commands of the hub or DPS it has a connection string for.
+    fn with_login<'a>(&'a self, args: &[&'a str]) -> Vec<&'a str> {
+        let mut args = args.to_vec();
+        if args.first().map_or(false, |a| a.starts_with("az iot ")) {
+            let login = if args.contains(&"--hub-name") {
+                self.login.hub.as_deref()
+            } else if args.contains(&"--dps-name") {
+                self.login.dps.as_deref()
+            } else {
+                None
+            };
+            if let Some(login) = login {
+                args.extend(&["--login", login]);
+            }
+        }
+
+        args
+    }
+
+    fn check_read_only(&self, args: &[&str]) -> Result<()> {
+        let command = args.first().copied().unwrap_or_default();
+        if self.login.read_only && command.starts_with("az ") && !only_reads(command) {
+            return Err(anyhow::Error::msg(format!(
+                "Not running {} because of --read-only. Run it with write credentials.",
+                command
+            )));
+        }
+
+        Ok(())
+    }
+
+    pub async fn output(&self, args: &[&str]) -> Result<Output> {
+        self.check_read_only(args)?;
+        let args = &self.with_login(args)[..];
+        let _permit = match &self.calls {
+            Some(calls) => Some(calls.acquire().await?),
+            None => None,
+        };
+        let start = Instant::now();
+        let output = match &self.mode {
+            RunMode::Live => self.run(args).await?,
+            RunMode::Record(file) => {
+                let output = self.run(args).await?;
+                self.record(file, args, &output).await?;
+                output
+            }
+            RunMode::Replay(_) => self.replay(args).await?,
+            RunMode::DryRun => {
+                let device_id = args
+                    .iter()
+                    .position(|a| *a == "--device-id")
+                    .and_then(|i| args.get(i + 1))
+                    .copied();
+                if let Some(plan) = &self.plan {
+                    plan.add(device_id, redact(&redact_args(args).join(" ")))
+                        .await;
+                }
+                if only_reads(args[0]) {
+                    self.run(args).await?
+                } else {
+                    dry_run_output(args)?
+                }
+            }
+        };
+        telemetry::record_hub_call(start.elapsed());
+        self.trace(args, output.status, &output.stdout, &output.stderr, start)
+            .await?;
+
+        Ok(output)
+    }
+
+    /// Runs the command, or answers it through the hub's REST API with --hub-api rest.
+    async fn run(&self, args: &[&str]) -> Result<Output> {
+        match &self.hub_rest {
+            Some(hub_rest) if HubRestClient::answers(args[0]) => hub_rest.output(args).await,
+            _ => run_command(args).bounded_output().await,
+        }
+    }
+
+    /// Like `output`, but for commands whose output is streamed to the console as it arrives.
+    pub async fn status(&self, args: &[&str]) -> Result<ExitStatus> {
+        self.check_read_only(args)?;
+        match &self.mode {
+            RunMode::Live => {
+                let args = &self.with_login(args)[..];
+                let start = Instant::now();
+                // Streams until it exits or is interrupted, so only killed with the run
+                let status = run_command(args).kill_on_drop(true).status().await?;
+                telemetry::record_hub_call(start.elapsed());
+                self.trace(args, status, b"", b"", start).await?;
+                Ok(status)
+            }
+            _ => {
+                let output = self.output(args).await?;
+                print!("{}", String::from_utf8_lossy(&output.stdout));
+                eprint!("{}", String::from_utf8_lossy(&output.stderr));
+                Ok(output.status)
+            }
+        }
+    }
+
+    async fn trace(
+        &self,
+        args: &[&str],
+        status: ExitStatus,
+        stdout: &[u8],
+        stderr: &[u8],
+        start: Instant,
+    ) -> Result<()> {
+        let trace = match &self.trace {
+            Some(trace) => trace,
+            None => return Ok(()),
+        };
+
+        let entry = format!(
+            "$ {}\nexit {} after {}ms\n--- stdout\n{}\n--- stderr\n{}\n\n",
+            redact(&redact_args(args).join(" ")),
+            status
+                .code()
+                .map_or_else(|| "signal".to_owned(), |c| c.to_string()),
+            start.elapsed().as_millis(),
+            redact(&String::from_utf8_lossy(stdout)),
+            redact(&String::from_utf8_lossy(stderr))
+        );
+        trace.lock().await.write_all(entry.as_bytes()).await?;
+
+        Ok(())
+    }
+
+    async fn record(&self, file: &Path, args: &[&str], output: &Output) -> Result<()> {
+        let mut interactions = self.interactions.lock().await;
+        interactions.push(Interaction {
+            command: redact_args(args).iter().map(|a| (*a).to_owned()).collect(),
+            status: output.status.code().unwrap_or(-1),
+            // Recordings are shared to reproduce bugs, so device keys are replayed as placeholders
+            stdout: redact(&String::from_utf8_lossy(&output.stdout)),
+            stderr: redact(&String::from_utf8_lossy(&output.stderr)),
+        });
+
+        // Saved after every command so the recording survives a failed run
+        fs::write(file, serde_json::to_string_pretty(&*interactions)?).await?;
+        Ok(())
+    }
+
+    async fn replay(&self, args: &[&str]) -> Result<Output> {
+        let key = match_key(args.iter().copied());
+
+        // Devices are created concurrently, so answers are matched by command instead of order
+        let mut interactions = self.interactions.lock().await;
+        let index = interactions
+            .iter()
+            .position(|i| match_key(i.command.iter().map(String::as_str)) == key)
+            .ok_or_else(|| {
+                anyhow::Error::msg(format!("No recorded response for {}", args.join(" ")))
+            })?;
+        let interaction = interactions.remove(index);
+
+        Ok(Output {
+            status: exit_status(interaction.status),
+            stdout: interaction.stdout.into_bytes(),
+            stderr: interaction.stderr.into_bytes(),
+        })
+    }
+}
+
+pub trait CommandExt {
+    /// Like `output`, but the process is killed if it runs longer than COMMAND_TIMEOUT or the
+    /// future is dropped, as when another device of a batch fails, and only the first bytes of
+    /// its stdout and stderr are kept.
+    fn bounded_output(&mut self) -> BoxFuture<'_, Result<Output>>;
+}
+
+impl CommandExt for Command {
+    fn bounded_output(&mut self) -> BoxFuture<'_, Result<Output>> {
+        self.stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true);
+        let program = self.as_std().get_program().to_string_lossy().into_owned();
+        Box::pin(async move { wait_bounded(self.spawn()?, &program).await })
+    }
+}
+
+/// Like `wait_with_output`, with the limits of `bounded_output`, for children spawned with piped
+/// stdout and stderr and `kill_on_drop` that are written to first.
+pub async fn wait_bounded(mut child: Child, program: &str) -> Result<Output> {
+    let stdout = read_capped(child.stdout.take(), MAX_STDOUT);
+    let stderr = read_capped(child.stderr.take(), MAX_STDERR);
+    let output = tokio::time::timeout(COMMAND_TIMEOUT, async {
+        let (status, stdout, stderr) = tokio::try_join!(child.wait(), stdout, stderr)?;
+        Ok::<_, std::io::Error>(Output {
+            status,
+            stdout,
+            stderr,
+        })
+    })
+    .await;
+
+    match output {
+        Ok(output) => Ok(output?),
+        Err(_) => {
+            // Waits for it to exit too, so it isn't left a zombie
+            child.kill().await?;
+            Err(anyhow::Error::msg(format!(
+                "Killed {} after it ran for {} minutes",
+                program,
+                COMMAND_TIMEOUT.as_secs() / 60
+            )))
+        }
+    }
+}
+
+/// Reads a pipe to its end, keeping the first `max` bytes.
+async fn read_capped<R: AsyncRead + Unpin>(
+    reader: Option<R>,
+    max: usize,
+) -> std::io::Result<Vec<u8>> {
+    let mut result = Vec::new();
+    let mut reader = match reader {
+        Some(reader) => reader,
+        None => return Ok(result),
+    };
+    let mut buffer = [0; 8192];
+    let mut truncated = false;
+    loop {
+        let read = reader.read(&mut buffer).await?;
+        if read == 0 {
+            break;
+        }
+        let keep = read.min(max - result.len().min(max));
+        result.extend_from_slice(&buffer[..keep]);
+        truncated |= keep < read;
+    }
+    if truncated {
+        result.extend_from_slice(b"\n(output truncated)");
+    }
+
+    Ok(result)
+}
+
+/// Whether the command is an az command that only reads.
+fn only_reads(command: &str) -> bool {
+    let verb = command.rsplit(' ').next().unwrap_or_default();
+    command.starts_with("az ") && READ_COMMANDS.contains(&verb)
+}
+
+/// A successful exit with the output, for commands a dry run does not run.
+pub fn placeholder_output(stdout: String) -> Output {
+    Output {
+        status: exit_status(0),
+        stdout: stdout.into_bytes(),
+        stderr: Vec::new(),
+    }
+}
+
+/// The answer to an az command a dry run does not run, shaped like az's where the run reads it.
+fn dry_run_output(args: &[&str]) -> Result<Output> {
+    let value = |name: &str| {
+        args.iter()
+            .position(|a| *a == name)
+            .and_then(|i| args.get(i + 1))
+            .copied()
+    };
+    let stdout = if args[0] == "az iot hub device-identity create" {
+        serde_json::to_string(&CreateResponse {
+            device_id: value("--device-id").unwrap_or_default().to_owned(),
+            capabilities: Capabilities {
+                iot_edge: args.contains(&"--edge-enabled"),
+            },
+            ..Default::default()
+        })?
+    } else if value("--query") == Some("attestation.symmetricKey.primaryKey") {
+        // The key of the enrollment group it would create
+        base64::encode([0; 64])
+    } else {
+        String::new()
+    };
+
+    Ok(placeholder_output(stdout))
+}
+
+/// The arguments with the values of `SECRET_ARGS` replaced.
+fn redact_args<'a>(args: &[&'a str]) -> Vec<&'a str> {
+    let mut result = Vec::new();
+    let mut secret = false;
+    for arg in args {
+        result.push(if secret { "<redacted>" } else { arg });
+        secret = SECRET_ARGS.contains(arg);
+    }
+
+    result
+}
+
+/// The text with the values after `SECRET_MARKERS` replaced.
+fn redact(text: &str) -> String {
+    let mut result = text.to_owned();
+    for marker in SECRET_MARKERS {
+        let mut from = 0;
+        while let Some(found) = result[from..].find(marker) {
+            let start = from + found + marker.len();
+            let end = result[start..]
+                .find(|c: char| c == ';' || c == '&' || c == '"' || c == '\'' || c.is_whitespace())
+                .map_or(result.len(), |i| start + i);
+            result.replace_range(start..end, "<redacted>");
+            from = start + "<redacted>".len();
+        }
+    }
+
+    result
+}
+
+fn match_key<'a>(args: impl Iterator<Item = &'a str>) -> Vec<&'a str> {
+    let mut result = Vec::new();
+    let mut volatile = false;
+    for arg in args {
+        result.push(if volatile { "*" } else { arg });
+        volatile = VOLATILE_ARGS.contains(&arg) || SECRET_ARGS.contains(&arg);
+    }
+
+    result
+}
+
+#[cfg(unix)]
+pub fn exit_status(code: i32) -> ExitStatus {
+    use std::os::unix::process::ExitStatusExt;
+    ExitStatus::from_raw(code << 8)
+}
+
+#[cfg(windows)]
+pub fn exit_status(code: i32) -> ExitStatus {
+    use std::os::windows::process::ExitStatusExt;
+    ExitStatus::from_raw(code as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[tokio::test]
+    async fn test_record_replay() {
+        let dir = tempdir().unwrap();
+        let file = dir.path().join("recording.json");
+
+        let recorder = CommandRunner::new(
+            RunMode::Record(file.clone()),
+            None,
+            AzLogin::default(),
+            Some(1),
+            None,
+        )
+        .await
+        .unwrap();
+        let recorded = recorder
+            .output(&["echo", "--primary-thumbprint", "ABC"])
+            .await
+            .unwrap();
+        assert!(recorded.status.success());
+
+        let trace = dir.path().join("trace.log");
+        let replayer = CommandRunner::new(
+            RunMode::Replay(file),
+            Some(&trace),
+            AzLogin::default(),
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+        let replayed = replayer
+            .output(&["echo", "--primary-thumbprint", "DEF"])
+            .await
+            .unwrap();
+        assert!(replayed.status.success());
+        assert_eq!(replayed.stdout, recorded.stdout);
+
+        // Each recorded response is only replayed once
+        assert!(replayer
+            .output(&["echo", "--primary-thumbprint", "DEF"])
+            .await
+            .is_err());
+        let trace = std::fs::read_to_string(trace).unwrap();
+        assert!(trace.starts_with("$ echo --primary-thumbprint DEF\nexit 0 after "));
+    }
+
+    #[tokio::test]
+    async fn test_record_redacts_keys() {
+        let dir = tempdir().unwrap();
+        let file = dir.path().join("recording.json");
+        let recorder = CommandRunner::new(
+            RunMode::Record(file.clone()),
+            None,
+            AzLogin::default(),
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+        let identity = r#"{"authentication": {"symmetricKey": {"primaryKey": "cHJpbWFyeQ==", "secondaryKey": "c2Vjb25kYXJ5"}}, "connectionString": "HostName=h;DeviceId=d;SharedAccessKey=cHJpbWFyeQ=="}"#;
+        // What `az iot hub device-identity create` answers, printed without a hub
+        let response = dir.path().join("identity.json");
+        std::fs::write(&response, identity).unwrap();
+        recorder
+            .output(&["cat", &quote_arg(&response.to_string_lossy())])
+            .await
+            .unwrap();
+
+        let recording = std::fs::read_to_string(file).unwrap();
+        assert!(recording.contains("primaryKey"));
+        assert!(!recording.contains("cHJpbWFyeQ=="));
+        assert!(!recording.contains("c2Vjb25kYXJ5"));
+    }
+
+    #[tokio::test]
+    async fn test_dry_run() {
+        let dir = tempdir().unwrap();
+        let file = dir.path().join("created");
+        let runner = CommandRunner::new(RunMode::DryRun, None, AzLogin::default(), None, None)
+            .await
+            .unwrap();
+        let touch = quote_arg(&file.to_string_lossy());
+        assert!(runner
+            .output(&["touch", &touch])
+            .await
+            .unwrap()
+            .status
+            .success());
+        assert!(!file.exists());
+
+        let created = runner
+            .output(&[
+                "az iot hub device-identity create",
+                "--device-id",
+                "A",
+                "--hub-name",
+                "hub",
+                "--edge-enabled",
+            ])
+            .await
+            .unwrap();
+        let created: CreateResponse = serde_json::from_slice(&created.stdout).unwrap();
+        assert_eq!(created.device_id, "A");
+        assert!(created.capabilities.iot_edge);
+
+        let steps = runner.plan().unwrap().steps().await;
+        assert_eq!(steps.len(), 2);
+        assert_eq!(steps[0].operation, format!("touch {}", touch));
+        assert_eq!(steps[1].device_id.as_deref(), Some("A"));
+        assert_eq!(
+            steps[1].operation,
+            "az iot hub device-identity create --device-id A --hub-name hub --edge-enabled"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_with_login() {
+        let login = AzLogin {
+            hub: Some("HostName=h;SharedAccessKeyName=owner;SharedAccessKey=abc".to_owned()),
+            dps: None,
+            read_only: true,
+        };
+        let runner = CommandRunner::new(RunMode::Live, None, login, None, None)
+            .await
+            .unwrap();
+        assert_eq!(
+            runner.with_login(&["az iot hub query", "--hub-name", "h"]),
+            vec![
+                "az iot hub query",
+                "--hub-name",
+                "h",
+                "--login",
+                "'HostName=h;SharedAccessKeyName=owner;SharedAccessKey=abc'"
+            ]
+        );
+        assert_eq!(
+            runner.with_login(&["az iot dps enrollment show", "--dps-name", "d"]),
+            vec!["az iot dps enrollment show", "--dps-name", "d"]
+        );
+        assert!(runner.check_read_only(&["az iot hub query"]).is_ok());
+        assert!(runner
+            .check_read_only(&["az iot hub device-identity create"])
+            .is_err());
+        assert!(runner.check_read_only(&["openssl", "x509"]).is_ok());
+    }
+
+    #[test]
+    fn test_redact() {
+        assert_eq!(
+            redact_args(&[
+                "az iot hub device-identity list",
+                "--login",
+                "'HostName=h;SharedAccessKey=abc'"
+            ]),
+            vec!["az iot hub device-identity list", "--login", "<redacted>"]
+        );
+        assert_eq!(
+            redact("HostName=h;SharedAccessKeyName=owner;SharedAccessKey=abc=\n{\"primaryKey\": \"def\", \"x\": 1}"),
+            "HostName=h;SharedAccessKeyName=owner;SharedAccessKey=<redacted>\n{\"primaryKey\": \"<redacted>\", \"x\": 1}"
+        );
+        assert_eq!(
+            redact("SharedAccessSignature sr=h&sig=abc%3D&se=1"),
+            "SharedAccessSignature sr=h&sig=<redacted>&se=1"
+        );
+    }
+}
@@ -1,22 +1,126 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+
 #[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
 pub struct ConfigVersion {
     pub config_version: String,
 }
 
+impl Config {
+    /// The device with this id, in any of the hierarchies.
+    pub fn find_device(&self, device_id: &str) -> Option<&DeviceConfig> {
+        self.root_devices.iter().find_map(|r| r.find(device_id))
+    }
+
+    /// Whether the run --resume picks up did the phase for the device.
+    pub fn was_done(&self, phase: &str, device_id: &str) -> bool {
+        self.resumed
+            .get(phase)
+            .map_or(false, |devices| devices.iter().any(|d| d == device_id))
+    }
+}
+
 #[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
 pub struct Config {
     pub iothub: IoTHub,
+    pub dps: Option<Dps>,
     pub certificates: Option<Certificates>,
+    #[serde(default)]
+    pub cert_names: CertNames,
+    #[serde(default)]
+    pub key_algorithms: KeyAlgorithms,
     pub configuration: Configuration,
-    #[serde(rename = "edgedevices")]
-    pub root_device: DeviceConfig,
+    pub ssh: Option<Ssh>,
+    pub bootstrap: Option<Bootstrap>,
+    pub hub_routing: Option<HubRouting>,
+    pub signing: Option<Signing>,
+    pub rollout: Option<Rollout>,
+    /// Maintenance windows of `push` and `deploy apply`. Devices in none can be changed anytime.
+    #[serde(default)]
+    pub schedule: Vec<MaintenanceWindow>,
+    #[serde(default)]
+    pub az_arguments: AzArguments,
+    /// Set by --namespace, not read from the file.
+    #[serde(skip)]
+    pub namespace: Option<String>,
+    /// Set by --disabled, not read from the file.
+    #[serde(skip)]
+    pub create_disabled: bool,
+    /// Set by --resume, not read from the file: the devices each phase was done for by the run it
+    /// picks up, by phase.
+    #[serde(skip)]
+    pub resumed: BTreeMap<String, Vec<String>>,
+    /// Set unless --gitops, not read from the file. Generated files are stamped with the run's
+    /// time when set.
+    #[serde(skip)]
+    pub stamp_time: bool,
+    /// SHA-256 of the config file, set when it is read.
+    #[serde(skip)]
+    pub source_hash: Option<String>,
+    /// The top devices of the hierarchy, nested. `edgedevices` is one device, or a list of them
+    /// for standalone hierarchies such as one per site. Built from `devices` when the config has
+    /// those instead.
+    #[serde(rename = "edgedevices", default, with = "one_or_many")]
+    pub root_devices: Vec<DeviceConfig>,
+    /// The hierarchy as a flat list with parent references, in place of `edgedevices`.
+    #[serde(default, skip_serializing)]
+    pub devices: Vec<FlatDevice>,
 }
 
 #[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
 pub struct IoTHub {
+    #[serde(default)]
     pub iothub_hostname: String,
+    #[serde(default)]
     pub iothub_name: String,
     pub authentication_method: IoTHubAuthMethod,
+    /// Overridden by the IOTHUB_CONNECTION_STRING environment variable.
+    pub connection_string: Option<String>,
+    pub event_grid: Option<EventGrid>,
+}
+
+/// Event Grid subscription that is sent the hub's device created and deleted events.
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+pub struct EventGrid {
+    pub subscription_name: String,
+    pub endpoint: String,
+    pub endpoint_type: Option<String>,
+}
+
+/// Message routing of the hub set up with the hierarchy, such as routing its telemetry to an
+/// endpoint of its own.
+#[derive(Clone, Debug, Default, serde::Deserialize, serde::Serialize)]
+pub struct HubRouting {
+    /// Consumer groups of the built-in events endpoint, created if missing.
+    #[serde(default)]
+    pub consumer_groups: Vec<String>,
+    #[serde(default)]
+    pub endpoints: Vec<RoutingEndpoint>,
+    #[serde(default)]
+    pub routes: Vec<HubRoute>,
+}
+
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+pub struct RoutingEndpoint {
+    pub name: String,
+    /// eventhub, servicebusqueue, servicebustopic or azurestoragecontainer.
+    pub endpoint_type: String,
+    pub connection_string: String,
+    /// Container of azurestoragecontainer endpoints.
+    pub container: Option<String>,
+    /// Resource group of the endpoint's resource. Defaults to the hub's.
+    pub resource_group: Option<String>,
+}
+
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+pub struct HubRoute {
+    pub name: String,
+    /// Name of the endpoint messages go to. Defaults to `events`, the built-in endpoint.
+    pub endpoint: Option<String>,
+    /// Defaults to `devicemessages`.
+    pub source: Option<String>,
+    /// Routing query. Defaults to messages of the devices this tool created, in the namespace
+    /// with --namespace.
+    pub condition: Option<String>,
 }
 
 #[derive(Clone, Debug, serde::Deserialize, serde::Serialize, PartialEq)]
@@ -27,32 +131,728 @@ pub enum IoTHubAuthMethod {
     X509Cert,
 }
 
+#[derive(Clone, Debug, Default, serde::Deserialize, serde::Serialize)]
+pub struct Dps {
+    #[serde(default)]
+    pub dps_name: String,
+    pub id_scope: Option<String>,
+    /// Symmetric key enrollment group used by dps_symmetric_key devices. Created if it does not exist.
+    pub enrollment_group: Option<String>,
+    /// Overridden by the IOTHUB_DPS_CONNECTION_STRING environment variable.
+    pub connection_string: Option<String>,
+}
+
 #[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
 pub struct Certificates {
     pub root_ca_cert_path: String,
     pub root_ca_cert_key_path: String,
+    /// Key Vault secret id holding the passphrase of an encrypted root key. The
+    /// IOTEDGE_CONFIG_ROOT_CA_PASSPHRASE environment variable takes precedence.
+    pub root_ca_cert_key_passphrase_secret: Option<String>,
+}
+
+/// File names of the generated certs and keys. `{device_id}` is replaced with the device's id.
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct CertNames {
+    pub root_ca_cert: String,
+    pub device_ca_cert: String,
+    pub device_ca_key: String,
+    pub device_ca_chain: String,
+    pub hub_auth_cert: String,
+    pub hub_auth_key: String,
+}
+
+impl Default for CertNames {
+    fn default() -> Self {
+        Self {
+            root_ca_cert: "iotedge_config_cli_root.pem".to_owned(),
+            device_ca_cert: "{device_id}.cert.pem".to_owned(),
+            device_ca_key: "{device_id}.key.pem".to_owned(),
+            device_ca_chain: "{device_id}.full-chain.cert.pem".to_owned(),
+            hub_auth_cert: "{device_id}.hub-auth.cert.pem".to_owned(),
+            hub_auth_key: "{device_id}.hub-auth.key.pem".to_owned(),
+        }
+    }
+}
+
+impl CertNames {
+    pub fn device_ca_cert(&self, device_id: &str) -> String {
+        self.device_ca_cert.replace("{device_id}", device_id)
+    }
+
+    pub fn device_ca_key(&self, device_id: &str) -> String {
+        self.device_ca_key.replace("{device_id}", device_id)
+    }
+
+    pub fn device_ca_chain(&self, device_id: &str) -> String {
+        self.device_ca_chain.replace("{device_id}", device_id)
+    }
+
+    pub fn hub_auth_cert(&self, device_id: &str) -> String {
+        self.hub_auth_cert.replace("{device_id}", device_id)
+    }
+
+    pub fn hub_auth_key(&self, device_id: &str) -> String {
+        self.hub_auth_key.replace("{device_id}", device_id)
+    }
+}
+
+#[derive(Clone, Copy, Debug, serde::Deserialize, serde::Serialize, PartialEq)]
+pub enum KeyAlgorithm {
+    #[serde(rename = "rsa_2048")]
+    Rsa2048,
+    #[serde(rename = "rsa_3072")]
+    Rsa3072,
+    #[serde(rename = "rsa_4096")]
+    Rsa4096,
+    #[serde(rename = "ecdsa_p256")]
+    EcdsaP256,
+    #[serde(rename = "ecdsa_p384")]
+    EcdsaP384,
+}
+
+impl KeyAlgorithm {
+    /// Arguments for `openssl req` to generate a new key of this type.
+    pub fn newkey_args(self) -> &'static [&'static str] {
+        match self {
+            Self::Rsa2048 => &["-newkey", "rsa:2048"],
+            Self::Rsa3072 => &["-newkey", "rsa:3072"],
+            Self::Rsa4096 => &["-newkey", "rsa:4096"],
+            Self::EcdsaP256 => &["-newkey", "ec", "-pkeyopt", "ec_paramgen_curve:P-256"],
+            Self::EcdsaP384 => &["-newkey", "ec", "-pkeyopt", "ec_paramgen_curve:P-384"],
+        }
+    }
+
+    /// Approximate security strength in bits, per NIST SP 800-57.
+    pub fn security_bits(self) -> u32 {
+        match self {
+            Self::Rsa2048 => 112,
+            Self::Rsa3072 | Self::EcdsaP256 => 128,
+            Self::Rsa4096 => 140,
+            Self::EcdsaP384 => 192,
+        }
+    }
+}
+
+/// Key algorithms of the generated keys. Root and device keys can differ, for example an EC root
+/// signing RSA device certs for hardware that only supports RSA.
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct KeyAlgorithms {
+    /// Only used for the self-signed root made when no root CA is given.
+    pub root_ca: KeyAlgorithm,
+    pub device_ca: KeyAlgorithm,
+    pub hub_auth: KeyAlgorithm,
+}
+
+impl Default for KeyAlgorithms {
+    fn default() -> Self {
+        Self {
+            root_ca: KeyAlgorithm::Rsa4096,
+            device_ca: KeyAlgorithm::Rsa4096,
+            hub_auth: KeyAlgorithm::Rsa4096,
+        }
+    }
+}
+
+impl KeyAlgorithms {
+    /// Problems with the combination of algorithms. A chain is only as strong as its weakest key,
+    /// so a device CA stronger than the root that signs it gains nothing.
+    pub fn warnings(&self, custom_root: bool) -> Vec<String> {
+        let mut warnings = Vec::new();
+        if !custom_root && self.device_ca.security_bits() > self.root_ca.security_bits() {
+            warnings.push(format!(
+                "device_ca key {:?} is stronger than the root_ca key {:?} that signs it. The chain is only as strong as {:?}.",
+                self.device_ca, self.root_ca, self.root_ca
+            ));
+        }
+
+        warnings
+    }
+}
+
+/// How SSH operations reach devices that are not directly reachable.
+#[derive(Clone, Debug, Default, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct Ssh {
+    /// Bastion every connection jumps through first, as [user@]host[:port].
+    pub proxy_jump: Option<String>,
+    /// Reaches lower layer devices by jumping through their parent devices' hostnames.
+    pub jump_through_parents: bool,
+    /// ssh config file to use instead of ~/.ssh/config.
+    pub config_file: Option<String>,
+}
+
+/// Signs the generated files and zips with cosign or Notation, so their provenance can be checked
+/// before they are applied.
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+pub struct Signing {
+    pub tool: SigningTool,
+    /// cosign: path or KMS URI of the private key. notation: name of a key in `notation key list`.
+    pub key: String,
+    /// cosign: public key copied into each bundle as signing.pub, for install.sh to verify with.
+    pub public_key: Option<String>,
+    /// install.sh verifies config.toml and deployment.json before applying them. The tool must be
+    /// installed on the devices, and notation needs a trust policy for the key there.
+    #[serde(default)]
+    pub verify_on_install: bool,
+}
+
+#[derive(Clone, Copy, Debug, serde::Deserialize, serde::Serialize, PartialEq)]
+pub enum SigningTool {
+    #[serde(rename = "cosign")]
+    Cosign,
+    #[serde(rename = "notation")]
+    Notation,
+}
+
+/// Order `deploy apply` and `push` go through the devices in, in waves with a health check
+/// between them.
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+pub struct Rollout {
+    pub strategy: RolloutStrategy,
+    /// canary: share of the devices done by the end of each wave, in percent. The devices that
+    /// are left go in a last wave.
+    #[serde(default = "default_canary_percentages")]
+    pub percentages: Vec<u8>,
+    /// Wait after each wave before its health check, 60 if not given.
+    pub pause_seconds: Option<u64>,
+    /// How long a wave's devices have to be connected to the hub, 300 if not given.
+    pub health_timeout_seconds: Option<u64>,
+    /// Command that also has to succeed after each wave, with the wave's device ids in
+    /// IOTEDGE_ROLLOUT_DEVICES.
+    pub health_check: Option<String>,
+    /// Checks run on each wave's devices, online if not given.
+    #[serde(default = "default_rollout_checks")]
+    pub checks: Vec<RolloutCheck>,
+    /// Share of a wave's devices, in percent, that may fail the operation or a check before the
+    /// rollout stops. 0 if not given.
+    #[serde(default)]
+    pub max_failure_percent: f64,
+}
+
+fn default_rollout_checks() -> Vec<RolloutCheck> {
+    vec![RolloutCheck::Online]
+}
+
+#[derive(Clone, Copy, Debug, serde::Deserialize, serde::Serialize, PartialEq)]
+pub enum RolloutCheck {
+    /// The device is connected to the hub, as with `wait-online`.
+    #[serde(rename = "online")]
+    Online,
+    /// The config.toml and certs on the device match the generated ones, as with `drift`. Only
+    /// run by `push`, which reaches the devices.
+    #[serde(rename = "drift")]
+    Drift,
+}
+
+/// Times a site's devices may be changed by `push` and `deploy apply`. A run outside the window
+/// queues the devices in state.json, and the next `push` or `deploy apply` resumes with them.
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+pub struct MaintenanceWindow {
+    /// Name of the site, for the log.
+    pub name: String,
+    /// Devices of the site, a selection like --select's. all if not given.
+    pub devices: Option<String>,
+    /// Days the window starts on, such as mon or saturday. Every day if empty.
+    #[serde(default)]
+    pub days: Vec<String>,
+    /// Start and end, HH:MM in the site's time. A window that ends before it starts ends the
+    /// next day.
+    pub start: String,
+    pub end: String,
+    /// UTC offset of the site's time, such as +02:00. UTC if not given.
+    pub utc_offset: Option<String>,
+}
+
+fn default_canary_percentages() -> Vec<u8> {
+    vec![10, 50]
+}
+
+#[derive(Clone, Copy, Debug, serde::Deserialize, serde::Serialize, PartialEq)]
+pub enum RolloutStrategy {
+    /// Every device in one wave.
+    #[serde(rename = "all")]
+    All,
+    /// Growing shares of the devices, in hierarchy order.
+    #[serde(rename = "canary")]
+    Canary,
+    /// One layer at a time, top layer first.
+    #[serde(rename = "layers")]
+    Layers,
+}
+
+/// Blob container the device bundles are uploaded to for the bootstrap commands.
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+pub struct Bootstrap {
+    pub storage_account: String,
+    pub container: String,
+    /// How long the signed URLs stay valid, 24 hours if not given.
+    pub expiry_hours: Option<i64>,
+}
+
+/// Extra arguments appended to the az commands of each hub operation, for identity options this
+/// tool has no setting for. Ex: `create: ["--status", "disabled"]`.
+#[derive(Clone, Debug, Default, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct AzArguments {
+    /// `az iot hub device-identity create`, also used when migrating devices.
+    pub create: Vec<String>,
+    /// `az iot hub device-identity parent set`.
+    pub parent: Vec<String>,
+    /// `az iot edge set-modules`.
+    pub deployment: Vec<String>,
+    /// `az iot hub device-twin update`, for twin templates and the creation tag.
+    pub twin: Vec<String>,
+    /// `az iot hub device-identity delete`.
+    pub delete: Vec<String>,
+}
+
+impl AzArguments {
+    /// Arguments this tool sets itself, which would point the command at another device or hub.
+    const RESERVED: &'static [&'static str] = &[
+        "--device-id",
+        "-d",
+        "--hub-name",
+        "-n",
+        "--parent-device-id",
+        "--pd",
+        "--login",
+        "-l",
+    ];
+
+    /// False if `create` turns off `--edge-enabled`, making leaf devices that can't have children.
+    pub fn edge_enabled(&self) -> bool {
+        let mut args = self.create.iter().map(String::as_str).peekable();
+        let mut enabled = true;
+        while let Some(arg) = args.next() {
+            enabled = match arg {
+                "--edge-enabled" | "--ee" => !matches!(args.peek(), Some(&"false")),
+                "--edge-enabled=false" | "--ee=false" => false,
+                "--edge-enabled=true" | "--ee=true" => true,
+                _ => enabled,
+            };
+        }
+
+        enabled
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        let phases = [
+            ("create", &self.create),
+            ("parent", &self.parent),
+            ("deployment", &self.deployment),
+            ("twin", &self.twin),
+            ("delete", &self.delete),
+        ];
+        for (phase, args) in phases.iter() {
+            if let Some(arg) = args
+                .iter()
+                .find(|a| Self::RESERVED.contains(&a.split('=').next().unwrap_or_default()))
+            {
+                return Err(format!(
+                    "az_arguments.{} can't contain {}, it is set by iotedge_config",
+                    phase, arg
+                ));
+            }
+        }
+
+        Ok(())
+    }
 }
 
 #[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
 pub struct Configuration {
     pub template_config_path: String,
     pub default_edge_agent: String,
+    /// Settings per layer of the hierarchy, starting with the top layer.
+    #[serde(default)]
+    pub layers: Vec<Layer>,
+    /// Messages per second a gateway can forward upstream. `config lint` warns about gateways
+    /// expected to forward more.
+    pub gateway_capacity: Option<f64>,
+}
+
+impl Configuration {
+    pub fn twin_template(&self, layer: usize) -> Option<&str> {
+        self.layers
+            .get(layer)
+            .and_then(|l| l.twin_template.as_deref())
+    }
+}
+
+#[derive(Clone, Debug, Default, serde::Deserialize, serde::Serialize)]
+pub struct Layer {
+    /// Path to twin JSON with tags and properties.desired, merged into the twin of every device
+    /// of the layer. {device_id} and {parent_id} are replaced.
+    pub twin_template: Option<String>,
+    /// Routes generated into the deployments of the layer's devices, replacing their own.
+    pub routes: Option<LayerRoutes>,
+    /// edgeHub settings set in the deployments of the layer's devices.
+    pub edge_hub: Option<EdgeHubSettings>,
+    /// Protocol edgeAgent and edgeHub of the layer's devices use to reach their parent or the hub.
+    pub upstream_protocol: Option<UpstreamProtocol>,
+    /// Port the layer's devices pull `$upstream` images from on their parent, 443 if not given.
+    pub parent_port: Option<u16>,
+}
+
+/// The `UpstreamProtocol` of edgeAgent and edgeHub. The WebSocket ones only need port 443 open
+/// to the parent, for restrictive firewalls.
+#[derive(Clone, Copy, Debug, serde::Deserialize, serde::Serialize, PartialEq)]
+pub enum UpstreamProtocol {
+    Amqp,
+    AmqpWs,
+    Mqtt,
+    MqttWs,
+}
+
+impl UpstreamProtocol {
+    pub fn port(self) -> u16 {
+        match self {
+            UpstreamProtocol::Amqp => 5671,
+            UpstreamProtocol::Mqtt => 8883,
+            UpstreamProtocol::AmqpWs | UpstreamProtocol::MqttWs => 443,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct EdgeHubSettings {
+    /// Set to false on devices with little memory.
+    pub optimize_for_performance: Option<bool>,
+    /// How long messages are kept while the upstream is unreachable.
+    pub store_and_forward_ttl_secs: Option<u32>,
+    pub authentication_mode: Option<EdgeHubAuthenticationMode>,
+}
+
+/// How edgeHub authenticates modules and child devices.
+#[derive(Clone, Copy, Debug, serde::Deserialize, serde::Serialize, PartialEq)]
+pub enum EdgeHubAuthenticationMode {
+    /// Only with the device scope synced from the hub, so it works offline.
+    Scope,
+    /// With the device scope, falling back to the hub.
+    CloudAndScope,
+    Cloud,
+}
+
+/// Routing intent of a layer, turned into edgeHub routes.
+#[derive(Clone, Debug, Default, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct LayerRoutes {
+    /// Forwards all messages to the parent device, or to the hub from the top layer.
+    pub upstream: bool,
+    /// Routes between modules of the same device.
+    pub local: Vec<LocalRoute>,
+    /// MQTT broker topics bridged with the parent device.
+    pub bridge: Vec<BridgeTopic>,
+}
+
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+pub struct LocalRoute {
+    pub from: String,
+    /// Output of the `from` module. All outputs if not given.
+    pub output: Option<String>,
+    pub to: String,
+    pub input: String,
 }
 
 #[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+pub struct BridgeTopic {
+    pub topic: String,
+    pub direction: BridgeDirection,
+    pub in_prefix: Option<String>,
+    pub out_prefix: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, serde::Deserialize, serde::Serialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum BridgeDirection {
+    /// From the parent to this device.
+    In,
+    /// From this device to the parent.
+    Out,
+    Both,
+}
+
+#[derive(Clone, Debug, Default, serde::Deserialize, serde::Serialize)]
 pub struct DeviceConfig {
     pub device_id: String,
     pub deployment: Option<String>,
     pub hostname: Option<String>,
+    /// Address the hostname resolves to, for labs without DNS. Hostnames with one are not looked
+    /// up by `config check-hostnames`.
+    pub ip_address: Option<std::net::IpAddr>,
     pub edge_agent: Option<String>,
     pub container_auth: Option<ContainerAuth>,
+    /// Path to openssl extension lines merged into the [ v3_ca ] section used to sign the device CA.
+    pub openssl_extensions: Option<String>,
+    #[serde(default)]
+    pub provisioning: DeviceProvisioning,
+    /// Endorsement key of the device's TPM, for tpm provisioning.
+    pub tpm_endorsement_key: Option<String>,
+    /// DPS registration id. Defaults to the device id.
+    pub registration_id: Option<String>,
+    /// SSH jump hosts to reach the device through, overriding the ssh settings.
+    pub ssh_proxy_jump: Option<String>,
+    /// Environment variables of edgeAgent, added to `[agent.env]` of the device's config.toml.
+    /// Ex: `https_proxy`, `UpstreamProtocol`.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub env: BTreeMap<String, String>,
+    /// Messages per second the device's own modules are expected to send upstream, for the
+    /// gateway load estimate of `config lint`.
+    pub messages_per_second: Option<f64>,
+    /// Grouping of the device, inherited by the devices under it that set none. Set as twin tags,
+    /// selectable with `site:`, `region:` and `environment:`, and summarized in run.json.
+    pub site: Option<String>,
+    pub region: Option<String>,
+    pub environment: Option<String>,
+    /// Gateway already in the hub that this top device is attached under. It is not created,
+    /// changed or deleted.
+    pub external_parent: Option<ExternalParent>,
+    /// What the device runs IoT Edge on. `eflow` devices also get an install.ps1.
+    #[serde(default)]
+    pub os: DeviceOs,
     #[serde(default, rename = "child")]
     pub children: Vec<DeviceConfig>,
 }
 
+impl DeviceConfig {
+    pub fn registration_id(&self) -> &str {
+        self.registration_id.as_deref().unwrap_or(&self.device_id)
+    }
+
+    /// The device with this id, searching this device and the devices under it.
+    pub fn find(&self, device_id: &str) -> Option<&DeviceConfig> {
+        if self.device_id == device_id {
+            return Some(self);
+        }
+
+        self.children.iter().find_map(|c| c.find(device_id))
+    }
+
+    /// Gives the devices under this one the grouping they leave out.
+    pub fn inherit_groups(&mut self) {
+        for child in &mut self.children {
+            if child.site.is_none() {
+                child.site = self.site.clone();
+            }
+            if child.region.is_none() {
+                child.region = self.region.clone();
+            }
+            if child.environment.is_none() {
+                child.environment = self.environment.clone();
+            }
+            child.inherit_groups();
+        }
+    }
+
+    /// The grouping fields that are set, by name.
+    pub fn groups(&self) -> Vec<(&'static str, &str)> {
+        [
+            ("site", &self.site),
+            ("region", &self.region),
+            ("environment", &self.environment),
+        ]
+        .iter()
+        .filter_map(|(name, value)| Some((*name, value.as_deref()?)))
+        .collect()
+    }
+}
+
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+pub struct ExternalParent {
+    pub device_id: String,
+    /// Written as parent_hostname of the device's config.toml. The install script prompts for it
+    /// without one.
+    pub hostname: Option<String>,
+}
+
+/// A device of the flat `devices` list.
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+pub struct FlatDevice {
+    /// Device id of the parent. Only the top device has none.
+    pub parent: Option<String>,
+    #[serde(flatten)]
+    pub device: DeviceConfig,
+}
+
+impl FlatDevice {
+    /// Builds the nested hierarchies, one per top device, keeping the order of the list among
+    /// siblings and top devices. Fails on duplicate ids, parents that are not in the list, no top
+    /// device and cycles.
+    pub fn build_hierarchies(devices: Vec<FlatDevice>) -> Result<Vec<DeviceConfig>, String> {
+        let mut ids = HashSet::new();
+        if let Some(duplicate) = devices.iter().find(|d| !ids.insert(&d.device.device_id)) {
+            return Err(format!(
+                r#"device id "{}" is used twice in devices"#,
+                duplicate.device.device_id
+            ));
+        }
+
+        let orphans: Vec<String> = devices
+            .iter()
+            .filter_map(|d| {
+                let parent = d.parent.as_ref()?;
+                if ids.contains(parent) {
+                    None
+                } else {
+                    Some(format!("{} (parent {})", d.device.device_id, parent))
+                }
+            })
+            .collect();
+        if !orphans.is_empty() {
+            return Err(format!(
+                "These devices have a parent that is not in devices: {}",
+                orphans.join(", ")
+            ));
+        }
+
+        let roots: Vec<usize> = (0..devices.len())
+            .filter(|&i| devices[i].parent.is_none())
+            .collect();
+        if roots.is_empty() {
+            return Err("devices needs at least one top device without a parent".to_owned());
+        }
+
+        let mut children: HashMap<String, Vec<usize>> = HashMap::new();
+        for (i, device) in devices.iter().enumerate() {
+            if let Some(parent) = &device.parent {
+                children.entry(parent.clone()).or_default().push(i);
+            }
+        }
+        let mut devices: Vec<Option<DeviceConfig>> =
+            devices.into_iter().map(|d| Some(d.device)).collect();
+        let roots: Vec<DeviceConfig> = roots
+            .into_iter()
+            .map(|root| Self::take_subtree(root, &mut devices, &children))
+            .collect();
+
+        // Every device has a parent in the list, so the ones not reached from a top device are in a
+        // cycle or under one
+        let cycle: Vec<String> = devices.into_iter().flatten().map(|d| d.device_id).collect();
+        if !cycle.is_empty() {
+            return Err(format!(
+                "These devices are in a cycle of parents, or under one: {}",
+                cycle.join(", ")
+            ));
+        }
+
+        Ok(roots)
+    }
+
+    fn take_subtree(
+        index: usize,
+        devices: &mut [Option<DeviceConfig>],
+        children: &HashMap<String, Vec<usize>>,
+    ) -> DeviceConfig {
+        let mut device = devices[index].take().unwrap_or_default();
+        for &child in children.get(&device.device_id).into_iter().flatten() {
+            if devices[child].is_some() {
+                let child = Self::take_subtree(child, devices, children);
+                device.children.push(child);
+            }
+        }
+
+        device
+    }
+}
+
+/// How a device gets its hub identity.
+#[derive(Clone, Copy, Debug, serde::Deserialize, serde::Serialize, PartialEq)]
+pub enum DeviceProvisioning {
+    /// Created in the hub by this tool, using iothub.authentication_method.
+    #[serde(rename = "hub")]
+    Hub,
+    /// Provisions through DPS using a TPM individual enrollment.
+    #[serde(rename = "tpm")]
+    Tpm,
+    /// Provisions through DPS with a key derived from a symmetric key enrollment group.
+    #[serde(rename = "dps_symmetric_key")]
+    DpsSymmetricKey,
+}
+
+impl Default for DeviceProvisioning {
+    fn default() -> Self {
+        Self::Hub
+    }
+}
+
+/// What a device runs IoT Edge on.
+#[derive(Clone, Copy, Debug, serde::Deserialize, serde::Serialize, PartialEq)]
+pub enum DeviceOs {
+    #[serde(rename = "linux")]
+    Linux,
+    /// IoT Edge for Linux on Windows. install.ps1 deploys the EFLOW VM if needed, copies the
+    /// device files into it and runs install.sh there.
+    #[serde(rename = "eflow")]
+    Eflow,
+}
+
+impl Default for DeviceOs {
+    fn default() -> Self {
+        Self::Linux
+    }
+}
+
 #[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
 pub struct ContainerAuth {
     pub serveraddress: String,
     pub username: String,
     pub password: String,
 }
+
+/// `edgedevices` as one device or a list of them. Written back as one device when there is one, so
+/// single hierarchy configs resolve the way they are written.
+mod one_or_many {
+    use std::fmt;
+
+    use serde::de::value::{MapAccessDeserializer, SeqAccessDeserializer};
+    use serde::de::{MapAccess, SeqAccess};
+    use serde::{Deserialize, Serialize};
+
+    use super::DeviceConfig;
+
+    pub fn serialize<S>(devices: &[DeviceConfig], serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        match devices {
+            [device] => device.serialize(serializer),
+            devices => devices.serialize(serializer),
+        }
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<Vec<DeviceConfig>, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        deserializer.deserialize_any(Visitor)
+    }
+
+    /// Dispatches on the shape rather than trying both, so errors inside a device keep its path.
+    struct Visitor;
+
+    impl<'de> serde::de::Visitor<'de> for Visitor {
+        type Value = Vec<DeviceConfig>;
+
+        fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.write_str("a device or a list of devices")
+        }
+
+        fn visit_map<A>(self, map: A) -> Result<Self::Value, A::Error>
+        where
+            A: MapAccess<'de>,
+        {
+            DeviceConfig::deserialize(MapAccessDeserializer::new(map)).map(|d| vec![d])
+        }
+
+        fn visit_seq<A>(self, seq: A) -> Result<Self::Value, A::Error>
+        where
+            A: SeqAccess<'de>,
+        {
+            Vec::deserialize(SeqAccessDeserializer::new(seq))
+        }
+    }
+}
@@ -0,0 +1,348 @@
+use std::path::PathBuf;
+
+use anyhow::{Context, Result};
+use serde_json::{json, Map, Value};
+use tokio::fs;
+
+use crate::cancel::CancellationToken;
+use crate::commands::CommandRunner;
+use crate::config;
+use crate::log::LogLevel;
+use crate::rollout::{self, RolloutManager};
+use crate::schedule::ScheduleManager;
+use crate::tags::Selection;
+use crate::throttle::{HubOperation, HubThrottle};
+use crate::{quote_arg, FileManager, FlatenedDevice, IoTHubDeviceManager};
+
+/// Where the deployment a device had before `deploy apply` is kept, in its output folder.
+const PREVIOUS_DEPLOYMENT: &str = "deployment.previous.json";
+
+/// Modules every deployment sets, which set-modules takes off the device when they are missing.
+const SYSTEM_MODULES: &[&str] = &["$edgeAgent", "$edgeHub"];
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ModuleTwin {
+    module_id: String,
+    #[serde(default)]
+    desired: Map<String, Value>,
+}
+
+/// Sets the config's deployments on devices that already exist, keeping what each device had
+/// before so a manifest that breaks a layer can be undone with `deploy rollback`.
+pub struct DeployManager<'a> {
+    config: &'a config::Config,
+    file_manager: &'a FileManager,
+    hub_manager: &'a IoTHubDeviceManager<'a>,
+    throttle: &'a HubThrottle,
+    runner: &'a CommandRunner,
+    rollout: RolloutManager<'a>,
+}
+
+impl<'a> DeployManager<'a> {
+    pub fn new(
+        config: &'a config::Config,
+        file_manager: &'a FileManager,
+        hub_manager: &'a IoTHubDeviceManager<'a>,
+        throttle: &'a HubThrottle,
+        runner: &'a CommandRunner,
+        cancel: &'a CancellationToken,
+    ) -> Self {
+        Self {
+            config,
+            file_manager,
+            hub_manager,
+            throttle,
+            runner,
+            rollout: RolloutManager::new(config, file_manager, throttle, runner, cancel),
+        }
+    }
+
+    /// Records the current deployment of every selected device with a deployment in the config,
+    /// then sets the new one, in the waves of `rollout`. A wave with more failed devices than
+    /// `max_failure_percent` stops the rollout. Devices outside their maintenance window are left
+    /// for a later `deploy apply`.
+    pub async fn apply(&self, selection: &Selection, schedule: &ScheduleManager<'_>) -> Result<()> {
+        let devices = FlatenedDevice::flatten_devices(&self.config.root_devices);
+        let selected: Vec<FlatenedDevice> = selection
+            .select(&devices)?
+            .into_iter()
+            .copied()
+            .filter(|d| {
+                d.device.deployment.is_some()
+                    && d.device.provisioning == config::DeviceProvisioning::Hub
+            })
+            .collect();
+        let selected = schedule.due("deploy", selected).await?;
+        self.file_manager
+            .log(
+                LogLevel::Info,
+                "deploy",
+                None,
+                format!("Applying the deployments of {} devices", selected.len()),
+            )
+            .await?;
+
+        let waves = rollout::waves(self.config.rollout.as_ref(), selected, |d| d.layer);
+        let mut all_failed = Vec::new();
+        for (i, wave) in waves.iter().enumerate() {
+            self.rollout.start_wave(i, waves.len(), wave).await?;
+
+            let mut failed = Vec::new();
+            for device in wave {
+                let device_id = device.device.device_id.as_str();
+                let result = async {
+                    check_deployment(device).await?;
+                    self.record_previous(device_id).await?;
+                    self.hub_manager.deploy(device).await
+                }
+                .await;
+                if let Err(e) = result {
+                    self.file_manager
+                        .log(
+                            LogLevel::Error,
+                            "deploy",
+                            Some(device_id),
+                            format!("Failed to apply the deployment of {}: {:#}", device_id, e),
+                        )
+                        .await?;
+                    failed.push(device_id);
+                }
+            }
+            let succeeded: Vec<&str> = wave
+                .iter()
+                .map(|d| d.device.device_id.as_str())
+                .filter(|d| !failed.contains(d))
+                .collect();
+            schedule.done("deploy", &succeeded).await?;
+            self.rollout
+                .check_wave(i, waves.len(), wave, &failed, None)
+                .await?;
+            all_failed.extend(failed);
+        }
+
+        failed_devices("apply the deployments of", &all_failed)
+    }
+
+    /// Sets back the deployment each selected device had before the last `deploy apply`.
+    pub async fn rollback(&self, selection: &Selection) -> Result<()> {
+        let devices = FlatenedDevice::flatten_devices(&self.config.root_devices);
+        let selected = selection.select(&devices)?;
+
+        let mut failed = Vec::new();
+        for device in selected {
+            let device_id = device.device.device_id.as_str();
+            let previous = self.previous_path(device_id).await?;
+            if !previous.exists() {
+                if device.device.deployment.is_some() {
+                    self.file_manager
+                        .log(
+                            LogLevel::Warn,
+                            "deploy",
+                            Some(device_id),
+                            format!(
+                                "{} has no previous deployment to roll back to, deploy apply was not run for it.",
+                                device_id
+                            ),
+                        )
+                        .await?;
+                }
+                continue;
+            }
+            self.file_manager
+                .log(
+                    LogLevel::Info,
+                    "deploy",
+                    Some(device_id),
+                    format!("Rolling back the deployment of {}", device_id),
+                )
+                .await?;
+            if let Err(e) = self
+                .hub_manager
+                .set_deployment(device_id, &previous.to_string_lossy())
+                .await
+            {
+                self.file_manager
+                    .log(
+                        LogLevel::Error,
+                        "deploy",
+                        Some(device_id),
+                        format!("Failed to roll back {}: {:#}", device_id, e),
+                    )
+                    .await?;
+                failed.push(device_id);
+            }
+        }
+
+        failed_devices("roll back", &failed)
+    }
+
+    /// Writes the device's current module twins as a deployment that `az iot edge set-modules`
+    /// takes back.
+    async fn record_previous(&self, device_id: &str) -> Result<()> {
+        let query = format!(
+            "SELECT moduleId, properties.desired FROM devices.modules WHERE deviceId = '{}'",
+            device_id
+        );
+        self.throttle.wait(HubOperation::Twin).await;
+        let command = self
+            .runner
+            .output(&[
+                "az iot hub query",
+                "--hub-name",
+                &self.config.iothub.iothub_name,
+                "--query-command",
+                &quote_arg(&query),
+                "--top",
+                "-1",
+                "-o",
+                "json",
+            ])
+            .await?;
+        if !command.status.success() {
+            return Err(anyhow::Error::msg(format!(
+                "Failed to read the module twins of {}:\n{}",
+                device_id,
+                String::from_utf8_lossy(&command.stderr)
+            )));
+        }
+        let twins: Vec<ModuleTwin> =
+            serde_json::from_slice(&command.stdout).context("Error parsing hub query result")?;
+        let content = deployment_content(twins)
+            .with_context(|| format!("Can't keep the current deployment of {}", device_id))?;
+
+        let path = self.previous_path(device_id).await?;
+        self.file_manager
+            .log(
+                LogLevel::Debug,
+                "deploy",
+                Some(device_id),
+                format!("Writing the current deployment to {:?}", path),
+            )
+            .await?;
+        fs::write(&path, serde_json::to_string_pretty(&content)?).await?;
+
+        Ok(())
+    }
+
+    async fn previous_path(&self, device_id: &str) -> Result<PathBuf> {
+        Ok(self
+            .file_manager
+            .get_folder(device_id)
+            .await?
+            .join(PREVIOUS_DEPLOYMENT))
+    }
+}
+
+/// Deployment manifest setting the modules' desired properties back to the twins'. Fails without
+/// the twins of the system modules, since rolling back to it would take them off the device.
+fn deployment_content(twins: Vec<ModuleTwin>) -> Result<Value> {
+    let modules_content: Map<String, Value> = twins
+        .into_iter()
+        .map(|mut twin| {
+            twin.desired.remove("$metadata");
+            twin.desired.remove("$version");
+            (
+                twin.module_id,
+                json!({ "properties.desired": twin.desired }),
+            )
+        })
+        .collect();
+    let missing = missing_system_modules(&modules_content);
+    if !missing.is_empty() {
+        return Err(anyhow::Error::msg(format!(
+            "The hub has no module twins of {}",
+            missing.join(" and ")
+        )));
+    }
+
+    Ok(json!({ "modulesContent": modules_content }))
+}
+
+/// Fails if the device's deployment in the config would take the system modules off it.
+async fn check_deployment(device: &FlatenedDevice<'_>) -> Result<()> {
+    let path = match &device.device.deployment {
+        Some(path) => path,
+        None => return Ok(()),
+    };
+    let manifest = fs::read_to_string(path)
+        .await
+        .with_context(|| format!("Error reading deployment {}", path))?;
+    let manifest: Value = serde_json::from_str(&manifest)
+        .with_context(|| format!("Error parsing deployment {}", path))?;
+    let empty = Map::new();
+    let modules_content = manifest
+        .get("modulesContent")
+        .and_then(Value::as_object)
+        .unwrap_or(&empty);
+    let missing = missing_system_modules(modules_content);
+    if !missing.is_empty() {
+        return Err(anyhow::Error::msg(format!(
+            "Deployment {} has no {}, setting it would remove them from the device",
+            path,
+            missing.join(" and ")
+        )));
+    }
+
+    Ok(())
+}
+
+fn missing_system_modules(modules_content: &Map<String, Value>) -> Vec<&'static str> {
+    SYSTEM_MODULES
+        .iter()
+        .copied()
+        .filter(|m| !modules_content.contains_key(*m))
+        .collect()
+}
+
+fn failed_devices(action: &str, failed: &[&str]) -> Result<()> {
+    if failed.is_empty() {
+        Ok(())
+    } else {
+        Err(anyhow::Error::msg(format!(
+            "Failed to {} {}. See the log for details.",
+            action,
+            failed.join(", ")
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_deployment_content() {
+        let twins: Vec<ModuleTwin> = serde_json::from_str(
+            r#"[
+                {"moduleId": "$edgeAgent", "desired": {"schemaVersion": "1.1", "modules": {}, "$metadata": {}, "$version": 4}},
+                {"moduleId": "$edgeHub", "desired": {"routes": {"up": "FROM /messages/* INTO $upstream"}, "$version": 2}}
+            ]"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            deployment_content(twins).unwrap(),
+            json!({
+                "modulesContent": {
+                    "$edgeAgent": {"properties.desired": {"schemaVersion": "1.1", "modules": {}}},
+                    "$edgeHub": {"properties.desired": {"routes": {"up": "FROM /messages/* INTO $upstream"}}}
+                }
+            })
+        );
+    }
+
+    #[test]
+    fn test_deployment_content_without_system_modules() {
+        let twins: Vec<ModuleTwin> = serde_json::from_str(
+            r#"[{"moduleId": "$edgeAgent", "desired": {"schemaVersion": "1.1", "modules": {}}}]"#,
+        )
+        .unwrap();
+        assert!(deployment_content(twins)
+            .unwrap_err()
+            .to_string()
+            .contains("$edgeHub"));
+
+        assert!(deployment_content(Vec::new()).is_err());
+    }
+}
@@ -0,0 +1,392 @@
+use anyhow::Result;
+use serde_json::{json, Value};
+
+use crate::config;
+
+const EDGE_AGENT: &str = "/modulesContent/$edgeAgent/properties.desired";
+const EDGE_HUB: &str = "/modulesContent/$edgeHub/properties.desired";
+
+/// Checks a deployment manifest against the parts of the edgeAgent and edgeHub schemas that the
+/// hub does not check before accepting it. Each problem starts with the JSON path it was found at.
+pub fn validate_manifest(manifest: &str) -> Vec<String> {
+    let manifest: Value = match serde_json::from_str(manifest) {
+        Ok(manifest) => manifest,
+        Err(e) => return vec![format!("line {} column {}: {}", e.line(), e.column(), e)],
+    };
+
+    let mut problems = Vec::new();
+    match manifest.pointer(EDGE_AGENT) {
+        Some(agent) => validate_agent(agent, &mut problems),
+        None => problems.push(format!("{}: missing", EDGE_AGENT)),
+    }
+    match manifest.pointer(EDGE_HUB) {
+        Some(hub) => validate_hub(hub, &mut problems),
+        None => problems.push(format!("{}: missing", EDGE_HUB)),
+    }
+
+    problems
+}
+
+/// Sets edgeHub's environment variables and store and forward time to live from the layer's
+/// settings, leaving the rest of the manifest as is.
+pub fn apply_edge_hub_settings(
+    manifest: &mut Value,
+    settings: &config::EdgeHubSettings,
+) -> Result<()> {
+    let env = manifest
+        .pointer_mut(&format!("{}/systemModules/edgeHub", EDGE_AGENT))
+        .and_then(Value::as_object_mut)
+        .ok_or_else(|| anyhow::Error::msg("Deployment has no edgeHub system module"))?
+        .entry("env")
+        .or_insert_with(|| json!({}))
+        .as_object_mut()
+        .ok_or_else(|| anyhow::Error::msg("edgeHub env must be an object"))?;
+    if let Some(optimize) = settings.optimize_for_performance {
+        env.insert(
+            "OptimizeForPerformance".to_owned(),
+            json!({ "value": optimize.to_string() }),
+        );
+    }
+    if let Some(mode) = settings.authentication_mode {
+        env.insert(
+            "AuthenticationMode".to_owned(),
+            json!({ "value": format!("{:?}", mode) }),
+        );
+    }
+
+    if let Some(ttl) = settings.store_and_forward_ttl_secs {
+        manifest
+            .pointer_mut(EDGE_HUB)
+            .and_then(Value::as_object_mut)
+            .ok_or_else(|| anyhow::Error::msg("Deployment has no $edgeHub properties.desired"))?
+            .entry("storeAndForwardConfiguration")
+            .or_insert_with(|| json!({}))
+            .as_object_mut()
+            .ok_or_else(|| anyhow::Error::msg("storeAndForwardConfiguration must be an object"))?
+            .insert("timeToLiveSecs".to_owned(), json!(ttl));
+    }
+
+    Ok(())
+}
+
+/// Sets the upstream protocol of edgeAgent and edgeHub, and points the `$upstream` images of all
+/// modules at the parent's port.
+pub fn apply_upstream(
+    manifest: &mut Value,
+    protocol: Option<config::UpstreamProtocol>,
+    parent_port: Option<u16>,
+) -> Result<()> {
+    let agent = manifest
+        .pointer_mut(EDGE_AGENT)
+        .and_then(Value::as_object_mut)
+        .ok_or_else(|| anyhow::Error::msg("Deployment has no $edgeAgent properties.desired"))?;
+
+    if let Some(protocol) = protocol {
+        for system_module in &["edgeAgent", "edgeHub"] {
+            agent
+                .get_mut("systemModules")
+                .and_then(|m| m.get_mut(*system_module))
+                .and_then(Value::as_object_mut)
+                .ok_or_else(|| {
+                    anyhow::Error::msg(format!("Deployment has no {} system module", system_module))
+                })?
+                .entry("env")
+                .or_insert_with(|| json!({}))
+                .as_object_mut()
+                .ok_or_else(|| {
+                    anyhow::Error::msg(format!("{} env must be an object", system_module))
+                })?
+                .insert(
+                    "UpstreamProtocol".to_owned(),
+                    json!({ "value": format!("{:?}", protocol) }),
+                );
+        }
+    }
+
+    if let Some(port) = parent_port {
+        for group in &["systemModules", "modules"] {
+            let modules = match agent.get_mut(*group).and_then(Value::as_object_mut) {
+                Some(modules) => modules,
+                None => continue,
+            };
+            for module in modules.values_mut() {
+                if let Some(image) = module.pointer_mut("/settings/image") {
+                    if let Some(rewritten) = image.as_str().map(|i| with_parent_port(i, port)) {
+                        *image = Value::String(rewritten);
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// `$upstream:<port>/name` for images pulled through the parent, other images as they are.
+pub fn with_parent_port(image: &str, port: u16) -> String {
+    match image.strip_prefix("$upstream") {
+        Some(rest) if rest.starts_with('/') || rest.starts_with(':') => {
+            let name = rest.find('/').map_or("", |i| &rest[i..]);
+            format!("$upstream:{}{}", port, name)
+        }
+        _ => image.to_owned(),
+    }
+}
+
+fn validate_agent(agent: &Value, problems: &mut Vec<String>) {
+    require_string(agent, EDGE_AGENT, "schemaVersion", problems);
+    match agent.get("runtime") {
+        Some(runtime) => {
+            let path = format!("{}/runtime", EDGE_AGENT);
+            require_string(runtime, &path, "type", problems);
+        }
+        None => problems.push(format!("{}/runtime: missing", EDGE_AGENT)),
+    }
+
+    for system_module in &["edgeAgent", "edgeHub"] {
+        let path = format!("{}/systemModules/{}", EDGE_AGENT, system_module);
+        match agent.pointer(&format!("/systemModules/{}", system_module)) {
+            Some(module) => validate_module(module, &path, *system_module == "edgeHub", problems),
+            None => problems.push(format!("{}: missing", path)),
+        }
+    }
+
+    if let Some(modules) = agent.get("modules") {
+        match modules.as_object() {
+            Some(modules) => {
+                for (name, module) in modules {
+                    let path = format!("{}/modules/{}", EDGE_AGENT, name);
+                    validate_module(module, &path, true, problems);
+                }
+            }
+            None => problems.push(format!("{}/modules: must be an object", EDGE_AGENT)),
+        }
+    }
+}
+
+/// edgeAgent itself is the only module that has no status or restart policy.
+fn validate_module(module: &Value, path: &str, managed: bool, problems: &mut Vec<String>) {
+    require_string(module, path, "type", problems);
+    if managed {
+        require_string(module, path, "status", problems);
+        require_string(module, path, "restartPolicy", problems);
+    }
+
+    let settings = match module.get("settings") {
+        Some(settings) => settings,
+        None => {
+            problems.push(format!("{}/settings: missing", path));
+            return;
+        }
+    };
+    let settings_path = format!("{}/settings", path);
+    require_string(settings, &settings_path, "image", problems);
+
+    // Long create options are split into createOptions, createOptions01, createOptions02...
+    let mut create_options = String::new();
+    for i in 0.. {
+        let key = if i == 0 {
+            "createOptions".to_owned()
+        } else {
+            format!("createOptions{:02}", i)
+        };
+        match settings.get(&key) {
+            Some(Value::String(part)) => create_options.push_str(part),
+            Some(_) => {
+                problems.push(format!(
+                    "{}/{}: must be a string of stringified JSON",
+                    settings_path, key
+                ));
+                return;
+            }
+            None => break,
+        }
+    }
+    if !create_options.is_empty() {
+        if let Err(e) = serde_json::from_str::<Value>(&create_options) {
+            problems.push(format!(
+                "{}/createOptions: is not valid JSON at column {}: {}",
+                settings_path,
+                e.column(),
+                e
+            ));
+        }
+    }
+}
+
+fn validate_hub(hub: &Value, problems: &mut Vec<String>) {
+    require_string(hub, EDGE_HUB, "schemaVersion", problems);
+
+    let routes = match hub.get("routes").and_then(Value::as_object) {
+        Some(routes) => routes,
+        None => {
+            problems.push(format!("{}/routes: missing or not an object", EDGE_HUB));
+            return;
+        }
+    };
+    for (name, route) in routes {
+        let path = format!("{}/routes/{}", EDGE_HUB, name);
+        // Schema 1.1 allows a route to be an object with a priority and time to live
+        let route = match route {
+            Value::String(route) => route.as_str(),
+            Value::Object(_) => match route.get("route").and_then(Value::as_str) {
+                Some(route) => route,
+                None => {
+                    problems.push(format!("{}/route: missing", path));
+                    continue;
+                }
+            },
+            _ => {
+                problems.push(format!("{}: must be a string or an object", path));
+                continue;
+            }
+        };
+        if let Err(e) = check_route(route) {
+            problems.push(format!("{}: {}", path, e));
+        }
+    }
+}
+
+/// Checks the route has the form `FROM <source> [WHERE <condition>] INTO <sink>`.
+fn check_route(route: &str) -> Result<(), String> {
+    let upper = route.to_ascii_uppercase();
+    let source = upper
+        .trim_start()
+        .strip_prefix("FROM ")
+        .ok_or_else(|| format!(r#""{}" does not start with FROM"#, route))?;
+    let into = upper
+        .rfind(" INTO ")
+        .ok_or_else(|| format!(r#""{}" has no INTO"#, route))?;
+    if source.trim_start().starts_with("INTO ") {
+        return Err(format!(r#""{}" has no source"#, route));
+    }
+
+    let sink = route[into + " INTO ".len()..].trim();
+    if sink == "$upstream" || (sink.starts_with("BrokeredEndpoint(") && sink.ends_with(')')) {
+        Ok(())
+    } else {
+        Err(format!(
+            r#"sink "{}" must be $upstream or BrokeredEndpoint("/modules/<module>/inputs/<input>")"#,
+            sink
+        ))
+    }
+}
+
+fn require_string(value: &Value, path: &str, key: &str, problems: &mut Vec<String>) {
+    match value.get(key) {
+        Some(Value::String(_)) => (),
+        Some(_) => problems.push(format!("{}/{}: must be a string", path, key)),
+        None => problems.push(format!("{}/{}: missing", path, key)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate_manifest() {
+        for manifest in &[
+            include_str!("../templates/tutorial/deploymentTopLayer.json"),
+            include_str!("../templates/tutorial/deploymentLowerLayer.json"),
+        ] {
+            assert_eq!(validate_manifest(manifest), Vec::<String>::new());
+        }
+
+        let broken = include_str!("../templates/tutorial/deploymentTopLayer.json")
+            .replace(r#""restartPolicy": "always","#, "")
+            .replace(
+                "FROM /messages/* INTO $upstream",
+                "FROM /messages/* INTO upstream",
+            )
+            .replace(r#""createOptions": """#, r#""createOptions": "{""#);
+        let problems = validate_manifest(&broken);
+        assert_eq!(problems.len(), 3, "{:#?}", problems);
+        assert!(problems[0].starts_with(
+            "/modulesContent/$edgeAgent/properties.desired/systemModules/edgeAgent/settings/createOptions:"
+        ));
+        assert!(
+            problems[2].starts_with("/modulesContent/$edgeHub/properties.desired/routes/route:")
+        );
+
+        assert!(validate_manifest("{")
+            .iter()
+            .any(|p| p.starts_with("line 1")));
+    }
+
+    #[test]
+    fn test_apply_edge_hub_settings() {
+        let mut manifest: Value = serde_json::from_str(include_str!(
+            "../templates/tutorial/deploymentTopLayer.json"
+        ))
+        .unwrap();
+        let settings = config::EdgeHubSettings {
+            optimize_for_performance: Some(false),
+            store_and_forward_ttl_secs: Some(86400),
+            authentication_mode: Some(config::EdgeHubAuthenticationMode::CloudAndScope),
+        };
+        apply_edge_hub_settings(&mut manifest, &settings).unwrap();
+
+        let env = manifest
+            .pointer(&format!("{}/systemModules/edgeHub/env", EDGE_AGENT))
+            .unwrap();
+        assert_eq!(env["OptimizeForPerformance"]["value"], "false");
+        assert_eq!(env["AuthenticationMode"]["value"], "CloudAndScope");
+        assert_eq!(
+            manifest
+                .pointer(&format!(
+                    "{}/storeAndForwardConfiguration/timeToLiveSecs",
+                    EDGE_HUB
+                ))
+                .unwrap(),
+            86400
+        );
+        assert!(validate_manifest(&manifest.to_string()).is_empty());
+    }
+
+    #[test]
+    fn test_apply_upstream() {
+        let mut manifest: Value = serde_json::from_str(include_str!(
+            "../templates/tutorial/deploymentLowerLayer.json"
+        ))
+        .unwrap();
+        apply_upstream(
+            &mut manifest,
+            Some(config::UpstreamProtocol::AmqpWs),
+            Some(8443),
+        )
+        .unwrap();
+
+        let agent = manifest.pointer(EDGE_AGENT).unwrap();
+        for system_module in &["edgeAgent", "edgeHub"] {
+            let module = &agent["systemModules"][*system_module];
+            assert_eq!(module["env"]["UpstreamProtocol"]["value"], "AmqpWs");
+        }
+        assert_eq!(
+            agent["systemModules"]["edgeHub"]["settings"]["image"],
+            "$upstream:8443/azureiotedge-hub:1.2"
+        );
+        assert!(validate_manifest(&manifest.to_string()).is_empty());
+
+        assert_eq!(
+            with_parent_port("$upstream/azureiotedge-agent:1.2", 8443),
+            "$upstream:8443/azureiotedge-agent:1.2"
+        );
+        assert_eq!(
+            with_parent_port("mcr.microsoft.com/azureiotedge-agent:1.2", 8443),
+            "mcr.microsoft.com/azureiotedge-agent:1.2"
+        );
+    }
+
+    #[test]
+    fn test_check_route() {
+        assert!(check_route("FROM /messages/* INTO $upstream").is_ok());
+        assert!(check_route(
+            r#"FROM /messages/modules/sensor/* WHERE temp > 30 INTO BrokeredEndpoint("/modules/filter/inputs/input1")"#
+        )
+        .is_ok());
+        assert!(check_route("/messages/* INTO $upstream").is_err());
+        assert!(check_route("FROM /messages/*").is_err());
+        assert!(check_route("FROM INTO $upstream").is_err());
+    }
+}
@@ -0,0 +1,321 @@
+use anyhow::Result;
+use tokio::sync::Mutex;
+
+use crate::commands::CommandRunner;
+use crate::config;
+use crate::log::LogLevel;
+use crate::{hmac_sha256, hub_responses, CreatedDevice, FileManager, FlatenedDevice};
+
+pub const GLOBAL_ENDPOINT: &str = "https://global.azure-devices-provisioning.net";
+const DEFAULT_ENROLLMENT_GROUP: &str = "iotedge_config_cli";
+
+/// Creates Device Provisioning Service enrollments for devices that provision themselves through
+/// DPS instead of being created in the hub directly.
+pub struct DpsManager<'a> {
+    config: &'a config::Config,
+    file_manager: &'a FileManager,
+    runner: &'a CommandRunner,
+    id_scope: Mutex<Option<String>>,
+    group_key: Mutex<Option<Vec<u8>>>,
+}
+
+impl<'a> DpsManager<'a> {
+    pub fn new(
+        config: &'a config::Config,
+        file_manager: &'a FileManager,
+        runner: &'a CommandRunner,
+    ) -> Self {
+        Self {
+            config,
+            file_manager,
+            runner,
+            id_scope: Mutex::new(None),
+            group_key: Mutex::new(None),
+        }
+    }
+
+    /// Creates a TPM individual enrollment for the device from its endorsement key.
+    pub async fn create_tpm_enrollment<'b>(
+        &self,
+        device: &FlatenedDevice<'b>,
+    ) -> Result<CreatedDevice<'b>> {
+        let device_id = device.device.device_id.as_str();
+        let registration_id = device.device.registration_id();
+        let endorsement_key = device
+            .device
+            .tpm_endorsement_key
+            .as_deref()
+            .ok_or_else(|| {
+                anyhow::Error::msg(format!(
+                    "{} uses tpm provisioning but has no tpm_endorsement_key",
+                    device_id
+                ))
+            })?;
+        let dps_name = self.dps_name()?;
+
+        self.file_manager
+            .log(
+                LogLevel::Debug,
+                "dps",
+                Some(device_id),
+                format!(
+                    "Creating TPM enrollment {} for {} in DPS {}",
+                    registration_id, device_id, dps_name
+                ),
+            )
+            .await?;
+
+        let args = &[
+            "az iot dps enrollment create",
+            "--dps-name",
+            dps_name,
+            "--enrollment-id",
+            registration_id,
+            "--device-id",
+            device_id,
+            "--attestation-type",
+            "tpm",
+            "--endorsement-key",
+            endorsement_key,
+            "--iot-hubs",
+            &self.config.iothub.iothub_hostname,
+            "--edge-enabled",
+        ];
+        let command = self.runner.output(args).await?;
+        if !command.status.success() {
+            let error = format!(
+                "Failed to create TPM enrollment for {}:\n{}\n{}",
+                device_id,
+                String::from_utf8_lossy(&command.stdout),
+                String::from_utf8_lossy(&command.stderr)
+            );
+            self.file_manager
+                .log(LogLevel::Debug, "dps", Some(device_id), &error)
+                .await?;
+
+            return Err(anyhow::Error::msg(error));
+        }
+
+        self.file_manager
+            .log(
+                LogLevel::Debug,
+                "dps",
+                Some(device_id),
+                format!(
+                    "Successfully created enrollment {}.\n{}",
+                    registration_id,
+                    String::from_utf8_lossy(&command.stdout)
+                ),
+            )
+            .await?;
+
+        Ok(CreatedDevice {
+            device: device.device,
+            parent: device.parent,
+            layer: device.layer,
+            create_response: hub_responses::CreateResponse {
+                device_id: device_id.to_owned(),
+                ..Default::default()
+            },
+            dps_id_scope: Some(self.id_scope().await?),
+        })
+    }
+
+    /// Makes the device's key from the enrollment group key. The group is only created once per
+    /// run, and only if it does not exist yet.
+    pub async fn create_group_device<'b>(
+        &self,
+        device: &FlatenedDevice<'b>,
+    ) -> Result<CreatedDevice<'b>> {
+        let device_id = device.device.device_id.as_str();
+        let registration_id = device.device.registration_id();
+        let group_key = self.group_key().await?;
+
+        self.file_manager
+            .log(
+                LogLevel::Debug,
+                "dps",
+                Some(device_id),
+                format!(
+                    "Deriving key of registration {} from enrollment group {}",
+                    registration_id,
+                    self.enrollment_group()
+                ),
+            )
+            .await?;
+        let device_key = hmac_sha256(&group_key, registration_id.as_bytes())?;
+
+        let mut create_response = hub_responses::CreateResponse {
+            device_id: device_id.to_owned(),
+            ..Default::default()
+        };
+        // Once registered, the device's hub identity uses the same derived key
+        create_response.authentication.symmetric_key.primary_key = Some(base64::encode(device_key));
+
+        Ok(CreatedDevice {
+            device: device.device,
+            parent: device.parent,
+            layer: device.layer,
+            create_response,
+            dps_id_scope: Some(self.id_scope().await?),
+        })
+    }
+
+    async fn group_key(&self) -> Result<Vec<u8>> {
+        let mut group_key = self.group_key.lock().await;
+        if let Some(group_key) = &*group_key {
+            return Ok(group_key.clone());
+        }
+
+        let dps_name = self.dps_name()?;
+        let group = self.enrollment_group();
+        let show = &[
+            "az iot dps enrollment-group show",
+            "--dps-name",
+            dps_name,
+            "--enrollment-id",
+            group,
+            "--show-keys",
+            "--query",
+            "attestation.symmetricKey.primaryKey",
+            "-o",
+            "tsv",
+        ];
+        let mut command = self.runner.output(show).await?;
+        if !command.status.success() {
+            self.file_manager
+                .log(
+                    LogLevel::Info,
+                    "dps",
+                    None,
+                    format!(
+                        "Creating symmetric key enrollment group {} in DPS {}.",
+                        group, dps_name
+                    ),
+                )
+                .await?;
+            command = self
+                .runner
+                .output(&[
+                    "az iot dps enrollment-group create",
+                    "--dps-name",
+                    dps_name,
+                    "--enrollment-id",
+                    group,
+                    "--iot-hubs",
+                    &self.config.iothub.iothub_hostname,
+                    "--edge-enabled",
+                    "--query",
+                    "attestation.symmetricKey.primaryKey",
+                    "-o",
+                    "tsv",
+                ])
+                .await?;
+        }
+
+        let key = String::from_utf8_lossy(&command.stdout).trim().to_owned();
+        if !command.status.success() || key.is_empty() {
+            return Err(anyhow::Error::msg(format!(
+                "Failed to get the key of enrollment group {}:\n{}",
+                group,
+                String::from_utf8_lossy(&command.stderr)
+            )));
+        }
+
+        let key = base64::decode(&key)?;
+        *group_key = Some(key.clone());
+        Ok(key)
+    }
+
+    pub async fn delete_enrollment(&self, device: &config::DeviceConfig) -> Result<bool> {
+        let dps_name = self.dps_name()?;
+        let registration_id = device.registration_id();
+        // Group enrollments are shared, so only the device's registration is deleted
+        let args: &[&str] = match device.provisioning {
+            config::DeviceProvisioning::DpsSymmetricKey => &[
+                "az iot dps enrollment-group registration delete",
+                "--dps-name",
+                dps_name,
+                "--registration-id",
+                registration_id,
+            ],
+            _ => &[
+                "az iot dps enrollment delete",
+                "--dps-name",
+                dps_name,
+                "--enrollment-id",
+                registration_id,
+            ],
+        };
+        let command = self.runner.output(args).await?;
+        let deleted = command.status.success()
+            || String::from_utf8_lossy(&command.stderr).contains("NotFound");
+
+        self.file_manager
+            .log(
+                LogLevel::Debug,
+                "dps",
+                Some(device.device_id.as_str()),
+                format!(
+                    "Deleting enrollment {} {}.\n{}",
+                    device.registration_id(),
+                    if deleted { "succeeded" } else { "failed" },
+                    String::from_utf8_lossy(&command.stderr)
+                ),
+            )
+            .await?;
+
+        Ok(deleted)
+    }
+
+    /// The DPS ID scope from the config, or looked up once per run.
+    pub async fn id_scope(&self) -> Result<String> {
+        if let Some(id_scope) = self.config.dps.as_ref().and_then(|d| d.id_scope.clone()) {
+            return Ok(id_scope);
+        }
+
+        let mut id_scope = self.id_scope.lock().await;
+        if let Some(id_scope) = &*id_scope {
+            return Ok(id_scope.clone());
+        }
+
+        let args = &[
+            "az iot dps show",
+            "--name",
+            self.dps_name()?,
+            "--query",
+            "properties.idScope",
+            "-o",
+            "tsv",
+        ];
+        let command = self.runner.output(args).await?;
+        let value = String::from_utf8_lossy(&command.stdout).trim().to_owned();
+        if !command.status.success() || value.is_empty() {
+            return Err(anyhow::Error::msg(format!(
+                "Failed to get the ID scope of DPS {}:\n{}",
+                self.dps_name()?,
+                String::from_utf8_lossy(&command.stderr)
+            )));
+        }
+
+        *id_scope = Some(value.clone());
+        Ok(value)
+    }
+
+    fn enrollment_group(&self) -> &'a str {
+        self.config
+            .dps
+            .as_ref()
+            .and_then(|d| d.enrollment_group.as_deref())
+            .unwrap_or(DEFAULT_ENROLLMENT_GROUP)
+    }
+
+    fn dps_name(&self) -> Result<&'a str> {
+        match &self.config.dps {
+            Some(dps) if !dps.dps_name.is_empty() => Ok(&dps.dps_name),
+            _ => Err(anyhow::Error::msg(
+                "Devices provisioned through DPS need dps.dps_name in the config",
+            )),
+        }
+    }
+}
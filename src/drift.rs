@@ -0,0 +1,243 @@
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use tokio::fs;
+
+use crate::config;
+use crate::diff::unified_diff;
+use crate::junit::{self, TestCase};
+use crate::log::LogLevel;
+use crate::remote::{run_on_devices, sh_quote, FleetLimits, RemoteExecutor};
+use crate::{CertManager, FileManager, FlatenedDevice};
+
+const LIVE_CONFIG: &str = "/etc/aziot/config.toml";
+const LIVE_CERT_DIR: &str = "/etc/aziot/certificates";
+
+/// Compares the config.toml and certs installed on each device with the ones generated for it.
+pub struct DriftManager<'a> {
+    config: &'a config::Config,
+    file_manager: &'a FileManager,
+    cert_manager: &'a CertManager<'a>,
+    executor: &'a dyn RemoteExecutor,
+    limits: FleetLimits,
+}
+
+impl<'a> DriftManager<'a> {
+    pub fn new(
+        config: &'a config::Config,
+        file_manager: &'a FileManager,
+        cert_manager: &'a CertManager,
+        executor: &'a dyn RemoteExecutor,
+        limits: FleetLimits,
+    ) -> Self {
+        Self {
+            config,
+            file_manager,
+            cert_manager,
+            executor,
+            limits,
+        }
+    }
+
+    /// Reports every device that drifted or could not be reached, and writes the checks to
+    /// the JUnit report if there is one. Fails if there was any, so it can be used as a check.
+    pub async fn check_all(&self, junit: Option<&Path>) -> Result<()> {
+        let devices = FlatenedDevice::flatten_devices(&self.config.root_devices);
+        let (drifted, cases) = self.check(&devices).await?;
+        if let Some(junit) = junit {
+            junit::write(junit, "drift", &cases).await?;
+        }
+
+        if drifted.is_empty() {
+            Ok(())
+        } else {
+            Err(anyhow::Error::msg(format!(
+                "{} devices do not match their generated config: {}",
+                drifted.len(),
+                drifted.join(", ")
+            )))
+        }
+    }
+
+    /// Reports whether each device drifted, and returns the ones that did or could not be
+    /// reached.
+    pub async fn drifted<'d>(&self, devices: &'d [FlatenedDevice<'_>]) -> Result<Vec<&'d str>> {
+        Ok(self.check(devices).await?.0)
+    }
+
+    async fn check<'d>(
+        &self,
+        devices: &'d [FlatenedDevice<'_>],
+    ) -> Result<(Vec<&'d str>, Vec<TestCase>)> {
+        let results = run_on_devices(devices, self.limits, |d| self.check_device(d)).await;
+
+        let mut drifted = Vec::new();
+        let mut cases = Vec::new();
+        for (device, result) in devices.iter().zip(results) {
+            let device_id = device.device.device_id.as_str();
+            let checks = match result {
+                Ok(checks) => checks,
+                Err(e) => vec![(
+                    "reachable".to_owned(),
+                    Some(format!("Could not be checked: {:#}", e)),
+                )],
+            };
+            let differences: Vec<&str> = checks.iter().filter_map(|(_, f)| f.as_deref()).collect();
+            if differences.is_empty() {
+                self.file_manager
+                    .log(
+                        LogLevel::Info,
+                        "drift",
+                        Some(device_id),
+                        format!("{} matches its generated config.", device_id),
+                    )
+                    .await?;
+            } else {
+                self.file_manager
+                    .log(
+                        LogLevel::Warn,
+                        "drift",
+                        Some(device_id),
+                        format!("{} drifted:\n{}", device_id, differences.join("\n")),
+                    )
+                    .await?;
+                drifted.push(device_id);
+            }
+            cases.extend(checks.into_iter().map(|(check, failure)| TestCase {
+                device_id: device_id.to_owned(),
+                check,
+                failure,
+            }));
+        }
+
+        Ok((drifted, cases))
+    }
+
+    /// Each check of the device, with what differs if it failed.
+    async fn check_device(
+        &self,
+        device: &config::DeviceConfig,
+    ) -> Result<Vec<(String, Option<String>)>> {
+        let device_id = device.device_id.as_str();
+        let mut checks = Vec::new();
+
+        let generated_path = self
+            .file_manager
+            .base_path()
+            .join(device_id)
+            .join("config.toml");
+        let generated = fs::read_to_string(&generated_path)
+            .await
+            .with_context(|| format!("Error reading {:?}", generated_path))?;
+        let live = self
+            .executor
+            .run(device, &format!("sudo cat {}", LIVE_CONFIG))
+            .await?;
+        let (generated, live) = normalize_configs(&generated, &live)?;
+        let diff = unified_diff(
+            &generated,
+            &live,
+            &format!("{}/config.toml", device_id),
+            &format!("{}:{}", device_id, LIVE_CONFIG),
+        );
+        checks.push((
+            "config.toml".to_owned(),
+            if diff.is_empty() { None } else { Some(diff) },
+        ));
+
+        for cert in self.installed_certs(device) {
+            let expected = self
+                .cert_manager
+                .get_thumbprint(&self.file_manager.base_path().join(device_id).join(&cert))
+                .await?;
+            let command = format!(
+                "sudo openssl x509 -noout -fingerprint -in {}",
+                sh_quote(&format!("{}/{}", LIVE_CERT_DIR, cert))
+            );
+            let live = self.executor.run(device, &command).await;
+            let failure = match live.map(|l| parse_fingerprint(&l)) {
+                Ok(Some(live)) if live == expected => None,
+                _ => Some(format!(
+                    "{}/{} is missing or is not the generated cert",
+                    LIVE_CERT_DIR, cert
+                )),
+            };
+            checks.push((cert, failure));
+        }
+
+        Ok(checks)
+    }
+
+    /// Certs the install scripts copy to the device's cert directory.
+    fn installed_certs(&self, device: &config::DeviceConfig) -> Vec<String> {
+        let names = &self.config.cert_names;
+        let mut certs = vec![
+            names.root_ca_cert.clone(),
+            names.device_ca_chain(&device.device_id),
+        ];
+        if self.config.iothub.authentication_method == config::IoTHubAuthMethod::X509Cert
+            && device.provisioning == config::DeviceProvisioning::Hub
+        {
+            certs.push(names.hub_auth_cert(&device.device_id));
+        }
+
+        certs
+    }
+}
+
+/// Puts both configs in the same format so only real differences are shown. Hostnames left for
+/// install.sh to fill in are taken from the live config.
+fn normalize_configs(generated: &str, live: &str) -> Result<(String, String)> {
+    let mut generated: toml::Value = toml::from_str(generated)?;
+    let live: toml::Value = toml::from_str(live).context("Error parsing the live config.toml")?;
+
+    for (key, placeholder) in &[
+        ("hostname", "{{HOSTNAME}}"),
+        ("parent_hostname", "{{PARENT_HOSTNAME}}"),
+    ] {
+        if generated.get(key).and_then(toml::Value::as_str) != Some(*placeholder) {
+            continue;
+        }
+        if let (Some(value), Some(table)) = (live.get(key), generated.as_table_mut()) {
+            table.insert((*key).to_owned(), value.clone());
+        }
+    }
+
+    Ok((toml::to_string(&generated)?, toml::to_string(&live)?))
+}
+
+/// Thumbprint from `openssl x509 -fingerprint` output, in the form `CertManager::get_thumbprint`
+/// returns.
+fn parse_fingerprint(output: &str) -> Option<String> {
+    let (_, fingerprint) = output.trim().split_once('=')?;
+    let mut fingerprint = fingerprint.trim().to_owned();
+    fingerprint.retain(|c| c != ':');
+
+    Some(fingerprint)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize_configs() {
+        let generated = "hostname = \"{{HOSTNAME}}\"\nparent_hostname = \"{{PARENT_HOSTNAME}}\"\n\n[agent]\nname = \"edgeAgent\"\n";
+        let live = "parent_hostname = \"10.0.0.1\"\nhostname = \"10.0.0.2\"\n[agent]\nname=\"edgeAgent\"\n";
+        let (generated, live) = normalize_configs(generated, live).unwrap();
+        assert_eq!(generated, live);
+
+        let (generated, live) =
+            normalize_configs(&generated, &live.replace("edgeAgent", "agent")).unwrap();
+        assert_ne!(generated, live);
+    }
+
+    #[test]
+    fn test_parse_fingerprint() {
+        assert_eq!(
+            parse_fingerprint("SHA1 Fingerprint=AB:CD:EF\n").as_deref(),
+            Some("ABCDEF")
+        );
+        assert_eq!(parse_fingerprint(""), None);
+    }
+}
@@ -0,0 +1,219 @@
+use anyhow::Result;
+
+use crate::config;
+use crate::{FileManager, FlatenedDevice};
+
+/// An IoT Hub tier, with its list price in USD at the time of writing. IoT Edge needs a standard
+/// tier, so basic tiers are left out.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Tier {
+    pub name: &'static str,
+    pub monthly_price: f64,
+    pub messages_per_day: u64,
+    /// Messages are metered in blocks of this size.
+    pub block_size: u64,
+    pub max_units: u64,
+    pub max_devices: Option<usize>,
+    /// Device-to-cloud throttle per unit, and at least.
+    pub d2c_per_unit: f64,
+    pub d2c_min: f64,
+}
+
+const TIERS: &[Tier] = &[
+    Tier {
+        name: "F1",
+        monthly_price: 0.0,
+        messages_per_day: 8_000,
+        block_size: 512,
+        max_units: 1,
+        max_devices: Some(500),
+        d2c_per_unit: 100.0,
+        d2c_min: 100.0,
+    },
+    Tier {
+        name: "S1",
+        monthly_price: 25.0,
+        messages_per_day: 400_000,
+        block_size: 4096,
+        max_units: 200,
+        max_devices: None,
+        d2c_per_unit: 12.0,
+        d2c_min: 100.0,
+    },
+    Tier {
+        name: "S2",
+        monthly_price: 250.0,
+        messages_per_day: 6_000_000,
+        block_size: 4096,
+        max_units: 200,
+        max_devices: None,
+        d2c_per_unit: 120.0,
+        d2c_min: 120.0,
+    },
+    Tier {
+        name: "S3",
+        monthly_price: 2500.0,
+        messages_per_day: 300_000_000,
+        block_size: 4096,
+        max_units: 10,
+        max_devices: None,
+        d2c_per_unit: 6000.0,
+        d2c_min: 6000.0,
+    },
+];
+
+/// USD per 1000 DPS operations.
+const DPS_OPERATION_PRICE: f64 = 0.10;
+
+/// USD per GB and month of hot blob storage.
+const STORAGE_PRICE: f64 = 0.02;
+
+/// Size of a device bundle that wasn't generated yet: certs, config.toml, deployment and scripts.
+const BUNDLE_BYTES: u64 = 20 * 1024;
+
+/// Expected costs of the hierarchy, from the devices' `messages_per_second`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Estimate {
+    pub devices: usize,
+    pub messages_per_second: f64,
+    pub messages_per_day: f64,
+    /// The cheapest tier that takes the messages, with the units needed. None if none does.
+    pub hub: Option<(Tier, u64)>,
+    /// One registration per device provisioned through DPS.
+    pub dps_operations: u64,
+    pub bundle_bytes: u64,
+}
+
+impl Estimate {
+    pub fn new(devices: &[FlatenedDevice], message_size: u64, bundle_bytes: u64) -> Self {
+        let messages_per_second: f64 = devices
+            .iter()
+            .filter_map(|d| d.device.messages_per_second)
+            .sum();
+        let messages_per_day = messages_per_second * 86_400.0;
+
+        let hub = TIERS
+            .iter()
+            .filter(|t| t.max_devices.map_or(true, |max| devices.len() <= max))
+            .filter_map(|t| {
+                let blocks = ((message_size.max(1) + t.block_size - 1) / t.block_size) as f64;
+                let mut units = ((messages_per_day * blocks) / t.messages_per_day as f64)
+                    .ceil()
+                    .max(1.0) as u64;
+                while (t.d2c_per_unit * units as f64).max(t.d2c_min) < messages_per_second {
+                    units += 1;
+                }
+                Some((*t, units)).filter(|_| units <= t.max_units)
+            })
+            .min_by(|(a, a_units), (b, b_units)| {
+                (a.monthly_price * *a_units as f64)
+                    .partial_cmp(&(b.monthly_price * *b_units as f64))
+                    .unwrap()
+            });
+
+        Self {
+            devices: devices.len(),
+            messages_per_second,
+            messages_per_day,
+            hub,
+            dps_operations: devices
+                .iter()
+                .filter(|d| d.device.provisioning != config::DeviceProvisioning::Hub)
+                .count() as u64,
+            bundle_bytes,
+        }
+    }
+}
+
+/// Prints the monthly cost of the hub tier the hierarchy's messages need, and the cost of
+/// provisioning it through DPS and keeping its bundles, at list prices.
+pub async fn print_estimate(
+    config: &config::Config,
+    file_manager: &FileManager,
+    message_size: u64,
+) -> Result<()> {
+    let devices = FlatenedDevice::flatten_devices(&config.root_devices);
+    let bundle_bytes = devices
+        .iter()
+        .map(|d| {
+            let zip = FileManager::path_to_zip(file_manager.base_path().join(&d.device.device_id));
+            std::fs::metadata(zip).map_or(BUNDLE_BYTES, |m| m.len())
+        })
+        .sum();
+    let estimate = Estimate::new(&devices, message_size, bundle_bytes);
+
+    let mut report = vec![format!(
+        "Estimate for {} devices sending {:.1} messages per second, {:.0} messages of {} bytes per day:",
+        estimate.devices, estimate.messages_per_second, estimate.messages_per_day, message_size
+    )];
+    if devices
+        .iter()
+        .all(|d| d.device.messages_per_second.is_none())
+    {
+        report.push(
+            "  No device has messages_per_second, the hub is sized for device management only."
+                .to_owned(),
+        );
+    }
+    report.push(match estimate.hub {
+        Some((tier, units)) => format!(
+            "  IoT Hub: {} x {} = ${:.2} per month",
+            units,
+            tier.name,
+            tier.monthly_price * units as f64
+        ),
+        None => {
+            "  IoT Hub: more messages than a hub takes, split the hierarchy across hubs".to_owned()
+        }
+    });
+    report.push(format!(
+        "  DPS: {} registrations = ${:.2} each time the devices provision",
+        estimate.dps_operations,
+        estimate.dps_operations as f64 / 1000.0 * DPS_OPERATION_PRICE
+    ));
+    let gigabytes = estimate.bundle_bytes as f64 / 1024.0 / 1024.0 / 1024.0;
+    report.push(format!(
+        "  Bundles: {:.1} MB = ${:.2} per month in blob storage",
+        gigabytes * 1024.0,
+        gigabytes * STORAGE_PRICE
+    ));
+    report.push(
+        "USD list prices at the time of writing, see https://azure.microsoft.com/pricing/details/iot-hub/ for current ones."
+            .to_owned(),
+    );
+
+    file_manager.print(report.join("\n")).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_estimate() {
+        let root: config::DeviceConfig = serde_yaml::from_str(
+            r#"
+device_id: top
+messages_per_second: 2
+child:
+  - device_id: a
+    messages_per_second: 2
+  - device_id: b
+    messages_per_second: 2
+    provisioning: tpm
+"#,
+        )
+        .unwrap();
+        let devices = FlatenedDevice::flatten_devices(std::slice::from_ref(&root));
+
+        let estimate = Estimate::new(&devices, 1024, 0);
+        assert_eq!(estimate.messages_per_day, 518_400.0);
+        let (tier, units) = estimate.hub.unwrap();
+        assert_eq!((tier.name, units), ("S1", 2));
+        assert_eq!(estimate.dps_operations, 1);
+
+        // Messages of 8 KB are two blocks each
+        let (tier, units) = Estimate::new(&devices, 8192, 0).hub.unwrap();
+        assert_eq!((tier.name, units), ("S1", 3));
+    }
+}
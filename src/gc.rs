@@ -0,0 +1,457 @@
+use std::collections::{HashMap, HashSet};
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Duration, Utc};
+
+use crate::commands::CommandRunner;
+use crate::config;
+use crate::log::LogLevel;
+use crate::throttle::{HubOperation, HubThrottle};
+use crate::{FileManager, FlatenedDevice, IoTHubDeviceManager};
+
+/// Twin tag written to every device this tool creates in the hub.
+pub const CREATION_TAG: &str = "iotedge_config";
+
+/// Value of the creation tag. Devices without it were not made by this tool, and are not deleted
+/// by it.
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize, PartialEq)]
+pub struct CreationTag {
+    /// Version of iotedge_config that created the device.
+    #[serde(default)]
+    pub tool_version: String,
+    pub namespace: Option<String>,
+    /// SHA-256 of the config file the device was created from.
+    #[serde(default)]
+    pub config_hash: Option<String>,
+    /// When the device was created, in RFC 3339.
+    pub created: String,
+}
+
+impl CreationTag {
+    pub fn new(config: &config::Config, now: DateTime<Utc>) -> Self {
+        Self {
+            tool_version: env!("CARGO_PKG_VERSION").to_owned(),
+            namespace: config.namespace.clone(),
+            config_hash: config.source_hash.clone(),
+            created: now.to_rfc3339(),
+        }
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct TaggedDevice {
+    device_id: String,
+    #[serde(default)]
+    parent_scopes: Vec<String>,
+    /// Null for devices without tags.
+    #[serde(default)]
+    tags: Option<HashMap<String, serde_json::Value>>,
+}
+
+impl TaggedDevice {
+    fn creation_tag(&self) -> Option<&serde_json::Value> {
+        self.tags.as_ref()?.get(CREATION_TAG)
+    }
+}
+
+/// What a run does with hub devices whose parent is in the config but that are not in it
+/// themselves, such as devices removed from the config since the last run.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StaleChildren {
+    Warn,
+    /// Removes them from the parent, keeping the devices.
+    Detach,
+    /// Deletes them, only those with the creation tag unless --include-untagged.
+    Delete,
+}
+
+impl std::str::FromStr for StaleChildren {
+    type Err = anyhow::Error;
+    fn from_str(string: &str) -> Result<Self> {
+        match string.to_lowercase().as_str() {
+            "warn" => Ok(Self::Warn),
+            "detach" => Ok(Self::Detach),
+            "delete" => Ok(Self::Delete),
+            _ => Err(anyhow::Error::msg(format!(
+                "Did not recognize stale children action: {}. Use warn, detach or delete.",
+                string
+            ))),
+        }
+    }
+}
+
+/// Finds and deletes devices this tool created, by their creation tag, to clean up abandoned lab
+/// environments.
+pub struct GcManager<'a> {
+    config: &'a config::Config,
+    file_manager: &'a FileManager,
+    hub_manager: &'a IoTHubDeviceManager<'a>,
+    throttle: &'a HubThrottle,
+    runner: &'a CommandRunner,
+}
+
+impl<'a> GcManager<'a> {
+    pub fn new(
+        config: &'a config::Config,
+        file_manager: &'a FileManager,
+        hub_manager: &'a IoTHubDeviceManager<'a>,
+        throttle: &'a HubThrottle,
+        runner: &'a CommandRunner,
+    ) -> Self {
+        Self {
+            config,
+            file_manager,
+            hub_manager,
+            throttle,
+            runner,
+        }
+    }
+
+    /// Deletes the tagged devices of the namespace, or of any namespace if None, that were
+    /// created longer than `older_than` ago. Children are deleted before their parents.
+    pub async fn collect(
+        &self,
+        namespace: Option<&str>,
+        older_than: Option<Duration>,
+        dry_run: bool,
+    ) -> Result<()> {
+        if namespace.is_none() && older_than.is_none() {
+            return Err(anyhow::Error::msg(
+                "gc needs --namespace, --older-than or both, so it never deletes every device this tool made by accident",
+            ));
+        }
+
+        let devices = self.tagged_devices().await?;
+        let cutoff = older_than.map(|d| self.file_manager.now() - d);
+        let mut stale = Vec::new();
+        for device in &devices {
+            let tag = match device.creation_tag().cloned() {
+                Some(tag) => serde_json::from_value::<CreationTag>(tag),
+                None => continue,
+            };
+            let tag = match tag {
+                Ok(tag) => tag,
+                Err(e) => {
+                    self.file_manager
+                        .log(
+                            LogLevel::Warn,
+                            "gc",
+                            Some(device.device_id.as_str()),
+                            format!(
+                                "Skipping {}, its {} tag is not readable: {}",
+                                device.device_id, CREATION_TAG, e
+                            ),
+                        )
+                        .await?;
+                    continue;
+                }
+            };
+            if namespace.is_some() && tag.namespace.as_deref() != namespace {
+                continue;
+            }
+            if let Some(cutoff) = cutoff {
+                let created = DateTime::parse_from_rfc3339(&tag.created).with_context(|| {
+                    format!("Error parsing creation time of {}", device.device_id)
+                })?;
+                if created > cutoff {
+                    continue;
+                }
+            }
+            stale.push(device);
+        }
+
+        let order = delete_order(&stale);
+        self.file_manager
+            .log(
+                LogLevel::Info,
+                "gc",
+                None,
+                format!(
+                    "{} {} devices from hub {}: {}",
+                    if dry_run { "Would delete" } else { "Deleting" },
+                    order.len(),
+                    self.config.iothub.iothub_name,
+                    order.join(", ")
+                ),
+            )
+            .await?;
+        if dry_run {
+            return Ok(());
+        }
+
+        let mut failed = Vec::new();
+        for device_id in order {
+            if !self.hub_manager.delete_device_identity(device_id).await? {
+                failed.push(device_id);
+            }
+        }
+        if failed.is_empty() {
+            Ok(())
+        } else {
+            Err(anyhow::Error::msg(format!(
+                "Failed to delete {}. See the log for details.",
+                failed.join(", ")
+            )))
+        }
+    }
+
+    /// Finds devices in the hub under a device of the config that are not in the config, and
+    /// warns about, detaches or deletes them so gateway scopes only hold the configured children.
+    pub async fn stale_children(
+        &self,
+        action: StaleChildren,
+        include_untagged: bool,
+    ) -> Result<()> {
+        let configured: HashSet<&str> = FlatenedDevice::flatten_devices(&self.config.root_devices)
+            .iter()
+            .map(|d| d.device.device_id.as_str())
+            .collect();
+        let devices = self
+            .query_devices("SELECT deviceId, parentScopes, tags FROM devices")
+            .await?;
+        let stale: HashMap<&str, (&TaggedDevice, &str)> = find_stale(&devices, &configured)
+            .into_iter()
+            .map(|(d, parent)| (d.device_id.as_str(), (d, parent)))
+            .collect();
+        let order = delete_order(&stale.values().map(|(d, _)| *d).collect::<Vec<_>>());
+
+        let mut failed = Vec::new();
+        for device_id in order {
+            let (device, parent) = stale[device_id];
+            let done = match action {
+                StaleChildren::Warn => {
+                    self.file_manager
+                        .log(
+                            LogLevel::Warn,
+                            "gc",
+                            Some(device_id),
+                            format!(
+                                "{} is a child of {} in the hub but is not in the config. Use --stale-children detach or delete to clean it up.",
+                                device_id, parent
+                            ),
+                        )
+                        .await?;
+                    true
+                }
+                StaleChildren::Detach => self.detach(parent, device_id).await?,
+                StaleChildren::Delete if !include_untagged && device.creation_tag().is_none() => {
+                    self.file_manager
+                        .log(
+                            LogLevel::Warn,
+                            "gc",
+                            Some(device_id),
+                            format!(
+                                "Not deleting {}, the stale child of {}, since this tool did not create it. Use --include-untagged to delete it anyway.",
+                                device_id, parent
+                            ),
+                        )
+                        .await?;
+                    true
+                }
+                StaleChildren::Delete => {
+                    self.file_manager
+                        .log(
+                            LogLevel::Info,
+                            "gc",
+                            Some(device_id),
+                            format!(
+                                "Deleting {}, a child of {} that is not in the config",
+                                device_id, parent
+                            ),
+                        )
+                        .await?;
+                    self.hub_manager.delete_device_identity(device_id).await?
+                }
+            };
+            if !done {
+                failed.push(device_id);
+            }
+        }
+
+        if failed.is_empty() {
+            Ok(())
+        } else {
+            Err(anyhow::Error::msg(format!(
+                "Failed to clean up the stale children {}. See the log for details.",
+                failed.join(", ")
+            )))
+        }
+    }
+
+    /// Removes the child from the parent's scope, false if the hub refused.
+    async fn detach(&self, parent: &str, device_id: &str) -> Result<bool> {
+        self.throttle.wait(HubOperation::Registry).await;
+        let command = self
+            .runner
+            .output(&[
+                "az iot hub device-identity children remove",
+                "--device-id",
+                parent,
+                "--child-list",
+                device_id,
+                "--hub-name",
+                &self.config.iothub.iothub_name,
+            ])
+            .await?;
+        let message = if command.status.success() {
+            format!(
+                "Detached {}, a child of {} that is not in the config",
+                device_id, parent
+            )
+        } else {
+            format!(
+                "Failed to detach {} from {}:\n{}",
+                device_id,
+                parent,
+                String::from_utf8_lossy(&command.stderr)
+            )
+        };
+        self.file_manager
+            .log(
+                if command.status.success() {
+                    LogLevel::Info
+                } else {
+                    LogLevel::Error
+                },
+                "gc",
+                Some(device_id),
+                message,
+            )
+            .await?;
+
+        Ok(command.status.success())
+    }
+
+    async fn tagged_devices(&self) -> Result<Vec<TaggedDevice>> {
+        self.query_devices(&format!(
+            "SELECT deviceId, parentScopes, tags FROM devices WHERE IS_DEFINED(tags.{})",
+            CREATION_TAG
+        ))
+        .await
+    }
+
+    async fn query_devices(&self, query: &str) -> Result<Vec<TaggedDevice>> {
+        self.throttle.wait(HubOperation::Twin).await;
+        let command = self
+            .runner
+            .output(&[
+                "az iot hub query",
+                "--hub-name",
+                &self.config.iothub.iothub_name,
+                "--query-command",
+                &crate::quote_arg(query),
+                "--top",
+                "-1",
+                "-o",
+                "json",
+            ])
+            .await?;
+        if !command.status.success() {
+            return Err(anyhow::Error::msg(format!(
+                "Failed to query the devices of hub {}:\n{}",
+                self.config.iothub.iothub_name,
+                String::from_utf8_lossy(&command.stderr)
+            )));
+        }
+
+        serde_json::from_slice(&command.stdout).context("Error parsing hub query result")
+    }
+}
+
+/// The parent device id in a parent scope, which looks like
+/// ms-azure-iot-edge://<parent id>-<generation id>.
+fn scope_parent(scope: &str) -> Option<&str> {
+    let (parent, _) = scope
+        .strip_prefix("ms-azure-iot-edge://")?
+        .rsplit_once('-')?;
+
+    Some(parent)
+}
+
+/// Devices not in the config whose parent is, with their parent.
+fn find_stale<'d>(
+    devices: &'d [TaggedDevice],
+    configured: &HashSet<&str>,
+) -> Vec<(&'d TaggedDevice, &'d str)> {
+    devices
+        .iter()
+        .filter(|d| !configured.contains(d.device_id.as_str()))
+        .filter_map(|d| {
+            let parent = scope_parent(d.parent_scopes.first()?)?;
+            Some((d, parent)).filter(|_| configured.contains(parent))
+        })
+        .collect()
+}
+
+/// Orders device ids so every device comes before its parent, the same way on every run.
+fn delete_order<'d>(devices: &[&'d TaggedDevice]) -> Vec<&'d str> {
+    let parents: HashMap<&str, &str> = devices
+        .iter()
+        .filter_map(|d| {
+            let parent = scope_parent(d.parent_scopes.first()?)?;
+            Some((d.device_id.as_str(), parent))
+        })
+        .collect();
+    let depth = |mut device_id: &str| {
+        let mut depth = 0;
+        while let Some(parent) = parents.get(device_id) {
+            device_id = parent;
+            depth += 1;
+            if depth > parents.len() {
+                break;
+            }
+        }
+        depth
+    };
+
+    // The hub returns devices in no set order, so devices at the same depth go by id
+    let mut order: Vec<&str> = devices.iter().map(|d| d.device_id.as_str()).collect();
+    order.sort_by_key(|d| (std::cmp::Reverse(depth(d)), *d));
+
+    order
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_delete_order() {
+        let devices: Vec<TaggedDevice> = serde_json::from_str(
+            r#"[
+                {"deviceId": "lab-top", "tags": {}},
+                {"deviceId": "lab-other", "parentScopes": ["ms-azure-iot-edge://lab-top-636"], "tags": {}},
+                {"deviceId": "lab-leaf", "parentScopes": ["ms-azure-iot-edge://lab-lower-637"], "tags": {}},
+                {"deviceId": "lab-lower", "parentScopes": ["ms-azure-iot-edge://lab-top-636"], "tags": {}}
+            ]"#,
+        )
+        .unwrap();
+        let devices: Vec<&TaggedDevice> = devices.iter().collect();
+
+        assert_eq!(
+            delete_order(&devices),
+            vec!["lab-leaf", "lab-lower", "lab-other", "lab-top"]
+        );
+    }
+
+    #[test]
+    fn test_find_stale() {
+        let devices: Vec<TaggedDevice> = serde_json::from_str(
+            r#"[
+                {"deviceId": "lab-top", "parentScopes": [], "tags": null},
+                {"deviceId": "lab-removed", "parentScopes": ["ms-azure-iot-edge://lab-top-636"], "tags": null},
+                {"deviceId": "lab-removed-leaf", "parentScopes": ["ms-azure-iot-edge://lab-removed-637"], "tags": null},
+                {"deviceId": "other-leaf", "parentScopes": ["ms-azure-iot-edge://other-top-638"], "tags": null}
+            ]"#,
+        )
+        .unwrap();
+        let configured: HashSet<&str> = vec!["lab-top"].into_iter().collect();
+
+        let stale: Vec<(&str, &str)> = find_stale(&devices, &configured)
+            .into_iter()
+            .map(|(d, parent)| (d.device_id.as_str(), parent))
+            .collect();
+        assert_eq!(stale, vec![("lab-removed", "lab-top")]);
+    }
+}
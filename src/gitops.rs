@@ -0,0 +1,166 @@
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use tokio::fs;
+
+use crate::config;
+use crate::log::LogLevel;
+use crate::{CreatedDevice, FileManager, FlatenedDevice};
+
+/// One device of the hierarchy model written to hierarchy.yaml.
+#[derive(Debug, serde::Serialize)]
+struct ModelDevice<'a> {
+    device_id: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    parent: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    hostname: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    edge_agent: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    deployment: Option<&'a str>,
+}
+
+/// Writes the non-secret artifacts of a run to a directory meant to be committed to git. Output
+/// only depends on the config, in a stable order and without timestamps, so reruns only show
+/// real changes. Secrets are replaced with `{{PLACEHOLDER}}` values, like the hostnames left for
+/// install.sh to fill in.
+pub struct GitOpsManager<'a> {
+    config: &'a config::Config,
+    file_manager: &'a FileManager,
+}
+
+impl<'a> GitOpsManager<'a> {
+    pub fn new(config: &'a config::Config, file_manager: &'a FileManager) -> Self {
+        Self {
+            config,
+            file_manager,
+        }
+    }
+
+    pub async fn write_all(&self, devices: &[CreatedDevice<'_>], dir: &Path) -> Result<()> {
+        self.file_manager
+            .log(
+                LogLevel::Info,
+                "gitops",
+                None,
+                format!("Writing non-secret artifacts to {:?}.", dir),
+            )
+            .await?;
+        fs::create_dir_all(dir).await?;
+
+        let model: Vec<ModelDevice> = FlatenedDevice::flatten_devices(&self.config.root_devices)
+            .into_iter()
+            .map(|d| ModelDevice {
+                device_id: &d.device.device_id,
+                parent: d.parent.map(|p| p.device_id.as_str()),
+                hostname: d.device.hostname.as_deref(),
+                edge_agent: d.device.edge_agent.as_deref(),
+                deployment: d.device.deployment.as_deref(),
+            })
+            .collect();
+        fs::write(dir.join("hierarchy.yaml"), serde_yaml::to_string(&model)?).await?;
+
+        for device in devices {
+            let device_id = device.device.device_id.as_str();
+            let config = self
+                .file_manager
+                .base_path()
+                .join(device_id)
+                .join("config.toml");
+            let config = fs::read_to_string(&config)
+                .await
+                .with_context(|| format!("Error reading {:?}", config))?;
+            let config = redact_config(&config)?;
+
+            let device_dir = dir.join(device_id);
+            self.file_manager
+                .log(
+                    LogLevel::Debug,
+                    "gitops",
+                    Some(device_id),
+                    format!("Writing redacted config.toml to {:?}", device_dir),
+                )
+                .await?;
+            fs::create_dir_all(&device_dir).await?;
+            fs::write(device_dir.join("config.toml"), config).await?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Secrets of a generated config.toml, with the placeholders that replace them.
+const CONFIG_SECRETS: &[(&[&str], &str)] = &[
+    (
+        &["provisioning", "authentication", "device_id_pk", "value"],
+        "{{DEVICE_KEY}}",
+    ),
+    (
+        &["provisioning", "attestation", "symmetric_key", "value"],
+        "{{DEVICE_KEY}}",
+    ),
+    (
+        &["agent", "config", "auth", "password"],
+        "{{CONTAINER_AUTH_PASSWORD}}",
+    ),
+];
+
+/// Replaces the secrets in a generated config.toml with placeholders.
+pub fn redact_config(config: &str) -> Result<String> {
+    let mut config: toml::Value = toml::from_str(config)?;
+
+    for (path, placeholder) in CONFIG_SECRETS {
+        let mut value = Some(&mut config);
+        for key in path.iter() {
+            value = value.and_then(|v| v.get_mut(key));
+        }
+        if let Some(value) = value {
+            *value = toml::Value::String((*placeholder).to_owned());
+        }
+    }
+
+    Ok(toml::to_string(&config)?)
+}
+
+/// The secrets in a generated config.toml that are not placeholders yet.
+pub fn config_secrets(config: &str) -> Result<Vec<String>> {
+    let config: toml::Value = toml::from_str(config)?;
+
+    Ok(CONFIG_SECRETS
+        .iter()
+        .filter_map(|(path, placeholder)| {
+            let value = path.iter().try_fold(&config, |v, key| v.get(key))?;
+            value
+                .as_str()
+                .filter(|v| v != placeholder)
+                .map(str::to_owned)
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_redact_config() {
+        let config = r#"
+hostname = "{{HOSTNAME}}"
+
+[provisioning]
+source = "manual"
+iothub_hostname = "hub.azure-devices.net"
+device_id = "top-layer"
+
+[provisioning.authentication]
+method = "sas"
+device_id_pk = { value = "a2V5" }
+"#;
+
+        let redacted = redact_config(config).unwrap();
+        assert!(!redacted.contains("a2V5"));
+        assert!(redacted.contains(r#"value = "{{DEVICE_KEY}}""#));
+        assert!(redacted.contains(r#"device_id = "top-layer""#));
+    }
+}
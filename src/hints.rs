@@ -0,0 +1,80 @@
+/// A failure the tool knows the fix for, recognized by text in the error or the output of the
+/// command that failed.
+struct Hint {
+    patterns: &'static [&'static str],
+    message: &'static str,
+}
+
+/// Checked in order, so the more specific ones come first.
+const HINTS: &[Hint] = &[
+    Hint {
+        patterns: &["az: not found", "az: command not found", "'az' is not recognized"],
+        message: "The Azure CLI is not installed or not on PATH. Install it from https://aka.ms/installazurecli, then run `az extension add --name azure-iot`.",
+    },
+    Hint {
+        patterns: &["Please run 'az login'", "Please run \"az login\""],
+        message: "az is not signed in. Run `az login`, then `az account set --subscription <subscription>` to pick the hub's subscription.",
+    },
+    Hint {
+        patterns: &[
+            "'iot' is misspelled or not recognized",
+            "is not in the 'az' command group",
+            "is not in the 'az iot' command group",
+        ],
+        message: "The azure-iot extension of az is missing or too old. Run `az extension add --name azure-iot`, or `az extension update --name azure-iot`.",
+    },
+    Hint {
+        patterns: &[
+            "Unable to find IoT Hub",
+            "not found in current subscription",
+            "ResourceNotFound",
+        ],
+        message: "The hub was not found. Check iothub.iothub_name in the config, and that `az account show` is the hub's subscription.",
+    },
+    Hint {
+        patterns: &["QuotaExceeded", "TooManyDevices"],
+        message: "The hub's quota is used up. Raise its units with `az iot hub update --name <hub> --unit <units>`, or delete unused devices with the gc subcommand.",
+    },
+    Hint {
+        patterns: &["Error running openssl"],
+        message: "openssl could not be run. Install it and make sure it is on PATH, or pass its path with --openssl-path.",
+    },
+    Hint {
+        patterns: &["Permission denied (os error 13)"],
+        message: "A file or folder could not be written. Check you own the output folder (--output), or remove it if an earlier run made it with sudo.",
+    },
+];
+
+/// Puts the fix for a known failure in front of the error, keeping the raw output as its cause.
+pub fn with_remediation(error: anyhow::Error) -> anyhow::Error {
+    let text = format!("{:#}", error);
+    match HINTS
+        .iter()
+        .find(|h| h.patterns.iter().any(|p| text.contains(p)))
+    {
+        Some(hint) => error.context(hint.message),
+        None => error,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_with_remediation() {
+        let error = with_remediation(anyhow::Error::msg(
+            "Failed to create A:\n\nERROR: Please run 'az login' to setup account.\n",
+        ));
+        assert!(error.to_string().contains("Run `az login`"));
+        assert_eq!(error.chain().count(), 2);
+
+        let error = with_remediation(anyhow::Error::msg(
+            "Failed to create A:\n\nERROR: (IotHubQuotaExceeded) Total number of messages on IotHub exceeded the allocated quota.\n",
+        ));
+        assert!(error.to_string().starts_with("The hub's quota is used up."));
+
+        let error = with_remediation(anyhow::Error::msg("device id \"A\" is used twice!"));
+        assert_eq!(error.chain().count(), 1);
+    }
+}
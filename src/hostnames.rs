@@ -0,0 +1,439 @@
+use std::collections::BTreeMap;
+use std::net::{IpAddr, ToSocketAddrs};
+use std::path::Path;
+
+use anyhow::Result;
+use chrono::{DateTime, Datelike, Utc};
+use tokio::fs;
+
+use crate::config;
+use crate::log::LogLevel;
+use crate::{FileManager, FlatenedDevice};
+
+/// Checks the hostnames the device certs are issued for before the bundles go out, since a child
+/// can only connect to its parent by a name that resolves to it.
+pub struct HostnameManager<'a> {
+    config: &'a config::Config,
+    file_manager: &'a FileManager,
+}
+
+impl<'a> HostnameManager<'a> {
+    pub fn new(config: &'a config::Config, file_manager: &'a FileManager) -> Self {
+        Self {
+            config,
+            file_manager,
+        }
+    }
+
+    /// Fails if two devices share a hostname or a hostname does not resolve from this machine.
+    /// Hostnames of devices with an `ip_address` are not looked up. Writes their `/etc/hosts`
+    /// entries to `hosts` if given.
+    pub async fn check(&self, hosts: Option<&Path>) -> Result<()> {
+        let devices = FlatenedDevice::flatten_devices(&self.config.root_devices);
+        let mut problems = shared_hostnames(&devices);
+
+        for device in &devices {
+            let device_id = device.device.device_id.as_str();
+            let hostname = match &device.device.hostname {
+                Some(hostname) => hostname,
+                None => continue,
+            };
+            if device.device.ip_address.is_some() || ip_literal(hostname).is_some() {
+                continue;
+            }
+
+            match resolve(hostname).await {
+                Ok(addresses) => {
+                    let addresses: Vec<String> = addresses.iter().map(IpAddr::to_string).collect();
+                    self.file_manager
+                        .log(
+                            LogLevel::Info,
+                            "hostnames",
+                            Some(device_id),
+                            format!("{} resolves to {}", hostname, addresses.join(", ")),
+                        )
+                        .await?;
+                }
+                Err(e) => problems.push(format!(
+                    "{} of {} does not resolve: {}",
+                    hostname, device_id, e
+                )),
+            }
+        }
+
+        if let Some(path) = hosts {
+            let devices: Vec<&config::DeviceConfig> = devices.iter().map(|d| d.device).collect();
+            fs::write(path, hosts_snippet(&devices)).await?;
+            self.file_manager
+                .log(
+                    LogLevel::Info,
+                    "hostnames",
+                    None,
+                    format!("Wrote /etc/hosts entries to {:?}", path),
+                )
+                .await?;
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(anyhow::Error::msg(format!(
+                "Hostname problems:\n{}",
+                problems.join("\n")
+            )))
+        }
+    }
+
+    /// Writes `dns/hosts` with the entries of all devices with an `ip_address`, and
+    /// `dns/<device_id>.hosts` with the ones each device needs: itself, its parent and its
+    /// children. With a `zone`, also writes `dns/<zone>.zone` for BIND or Azure Private DNS, served
+    /// by `name_server`, `ns.<zone>` if not given.
+    pub async fn write_dns_files(
+        &self,
+        zone: Option<&str>,
+        ttl: u32,
+        name_server: Option<&str>,
+        name_server_address: Option<IpAddr>,
+    ) -> Result<()> {
+        let devices = FlatenedDevice::flatten_devices(&self.config.root_devices);
+        let missing: Vec<&str> = devices
+            .iter()
+            .filter(|d| d.device.hostname.is_some() && d.device.ip_address.is_none())
+            .map(|d| d.device.device_id.as_str())
+            .collect();
+        if !missing.is_empty() {
+            self.file_manager
+                .log(
+                    LogLevel::Warn,
+                    "hostnames",
+                    None,
+                    format!(
+                        "{} have a hostname but no ip_address and are left out of the DNS files",
+                        missing.join(", ")
+                    ),
+                )
+                .await?;
+        }
+
+        let dir = self.file_manager.base_path().join("dns");
+        fs::create_dir_all(&dir).await?;
+        let all: Vec<&config::DeviceConfig> = devices.iter().map(|d| d.device).collect();
+        fs::write(dir.join("hosts"), hosts_snippet(&all)).await?;
+        for device in &devices {
+            let mut neighbours: Vec<&config::DeviceConfig> = device.parent.into_iter().collect();
+            neighbours.push(device.device);
+            neighbours.extend(&device.device.children);
+            fs::write(
+                dir.join(format!("{}.hosts", device.device.device_id)),
+                hosts_snippet(&neighbours),
+            )
+            .await?;
+        }
+
+        if let Some(zone) = zone {
+            let path = dir.join(format!("{}.zone", zone));
+            let previous = fs::read_to_string(&path)
+                .await
+                .ok()
+                .and_then(|z| zone_serial(&z));
+            let serial = next_serial(previous, self.file_manager.now());
+            let (records, warnings) = zone_file(
+                &devices,
+                zone,
+                ttl,
+                name_server,
+                name_server_address,
+                serial,
+            );
+            for warning in warnings {
+                self.file_manager
+                    .log(LogLevel::Warn, "hostnames", None, warning)
+                    .await?;
+            }
+            fs::write(path, records).await?;
+        }
+
+        self.file_manager
+            .log(
+                LogLevel::Info,
+                "hostnames",
+                None,
+                format!("Wrote DNS files to {:?}", dir),
+            )
+            .await?;
+
+        Ok(())
+    }
+}
+
+/// The address of a hostname that is an IP literal, IPv6 ones with or without brackets.
+pub fn ip_literal(hostname: &str) -> Option<IpAddr> {
+    hostname
+        .strip_prefix('[')
+        .and_then(|h| h.strip_suffix(']'))
+        .unwrap_or(hostname)
+        .parse()
+        .ok()
+}
+
+/// The hostname as it goes in `host:port` pairs and URLs: IPv6 addresses in brackets.
+pub fn url_host(hostname: &str) -> String {
+    match ip_literal(hostname) {
+        Some(IpAddr::V6(address)) => format!("[{}]", address),
+        Some(address) => address.to_string(),
+        None => hostname.to_owned(),
+    }
+}
+
+/// The hostname as it goes in certs and config.toml's `hostname`: IP addresses without brackets.
+pub fn bare_host(hostname: &str) -> String {
+    match ip_literal(hostname) {
+        Some(address) => address.to_string(),
+        None => hostname.to_owned(),
+    }
+}
+
+async fn resolve(hostname: &str) -> Result<Vec<IpAddr>> {
+    let host = hostname.to_owned();
+    let addresses =
+        tokio::task::spawn_blocking(move || (host.as_str(), 0).to_socket_addrs()).await??;
+
+    Ok(addresses.map(|a| a.ip()).collect())
+}
+
+/// Hostnames used by more than one device. Only one of them can be reached by it.
+pub fn shared_hostnames(devices: &[FlatenedDevice<'_>]) -> Vec<String> {
+    let mut by_hostname: BTreeMap<String, Vec<&str>> = BTreeMap::new();
+    for device in devices {
+        if let Some(hostname) = &device.device.hostname {
+            by_hostname
+                .entry(hostname.to_ascii_lowercase())
+                .or_default()
+                .push(&device.device.device_id);
+        }
+    }
+
+    by_hostname
+        .into_iter()
+        .filter(|(_, device_ids)| device_ids.len() > 1)
+        .map(|(hostname, device_ids)| {
+            format!("{} share the hostname {}", device_ids.join(", "), hostname)
+        })
+        .collect()
+}
+
+/// `/etc/hosts` lines of the devices with both a hostname that is not an IP literal and an
+/// `ip_address`.
+fn hosts_snippet(devices: &[&config::DeviceConfig]) -> String {
+    let mut snippet = String::from("# IoT Edge devices, generated by iotedge_config\n");
+    for device in devices {
+        if let (Some(hostname), Some(ip_address)) = (&device.hostname, &device.ip_address) {
+            if ip_literal(hostname).is_some() {
+                continue;
+            }
+            snippet.push_str(&format!(
+                "{}\t{}\t# {}\n",
+                ip_address, hostname, device.device_id
+            ));
+        }
+    }
+
+    snippet
+}
+
+/// Zone file with the A and AAAA records of the devices with an `ip_address`, and warnings for
+/// the hostnames left out because they are not in the zone. A name server in the zone gets a glue
+/// record from `name_server_address`, or from the device of that hostname.
+fn zone_file(
+    devices: &[FlatenedDevice<'_>],
+    zone: &str,
+    ttl: u32,
+    name_server: Option<&str>,
+    name_server_address: Option<IpAddr>,
+    serial: u32,
+) -> (String, Vec<String>) {
+    let zone = zone.trim_end_matches('.').to_ascii_lowercase();
+    let name_server = match name_server {
+        Some(name_server) => name_server.trim_end_matches('.').to_ascii_lowercase(),
+        None => format!("ns.{}", zone),
+    };
+    let mut records = format!(
+        "$ORIGIN {zone}.\n$TTL {ttl}\n@\tIN\tSOA\t{ns}. hostmaster.{zone}. ({serial} 3600 600 86400 {ttl})\n@\tIN\tNS\t{ns}.\n",
+        zone = zone,
+        ttl = ttl,
+        ns = name_server,
+        serial = serial,
+    );
+    let mut warnings = Vec::new();
+    let mut glued = false;
+    for device in devices {
+        let (hostname, ip_address) = match (&device.device.hostname, &device.device.ip_address) {
+            (Some(hostname), Some(ip_address)) if ip_literal(hostname).is_none() => {
+                (hostname.to_ascii_lowercase(), ip_address)
+            }
+            _ => continue,
+        };
+        let hostname = hostname.trim_end_matches('.');
+        let name = match relative_name(hostname, &zone) {
+            Some(name) => name,
+            None => {
+                warnings.push(format!(
+                    "{} is not in zone {} and is left out of it",
+                    hostname, zone
+                ));
+                continue;
+            }
+        };
+        glued |= hostname == name_server;
+        records.push_str(&format!(
+            "{}\tIN\t{}\t{}\t; {}\n",
+            name,
+            record_type(ip_address),
+            ip_address,
+            device.device.device_id
+        ));
+    }
+
+    if let Some(name) = relative_name(&name_server, &zone).filter(|_| !glued) {
+        match name_server_address {
+            Some(address) => records.push_str(&format!(
+                "{}\tIN\t{}\t{}\t; name server\n",
+                name,
+                record_type(&address),
+                address
+            )),
+            None => warnings.push(format!(
+                "Name server {} is in zone {} but has no address, pass --name-server-address",
+                name_server, zone
+            )),
+        }
+    }
+
+    (records, warnings)
+}
+
+/// The name of the hostname relative to the zone, `@` for the zone itself, if it is in it.
+fn relative_name(hostname: &str, zone: &str) -> Option<String> {
+    if hostname == zone {
+        Some("@".to_owned())
+    } else {
+        hostname
+            .strip_suffix(&format!(".{}", zone))
+            .map(str::to_owned)
+    }
+}
+
+fn record_type(address: &IpAddr) -> &'static str {
+    if address.is_ipv4() {
+        "A"
+    } else {
+        "AAAA"
+    }
+}
+
+/// The serial of a zone file's SOA record.
+fn zone_serial(zone_file: &str) -> Option<u32> {
+    zone_file
+        .lines()
+        .find(|l| l.contains("\tSOA\t"))?
+        .split('(')
+        .nth(1)?
+        .split_whitespace()
+        .next()?
+        .parse()
+        .ok()
+}
+
+/// The serial of a regenerated zone: the date with a two digit count, above the previous one so
+/// secondaries pick up every regeneration, also several on one day.
+fn next_serial(previous: Option<u32>, now: DateTime<Utc>) -> u32 {
+    let today = now.year() as u32 * 1_000_000 + now.month() * 10_000 + now.day() * 100 + 1;
+    previous.map_or(today, |p| today.max(p.saturating_add(1)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_hostnames() {
+        let root: config::DeviceConfig = serde_yaml::from_str(
+            r#"
+device_id: top
+hostname: gateway.lab
+ip_address: 10.0.0.4
+child:
+  - device_id: lower
+    hostname: lower.lab
+    ip_address: 10.0.1.4
+  - device_id: other
+    hostname: Gateway.lab
+  - device_id: prompted
+"#,
+        )
+        .unwrap();
+        let devices = FlatenedDevice::flatten_devices(std::slice::from_ref(&root));
+
+        assert_eq!(
+            shared_hostnames(&devices),
+            vec!["top, other share the hostname gateway.lab"]
+        );
+        let all: Vec<&config::DeviceConfig> = devices.iter().map(|d| d.device).collect();
+        assert_eq!(
+            hosts_snippet(&all),
+            "# IoT Edge devices, generated by iotedge_config\n10.0.0.4\tgateway.lab\t# top\n10.0.1.4\tlower.lab\t# lower\n"
+        );
+
+        assert_eq!(url_host("fd00::1"), "[fd00::1]");
+        assert_eq!(url_host("[fd00::1]"), "[fd00::1]");
+        assert_eq!(bare_host("[fd00::1]"), "fd00::1");
+        assert_eq!(url_host("10.0.0.4"), "10.0.0.4");
+        assert_eq!(url_host("gateway.lab"), "gateway.lab");
+
+        let now = Utc.ymd(2021, 6, 1).and_hms(0, 0, 0);
+        let address = Some("10.0.0.53".parse().unwrap());
+        let (zone, warnings) = zone_file(&devices, "lab.", 300, None, address, 2021060101);
+        assert!(zone.starts_with(
+            "$ORIGIN lab.\n$TTL 300\n@\tIN\tSOA\tns.lab. hostmaster.lab. (2021060101 "
+        ));
+        assert!(zone.ends_with(
+            "gateway\tIN\tA\t10.0.0.4\t; top\nlower\tIN\tA\t10.0.1.4\t; lower\nns\tIN\tA\t10.0.0.53\t; name server\n"
+        ));
+        assert!(warnings.is_empty());
+        assert_eq!(zone_serial(&zone), Some(2021060101));
+
+        // A device serving the zone is its own glue, one outside the zone needs none
+        let (zone, warnings) =
+            zone_file(&devices, "lab", 300, Some("gateway.lab."), None, 2021060101);
+        assert!(zone.contains("@\tIN\tNS\tgateway.lab.\n"));
+        assert!(!zone.contains("; name server"));
+        assert!(warnings.is_empty());
+        let (_, warnings) = zone_file(&devices, "lab", 300, None, None, 2021060101);
+        assert_eq!(
+            warnings,
+            vec![
+                "Name server ns.lab is in zone lab but has no address, pass --name-server-address"
+            ]
+        );
+        let (_, warnings) = zone_file(
+            &devices,
+            "contoso.com",
+            300,
+            Some("ns1.example.net"),
+            None,
+            2021060101,
+        );
+        assert_eq!(
+            warnings,
+            vec![
+                "gateway.lab is not in zone contoso.com and is left out of it",
+                "lower.lab is not in zone contoso.com and is left out of it"
+            ]
+        );
+
+        assert_eq!(next_serial(None, now), 2021060101);
+        assert_eq!(next_serial(Some(2021060101), now), 2021060102);
+        assert_eq!(next_serial(Some(2021053107), now), 2021060101);
+    }
+}
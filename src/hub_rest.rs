@@ -0,0 +1,522 @@
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::process::Output;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use url::Url;
+
+use crate::commands::exit_status;
+use crate::sas::{self, ConnectionString};
+
+/// Version of the IoT Hub service API the requests are made with.
+const API_VERSION: &str = "2021-04-12";
+
+/// Longest a request may wait to connect or for the hub to answer.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How long the SAS token of a request is valid.
+const TOKEN_LIFETIME_SECS: i64 = 3600;
+
+/// Prefix of the device scopes of edge devices, followed by `<device_id>-<generation>`.
+const EDGE_SCOPE_PREFIX: &str = "ms-azure-iot-edge://";
+
+/// With --hub-api rest, answers the az commands that create and delete devices and set and show
+/// their parents through the IoT Hub service API over HTTPS instead, authenticated with a SAS
+/// token of the hub's connection string. The answers are shaped like az's, so the command runner
+/// records, replays and traces them the same way.
+pub struct HubRestClient {
+    host_name: String,
+    key_name: String,
+    key: String,
+}
+
+/// An HTTP response.
+#[derive(Debug, PartialEq)]
+struct Response {
+    status: u16,
+    reason: String,
+    body: Vec<u8>,
+}
+
+impl HubRestClient {
+    /// Fails without a shared access policy and key in the connection string.
+    pub fn new(connection_string: &str) -> Result<Self> {
+        let connection: ConnectionString = connection_string.parse()?;
+        match (
+            connection.shared_access_key_name,
+            connection.shared_access_key,
+        ) {
+            (Some(key_name), Some(key)) => Ok(Self {
+                host_name: connection.host_name,
+                key_name,
+                key,
+            }),
+            _ => Err(anyhow::Error::msg(
+                "--hub-api rest needs a hub connection string with SharedAccessKeyName and SharedAccessKey",
+            )),
+        }
+    }
+
+    /// Whether the az command is one this client answers.
+    pub fn answers(command: &str) -> bool {
+        matches!(
+            command,
+            "az iot hub device-identity create"
+                | "az iot hub device-identity delete"
+                | "az iot hub device-identity parent set"
+                | "az iot hub device-identity parent show"
+        )
+    }
+
+    /// Answers the az command with requests to the hub. Failed requests exit with 1 and the
+    /// status and error body on stderr, as az prints them.
+    pub async fn output(&self, args: &[&str]) -> Result<Output> {
+        let device_id = arg(args, "--device-id")?;
+        match args[0] {
+            "az iot hub device-identity create" => {
+                let body = create_body(args)?;
+                self.request("PUT", device_id, None, Some(body)).await
+            }
+            "az iot hub device-identity delete" => {
+                check_args(args, &["--device-id", "--hub-name"], &[])?;
+                self.request("DELETE", device_id, Some("*"), None).await
+            }
+            "az iot hub device-identity parent set" => {
+                check_args(
+                    args,
+                    &["--device-id", "--parent-device-id", "--hub-name"],
+                    &[],
+                )?;
+                let parent = self.request("GET", arg(args, "--parent-device-id")?, None, None);
+                let child = self.request("GET", device_id, None, None);
+                let (parent, child) = futures::future::try_join(parent, child).await?;
+                for output in &[&parent, &child] {
+                    if !output.status.success() {
+                        return Ok((*output).clone());
+                    }
+                }
+
+                let scope = device_json(&parent)?["deviceScope"].clone();
+                let mut child = device_json(&child)?;
+                child["parentScopes"] = serde_json::json!([scope]);
+                let etag = child["etag"].as_str().map(|e| format!("\"{}\"", e));
+                self.request("PUT", device_id, etag.as_deref(), Some(child))
+                    .await
+            }
+            "az iot hub device-identity parent show" => {
+                check_args(args, &["--device-id", "--hub-name"], &[])?;
+                let child = self.request("GET", device_id, None, None).await?;
+                if !child.status.success() {
+                    return Ok(child);
+                }
+                let parent = device_json(&child)?["parentScopes"]
+                    .get(0)
+                    .and_then(serde_json::Value::as_str)
+                    .and_then(scope_device_id);
+                match parent {
+                    Some(parent) => self.request("GET", &parent, None, None).await,
+                    None => Ok(Output {
+                        status: exit_status(1),
+                        stdout: Vec::new(),
+                        stderr: format!("ERROR: Device {} does not have a parent\n", device_id)
+                            .into_bytes(),
+                    }),
+                }
+            }
+            command => Err(anyhow::Error::msg(format!(
+                "{} is not answered by --hub-api rest",
+                command
+            ))),
+        }
+    }
+
+    /// Makes a request to `/devices/<device_id>`.
+    async fn request(
+        &self,
+        method: &'static str,
+        device_id: &str,
+        if_match: Option<&str>,
+        body: Option<serde_json::Value>,
+    ) -> Result<Output> {
+        let mut url = Url::parse(&format!("https://{}/", self.host_name))?;
+        url.path_segments_mut()
+            .map_err(|()| anyhow::Error::msg(format!("Unexpected hub {}", self.host_name)))?
+            .pop_if_empty()
+            .push("devices")
+            .push(device_id);
+        url.query_pairs_mut()
+            .append_pair("api-version", API_VERSION);
+
+        let expiry = chrono::Utc::now().timestamp() + TOKEN_LIFETIME_SECS;
+        let token = sas::signed_token(&self.host_name, &self.key, Some(&self.key_name), expiry)?;
+        let mut headers = vec![
+            ("Authorization".to_owned(), token),
+            ("Content-Type".to_owned(), "application/json".to_owned()),
+        ];
+        if let Some(if_match) = if_match {
+            headers.push(("If-Match".to_owned(), if_match.to_owned()));
+        }
+        let body = match body {
+            Some(body) => serde_json::to_vec(&body)?,
+            None => Vec::new(),
+        };
+
+        let host = self.host_name.clone();
+        let target = format!("{}?{}", url.path(), url.query().unwrap_or_default());
+        let response = tokio::task::spawn_blocking(move || {
+            https_request(&host, method, &target, &headers, &body)
+        })
+        .await??;
+
+        Ok(response.into_output())
+    }
+}
+
+impl Response {
+    /// The response as az would print it: the JSON body indented on stdout, or the status and
+    /// error body on stderr.
+    fn into_output(self) -> Output {
+        if (200..300).contains(&self.status) {
+            let stdout = serde_json::from_slice::<serde_json::Value>(&self.body)
+                .and_then(|v| serde_json::to_vec_pretty(&v))
+                .unwrap_or(self.body);
+            Output {
+                status: exit_status(0),
+                stdout,
+                stderr: Vec::new(),
+            }
+        } else {
+            Output {
+                status: exit_status(1),
+                stdout: Vec::new(),
+                stderr: format!(
+                    "ERROR: ({}) {}\n{}\n",
+                    self.status,
+                    self.reason,
+                    String::from_utf8_lossy(&self.body)
+                )
+                .into_bytes(),
+            }
+        }
+    }
+}
+
+/// The value of the argument.
+fn arg<'a>(args: &[&'a str], name: &str) -> Result<&'a str> {
+    args.iter()
+        .position(|a| *a == name)
+        .and_then(|i| args.get(i + 1))
+        .copied()
+        .ok_or_else(|| anyhow::Error::msg(format!("{} needs {}", args[0], name)))
+}
+
+/// Fails on arguments the request has nothing for, such as az_arguments of the config. `flags`
+/// take no value. `--login` is the connection string and is already used.
+fn check_args(args: &[&str], options: &[&str], flags: &[&str]) -> Result<()> {
+    let mut rest = args[1..].iter();
+    while let Some(arg) = rest.next() {
+        if flags.contains(arg) {
+            continue;
+        }
+        if !options.contains(arg) && *arg != "--login" {
+            return Err(anyhow::Error::msg(format!(
+                "{} of {} has no equivalent with --hub-api rest, use --hub-api az",
+                arg, args[0]
+            )));
+        }
+        rest.next();
+    }
+
+    Ok(())
+}
+
+/// The device of `device-identity create`.
+fn create_body(args: &[&str]) -> Result<serde_json::Value> {
+    check_args(
+        args,
+        &[
+            "--device-id",
+            "--hub-name",
+            "--auth-method",
+            "--primary-thumbprint",
+            "--secondary-thumbprint",
+            "--status",
+        ],
+        &["--edge-enabled"],
+    )?;
+    let authentication = match args
+        .iter()
+        .position(|a| *a == "--auth-method")
+        .and_then(|i| args.get(i + 1))
+    {
+        Some(&"x509_thumbprint") => serde_json::json!({
+            "type": "selfSigned",
+            "x509Thumbprint": {
+                "primaryThumbprint": arg(args, "--primary-thumbprint")?,
+                "secondaryThumbprint": arg(args, "--secondary-thumbprint")?,
+            },
+        }),
+        // The hub makes the keys
+        None | Some(&"shared_private_key") => serde_json::json!({
+            "type": "sas",
+            "symmetricKey": { "primaryKey": null, "secondaryKey": null },
+        }),
+        Some(method) => {
+            return Err(anyhow::Error::msg(format!(
+                "--auth-method {} has no equivalent with --hub-api rest",
+                method
+            )))
+        }
+    };
+    let status = match arg(args, "--status") {
+        Ok("disabled") => "disabled",
+        _ => "enabled",
+    };
+
+    Ok(serde_json::json!({
+        "deviceId": arg(args, "--device-id")?,
+        "status": status,
+        "authentication": authentication,
+        "capabilities": { "iotEdge": args.contains(&"--edge-enabled") },
+    }))
+}
+
+fn device_json(output: &Output) -> Result<serde_json::Value> {
+    serde_json::from_slice(&output.stdout).context("Error parsing the device from the hub")
+}
+
+/// The device id of an edge device scope, `ms-azure-iot-edge://<device_id>-<generation>`.
+fn scope_device_id(scope: &str) -> Option<String> {
+    let (device_id, generation) = scope.strip_prefix(EDGE_SCOPE_PREFIX)?.rsplit_once('-')?;
+    if generation.chars().all(|c| c.is_ascii_digit()) {
+        Some(device_id.to_owned())
+    } else {
+        None
+    }
+}
+
+/// Makes an HTTP/1.1 request over TLS to port 443 of the host and reads the whole response.
+fn https_request(
+    host: &str,
+    method: &str,
+    target: &str,
+    headers: &[(String, String)],
+    body: &[u8],
+) -> Result<Response> {
+    let address = std::net::ToSocketAddrs::to_socket_addrs(&(host, 443))?
+        .next()
+        .ok_or_else(|| anyhow::Error::msg(format!("{} does not resolve", host)))?;
+    let stream = TcpStream::connect_timeout(&address, REQUEST_TIMEOUT)
+        .with_context(|| format!("Error connecting to {}", host))?;
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    stream.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+    let mut stream = native_tls::TlsConnector::new()?
+        .connect(host, stream)
+        .with_context(|| format!("Error connecting to {} over TLS", host))?;
+
+    let mut request = format!(
+        "{} {} HTTP/1.1\r\nHost: {}\r\nContent-Length: {}\r\nConnection: close\r\n",
+        method,
+        target,
+        host,
+        body.len()
+    );
+    for (name, value) in headers {
+        request.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes())?;
+    stream.write_all(body)?;
+    stream.flush()?;
+
+    let mut raw = Vec::new();
+    let mut buffer = [0; 8192];
+    loop {
+        match stream.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(read) => raw.extend_from_slice(&buffer[..read]),
+            // Some servers close without a TLS close_notify once the response is sent
+            Err(_) if parse_response(&raw).is_some() => break,
+            Err(e) => return Err(e).with_context(|| format!("Error reading from {}", host)),
+        }
+        if let Some(response) = parse_response(&raw) {
+            return response;
+        }
+    }
+
+    parse_response(&raw).unwrap_or_else(|| {
+        Err(anyhow::Error::msg(format!(
+            "{} closed the connection before the end of the response",
+            host
+        )))
+    })
+}
+
+/// Parses a complete response, None while more of it is to come. Bodies are delimited by
+/// Content-Length or chunked.
+fn parse_response(raw: &[u8]) -> Option<Result<Response>> {
+    let end = raw.windows(4).position(|w| w == b"\r\n\r\n")?;
+    let head = String::from_utf8_lossy(&raw[..end]);
+    let rest = &raw[end + 4..];
+    let mut lines = head.split("\r\n");
+    let mut status_line = lines.next().unwrap_or_default().splitn(3, ' ');
+    let status = match status_line.nth(1).and_then(|s| s.parse().ok()) {
+        Some(status) => status,
+        None => {
+            return Some(Err(anyhow::Error::msg(format!(
+                "Unexpected HTTP response: {}",
+                head
+            ))))
+        }
+    };
+    let reason = status_line.next().unwrap_or_default().to_owned();
+    let header = |name: &str| {
+        head.split("\r\n")
+            .skip(1)
+            .filter_map(|l| l.split_once(':'))
+            .find(|(key, _)| key.trim().eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.trim().to_ascii_lowercase())
+    };
+
+    let body = if header("Transfer-Encoding").map_or(false, |t| t.contains("chunked")) {
+        dechunk(rest)?
+    } else {
+        match header("Content-Length").and_then(|l| l.parse::<usize>().ok()) {
+            Some(length) if rest.len() >= length => rest[..length].to_vec(),
+            Some(_) => return None,
+            None if status == 204 => Vec::new(),
+            // Until the connection closes
+            None => rest.to_vec(),
+        }
+    };
+
+    Some(Ok(Response {
+        status,
+        reason,
+        body,
+    }))
+}
+
+/// The body of a chunked response, None until its last chunk.
+fn dechunk(mut rest: &[u8]) -> Option<Vec<u8>> {
+    let mut body = Vec::new();
+    loop {
+        let line_end = rest.windows(2).position(|w| w == b"\r\n")?;
+        let size = String::from_utf8_lossy(&rest[..line_end]);
+        let size = usize::from_str_radix(size.split(';').next()?.trim(), 16).ok()?;
+        rest = &rest[line_end + 2..];
+        if size == 0 {
+            return Some(body);
+        }
+        if rest.len() < size + 2 {
+            return None;
+        }
+        body.extend_from_slice(&rest[..size]);
+        rest = &rest[size + 2..];
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_create_body() {
+        let body = create_body(&[
+            "az iot hub device-identity create",
+            "--device-id",
+            "A",
+            "--hub-name",
+            "hub",
+            "--edge-enabled",
+            "--auth-method",
+            "x509_thumbprint",
+            "--primary-thumbprint",
+            "AB",
+            "--secondary-thumbprint",
+            "CD",
+            "--status",
+            "disabled",
+            "--login",
+            "HostName=hub",
+        ])
+        .unwrap();
+        assert_eq!(
+            body,
+            serde_json::json!({
+                "deviceId": "A",
+                "status": "disabled",
+                "authentication": {
+                    "type": "selfSigned",
+                    "x509Thumbprint": { "primaryThumbprint": "AB", "secondaryThumbprint": "CD" },
+                },
+                "capabilities": { "iotEdge": true },
+            })
+        );
+
+        let body = create_body(&[
+            "az iot hub device-identity create",
+            "--device-id",
+            "A",
+            "--edge-enabled",
+        ])
+        .unwrap();
+        assert_eq!(body["authentication"]["type"], "sas");
+        assert_eq!(body["status"], "enabled");
+
+        assert!(create_body(&[
+            "az iot hub device-identity create",
+            "--device-id",
+            "A",
+            "--device-scope",
+            "scope",
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn test_parse_response() {
+        let response =
+            b"HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: 2\r\n\r\n{}";
+        assert_eq!(
+            parse_response(response).unwrap().unwrap(),
+            Response {
+                status: 200,
+                reason: "OK".to_owned(),
+                body: b"{}".to_vec(),
+            }
+        );
+        assert!(parse_response(&response[..response.len() - 1]).is_none());
+
+        let chunked = b"HTTP/1.1 412 Precondition Failed\r\nTransfer-Encoding: chunked\r\n\r\n4\r\n{\"a\"\r\n3\r\n:1}\r\n0\r\n\r\n";
+        let response = parse_response(chunked).unwrap().unwrap();
+        assert_eq!(response.body, b"{\"a\":1}");
+        assert!(parse_response(&chunked[..chunked.len() - 5]).is_none());
+
+        let output = response.into_output();
+        assert!(!output.status.success());
+        assert!(String::from_utf8_lossy(&output.stderr)
+            .starts_with("ERROR: (412) Precondition Failed\n"));
+        assert!(crate::is_conflict(&String::from_utf8_lossy(&output.stderr)));
+
+        let output = parse_response(
+            b"HTTP/1.1 200 OK\r\nContent-Length: 23\r\n\r\n{\"primaryKey\":\"secret\"}",
+        )
+        .unwrap()
+        .unwrap()
+        .into_output();
+        // Indented like az's, so the trace and recordings redact the key
+        assert!(String::from_utf8_lossy(&output.stdout).contains("\"primaryKey\": \""));
+    }
+
+    #[test]
+    fn test_scope_device_id() {
+        assert_eq!(
+            scope_device_id("ms-azure-iot-edge://top-layer-637579136540221469").as_deref(),
+            Some("top-layer")
+        );
+        assert_eq!(scope_device_id("ms-azure-iot-edge://top"), None);
+        assert_eq!(scope_device_id("other://top-1"), None);
+    }
+}
@@ -0,0 +1,249 @@
+use anyhow::Result;
+
+use crate::commands::CommandRunner;
+use crate::config;
+use crate::gc::CREATION_TAG;
+use crate::log::LogLevel;
+use crate::{quote_arg, FileManager, IoTHubDeviceManager};
+
+/// Sets up the consumer groups, endpoints and routes of `hub_routing`, so the hierarchy's
+/// telemetry reaches its consumers without setting up the hub by hand. Reruns create what is
+/// missing and update the routes.
+pub struct HubRoutingManager<'a> {
+    config: &'a config::Config,
+    file_manager: &'a FileManager,
+    hub_manager: &'a IoTHubDeviceManager<'a>,
+    runner: &'a CommandRunner,
+}
+
+impl<'a> HubRoutingManager<'a> {
+    pub fn new(
+        config: &'a config::Config,
+        file_manager: &'a FileManager,
+        hub_manager: &'a IoTHubDeviceManager<'a>,
+        runner: &'a CommandRunner,
+    ) -> Self {
+        Self {
+            config,
+            file_manager,
+            hub_manager,
+            runner,
+        }
+    }
+
+    pub async fn setup(&self, routing: &config::HubRouting) -> Result<()> {
+        let hub = self.config.iothub.iothub_name.as_str();
+        self.file_manager
+            .log(
+                LogLevel::Info,
+                "routing",
+                None,
+                format!(
+                    "Setting up {} consumer groups, {} endpoints and {} routes in hub {}",
+                    routing.consumer_groups.len(),
+                    routing.endpoints.len(),
+                    routing.routes.len(),
+                    hub
+                ),
+            )
+            .await?;
+
+        if !routing.consumer_groups.is_empty() {
+            let existing = self
+                .names(&["az iot hub consumer-group list", "--hub-name", hub])
+                .await?;
+            for group in &routing.consumer_groups {
+                if existing.contains(group) {
+                    continue;
+                }
+                self.run(
+                    &format!("consumer group {}", group),
+                    &[
+                        "az iot hub consumer-group create",
+                        "--hub-name",
+                        hub,
+                        "--name",
+                        group,
+                    ],
+                )
+                .await?;
+            }
+        }
+
+        if !routing.endpoints.is_empty() {
+            let hub_id = self.hub_manager.hub_resource_id().await?;
+            let (subscription, resource_group) = resource_scope(&hub_id).ok_or_else(|| {
+                anyhow::Error::msg(format!("Unexpected hub resource id {}", hub_id))
+            })?;
+            let existing = self
+                .names(&["az iot hub routing-endpoint list", "--hub-name", hub])
+                .await?;
+            for endpoint in &routing.endpoints {
+                if existing.contains(&endpoint.name) {
+                    continue;
+                }
+                let connection_string = quote_arg(&endpoint.connection_string);
+                let mut args = vec![
+                    "az iot hub routing-endpoint create",
+                    "--hub-name",
+                    hub,
+                    "--endpoint-name",
+                    &endpoint.name,
+                    "--endpoint-type",
+                    &endpoint.endpoint_type,
+                    "--endpoint-resource-group",
+                    endpoint.resource_group.as_deref().unwrap_or(resource_group),
+                    "--endpoint-subscription-id",
+                    subscription,
+                    "--connection-string",
+                    &connection_string,
+                ];
+                if let Some(container) = &endpoint.container {
+                    args.extend(&["--container", container.as_str()]);
+                }
+                self.run(&format!("endpoint {}", endpoint.name), &args)
+                    .await?;
+            }
+        }
+
+        if !routing.routes.is_empty() {
+            let existing = self
+                .names(&["az iot hub route list", "--hub-name", hub])
+                .await?;
+            for route in &routing.routes {
+                let condition = quote_arg(
+                    &route
+                        .condition
+                        .clone()
+                        .unwrap_or_else(|| default_condition(self.config.namespace.as_deref())),
+                );
+                let verb = if existing.contains(&route.name) {
+                    "az iot hub route update"
+                } else {
+                    "az iot hub route create"
+                };
+                self.run(
+                    &format!("route {}", route.name),
+                    &[
+                        verb,
+                        "--hub-name",
+                        hub,
+                        "--route-name",
+                        &route.name,
+                        "--endpoint-name",
+                        route.endpoint.as_deref().unwrap_or("events"),
+                        "--source",
+                        route.source.as_deref().unwrap_or("devicemessages"),
+                        "--condition",
+                        &condition,
+                        "--enabled",
+                        "true",
+                    ],
+                )
+                .await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Names of the items an `az ... list` command returns. Endpoints are listed by type.
+    async fn names(&self, args: &[&str]) -> Result<Vec<String>> {
+        let query = if args[0].ends_with("routing-endpoint list") {
+            "*[].name"
+        } else {
+            "[].name"
+        };
+        let mut args = args.to_vec();
+        args.extend(&["--query", query, "-o", "tsv"]);
+        let command = self.runner.output(&args).await?;
+        if !command.status.success() {
+            return Err(anyhow::Error::msg(format!(
+                "Failed to run {}:\n{}",
+                args[0],
+                String::from_utf8_lossy(&command.stderr)
+            )));
+        }
+
+        Ok(String::from_utf8_lossy(&command.stdout)
+            .lines()
+            .map(|l| l.trim().to_owned())
+            .filter(|l| !l.is_empty())
+            .collect())
+    }
+
+    async fn run(&self, what: &str, args: &[&str]) -> Result<()> {
+        let command = self.runner.output(args).await?;
+        if command.status.success() {
+            self.file_manager
+                .log(
+                    LogLevel::Debug,
+                    "routing",
+                    None,
+                    format!("Set up {}.", what),
+                )
+                .await?;
+
+            Ok(())
+        } else {
+            let error = format!(
+                "Failed to set up {}:\n{}\n{}\n",
+                what,
+                String::from_utf8_lossy(&command.stdout),
+                String::from_utf8_lossy(&command.stderr)
+            );
+            self.file_manager
+                .log(LogLevel::Debug, "routing", None, &error)
+                .await?;
+
+            Err(anyhow::Error::msg(error))
+        }
+    }
+}
+
+/// Routing query matching messages of the devices this tool created, by their creation tag.
+fn default_condition(namespace: Option<&str>) -> String {
+    match namespace {
+        // Namespaces have no quotes
+        Some(namespace) => format!("$twin.tags.{}.namespace = '{}'", CREATION_TAG, namespace),
+        None => format!("IS_DEFINED($twin.tags.{})", CREATION_TAG),
+    }
+}
+
+/// Subscription id and resource group of an Azure resource id.
+fn resource_scope(id: &str) -> Option<(&str, &str)> {
+    let parts: Vec<&str> = id.split('/').collect();
+    let after = |name: &str| {
+        parts
+            .iter()
+            .position(|p| p.eq_ignore_ascii_case(name))
+            .and_then(|i| parts.get(i + 1).copied())
+    };
+
+    Some((after("subscriptions")?, after("resourceGroups")?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_routing_defaults() {
+        assert_eq!(
+            resource_scope(
+                "/subscriptions/0000-1111/resourceGroups/edge-rg/providers/Microsoft.Devices/IotHubs/hub"
+            ),
+            Some(("0000-1111", "edge-rg"))
+        );
+        assert_eq!(resource_scope("hub"), None);
+
+        assert_eq!(
+            default_condition(None),
+            "IS_DEFINED($twin.tags.iotedge_config)"
+        );
+        assert_eq!(
+            default_condition(Some("lab1")),
+            "$twin.tags.iotedge_config.namespace = 'lab1'"
+        );
+    }
+}
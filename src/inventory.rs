@@ -0,0 +1,222 @@
+use std::collections::HashMap;
+
+use anyhow::{Context, Result};
+
+use crate::commands::CommandRunner;
+use crate::config;
+use crate::log::LogLevel;
+use crate::throttle::{HubOperation, HubThrottle};
+use crate::{quote_arg, FileManager, FlatenedDevice};
+
+#[derive(Debug, Default, serde::Deserialize)]
+struct ReportedModule {
+    #[serde(default)]
+    settings: ModuleSettings,
+}
+
+#[derive(Debug, Default, serde::Deserialize)]
+struct ModuleSettings {
+    image: Option<String>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct EdgeAgentTwin {
+    device_id: String,
+    #[serde(default)]
+    system_modules: HashMap<String, ReportedModule>,
+    #[serde(default)]
+    modules: HashMap<String, ReportedModule>,
+}
+
+/// Module versions a device's edgeAgent reports, taken from the tags of the module images.
+#[derive(Debug, Default, PartialEq)]
+pub struct RuntimeVersions {
+    pub edge_agent: Option<String>,
+    pub edge_hub: Option<String>,
+    /// Custom modules by name.
+    pub modules: HashMap<String, String>,
+}
+
+/// Lists the edge runtime and module versions of every device in the hierarchy, to find devices
+/// left behind by an upgrade.
+pub struct InventoryManager<'a> {
+    config: &'a config::Config,
+    file_manager: &'a FileManager,
+    throttle: &'a HubThrottle,
+    runner: &'a CommandRunner,
+}
+
+impl<'a> InventoryManager<'a> {
+    pub fn new(
+        config: &'a config::Config,
+        file_manager: &'a FileManager,
+        throttle: &'a HubThrottle,
+        runner: &'a CommandRunner,
+    ) -> Self {
+        Self {
+            config,
+            file_manager,
+            throttle,
+            runner,
+        }
+    }
+
+    /// Prints the version matrix and warns about devices whose edgeHub version differs from their
+    /// parent's, which breaks nested connections.
+    pub async fn print_versions(&self) -> Result<()> {
+        let versions = self.runtime_versions().await?;
+        let devices = FlatenedDevice::flatten_devices(&self.config.root_devices);
+
+        let unknown = "-".to_owned();
+        println!(
+            "{:<24} {:<6} {:<12} {:<12} {}",
+            "DEVICE", "LAYER", "EDGEAGENT", "EDGEHUB", "MODULES"
+        );
+        for device in &devices {
+            let device_id = device.device.device_id.as_str();
+            let empty = RuntimeVersions::default();
+            let device_versions = versions.get(device_id).unwrap_or(&empty);
+            let mut modules: Vec<String> = device_versions
+                .modules
+                .iter()
+                .map(|(name, version)| format!("{}={}", name, version))
+                .collect();
+            modules.sort();
+            println!(
+                "{:<24} {:<6} {:<12} {:<12} {}",
+                device_id,
+                device.layer,
+                device_versions.edge_agent.as_ref().unwrap_or(&unknown),
+                device_versions.edge_hub.as_ref().unwrap_or(&unknown),
+                modules.join(" ")
+            );
+        }
+
+        for warning in edge_hub_mismatches(&devices, &versions) {
+            self.file_manager
+                .log(LogLevel::Warn, "inventory", None, warning)
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Versions by device id, for the devices whose edgeAgent has reported.
+    async fn runtime_versions(&self) -> Result<HashMap<String, RuntimeVersions>> {
+        let query = "SELECT deviceId, properties.reported.systemModules, properties.reported.modules FROM devices.modules WHERE moduleId = '$edgeAgent'";
+        self.throttle.wait(HubOperation::Twin).await;
+        let command = self
+            .runner
+            .output(&[
+                "az iot hub query",
+                "--hub-name",
+                &self.config.iothub.iothub_name,
+                "--query-command",
+                &quote_arg(query),
+                "--top",
+                "-1",
+                "-o",
+                "json",
+            ])
+            .await?;
+        if !command.status.success() {
+            return Err(anyhow::Error::msg(format!(
+                "Failed to query the edgeAgent twins of hub {}:\n{}",
+                self.config.iothub.iothub_name,
+                String::from_utf8_lossy(&command.stderr)
+            )));
+        }
+
+        let twins: Vec<EdgeAgentTwin> =
+            serde_json::from_slice(&command.stdout).context("Error parsing hub query result")?;
+
+        Ok(twins
+            .into_iter()
+            .map(|twin| {
+                let version = |module: Option<&ReportedModule>| {
+                    module.and_then(|m| m.settings.image.as_deref().map(image_tag))
+                };
+                let versions = RuntimeVersions {
+                    edge_agent: version(twin.system_modules.get("edgeAgent")),
+                    edge_hub: version(twin.system_modules.get("edgeHub")),
+                    modules: twin
+                        .modules
+                        .iter()
+                        .filter_map(|(name, m)| Some((name.clone(), version(Some(m))?)))
+                        .collect(),
+                };
+                (twin.device_id, versions)
+            })
+            .collect())
+    }
+}
+
+/// The tag of an image, `latest` if it has none.
+fn image_tag(image: &str) -> String {
+    let name = image.rsplit('/').next().unwrap_or(image);
+    match name.split_once(':') {
+        Some((_, tag)) => tag.to_owned(),
+        None => "latest".to_owned(),
+    }
+}
+
+fn edge_hub_mismatches(
+    devices: &[FlatenedDevice<'_>],
+    versions: &HashMap<String, RuntimeVersions>,
+) -> Vec<String> {
+    let edge_hub = |device_id: &str| versions.get(device_id).and_then(|v| v.edge_hub.as_ref());
+
+    devices
+        .iter()
+        .filter_map(|device| {
+            let parent = device.parent?;
+            let child_version = edge_hub(&device.device.device_id)?;
+            let parent_version = edge_hub(&parent.device_id)?;
+            if child_version == parent_version {
+                return None;
+            }
+
+            Some(format!(
+                "{} runs edgeHub {} but its parent {} runs edgeHub {}. Nested devices should run the same edgeHub version as their parent.",
+                device.device.device_id, child_version, parent.device_id, parent_version
+            ))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_edge_hub_mismatches() {
+        let config = config::Config::read_config("src/test_files/cert_test.yaml")
+            .await
+            .unwrap();
+        let devices = FlatenedDevice::flatten_devices(&config.root_devices);
+        let with_edge_hub = |image: &str| RuntimeVersions {
+            edge_hub: Some(image_tag(image)),
+            ..Default::default()
+        };
+
+        let mut versions = HashMap::new();
+        versions.insert(
+            "A".to_owned(),
+            with_edge_hub("mcr.microsoft.com/azureiotedge-hub:1.2"),
+        );
+        versions.insert(
+            "AA".to_owned(),
+            with_edge_hub("$upstream:443/azureiotedge-hub:1.2"),
+        );
+        versions.insert(
+            "AB".to_owned(),
+            with_edge_hub("mcr.microsoft.com/azureiotedge-hub:1.1"),
+        );
+
+        let warnings = edge_hub_mismatches(&devices, &versions);
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].starts_with("AB runs edgeHub 1.1 but its parent A runs edgeHub 1.2"));
+        assert_eq!(image_tag("localhost:5000/module"), "latest");
+    }
+}
@@ -0,0 +1,353 @@
+use std::collections::HashSet;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Datelike, Duration, NaiveDate, NaiveDateTime, TimeZone, Utc};
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+
+/// What happened to a cert in the issuance log.
+#[derive(Clone, Copy, Debug, serde::Deserialize, serde::Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum IssuanceEvent {
+    Issued,
+    /// Issued for a device and subject that already had a cert.
+    Rotated,
+    /// Revoked by `certs revoke`. The line repeats the revoked cert, with the time it was revoked.
+    Revoked,
+}
+
+/// One line of `certificates/issued_certs.jsonl`, the append-only record of every cert this tool
+/// has made.
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize, PartialEq)]
+pub struct IssuedCert {
+    pub event: IssuanceEvent,
+    pub time: String,
+    pub device_id: Option<String>,
+    pub serial: String,
+    pub subject: String,
+    pub issuer: String,
+    pub not_before: String,
+    pub not_after: String,
+    pub fingerprint: String,
+}
+
+impl IssuedCert {
+    /// Parses the output of `openssl x509 -noout -serial -subject -issuer -dates -fingerprint -sha256`.
+    pub fn from_openssl_text(
+        text: &str,
+        event: IssuanceEvent,
+        device_id: Option<&str>,
+        now: DateTime<Utc>,
+    ) -> Result<Self> {
+        let field = |name: &str| -> Result<String> {
+            text.lines()
+                .filter_map(|l| l.split_once('='))
+                .find(|(key, _)| key.trim().eq_ignore_ascii_case(name))
+                .map(|(_, value)| value.trim().to_owned())
+                .ok_or_else(|| {
+                    anyhow::Error::msg(format!("openssl output is missing {}:\n{}", name, text))
+                })
+        };
+
+        Ok(Self {
+            event,
+            time: now.to_rfc3339(),
+            device_id: device_id.map(str::to_owned),
+            serial: field("serial")?,
+            subject: field("subject")?,
+            issuer: field("issuer")?,
+            not_before: field("notBefore")?,
+            not_after: field("notAfter")?,
+            fingerprint: field("sha256 Fingerprint")?,
+        })
+    }
+}
+
+impl IssuedCert {
+    /// Parses not_after, which is in openssl's `Jun  1 12:00:00 2022 GMT` form.
+    pub fn expiry(&self) -> Result<DateTime<Utc>> {
+        let not_after = self
+            .not_after
+            .split_whitespace()
+            .collect::<Vec<_>>()
+            .join(" ");
+        let expiry = NaiveDateTime::parse_from_str(&not_after, "%b %d %H:%M:%S %Y GMT")
+            .with_context(|| format!("Error parsing notAfter {:?}", self.not_after))?;
+
+        Ok(Utc.from_utc_datetime(&expiry))
+    }
+}
+
+/// A device, or the root CA when device_id is None, scheduled for renewal in the week starting on
+/// `week`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Renewal {
+    pub week: NaiveDate,
+    pub device_id: Option<String>,
+    pub expiry: DateTime<Utc>,
+    /// The week starts after the cert expires, because earlier weeks are full.
+    pub late: bool,
+}
+
+/// Serials of the certs with a `revoked` line.
+pub fn revoked_serials(certs: &[IssuedCert]) -> HashSet<String> {
+    certs
+        .iter()
+        .filter(|c| c.event == IssuanceEvent::Revoked)
+        .map(|c| normalize_serial(&c.serial))
+        .collect()
+}
+
+/// The latest cert of each device and subject, unless it was revoked. A revoked cert leaves its
+/// subject without one, the certs it replaced do not count again.
+pub fn current(certs: &[IssuedCert]) -> Vec<&IssuedCert> {
+    let revoked = revoked_serials(certs);
+    let mut latest: Vec<&IssuedCert> = Vec::new();
+    for cert in certs.iter().filter(|c| c.event != IssuanceEvent::Revoked) {
+        latest.retain(|c| c.device_id != cert.device_id || c.subject != cert.subject);
+        latest.push(cert);
+    }
+    latest.retain(|c| !revoked.contains(&normalize_serial(&c.serial)));
+
+    latest
+}
+
+/// Schedules every device whose certs expire before `now + within` into weeks of at most
+/// `per_week` devices, soonest expiry first, starting this week. Only the latest cert of each
+/// device and subject counts, unless it was revoked, and a device is due when its first cert
+/// expires. The root CA, if
+/// due, always goes first since everything under it is reissued with it.
+pub fn renewal_plan(
+    certs: &[IssuedCert],
+    now: DateTime<Utc>,
+    within: Duration,
+    per_week: usize,
+) -> Result<Vec<Renewal>> {
+    let mut due: Vec<(Option<String>, DateTime<Utc>)> = Vec::new();
+    for cert in current(certs) {
+        let expiry = cert.expiry()?;
+        if expiry > now + within {
+            continue;
+        }
+        match due
+            .iter_mut()
+            .find(|(device_id, _)| *device_id == cert.device_id)
+        {
+            Some((_, first)) => *first = (*first).min(expiry),
+            None => due.push((cert.device_id.clone(), expiry)),
+        }
+    }
+    // Ties go by device id, since certs made in the same run are logged in no set order
+    due.sort_by(|(a_id, a_expiry), (b_id, b_expiry)| {
+        (a_id.is_some(), a_expiry, a_id).cmp(&(b_id.is_some(), b_expiry, b_id))
+    });
+
+    let today = now.date().naive_utc();
+    let monday = today - Duration::days(today.weekday().num_days_from_monday().into());
+    let per_week = per_week.max(1);
+    let plan = due
+        .into_iter()
+        .enumerate()
+        .map(|(i, (device_id, expiry))| {
+            let week = monday + Duration::weeks((i / per_week) as i64);
+            Renewal {
+                week,
+                device_id,
+                expiry,
+                late: week > expiry.date().naive_utc(),
+            }
+        })
+        .collect();
+
+    Ok(plan)
+}
+
+/// iCalendar file with an all-day reminder on the first day of each week of the plan.
+pub fn renewal_calendar(plan: &[Renewal], now: DateTime<Utc>) -> String {
+    let mut lines = vec![
+        "BEGIN:VCALENDAR".to_owned(),
+        "VERSION:2.0".to_owned(),
+        "PRODID:-//iotedge_config//renewal plan//EN".to_owned(),
+    ];
+    let mut weeks: Vec<NaiveDate> = plan.iter().map(|r| r.week).collect();
+    weeks.dedup();
+    for week in weeks {
+        let devices: Vec<&str> = plan
+            .iter()
+            .filter(|r| r.week == week)
+            .map(|r| r.device_id.as_deref().unwrap_or("root CA"))
+            .collect();
+        lines.extend(vec![
+            "BEGIN:VEVENT".to_owned(),
+            format!("UID:renewal-{}@iotedge-config", week.format("%Y%m%d")),
+            format!("DTSTAMP:{}", now.format("%Y%m%dT%H%M%SZ")),
+            format!("DTSTART;VALUE=DATE:{}", week.format("%Y%m%d")),
+            format!(
+                "SUMMARY:Renew IoT Edge certs: {}",
+                escape_ics(&devices.join(", "))
+            ),
+            "END:VEVENT".to_owned(),
+        ]);
+    }
+    lines.push("END:VCALENDAR".to_owned());
+
+    // iCalendar lines end in CRLF
+    lines.into_iter().map(|l| l + "\r\n").collect()
+}
+
+fn escape_ics(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace(';', "\\;")
+        .replace(',', "\\,")
+}
+
+/// Serials in the form openssl prints them: uppercase hex without `0x` or leading zeros.
+pub fn normalize_serial(serial: &str) -> String {
+    let serial = serial.trim();
+    let serial = serial
+        .strip_prefix("0x")
+        .unwrap_or(serial)
+        .trim_start_matches('0');
+    if serial.is_empty() {
+        "0".to_owned()
+    } else {
+        serial.to_uppercase()
+    }
+}
+
+pub async fn append(log: &Path, cert: &IssuedCert) -> Result<()> {
+    let mut line = serde_json::to_string(cert)?;
+    line.push('\n');
+
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(log)
+        .await?;
+    file.write_all(line.as_bytes()).await?;
+
+    Ok(())
+}
+
+pub async fn read(log: &Path) -> Result<Vec<IssuedCert>> {
+    let contents = match fs::read_to_string(log).await {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+
+    contents
+        .lines()
+        .filter(|l| !l.trim().is_empty())
+        .enumerate()
+        .map(|(i, l)| {
+            serde_json::from_str(l)
+                .with_context(|| format!("Error parsing line {} of {:?}", i + 1, log))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_openssl_text() {
+        let text = "serial=1A2B\nsubject=CN = top-layer.deviceca\nissuer=CN = Azure_IoT_Config_Cli_Cert\nnotBefore=Jun  1 12:00:00 2021 GMT\nnotAfter=Jun  1 12:00:00 2022 GMT\nSHA256 Fingerprint=AB:CD:EF\n";
+        let cert = IssuedCert::from_openssl_text(
+            text,
+            IssuanceEvent::Issued,
+            Some("top-layer"),
+            Utc::now(),
+        )
+        .unwrap();
+
+        assert_eq!(cert.serial, "1A2B");
+        assert_eq!(cert.subject, "CN = top-layer.deviceca");
+        assert_eq!(cert.issuer, "CN = Azure_IoT_Config_Cli_Cert");
+        assert_eq!(cert.not_after, "Jun  1 12:00:00 2022 GMT");
+        assert_eq!(cert.fingerprint, "AB:CD:EF");
+        assert_eq!(cert.device_id.as_deref(), Some("top-layer"));
+
+        assert!(IssuedCert::from_openssl_text(
+            "serial=1A2B",
+            IssuanceEvent::Issued,
+            None,
+            Utc::now()
+        )
+        .is_err());
+    }
+
+    fn cert(device_id: Option<&str>, subject: &str, not_after: &str) -> IssuedCert {
+        IssuedCert {
+            event: IssuanceEvent::Issued,
+            time: String::new(),
+            device_id: device_id.map(str::to_owned),
+            serial: "1".to_owned(),
+            subject: subject.to_owned(),
+            issuer: String::new(),
+            not_before: String::new(),
+            not_after: not_after.to_owned(),
+            fingerprint: String::new(),
+        }
+    }
+
+    #[test]
+    fn test_renewal_plan() {
+        let certs = vec![
+            cert(Some("a"), "CN = a.deviceca", "Jun  1 12:00:00 2022 GMT"),
+            cert(Some("b"), "CN = b.deviceca", "Jun 20 12:00:00 2022 GMT"),
+            cert(Some("b"), "CN = b", "Jun  2 12:00:00 2022 GMT"),
+            cert(Some("c"), "CN = c.deviceca", "May 30 12:00:00 2022 GMT"),
+            // Rotated, so the first cert of c no longer counts
+            cert(Some("c"), "CN = c.deviceca", "May 30 12:00:00 2023 GMT"),
+            cert(None, "CN = root", "Jul  1 12:00:00 2022 GMT"),
+            IssuedCert {
+                serial: "D".to_owned(),
+                ..cert(Some("d"), "CN = d.deviceca", "Jun  1 12:00:00 2022 GMT")
+            },
+            // Revoked, so d has no cert to renew
+            IssuedCert {
+                event: IssuanceEvent::Revoked,
+                serial: "0x0d".to_owned(),
+                ..cert(Some("d"), "CN = d.deviceca", "Jun  1 12:00:00 2022 GMT")
+            },
+        ];
+        assert_eq!(
+            revoked_serials(&certs).into_iter().collect::<Vec<_>>(),
+            vec!["D"]
+        );
+        assert_eq!(current(&certs).len(), 5);
+        // A Wednesday
+        let now = Utc.ymd(2022, 5, 25).and_hms(0, 0, 0);
+        let plan = renewal_plan(&certs, now, Duration::days(60), 1).unwrap();
+
+        let monday = NaiveDate::from_ymd(2022, 5, 23);
+        let order: Vec<(Option<&str>, NaiveDate, bool)> = plan
+            .iter()
+            .map(|r| (r.device_id.as_deref(), r.week, r.late))
+            .collect();
+        assert_eq!(
+            order,
+            vec![
+                (None, monday, false),
+                (Some("a"), monday + Duration::weeks(1), false),
+                (Some("b"), monday + Duration::weeks(2), true),
+            ]
+        );
+
+        let calendar = renewal_calendar(&plan, now);
+        assert!(calendar.contains("DTSTART;VALUE=DATE:20220523\r\n"));
+        assert!(calendar.contains("SUMMARY:Renew IoT Edge certs: root CA\r\n"));
+        assert_eq!(calendar.matches("BEGIN:VEVENT").count(), 3);
+        assert_eq!(escape_ics("a,b;c"), "a\\,b\\;c");
+    }
+
+    #[test]
+    fn test_normalize_serial() {
+        assert_eq!(normalize_serial("0x0a1b"), "A1B");
+        assert_eq!(normalize_serial("0A1B"), "A1B");
+        assert_eq!(normalize_serial("00"), "0");
+    }
+}
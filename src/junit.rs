@@ -0,0 +1,93 @@
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use tokio::fs;
+
+/// One check of one device. The device is the test case's class, so CI test tabs group the
+/// checks by device.
+pub struct TestCase {
+    pub device_id: String,
+    pub check: String,
+    /// What was wrong, if the check failed.
+    pub failure: Option<String>,
+}
+
+/// JUnit XML report of the test cases, as read by Azure DevOps and GitHub Actions.
+pub fn report(suite: &str, cases: &[TestCase]) -> String {
+    let failures = cases.iter().filter(|c| c.failure.is_some()).count();
+    let mut xml = format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<testsuites>\n  <testsuite name=\"{}\" tests=\"{}\" failures=\"{}\">\n",
+        escape(suite),
+        cases.len(),
+        failures
+    );
+    for case in cases {
+        let attributes = format!(
+            "classname=\"{}\" name=\"{}\"",
+            escape(&case.device_id),
+            escape(&case.check)
+        );
+        match &case.failure {
+            None => xml.push_str(&format!("    <testcase {}/>\n", attributes)),
+            Some(failure) => xml.push_str(&format!(
+                "    <testcase {}>\n      <failure message=\"{}\">{}</failure>\n    </testcase>\n",
+                attributes,
+                escape(failure.lines().next().unwrap_or_default()),
+                escape(failure)
+            )),
+        }
+    }
+    xml.push_str("  </testsuite>\n</testsuites>\n");
+
+    xml
+}
+
+pub async fn write(path: &Path, suite: &str, cases: &[TestCase]) -> Result<()> {
+    fs::write(path, report(suite, cases))
+        .await
+        .with_context(|| format!("Error writing JUnit report {:?}", path))
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_report() {
+        let cases = vec![
+            TestCase {
+                device_id: "top".to_owned(),
+                check: "config.toml".to_owned(),
+                failure: None,
+            },
+            TestCase {
+                device_id: "top".to_owned(),
+                check: "certs/root.pem".to_owned(),
+                failure: Some("Missing <root.pem>\nsecond line".to_owned()),
+            },
+        ];
+
+        assert_eq!(
+            report("drift", &cases),
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<testsuites>
+  <testsuite name="drift" tests="2" failures="1">
+    <testcase classname="top" name="config.toml"/>
+    <testcase classname="top" name="certs/root.pem">
+      <failure message="Missing &lt;root.pem&gt;">Missing &lt;root.pem&gt;
+second line</failure>
+    </testcase>
+  </testsuite>
+</testsuites>
+"#
+        );
+    }
+}
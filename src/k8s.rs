@@ -0,0 +1,194 @@
+use anyhow::{Context, Result};
+use tokio::fs;
+
+use crate::artifacts;
+use crate::config;
+use crate::hostnames;
+use crate::log::LogLevel;
+use crate::{CreatedDevice, FileManager};
+
+/// Writes a Kubernetes Secret and ConfigMap per device with its certs and config.toml, so edge
+/// devices simulated in a cluster (IoT Edge on K8s, KubeVirt) can mount the generated identities.
+pub struct ManifestManager<'a> {
+    config: &'a config::Config,
+    file_manager: &'a FileManager,
+}
+
+impl<'a> ManifestManager<'a> {
+    pub fn new(config: &'a config::Config, file_manager: &'a FileManager) -> Self {
+        Self {
+            config,
+            file_manager,
+        }
+    }
+
+    pub async fn make_all_manifests(
+        &self,
+        devices: &[CreatedDevice<'_>],
+        namespace: Option<&str>,
+    ) -> Result<()> {
+        self.file_manager
+            .log(
+                LogLevel::Info,
+                "k8s",
+                None,
+                format!(
+                    "Creating Kubernetes manifests for {} devices.",
+                    devices.len()
+                ),
+            )
+            .await?;
+
+        for device in devices {
+            self.make_manifest(device, namespace).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn make_manifest(
+        &self,
+        device: &CreatedDevice<'_>,
+        namespace: Option<&str>,
+    ) -> Result<()> {
+        let device_id = device.device.device_id.as_str();
+        let device_folder = self.file_manager.get_folder(device_id).await?;
+        let name = resource_name(device_id);
+
+        let names = &self.config.cert_names;
+        let mut secret_files = vec![
+            names.device_ca_chain(device_id),
+            names.device_ca_key(device_id),
+        ];
+        if self.config.iothub.authentication_method == config::IoTHubAuthMethod::X509Cert {
+            secret_files.push(names.hub_auth_cert(device_id));
+            secret_files.push(names.hub_auth_key(device_id));
+        }
+        let mut config_files = vec![names.root_ca_cert.clone()];
+
+        // With symmetric keys the device key is inline in config.toml, so it belongs in the Secret
+        if self.config.iothub.authentication_method == config::IoTHubAuthMethod::SymmetricKey {
+            secret_files.push("config.toml".to_owned());
+        } else {
+            config_files.push("config.toml".to_owned());
+        }
+
+        let mut secret_data = serde_json::Map::new();
+        for file in &secret_files {
+            let contents = fs::read(device_folder.join(file))
+                .await
+                .with_context(|| format!("Error reading {} of {}", file, device_id))?;
+            secret_data.insert(file.clone(), base64::encode(contents).into());
+        }
+
+        let mut config_data = serde_json::Map::new();
+        config_data.insert("device_id".to_owned(), device_id.into());
+        if let Some(hostname) = &device.device.hostname {
+            config_data.insert("hostname".to_owned(), hostnames::bare_host(hostname).into());
+        }
+        let external_parent = device.device.external_parent.as_ref();
+        if let Some(parent_hostname) = device
+            .parent
+            .and_then(|p| p.hostname.as_deref())
+            .or_else(|| external_parent.and_then(|p| p.hostname.as_deref()))
+        {
+            config_data.insert(
+                "parent_hostname".to_owned(),
+                hostnames::url_host(parent_hostname).into(),
+            );
+        }
+        for file in &config_files {
+            let contents = fs::read_to_string(device_folder.join(file))
+                .await
+                .with_context(|| format!("Error reading {} of {}", file, device_id))?;
+            config_data.insert(file.clone(), contents.into());
+        }
+
+        let metadata = |name: String| {
+            let mut metadata = serde_json::json!({
+                "name": name,
+                "labels": {
+                    "app.kubernetes.io/managed-by": "iotedge_config_cli",
+                },
+                "annotations": {
+                    "iotedge_config_cli/device-id": device_id,
+                    "iotedge_config_cli/iothub-hostname": self.config.iothub.iothub_hostname,
+                },
+            });
+            if let Some(namespace) = namespace {
+                metadata["namespace"] = namespace.into();
+            }
+            metadata
+        };
+        let secret = serde_json::json!({
+            "apiVersion": "v1",
+            "kind": "Secret",
+            "metadata": metadata(format!("{}-certs", name)),
+            "type": "Opaque",
+            "data": secret_data,
+        });
+        let config_map = serde_json::json!({
+            "apiVersion": "v1",
+            "kind": "ConfigMap",
+            "metadata": metadata(format!("{}-config", name)),
+            "data": config_data,
+        });
+
+        let manifest = format!(
+            "{}\n---\n{}",
+            serde_yaml::to_string(&secret)?,
+            serde_yaml::to_string(&config_map)?
+        );
+        let manifest = artifacts::stamp(self.config, self.file_manager, &manifest);
+        let file = device_folder.join("k8s.yaml");
+        self.file_manager
+            .log(
+                LogLevel::Debug,
+                "k8s",
+                Some(device_id),
+                format!(
+                    "Writing Secret {}-certs and ConfigMap {}-config to {:?}",
+                    name, name, file
+                ),
+            )
+            .await?;
+        self.file_manager
+            .write_generated(&file, &manifest, Some(device_id))
+            .await?;
+
+        Ok(())
+    }
+}
+
+/// Kubernetes object names must be lowercase alphanumerics, '-' or '.', and start and end with an
+/// alphanumeric. Device ids allow more than that, so other characters are replaced with '-'.
+pub(crate) fn resource_name(device_id: &str) -> String {
+    let name: String = device_id
+        .to_lowercase()
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '.' {
+                c
+            } else {
+                '-'
+            }
+        })
+        .collect();
+
+    // Leave room for the -certs and -config suffixes within the 253 character limit
+    let name: String = name.chars().take(240).collect();
+    name.trim_matches(|c: char| !c.is_ascii_alphanumeric())
+        .to_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resource_name() {
+        assert_eq!(resource_name("top-layer"), "top-layer");
+        assert_eq!(resource_name("Lower_Layer:1"), "lower-layer-1");
+        assert_eq!(resource_name("_device.01_"), "device.01");
+    }
+}
@@ -0,0 +1,161 @@
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+
+use anyhow::{Context, Result};
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+use tokio::sync::Mutex;
+
+use crate::commands;
+
+/// Environment variable with the passphrase of `--key-cache`. Prompted for when unset.
+pub const KEY_CACHE_PASSPHRASE_ENV: &str = "IOTEDGE_CONFIG_KEY_CACHE_PASSPHRASE";
+
+/// Device keys created in or read from the hub, kept in a file encrypted with AES-256 and a key
+/// derived from the passphrase, so later runs read them locally instead of querying the hub.
+/// Keys are by hub and device id, so one cache can serve several hubs.
+pub struct KeyCache {
+    path: PathBuf,
+    openssl_path: Option<PathBuf>,
+    passphrase: String,
+    keys: Mutex<BTreeMap<String, String>>,
+}
+
+/// The cache's passphrase from the environment, or by prompting.
+pub fn passphrase(path: &Path) -> Result<String> {
+    match std::env::var(KEY_CACHE_PASSPHRASE_ENV) {
+        Ok(passphrase) => Ok(passphrase),
+        Err(_) => crate::prompt_secret(&format!("Enter pass phrase for key cache {:?}: ", path)),
+    }
+}
+
+impl KeyCache {
+    /// Decrypts the cache at `path`, or starts an empty one if there is no file yet.
+    pub async fn open(
+        path: &Path,
+        openssl_path: Option<&Path>,
+        passphrase: String,
+    ) -> Result<Self> {
+        let mut cache = Self {
+            path: path.to_owned(),
+            openssl_path: openssl_path.map(Path::to_owned),
+            passphrase,
+            keys: Mutex::new(BTreeMap::new()),
+        };
+        if path.exists() {
+            let json = cache
+                .openssl(
+                    &[OsStr::new("-d"), OsStr::new("-in"), path.as_os_str()],
+                    &[],
+                )
+                .await
+                .with_context(|| {
+                    format!(
+                        "Error decrypting key cache {:?}, check the passphrase",
+                        path
+                    )
+                })?;
+            cache.keys = Mutex::new(
+                serde_json::from_slice(&json)
+                    .with_context(|| format!("Error parsing key cache {:?}", path))?,
+            );
+        }
+
+        Ok(cache)
+    }
+
+    pub async fn get(&self, hub: &str, device_id: &str) -> Option<String> {
+        self.keys
+            .lock()
+            .await
+            .get(&cache_key(hub, device_id))
+            .cloned()
+    }
+
+    pub async fn insert(&self, hub: &str, device_id: &str, key: &str) {
+        self.keys
+            .lock()
+            .await
+            .insert(cache_key(hub, device_id), key.to_owned());
+    }
+
+    /// Encrypts the keys to the cache file, replacing it.
+    pub async fn save(&self) -> Result<()> {
+        let json = serde_json::to_vec(&*self.keys.lock().await)?;
+        if let Some(parent) = self.path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        self.openssl(&[OsStr::new("-out"), self.path.as_os_str()], &json)
+            .await
+            .with_context(|| format!("Error encrypting key cache {:?}", self.path))?;
+
+        Ok(())
+    }
+
+    async fn openssl(&self, args: &[&OsStr], input: &[u8]) -> Result<Vec<u8>> {
+        // The passphrase is passed through the environment so it never shows up in the process list
+        let mut child = self
+            .openssl_path
+            .as_ref()
+            .map_or_else(|| Command::new("openssl"), Command::new)
+            .args(&["enc", "-aes-256-cbc", "-pbkdf2", "-iter", "100000", "-salt"])
+            .args(&["-pass", &format!("env:{}", KEY_CACHE_PASSPHRASE_ENV)])
+            .args(args)
+            .env(KEY_CACHE_PASSPHRASE_ENV, &self.passphrase)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .context("Error running openssl")?;
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin.write_all(input).await?;
+        }
+        let command = commands::wait_bounded(child, "openssl").await?;
+        if !command.status.success() {
+            return Err(anyhow::Error::msg(
+                String::from_utf8_lossy(&command.stderr).into_owned(),
+            ));
+        }
+
+        Ok(command.stdout)
+    }
+}
+
+fn cache_key(hub: &str, device_id: &str) -> String {
+    format!("{}/{}", hub, device_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_key_cache() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("keys.enc");
+
+        let cache = KeyCache::open(&path, None, "passphrase".to_owned())
+            .await
+            .unwrap();
+        assert_eq!(cache.get("hub", "top").await, None);
+        cache.insert("hub", "top", "a2V5").await;
+        cache.save().await.unwrap();
+        assert!(!std::fs::read(&path)
+            .unwrap()
+            .windows(4)
+            .any(|w| w == b"a2V5"));
+
+        let cache = KeyCache::open(&path, None, "passphrase".to_owned())
+            .await
+            .unwrap();
+        assert_eq!(cache.get("hub", "top").await.as_deref(), Some("a2V5"));
+        assert_eq!(cache.get("other-hub", "top").await, None);
+
+        assert!(KeyCache::open(&path, None, "wrong".to_owned())
+            .await
+            .is_err());
+    }
+}
@@ -0,0 +1,231 @@
+use anyhow::Result;
+
+use crate::config;
+use crate::log::LogLevel;
+use crate::{FileManager, FlatenedDevice};
+
+/// Moves are suggested while the busiest gateway of a layer is this much above the layer's
+/// average.
+const IMBALANCE: f64 = 1.25;
+
+/// Suggested moves per layer at most, so a lopsided layer doesn't print every child.
+const MAX_MOVES: usize = 10;
+
+/// Messages per second going upstream through a gateway: its own and those of every device under
+/// it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct GatewayLoad<'a> {
+    pub device_id: &'a str,
+    /// Index of the hierarchy the gateway is in, among the top devices.
+    pub tree: usize,
+    pub layer: usize,
+    pub own: f64,
+    pub total: f64,
+    /// Children with the messages per second that come up from each.
+    pub children: Vec<(&'a str, f64)>,
+}
+
+/// A child to give another parent of the same layer, to even out the load.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Move<'a> {
+    pub child: &'a str,
+    pub rate: f64,
+    pub from: &'a str,
+    pub to: &'a str,
+}
+
+fn subtree_rate(device: &config::DeviceConfig) -> f64 {
+    device.messages_per_second.unwrap_or_default()
+        + device.children.iter().map(subtree_rate).sum::<f64>()
+}
+
+/// Loads of the devices with children, in the order of the hierarchy.
+pub fn gateway_loads<'a>(devices: &[FlatenedDevice<'a>]) -> Vec<GatewayLoad<'a>> {
+    let mut tree = 0;
+    let mut loads = Vec::new();
+    for (i, device) in devices.iter().enumerate() {
+        if device.layer == 0 && i > 0 {
+            tree += 1;
+        }
+        if device.device.children.is_empty() {
+            continue;
+        }
+        loads.push(GatewayLoad {
+            device_id: &device.device.device_id,
+            tree,
+            layer: device.layer,
+            own: device.device.messages_per_second.unwrap_or_default(),
+            total: subtree_rate(device.device),
+            children: device
+                .device
+                .children
+                .iter()
+                .map(|c| (c.device_id.as_str(), subtree_rate(c)))
+                .collect(),
+        });
+    }
+
+    loads
+}
+
+/// Moves of children between the gateways of each layer of a hierarchy, busiest gateway first,
+/// each picked to bring the pair it is between closest to even.
+pub fn suggest_moves<'a>(loads: &[GatewayLoad<'a>]) -> Vec<Move<'a>> {
+    let mut groups: Vec<(usize, usize)> = loads.iter().map(|l| (l.tree, l.layer)).collect();
+    groups.sort_unstable();
+    groups.dedup();
+
+    let mut moves = Vec::new();
+    for group in groups {
+        let mut layer: Vec<GatewayLoad<'a>> = loads
+            .iter()
+            .filter(|l| (l.tree, l.layer) == group)
+            .cloned()
+            .collect();
+        if layer.len() < 2 {
+            continue;
+        }
+        let average = layer.iter().map(|l| l.total).sum::<f64>() / layer.len() as f64;
+
+        for _ in 0..MAX_MOVES {
+            let busiest = (0..layer.len())
+                .max_by(|&a, &b| layer[a].total.partial_cmp(&layer[b].total).unwrap())
+                .unwrap_or_default();
+            let idlest = (0..layer.len())
+                .min_by(|&a, &b| layer[a].total.partial_cmp(&layer[b].total).unwrap())
+                .unwrap_or_default();
+            if layer[busiest].total <= average * IMBALANCE {
+                break;
+            }
+            let difference = layer[busiest].total - layer[idlest].total;
+            let child = layer[busiest]
+                .children
+                .iter()
+                .enumerate()
+                .filter(|(_, (_, rate))| *rate > 0.0 && *rate < difference)
+                .min_by(|(_, (_, a)), (_, (_, b))| {
+                    (difference - 2.0 * a)
+                        .abs()
+                        .partial_cmp(&(difference - 2.0 * b).abs())
+                        .unwrap()
+                })
+                .map(|(i, _)| i);
+            let child = match child {
+                Some(child) => layer[busiest].children.remove(child),
+                None => break,
+            };
+
+            layer[busiest].total -= child.1;
+            layer[idlest].total += child.1;
+            layer[idlest].children.push(child);
+            moves.push(Move {
+                child: child.0,
+                rate: child.1,
+                from: layer[busiest].device_id,
+                to: layer[idlest].device_id,
+            });
+        }
+    }
+
+    moves
+}
+
+/// Prints the expected load of each gateway from the devices' `messages_per_second`, warns about
+/// gateways above `configuration.gateway_capacity` and suggests children to move.
+pub async fn print_load(config: &config::Config, file_manager: &FileManager) -> Result<()> {
+    let devices = FlatenedDevice::flatten_devices(&config.root_devices);
+    if devices
+        .iter()
+        .all(|d| d.device.messages_per_second.is_none())
+    {
+        return file_manager
+            .print("No device has messages_per_second, gateway load not estimated.")
+            .await;
+    }
+
+    let loads = gateway_loads(&devices);
+    let mut report = vec!["Expected messages per second through each gateway:".to_owned()];
+    for load in &loads {
+        report.push(format!(
+            "{}{}: {:.1} ({:.1} own, {:.1} from {} children)",
+            "  ".repeat(load.layer + 1),
+            load.device_id,
+            load.total,
+            load.own,
+            load.total - load.own,
+            load.children.len()
+        ));
+    }
+    file_manager.print(report.join("\n")).await?;
+
+    if let Some(capacity) = config.configuration.gateway_capacity {
+        for load in loads.iter().filter(|l| l.total > capacity) {
+            file_manager
+                .log(
+                    LogLevel::Warn,
+                    "load",
+                    Some(load.device_id),
+                    format!(
+                        "{} forwards {:.1} messages per second, above gateway_capacity {:.1}",
+                        load.device_id, load.total, capacity
+                    ),
+                )
+                .await?;
+        }
+    }
+
+    for suggestion in suggest_moves(&loads) {
+        file_manager
+            .print(format!(
+                "Suggestion: move {} ({:.1} messages per second) from {} to {}",
+                suggestion.child, suggestion.rate, suggestion.from, suggestion.to
+            ))
+            .await?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_gateway_loads() {
+        let root: config::DeviceConfig = serde_yaml::from_str(
+            r#"
+device_id: top
+messages_per_second: 1
+child:
+  - device_id: busy
+    child:
+      - device_id: a
+        messages_per_second: 40
+      - device_id: b
+        messages_per_second: 30
+      - device_id: c
+        messages_per_second: 10
+  - device_id: idle
+    child:
+      - device_id: d
+        messages_per_second: 0
+"#,
+        )
+        .unwrap();
+        let devices = FlatenedDevice::flatten_devices(std::slice::from_ref(&root));
+        let loads = gateway_loads(&devices);
+
+        let totals: Vec<(&str, f64)> = loads.iter().map(|l| (l.device_id, l.total)).collect();
+        assert_eq!(totals, vec![("top", 81.0), ("busy", 80.0), ("idle", 0.0)]);
+
+        assert_eq!(
+            suggest_moves(&loads),
+            vec![Move {
+                child: "a",
+                rate: 40.0,
+                from: "busy",
+                to: "idle",
+            },]
+        );
+    }
+}
@@ -0,0 +1,174 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use anyhow::Result;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl std::str::FromStr for LogLevel {
+    type Err = anyhow::Error;
+    fn from_str(string: &str) -> Result<Self> {
+        let result = match string.to_lowercase().as_str() {
+            "error" => Self::Error,
+            "warn" | "warning" => Self::Warn,
+            "info" => Self::Info,
+            "debug" => Self::Debug,
+            "trace" => Self::Trace,
+            _ => {
+                return Err(anyhow::Error::msg(format!(
+                    "Did not recognize log level: {}",
+                    string
+                )))
+            }
+        };
+
+        Ok(result)
+    }
+}
+
+impl fmt::Display for LogLevel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let level = match self {
+            Self::Error => "ERROR",
+            Self::Warn => "WARN",
+            Self::Info => "INFO",
+            Self::Debug => "DEBUG",
+            Self::Trace => "TRACE",
+        };
+
+        // Pad so that messages line up in the log file
+        f.pad(level)
+    }
+}
+
+/// Decides which lines make it into the persistent log file.
+///
+/// Parsed from a comma separated list of `target=level` pairs, e.g. `hub=debug,certs=info`.
+/// An entry without a target sets the level for every target not listed.
+#[derive(Clone, Debug)]
+pub struct LogFilter {
+    default: LogLevel,
+    targets: HashMap<String, LogLevel>,
+}
+
+impl LogFilter {
+    pub fn enabled(&self, target: &str, level: LogLevel) -> bool {
+        level <= *self.targets.get(target).unwrap_or(&self.default)
+    }
+}
+
+impl Default for LogFilter {
+    fn default() -> Self {
+        Self {
+            default: LogLevel::Trace,
+            targets: HashMap::new(),
+        }
+    }
+}
+
+impl std::str::FromStr for LogFilter {
+    type Err = anyhow::Error;
+    fn from_str(string: &str) -> Result<Self> {
+        let mut result = Self::default();
+
+        for directive in string.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+            match directive.split_once('=') {
+                Some((target, level)) => {
+                    result
+                        .targets
+                        .insert(target.trim().to_owned(), level.trim().parse()?);
+                }
+                None => result.default = directive.parse()?,
+            }
+        }
+
+        Ok(result)
+    }
+}
+
+/// How progress is reported on the console.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ProgressFormat {
+    /// Log messages only.
+    Text,
+    /// One JSON event per line on stdout for every operation, with log messages moved to stderr.
+    Ndjson,
+}
+
+impl std::str::FromStr for ProgressFormat {
+    type Err = anyhow::Error;
+    fn from_str(string: &str) -> Result<Self> {
+        match string.to_lowercase().as_str() {
+            "text" => Ok(Self::Text),
+            "ndjson" => Ok(Self::Ndjson),
+            _ => Err(anyhow::Error::msg(format!(
+                "Did not recognize progress format: {}. Use text or ndjson.",
+                string
+            ))),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProgressEvent {
+    Start,
+    Success,
+    Failure,
+}
+
+/// One line of `--progress-format ndjson` output.
+#[derive(Debug, serde::Serialize)]
+pub struct Progress<'a> {
+    pub time: String,
+    pub event: ProgressEvent,
+    /// certs, hub, configs, scripts or zip.
+    pub phase: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub device_id: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_log_filter() {
+        let filter: LogFilter = "hub=debug, certs=info,warn".parse().unwrap();
+
+        assert!(filter.enabled("hub", LogLevel::Debug));
+        assert!(!filter.enabled("hub", LogLevel::Trace));
+        assert!(filter.enabled("certs", LogLevel::Info));
+        assert!(!filter.enabled("certs", LogLevel::Debug));
+        assert!(filter.enabled("scripts", LogLevel::Warn));
+        assert!(!filter.enabled("scripts", LogLevel::Info));
+
+        assert!("hub=loud".parse::<LogFilter>().is_err());
+        assert!(LogFilter::default().enabled("anything", LogLevel::Trace));
+    }
+
+    #[test]
+    fn test_progress() {
+        let progress = Progress {
+            time: "2021-06-01T12:00:00+00:00".to_owned(),
+            event: ProgressEvent::Failure,
+            phase: "hub",
+            device_id: Some("top-layer"),
+            error: Some("Failed to create top-layer".to_owned()),
+        };
+        assert_eq!(
+            serde_json::to_string(&progress).unwrap(),
+            r#"{"time":"2021-06-01T12:00:00+00:00","event":"failure","phase":"hub","device_id":"top-layer","error":"Failed to create top-layer"}"#
+        );
+        assert!("NDJSON".parse::<ProgressFormat>().is_ok());
+    }
+}
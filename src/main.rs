@@ -1,10 +1,12 @@
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::ffi::OsStr;
+use std::process::Stdio;
 use std::str::FromStr;
 use std::sync::Arc;
 
 use anyhow::{Context, Result};
 use chrono::Local;
+use futures::StreamExt;
 use structopt::StructOpt;
 use tokio::fs;
 use tokio::io::AsyncWriteExt;
@@ -15,75 +17,848 @@ use url::Url;
 use aziotctl_common::config::super_config as aziot_config;
 use iotedge::config::super_config as iotedge_config;
 
+mod anonymize;
+mod artifacts;
+mod bench;
+mod bootstrap;
+mod bundle;
+mod cancel;
+mod clock;
+mod commands;
 mod config;
+mod deploy;
+mod deployment;
+mod diff;
+mod dps;
+mod drift;
+mod escrow;
+mod estimate;
+mod gc;
+mod gitops;
+mod hints;
+mod hostnames;
 mod hub_responses;
+mod hub_rest;
+mod hub_routing;
+mod inventory;
+mod issuance;
+mod junit;
+mod k8s;
+mod key_cache;
+mod load;
+mod log;
+mod messages;
+mod migrate;
+mod native_certs;
+mod online;
+mod plan;
+mod rbac;
+mod remote;
+mod rollout;
+mod routes;
+mod run;
+mod sas;
+mod schedule;
+mod scrub;
+mod secrets;
+mod signing;
+mod simulate;
+mod state;
+mod tags;
+mod telemetry;
+mod throttle;
+mod upgrade;
+mod visualize;
+
+use bench::BenchManager;
+use bootstrap::BootstrapManager;
+use cancel::CancellationToken;
+use clock::{Clock, FixedClock, NativeRng, OpensslRng, Rng, SeededRng, SystemClock};
+use commands::{AzLogin, CommandExt, CommandRunner, RunMode};
+use deploy::DeployManager;
+use dps::DpsManager;
+use drift::DriftManager;
+use escrow::EscrowManager;
+use gc::{CreationTag, GcManager, StaleChildren, CREATION_TAG};
+use gitops::GitOpsManager;
+use hostnames::HostnameManager;
+use hub_rest::HubRestClient;
+use hub_routing::HubRoutingManager;
+use inventory::InventoryManager;
+use issuance::{IssuanceEvent, IssuedCert};
+use k8s::ManifestManager;
+use key_cache::KeyCache;
+use log::{LogFilter, LogLevel, Progress, ProgressEvent, ProgressFormat};
+use messages::MessageManager;
+use migrate::MigrationManager;
+use online::OnlineManager;
+use plan::Plan;
+use rbac::RoleManager;
+use remote::{
+    ArcExecutor, DirectMethodExecutor, FleetLimits, RemoteExecutor, RemoteManager, SshExecutor,
+    Transport,
+};
+use rollout::RolloutManager;
+use sas::{ConnectionString, TokenManager};
+use schedule::ScheduleManager;
+use scrub::ScrubManager;
+use secrets::{SecretManager, SecretStore};
+use signing::SigningManager;
+use simulate::SimulationManager;
+use tags::{Selection, TagAssignment, TagManager};
+use throttle::{HubOperation, HubThrottle, HubTier};
+use upgrade::{RuntimeVersion, UpgradeManager};
 
 #[tokio::main]
 async fn main() -> Result<()> {
+    try_main().await.map_err(hints::with_remediation)
+}
+
+async fn try_main() -> Result<()> {
     let args: Arguments = StructOpt::from_args();
+    if args.read_only && !args.reads_only() {
+        return Err(anyhow::Error::msg(format!(
+            "{} changes the hub or devices, so it can't run with --read-only. Run it with write credentials.",
+            args.command_name()
+        )));
+    }
+    if args.dry_run && args.run_phases().is_none() {
+        return Err(anyhow::Error::msg(format!(
+            "--dry-run only plans runs that create, delete, make certs or identities or verify devices, it can't be used with {}",
+            args.command_name()
+        )));
+    }
+    let output = match &args.namespace {
+        Some(namespace) => args.output.join(namespace),
+        None => args.output.clone(),
+    };
     if args.clean {
-        let _ = fs::remove_dir_all(&args.output).await;
+        let _ = fs::remove_dir_all(&output).await;
+    }
+
+    let mut config = config::Config::read_config(&args.config).await?;
+    config.source_hash = Some(sha256_hex(&fs::read(&args.config).await?));
+    if let Some(namespace) = &args.namespace {
+        config.apply_namespace(namespace)?;
+    }
+    config.create_disabled = args.disabled;
+    config.stamp_time = args.gitops.is_none();
+    if args.resume {
+        if args.clean || args.force {
+            return Err(anyhow::Error::msg(
+                "--resume keeps what an earlier run made, it can't be used with --clean or --force",
+            ));
+        }
+        config.resumed = state::resume(&output.join("state.json"), &config).await?;
+    }
+    if (args.fixed_time.is_some() || args.seed.is_some()) && args.cert_profile != CertProfile::Test
+    {
+        return Err(anyhow::Error::msg(
+            "--fixed-time and --seed make predictable certs and logs, they need --cert-profile test",
+        ));
+    }
+    if args.fixed_time.is_some() && args.cert_backend == CertBackend::OpensslCli {
+        let version = openssl_version(args.openssl_path.as_deref()).await?;
+        if !sets_validity_dates(&version) {
+            return Err(anyhow::Error::msg(format!(
+                "--fixed-time gives openssl the validity dates of certs, which needs OpenSSL 3.4 or later. Found {}",
+                version
+            )));
+        }
+    }
+    let clock: Box<dyn Clock> = match args.fixed_time {
+        Some(time) => Box::new(FixedClock(time)),
+        None => Box::new(SystemClock),
+    };
+    let rng: Box<dyn Rng> = match args.seed {
+        Some(seed) => Box::new(SeededRng::new(seed)),
+        None => args.serial_rng(),
+    };
+    let file_manager = FileManager::new(
+        &output,
+        args.verbose > 0,
+        args.log_filter.clone().unwrap_or_default(),
+        args.overwrite,
+        args.progress_format,
+        clock,
+    )
+    .await?;
+
+    let started = file_manager.now();
+    let started_at = std::time::SystemTime::now();
+    let result = run(&args, &config, &file_manager, rng).await;
+    let mut manifest = run::RunManifest::new(
+        args.command_name(),
+        &config,
+        started,
+        file_manager.now(),
+        &result,
+    );
+    manifest.devices = file_manager.outcomes();
+    manifest.group_devices(&config);
+    let base_path = file_manager.base_path();
+    let manifest_path = if base_path.exists() {
+        manifest.artifacts = run::artifacts(base_path);
+        base_path.join("run.json")
+    } else {
+        // Zipped with --zip-options all
+        let zip = FileManager::path_to_zip(base_path);
+        manifest.artifacts = vec![zip.to_string_lossy().into_owned()];
+        zip.with_extension("run.json")
+    };
+    run::write(&manifest_path, &manifest).await?;
+    if let Some(otlp_file) = &args.otlp_file {
+        telemetry::write(
+            otlp_file,
+            &args.command_name(),
+            started_at,
+            &file_manager.spans(),
+            &result,
+        )
+        .await?;
     }
 
-    let config = config::Config::read_config(&args.config).await?;
-    let file_manager = FileManager::new(&args.output, args.verbose).await?;
-    let cert_manager = CertManager::new(&config, &file_manager, args.openssl_path.as_deref());
-    let hub_manager = IoTHubDeviceManager::new(&config, &file_manager, &cert_manager);
-    let device_config_manager = DeviceConfigManager::new(&config, &file_manager);
-    let script_manager = ScriptManager::new(&config, &file_manager);
+    result
+}
+
+/// Runs the command once the config is read and the output folder is set up. Its outcome is
+/// written to run.json by main.
+async fn run(
+    args: &Arguments,
+    config: &config::Config,
+    file_manager: &FileManager,
+    rng: Box<dyn Rng>,
+) -> Result<()> {
+    let cancel = CancellationToken::new();
+    cancel.cancel_on_ctrl_c();
+    let hub_throttle = HubThrottle::new(args.hub_tier, args.hub_units);
+    let run_mode = match (&args.record, &args.replay) {
+        _ if args.dry_run => RunMode::DryRun,
+        (Some(file), _) => RunMode::Record(file.clone()),
+        (None, Some(file)) => RunMode::Replay(file.clone()),
+        (None, None) => RunMode::Live,
+    };
+    let trace = if args.verbose > 1 {
+        Some(file_manager.base_path().join("trace.log"))
+    } else {
+        None
+    };
+    let login = if args.connection_string_login {
+        let read_connection_string = std::env::var("IOTHUB_READ_CONNECTION_STRING")
+            .ok()
+            .filter(|_| args.read_only);
+        let hub = read_connection_string
+            .or_else(|| config.iothub.connection_string.clone())
+            .ok_or_else(|| {
+                anyhow::Error::msg(
+                    "--connection-string-login needs iothub.connection_string in the config or IOTHUB_CONNECTION_STRING",
+                )
+            })?;
+        AzLogin {
+            hub: Some(hub),
+            dps: config
+                .dps
+                .as_ref()
+                .and_then(|d| d.connection_string.clone()),
+            read_only: args.read_only,
+        }
+    } else {
+        AzLogin {
+            read_only: args.read_only,
+            ..Default::default()
+        }
+    };
+    let hub_rest = match args.hub_api {
+        HubApi::Az => None,
+        HubApi::Rest => {
+            let connection_string = config.iothub.connection_string.as_deref().ok_or_else(|| {
+                anyhow::Error::msg(
+                    "--hub-api rest needs iothub.connection_string in the config or IOTHUB_CONNECTION_STRING",
+                )
+            })?;
+            Some(HubRestClient::new(connection_string)?)
+        }
+    };
+    let runner = CommandRunner::new(
+        run_mode,
+        trace.as_deref(),
+        login,
+        args.max_concurrency.map(std::num::NonZeroUsize::get),
+        hub_rest,
+    )
+    .await?;
+    let cert_manager = CertManager::new(
+        config,
+        file_manager,
+        args.openssl_path.as_deref(),
+        args.cert_profile,
+        args.cert_backend,
+        rng,
+        args.secret_store,
+        runner.plan(),
+        cancel.clone(),
+    );
+    let hub_manager = IoTHubDeviceManager::new(
+        config,
+        file_manager,
+        &cert_manager,
+        &hub_throttle,
+        &runner,
+        cancel.clone(),
+    );
+    let device_config_manager = DeviceConfigManager::new(config, file_manager, args.secret_store);
+    let script_manager = ScriptManager::new(config, file_manager);
+    let key_cache = match &args.key_cache {
+        Some(path) => {
+            let stored = args.secret_store.passphrase(path).await;
+            let passphrase = match &stored {
+                Some(passphrase) => passphrase.clone(),
+                None => key_cache::passphrase(path)?,
+            };
+            let key_cache =
+                KeyCache::open(path, args.openssl_path.as_deref(), passphrase.clone()).await?;
+            // Kept once it opened the cache, so a mistyped passphrase is not stored
+            if stored.is_none() {
+                args.secret_store.keep_passphrase(path, &passphrase).await?;
+            }
+            Some(key_cache)
+        }
+        None => None,
+    };
+    let secret_manager =
+        SecretManager::new(config, file_manager, args.secret_store, key_cache.as_ref());
+    let token_manager = TokenManager::new(config, file_manager, &secret_manager, &runner);
+    let message_manager = MessageManager::new(config, file_manager, &runner);
 
     file_manager
         .print_verbose(format!("Using options:\n{:#?}", args))
         .await?;
 
+    let state_path = file_manager.base_path().join("state.json");
+    if let Some(warning) = state::read(&state_path)
+        .await?
+        .and_then(|s| s.staleness(config))
+    {
+        file_manager
+            .log(LogLevel::Warn, "main", None, warning)
+            .await?;
+    }
+
+    let phases = args.run_phases();
+    if let (Some(command), None) = (&args.command, phases) {
+        return match command {
+            SubCommand::Create
+            | SubCommand::Delete
+            | SubCommand::CertsOnly
+            | SubCommand::IdentitiesOnly
+            | SubCommand::Verify => unreachable!("{:?} is run by the phases below", command),
+            SubCommand::Certs(CertsCommand::List { device_id, revoked }) => {
+                cert_manager
+                    .list_issued(device_id.as_deref(), *revoked)
+                    .await
+            }
+            SubCommand::Certs(CertsCommand::Revoke { device_id, serial }) => {
+                cert_manager.revoke(device_id, serial.as_deref()).await
+            }
+            SubCommand::Certs(CertsCommand::RenewalPlan {
+                within,
+                per_week,
+                ics,
+            }) => {
+                cert_manager
+                    .renewal_plan(within.0, *per_week, ics.as_deref())
+                    .await
+            }
+            SubCommand::Certs(CertsCommand::SplitRoot { threshold, shares }) => {
+                EscrowManager::new(file_manager, &cert_manager)
+                    .split_root(*threshold, shares)
+                    .await
+            }
+            SubCommand::Certs(CertsCommand::CombineRoot { shares, output }) => {
+                EscrowManager::new(file_manager, &cert_manager)
+                    .combine_root(shares, output)
+                    .await
+            }
+            SubCommand::Certs(CertsCommand::EscrowRoot { recipient, output }) => {
+                EscrowManager::new(file_manager, &cert_manager)
+                    .escrow_root(recipient, output)
+                    .await
+            }
+            SubCommand::Secrets(SecretsCommand::Show { device_id, key }) => {
+                secret_manager.show(device_id, *key).await
+            }
+            SubCommand::Artifacts(ArtifactsCommand::Info) => {
+                artifacts::info(config, file_manager).await
+            }
+            SubCommand::Bundle(BundleCommand::Diff { old, new, patch }) => {
+                let devices = FlatenedDevice::flatten_devices(&config.root_devices);
+                let device_ids: Vec<&str> = devices
+                    .iter()
+                    .map(|d| d.device.device_id.as_str())
+                    .collect();
+                print!(
+                    "{}",
+                    bundle::diff_bundles(old, new, &device_ids, *patch).await?
+                );
+                Ok(())
+            }
+            SubCommand::Config(ConfigCommand::Resolve { show_secrets }) => {
+                let config = if *show_secrets {
+                    config.clone()
+                } else {
+                    config.redacted()
+                };
+                print!("{}", serde_yaml::to_string(&config)?);
+                Ok(())
+            }
+            SubCommand::Config(ConfigCommand::Anonymize) => {
+                print!("{}", serde_yaml::to_string(&anonymize::anonymize(config))?);
+                Ok(())
+            }
+            SubCommand::Config(ConfigCommand::CheckHostnames { hosts }) => {
+                HostnameManager::new(config, file_manager)
+                    .check(hosts.as_deref())
+                    .await
+            }
+            SubCommand::Tags(TagsCommand::Set {
+                tags,
+                select,
+                dry_run,
+            }) => {
+                TagManager::new(config, file_manager, &hub_throttle, &runner)
+                    .set(tags, select, *dry_run)
+                    .await
+            }
+            SubCommand::Deploy(DeployCommand::Apply { select }) => {
+                DeployManager::new(
+                    config,
+                    file_manager,
+                    &hub_manager,
+                    &hub_throttle,
+                    &runner,
+                    &cancel,
+                )
+                .apply(
+                    select,
+                    &ScheduleManager::new(config, file_manager, &state_path),
+                )
+                .await
+            }
+            SubCommand::Deploy(DeployCommand::Rollback { select }) => {
+                DeployManager::new(
+                    config,
+                    file_manager,
+                    &hub_manager,
+                    &hub_throttle,
+                    &runner,
+                    &cancel,
+                )
+                .rollback(select)
+                .await
+            }
+            SubCommand::Config(ConfigCommand::Lint) => {
+                config.check_device_ids().await?;
+                config.check_edge_parents().await?;
+                config.check_hostnames(file_manager).await?;
+                config.check_key_algorithms(file_manager).await?;
+                config.check_deployments().await?;
+                device_config_manager.validate_config().await?;
+                SigningManager::new(config, file_manager).check()?;
+                load::print_load(config, file_manager).await
+            }
+            SubCommand::Config(ConfigCommand::Dns {
+                zone,
+                ttl,
+                name_server,
+                name_server_address,
+            }) => {
+                HostnameManager::new(config, file_manager)
+                    .write_dns_files(
+                        zone.as_deref(),
+                        *ttl,
+                        name_server.as_deref(),
+                        *name_server_address,
+                    )
+                    .await
+            }
+            SubCommand::Token {
+                device_id,
+                ttl,
+                policy,
+            } => {
+                let token = token_manager
+                    .make_token(device_id, policy.as_deref(), ttl.0)
+                    .await?;
+                println!("{}", token);
+                Ok(())
+            }
+            SubCommand::SendD2c {
+                device_id,
+                data,
+                count,
+            } => message_manager.send_d2c(device_id, data, *count).await,
+            SubCommand::MonitorEvents {
+                device_id,
+                consumer_group,
+                timeout,
+            } => {
+                message_manager
+                    .monitor_events(device_id.as_deref(), consumer_group, timeout.0)
+                    .await
+            }
+            SubCommand::Drift { junit, remote } => {
+                let executor = remote.executor(config, &runner)?;
+                DriftManager::new(
+                    config,
+                    file_manager,
+                    &cert_manager,
+                    executor.as_ref(),
+                    remote.limits()?,
+                )
+                .check_all(junit.as_deref())
+                .await
+            }
+            SubCommand::Logs {
+                module,
+                since,
+                remote,
+            } => {
+                let executor = remote.executor(config, &runner)?;
+                RemoteManager::new(config, file_manager, executor.as_ref(), remote.limits()?)
+                    .collect_logs(module, since)
+                    .await
+            }
+            SubCommand::Push { remote } => {
+                let executor = remote.executor(config, &runner)?;
+                let rollout =
+                    RolloutManager::new(config, file_manager, &hub_throttle, &runner, &cancel);
+                let drift = DriftManager::new(
+                    config,
+                    file_manager,
+                    &cert_manager,
+                    executor.as_ref(),
+                    remote.limits()?,
+                );
+                RemoteManager::new(config, file_manager, executor.as_ref(), remote.limits()?)
+                    .push_bundles(
+                        &rollout,
+                        &drift,
+                        &ScheduleManager::new(config, file_manager, &state_path),
+                    )
+                    .await
+            }
+            SubCommand::Enable { device_id } => {
+                let roots = match device_id {
+                    Some(device_id) => {
+                        std::slice::from_ref(config.find_device(device_id).ok_or_else(|| {
+                            anyhow::Error::msg(format!("{} is not in the config", device_id))
+                        })?)
+                    }
+                    None => &config.root_devices[..],
+                };
+                hub_manager.enable_devices(roots).await
+            }
+            SubCommand::Visualize => {
+                visualize_terminal(&config.root_devices, file_manager).await?;
+                visualize::write_files(config, file_manager).await
+            }
+            SubCommand::Versions => {
+                InventoryManager::new(config, file_manager, &hub_throttle, &runner)
+                    .print_versions()
+                    .await
+            }
+            SubCommand::WaitOnline {
+                device_id,
+                timeout,
+                interval,
+            } => {
+                let roots = match device_id {
+                    Some(device_id) => {
+                        std::slice::from_ref(config.find_device(device_id).ok_or_else(|| {
+                            anyhow::Error::msg(format!("{} is not in the config", device_id))
+                        })?)
+                    }
+                    None => &config.root_devices[..],
+                };
+                OnlineManager::new(config, file_manager, &hub_throttle, &runner, &cancel)
+                    .wait(roots, timeout.0.to_std()?, interval.0.to_std()?)
+                    .await
+            }
+            SubCommand::Gc {
+                namespace,
+                older_than,
+                dry_run,
+            } => {
+                GcManager::new(config, file_manager, &hub_manager, &hub_throttle, &runner)
+                    .collect(
+                        namespace.as_deref().or(config.namespace.as_deref()),
+                        older_than.map(|d| d.0),
+                        *dry_run,
+                    )
+                    .await
+            }
+            SubCommand::Migrate {
+                from_hub,
+                to_hub,
+                to_hub_hostname,
+                delete_source,
+            } => {
+                config.check_device_ids().await?;
+                config.check_edge_parents().await?;
+
+                let mut source = config.clone();
+                source.iothub.iothub_name = from_hub.clone();
+                let mut target = config.clone();
+                target.iothub.iothub_name = to_hub.clone();
+                target.iothub.iothub_hostname = to_hub_hostname
+                    .clone()
+                    .unwrap_or_else(|| format!("{}.azure-devices.net", to_hub));
+                target.iothub.connection_string = None;
+
+                let migration = MigrationManager::new(
+                    &source,
+                    &target,
+                    file_manager,
+                    &cert_manager,
+                    &hub_throttle,
+                    &runner,
+                    &cancel,
+                );
+                let created_devices = migration.copy_devices().await?;
+                DeviceConfigManager::new(&target, file_manager, args.secret_store)
+                    .make_all_device_configs(&created_devices)
+                    .await?;
+                ScriptManager::new(&target, file_manager)
+                    .add_install_scripts(&created_devices)
+                    .await?;
+                SecretManager::new(&target, file_manager, args.secret_store, key_cache.as_ref())
+                    .store_device_secrets(&created_devices)
+                    .await?;
+                if *delete_source {
+                    migration.delete_source(&created_devices).await?;
+                }
+
+                file_manager
+                    .print(format!(
+                        "Migrated {} devices to hub {}. Reinstall the regenerated config.toml on each device.",
+                        created_devices.len(),
+                        to_hub
+                    ))
+                    .await
+            }
+            SubCommand::Simulate { local, image } => {
+                if !local {
+                    return Err(anyhow::Error::msg(
+                        "Only local simulation with docker compose is supported. Use simulate --local.",
+                    ));
+                }
+                SimulationManager::new(config, file_manager)
+                    .make_compose_file(image)
+                    .await
+            }
+            SubCommand::Upgrade { to } => {
+                UpgradeManager::new(config, file_manager).upgrade(*to).await
+            }
+            SubCommand::Bench { devices } => {
+                let mut bench = config.clone();
+                bench.root_devices = bench::synthetic_devices(*devices);
+                bench.configuration.layers.clear();
+                bench.apply_namespace(&format!(
+                    "bench-{}",
+                    file_manager.now().format("%Y%m%d%H%M%S")
+                ))?;
+                let bench_certs = CertManager::new(
+                    &bench,
+                    file_manager,
+                    args.openssl_path.as_deref(),
+                    args.cert_profile,
+                    args.cert_backend,
+                    args.serial_rng(),
+                    args.secret_store,
+                    None,
+                    cancel.clone(),
+                );
+                let bench_hub = IoTHubDeviceManager::new(
+                    &bench,
+                    file_manager,
+                    &bench_certs,
+                    &hub_throttle,
+                    &runner,
+                    cancel.clone(),
+                );
+                BenchManager::new(&bench, file_manager, &bench_certs, &bench_hub)
+                    .run()
+                    .await
+            }
+            SubCommand::Estimate { message_size } => {
+                estimate::print_estimate(config, file_manager, *message_size).await
+            }
+            SubCommand::Scrub { dry_run } => ScrubManager::new(file_manager).scrub(*dry_run).await,
+        };
+    }
+    let phases = phases.unwrap_or(RunPhases::Create);
+
     config.check_device_ids().await?;
-    config.check_hostnames(&file_manager).await?;
+    config.check_edge_parents().await?;
+    config.check_hostnames(file_manager).await?;
+    config.check_key_algorithms(file_manager).await?;
+    config.check_deployments().await?;
     device_config_manager.validate_config().await?;
+    let signing_manager = SigningManager::new(config, file_manager);
+    signing_manager.check()?;
 
-    visualize_terminal(&config.root_device, &file_manager).await?;
+    visualize_terminal(&config.root_devices, file_manager).await?;
     if args.visualize {
-        return Ok(());
+        return visualize::write_files(config, file_manager).await;
     }
 
-    if args.delete || args.force {
-        hub_manager.delete_devices().await?;
+    if let Some(dir) = &args.export_csrs {
+        return cert_manager.export_device_csrs(dir).await;
+    }
 
-        if args.delete {
-            return Ok(());
+    if args.check_roles || args.assign_roles {
+        RoleManager::new(config, file_manager, &hub_manager, &runner)
+            .check_roles(args.assign_roles)
+            .await?;
+    }
+
+    if phases == RunPhases::Verify {
+        return hub_manager.verify_devices().await;
+    }
+
+    // With --dry-run the runner and the cert manager add the operations to the plan instead of
+    // running them, and the plan is written before the device files
+    let force = args.force && phases != RunPhases::CertsOnly;
+    if phases == RunPhases::Delete || force {
+        hub_manager.delete_devices(args.include_untagged).await?;
+
+        if phases == RunPhases::Delete {
+            return match runner.plan() {
+                Some(plan) => plan.write(file_manager).await,
+                None => Ok(()),
+            };
         }
     }
 
-    cert_manager.make_all_device_ca_certs().await?;
-    let created_devices = hub_manager.create_devices().await?;
+    if phases != RunPhases::IdentitiesOnly {
+        let certs = match &args.import_certs {
+            Some(dir) => cert_manager.import_device_certs(dir).await,
+            None => cert_manager.make_all_device_ca_certs().await,
+        };
+        if !args.dry_run {
+            record_unfinished(&certs, &cancel, &state_path, config, file_manager).await?;
+        }
+        certs?;
+    }
+    if let (RunPhases::CertsOnly, Some(plan)) = (phases, runner.plan()) {
+        return plan.write(file_manager).await;
+    }
+    if phases == RunPhases::CertsOnly {
+        return file_manager
+            .print(format!(
+                "Done! Device CA certs are in the device folders of {:?}.",
+                file_manager.base_path()
+            ))
+            .await;
+    }
+    if let Some(event_grid) = &config.iothub.event_grid {
+        hub_manager.create_event_subscription(event_grid).await?;
+    }
+    if let Some(routing) = &config.hub_routing {
+        HubRoutingManager::new(config, file_manager, &hub_manager, &runner)
+            .setup(routing)
+            .await?;
+    }
+    let created_devices = hub_manager.create_devices().await;
+    if !args.dry_run {
+        record_unfinished(&created_devices, &cancel, &state_path, config, file_manager).await?;
+    }
+    let created_devices = created_devices?;
+    GcManager::new(config, file_manager, &hub_manager, &hub_throttle, &runner)
+        .stale_children(args.stale_children, args.include_untagged)
+        .await?;
+    if let Some(plan) = runner.plan() {
+        return plan.write(file_manager).await;
+    }
+    if phases == RunPhases::IdentitiesOnly {
+        return file_manager
+            .print(format!(
+                "Done! Created {} devices in hub {}.",
+                created_devices.len(),
+                config.iothub.iothub_name
+            ))
+            .await;
+    }
 
     device_config_manager
         .make_all_device_configs(&created_devices)
         .await?;
 
     script_manager.add_install_scripts(&created_devices).await?;
+    signing_manager.sign_device_files(&created_devices).await?;
+    secret_manager
+        .store_device_secrets(&created_devices)
+        .await?;
+    if args.k8s_manifests {
+        ManifestManager::new(config, file_manager)
+            .make_all_manifests(&created_devices, args.k8s_namespace.as_deref())
+            .await?;
+    }
+    if let Some(gitops) = &args.gitops {
+        GitOpsManager::new(config, file_manager)
+            .write_all(&created_devices, gitops)
+            .await?;
+    }
 
-    fs::write(
-        file_manager.base_path().join("README.md"),
-        include_str!(r#"docs/root_readme.md"#),
-    )
-    .await?;
+    let state = state::for_run(&state_path, config, file_manager.now()).await?;
+    state::write(&state_path, &state).await?;
+
+    let mut readme = include_str!(r#"docs/root_readme.md"#).to_owned();
+    if args.cert_profile == CertProfile::Test {
+        readme.insert_str(0, TEST_CERTS_WARNING);
+    }
+    fs::write(file_manager.base_path().join("README.md"), readme).await?;
 
     if args.zip_options != ZipOptions::None {
         file_manager
             .print_verbose("Zipping all device folders.")
             .await?;
-        for device in created_devices {
+        for device in &created_devices {
+            let device_id = device.device.device_id.as_str();
             file_manager
-                .zip_dir(file_manager.get_folder(&device.device.device_id).await?)
+                .track("zip", Some(device_id), async {
+                    file_manager
+                        .zip_dir(file_manager.get_folder(device_id).await?)
+                        .await
+                })
                 .await?
         }
+        signing_manager.sign_zips(&created_devices).await?;
+
+        BootstrapManager::new(config, file_manager, &runner)
+            .publish_all(&created_devices)
+            .await?;
 
         if args.zip_options == ZipOptions::All {
             file_manager.print_verbose("Zipping output folder.").await?;
             file_manager.zip_dir(file_manager.base_path()).await?;
+            signing_manager
+                .sign(&FileManager::path_to_zip(file_manager.base_path()))
+                .await?;
         }
     }
 
+    if args.zip_options == ZipOptions::None && config.bootstrap.is_some() {
+        file_manager
+            .log(
+                LogLevel::Warn,
+                "bootstrap",
+                None,
+                "Bootstrap commands need the device zips. Skipped because of --zip-options none.",
+            )
+            .await?;
+    }
+
     let output = if args.zip_options == ZipOptions::All {
         FileManager::path_to_zip(file_manager.base_path())
     } else {
@@ -96,15 +871,25 @@ async fn main() -> Result<()> {
             output
         ))
         .await?;
+    if args.cert_profile == CertProfile::Test {
+        file_manager
+            .log(
+                LogLevel::Warn,
+                "certs",
+                None,
+                TEST_CERTS_WARNING.trim().trim_start_matches("WARNING: "),
+            )
+            .await?;
+    }
 
     Ok(())
 }
 
 #[derive(StructOpt, Debug)]
 struct Arguments {
-    /// Verbose: gives more detailed output
-    #[structopt(short, long)]
-    verbose: bool,
+    /// Verbose: gives more detailed output. Twice (-vv) also writes every az command and its raw output to trace.log in the output folder, with secrets redacted
+    #[structopt(short, long, parse(from_occurrences))]
+    verbose: u8,
 
     /// Delete: deletes devices in hub instead of creating them
     #[structopt(short, long)]
@@ -114,469 +899,2842 @@ struct Arguments {
     #[structopt(short, long)]
     force: bool,
 
+    /// Dry Run: writes every hub and openssl operation the run would do to plan.txt in the output folder and prints it, only running the az commands that read. Not for subcommands other than create, delete, certs-only, identities-only and verify
+    #[structopt(long)]
+    dry_run: bool,
+
+    /// Resume: picks up a run that failed or was cancelled, keeping the certs and hub devices it made per state.json instead of making them again
+    #[structopt(long)]
+    resume: bool,
+
+    /// Disabled: creates the devices disabled, so they can't connect until the enable subcommand is run
+    #[structopt(long)]
+    disabled: bool,
+
+    /// Include Untagged: lets --delete and --force delete devices without the creation tag, such as ones made by hand or by older versions of this tool
+    #[structopt(long)]
+    include_untagged: bool,
+
+    /// Stale Children: what to do with hub devices under a device of the config that are not in the config: warn, detach them from their parent, or delete them
+    #[structopt(long, default_value = "warn")]
+    stale_children: StaleChildren,
+
     /// Clean: deletes working directory at start
     #[structopt(long)]
     clean: bool,
 
-    /// Visualize: only outputs visualization file, does no other work
+    /// Overwrite: replaces generated files that were edited since they were written. Otherwise the new version is written next to them as *.new
+    #[structopt(long)]
+    overwrite: bool,
+
+    /// Visualize: only checks the config and outputs the visualization files, does no other work
     #[structopt(long)]
     visualize: bool,
 
+    /// Check Roles: checks the signed in az principal has the roles needed on the hub before making changes
+    #[structopt(long)]
+    check_roles: bool,
+
+    /// Assign Roles: like --check-roles, but assigns missing roles instead of failing. Requires rights to assign roles
+    #[structopt(long)]
+    assign_roles: bool,
+
     /// Output: path to create directory at.
     #[structopt(short, long, default_value = "./iotedge_config_cli")]
     output: PathBuf,
 
+    /// Namespace: prefixes device ids and the Event Grid subscription name with `<namespace>-` and writes output to <output>/<namespace>, so copies of the same config can share a hub.
+    #[structopt(long)]
+    namespace: Option<String>,
+
     /// Config: path to config file.
     #[structopt(short, long, default_value = "./iotedge_config_cli.yaml")]
     config: PathBuf,
 
+    /// Hub Tier: free, s1, s2 or s3. Spaces out hub requests to stay within the tier's throttling limits.
+    #[structopt(long)]
+    hub_tier: Option<HubTier>,
+
+    /// Hub Units: number of units of the hub. Used with --hub-tier.
+    #[structopt(long, default_value = "1")]
+    hub_units: u32,
+
+    /// Max Concurrency: most az commands run at the same time, across devices and phases. Without it, up to 64 devices are worked on at once.
+    #[structopt(long)]
+    max_concurrency: Option<std::num::NonZeroUsize>,
+
     /// Openssl Path: Path to openssl executable. Only needed if `openssl` is not in PATH.
     #[structopt(long)]
     openssl_path: Option<PathBuf>,
 
+    /// Cert Profile: production, or test for 1 day certs under a root marked TEST, for lab environments.
+    #[structopt(long, default_value = "production")]
+    cert_profile: CertProfile,
+
+    /// Cert Backend: openssl-cli to make the certs with the openssl executable, or native to make them in-process, for machines without one. native does not take devices' openssl_extensions.
+    #[structopt(long, default_value = "openssl-cli")]
+    cert_backend: CertBackend,
+
+    /// Fixed Time: RFC 3339 time written to logs, state.json, the issuance log and creation tags, and certs are valid from, instead of the current time. Needs --cert-profile test, and openssl 3.4 or later with --cert-backend openssl-cli.
+    #[structopt(long)]
+    fixed_time: Option<chrono::DateTime<chrono::Utc>>,
+
+    /// Seed: makes cert serial numbers from this seed instead of openssl rand, so reruns make the same serials. Needs --cert-profile test.
+    #[structopt(long)]
+    seed: Option<u64>,
+
     /// Zip Options: what should be zipped: all, devices, or none.
     #[structopt(long, default_value = "devices")]
     zip_options: ZipOptions,
-}
 
-#[derive(StructOpt, Debug, PartialEq)]
-enum ZipOptions {
-    None,
-    Devices,
-    All,
-}
+    /// Log Filter: minimum level written to the log file, per target. Ex: `hub=debug,certs=info,warn`.
+    /// Targets include main, config, hub, certs, configs, scripts and files.
+    #[structopt(long)]
+    log_filter: Option<LogFilter>,
 
-impl std::str::FromStr for ZipOptions {
-    type Err = anyhow::Error;
-    fn from_str(string: &str) -> Result<Self> {
-        let result = match string.to_lowercase().as_str() {
-            "all" => Self::All,
-            "devices" => Self::Devices,
-            "none" => Self::None,
-            _ => {
-                return Err(anyhow::Error::msg(format!(
-                    "Did not recognize zip argument: {}",
-                    string
-                )))
-            }
-        };
+    /// Progress Format: text, or ndjson for one JSON event per operation on stdout, with messages moved to stderr.
+    #[structopt(long, default_value = "text")]
+    progress_format: ProgressFormat,
 
-        Ok(result)
-    }
-}
+    /// Secret Store: where generated connection strings and key passphrases are kept: none or keychain (the OS credential store).
+    #[structopt(long, default_value = "none")]
+    secret_store: SecretStore,
 
-impl config::Config {
-    pub async fn read_config<P>(file_path: P) -> Result<Self>
-    where
-        P: AsRef<Path>,
-    {
-        println!("Reading {:?}", file_path.as_ref());
-        let data = fs::read(file_path).await.context("Error reading file")?;
+    /// Key Cache: keeps device keys in this file, encrypted with the passphrase in IOTEDGE_CONFIG_KEY_CACHE_PASSPHRASE or prompted for, so later runs don't query the hub for them.
+    #[structopt(long)]
+    key_cache: Option<PathBuf>,
 
-        let version: config::ConfigVersion =
-            serde_yaml::from_slice(&data).context("Error parsing config version")?;
-        match version.config_version.as_str() {
-            "1.0" => (),
-            _ => {
-                return Err(anyhow::Error::msg(
-                    "Invalid api_version. Accepted values are: 1.0",
-                ))
-            }
-        }
+    /// K8s Manifests: also writes a Kubernetes Secret and ConfigMap with each device's certs and config to k8s.yaml.
+    #[structopt(long)]
+    k8s_manifests: bool,
 
-        serde_yaml::from_slice(&data).context("Error parsing data")
-    }
+    /// K8s Namespace: namespace set on the manifests written by --k8s-manifests.
+    #[structopt(long)]
+    k8s_namespace: Option<String>,
 
-    async fn check_device_ids(&self) -> Result<()> {
-        let devices = FlatenedDevice::flatten_devices(&self.root_device);
-        let mut map = HashSet::new();
+    /// OTLP File: writes OpenTelemetry spans of the run, its phases and each device operation, with the time spent in hub commands, to this file as OTLP JSON for a collector's otlpjsonfile receiver.
+    #[structopt(long)]
+    otlp_file: Option<PathBuf>,
 
-        for device in devices {
-            if !map.insert(&device.device.device_id) {
-                let error = format!(r#"device id "{}" is used twice!"#, device.device.device_id);
+    /// Record: saves the output of every az command to this file, for replaying with --replay.
+    #[structopt(long, conflicts_with = "replay")]
+    record: Option<PathBuf>,
 
-                return Err(anyhow::Error::msg(error));
-            }
-        }
+    /// Replay: answers az commands from a file saved with --record instead of calling the hub.
+    #[structopt(long)]
+    replay: Option<PathBuf>,
 
-        Ok(())
-    }
+    /// Connection String Login: passes the hub and DPS connection strings of the config or IOTHUB_CONNECTION_STRING and IOTHUB_DPS_CONNECTION_STRING to az iot commands with --login, so no az login session is needed
+    #[structopt(long)]
+    connection_string_login: bool,
 
-    async fn check_hostnames(&self, file_manager: &FileManager) -> Result<()> {
-        let devices = FlatenedDevice::flatten_devices(&self.root_device);
-        let mut map = HashMap::new();
+    /// Hub Api: az, or rest to create and delete devices and set their parents with the IoT Hub REST API instead of az, authenticated with the hub connection string of the config or IOTHUB_CONNECTION_STRING
+    #[structopt(long, default_value = "az")]
+    hub_api: HubApi,
 
-        for device in devices {
-            if let Some(hostname) = &device.device.hostname {
-                if let Some(old) = map.insert(hostname, &device.device.device_id) {
-                    file_manager
-                        .print(format!(
-                            "\n\nWARNING: {} and {} share the hostname {}\n\n",
-                            old, device.device.device_id, hostname
-                        ))
-                        .await?;
-                }
-            }
-        }
+    /// Read Only: only runs commands that read the hub, such as verify, drift, versions and wait-online, and refuses az commands that would change anything. With --connection-string-login, IOTHUB_READ_CONNECTION_STRING is used when set
+    #[structopt(long)]
+    read_only: bool,
 
-        Ok(())
-    }
-}
+    /// Export CSRs: makes the device CA keys and writes their CSRs to this directory for an offline CA to sign, then stops.
+    #[structopt(long, conflicts_with = "import-certs")]
+    export_csrs: Option<PathBuf>,
 
-struct FlatenedDevice<'a> {
-    device: &'a config::DeviceConfig,
-    parent: Option<&'a config::DeviceConfig>,
+    /// Import Certs: directory with the certs signed from --export-csrs, used instead of making device CA certs.
+    #[structopt(long)]
+    import_certs: Option<PathBuf>,
+
+    /// GitOps: directory to also write non-secret artifacts to, in a stable form meant to be committed to git.
+    #[structopt(long)]
+    gitops: Option<PathBuf>,
+
+    #[structopt(subcommand)]
+    command: Option<SubCommand>,
 }
 
-impl<'a> FlatenedDevice<'a> {
-    pub fn flatten_devices(root: &'a config::DeviceConfig) -> Vec<Self> {
-        Self::flatten_devices_internal(root, None)
+impl Arguments {
+    /// Whether the command only reads the hub, DPS and devices, so it can run with --read-only.
+    fn reads_only(&self) -> bool {
+        match &self.command {
+            None => self.visualize,
+            Some(command) => matches!(
+                command,
+                SubCommand::Verify
+                    | SubCommand::Drift { .. }
+                    | SubCommand::Versions
+                    | SubCommand::WaitOnline { .. }
+                    | SubCommand::Visualize
+                    | SubCommand::Estimate { .. }
+                    | SubCommand::Artifacts(_)
+                    | SubCommand::Bundle(_)
+                    | SubCommand::Config(_)
+                    | SubCommand::Certs(CertsCommand::List { .. })
+                    | SubCommand::Certs(CertsCommand::RenewalPlan { .. })
+            ),
+        }
     }
 
-    fn flatten_devices_internal(
-        device: &'a config::DeviceConfig,
-        parent: Option<&'a config::DeviceConfig>,
-    ) -> Vec<Self> {
-        let mut result: Vec<FlatenedDevice> = vec![FlatenedDevice { device, parent }];
-        for child in &device.children {
-            result.append(&mut Self::flatten_devices_internal(&child, Some(device)));
+    /// Where cert serials come from without --seed: the cert backend.
+    fn serial_rng(&self) -> Box<dyn Rng> {
+        match self.cert_backend {
+            CertBackend::OpensslCli => Box::new(OpensslRng {
+                openssl_path: self.openssl_path.clone(),
+            }),
+            CertBackend::Native => Box::new(NativeRng),
         }
+    }
 
-        result
+    /// The part of the run without a subcommand to do, None for other subcommands.
+    fn run_phases(&self) -> Option<RunPhases> {
+        match &self.command {
+            None if self.delete => Some(RunPhases::Delete),
+            None | Some(SubCommand::Create) => Some(RunPhases::Create),
+            Some(SubCommand::Delete) => Some(RunPhases::Delete),
+            Some(SubCommand::CertsOnly) => Some(RunPhases::CertsOnly),
+            Some(SubCommand::IdentitiesOnly) => Some(RunPhases::IdentitiesOnly),
+            Some(SubCommand::Verify) => Some(RunPhases::Verify),
+            Some(_) => None,
+        }
+    }
+
+    /// Name of the command for run.json, as typed on the command line. Ex: `certs list`, `wait-online`.
+    fn command_name(&self) -> String {
+        let command = match &self.command {
+            Some(command) => command,
+            None if self.visualize => return "visualize".to_owned(),
+            None if self.delete => return "delete".to_owned(),
+            None => return "create".to_owned(),
+        };
+
+        // The Debug output starts with the variant names, ex: `Certs(List { device_id: None })`
+        format!("{:?}", command)
+            .split(|c: char| !c.is_ascii_alphanumeric())
+            .take_while(|word| word.starts_with(|c: char| c.is_ascii_uppercase()))
+            .map(|word| {
+                let mut name = String::new();
+                for (i, c) in word.chars().enumerate() {
+                    if c.is_ascii_uppercase() && i > 0 {
+                        name.push('-');
+                    }
+                    name.push(c.to_ascii_lowercase());
+                }
+                name
+            })
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
+/// How much of the run without a subcommand to do, set by --delete and the create, delete,
+/// certs-only, identities-only and verify subcommands.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum RunPhases {
+    Create,
+    Delete,
+    CertsOnly,
+    IdentitiesOnly,
+    Verify,
+}
+
+#[derive(StructOpt, Debug)]
+enum SubCommand {
+    /// Create: makes the certs, creates the devices in hub and writes their files, the same as running without a subcommand
+    Create,
+
+    /// Delete: deletes the devices in hub, the same as --delete
+    Delete,
+
+    /// Certs Only: makes the root CA, or uses the configured one, and the device CA certs, without changing the hub
+    CertsOnly,
+
+    /// Identities Only: creates the devices in hub and sets their parents, without making device CA certs or device files
+    IdentitiesOnly,
+
+    /// Verify: checks that every device of the config is in the hub, created by this tool, with the parent of the config
+    Verify,
+
+    /// Artifacts: reads the tool version, config hash and generation time stamped on the generated files
+    Artifacts(ArtifactsCommand),
+
+    /// Bundle: compares the generated device bundles of two runs
+    Bundle(BundleCommand),
+
+    /// Certs: reads the log of certs issued by this tool and backs up the root key
+    Certs(CertsCommand),
+
+    /// Secrets: reads secrets kept in the OS credential store by --secret-store keychain
+    Secrets(SecretsCommand),
+
+    /// Config: shows the config the tool acts on
+    Config(ConfigCommand),
+
+    /// Deploy: sets the config's deployments on existing devices, or rolls them back
+    Deploy(DeployCommand),
+
+    /// Tags: changes the twin tags of created devices
+    Tags(TagsCommand),
+
+    /// Token: prints a SAS token for a device created by this tool
+    Token {
+        device_id: String,
+
+        /// TTL: how long the token is valid for. Ex: 30m, 1h, 7d.
+        #[structopt(long, default_value = "1h")]
+        ttl: HumanDuration,
+
+        /// Policy: sign with this hub shared access policy (ex: iothubowner) instead of the device key.
+        #[structopt(long)]
+        policy: Option<String>,
+    },
+
+    /// Send D2C: sends device-to-cloud messages as a created device
+    SendD2c {
+        device_id: String,
+
+        /// Data: message body to send.
+        #[structopt(long, default_value = "Ping from iotedge_config")]
+        data: String,
+
+        /// Count: number of messages to send.
+        #[structopt(long, default_value = "1")]
+        count: u32,
+    },
+
+    /// Monitor Events: prints messages arriving at the hub's event hub-compatible endpoint
+    MonitorEvents {
+        /// Device Id: only show messages from this device. Shows all devices if not given.
+        device_id: Option<String>,
+
+        /// Consumer Group: consumer group of the built-in endpoint to read from.
+        #[structopt(long, default_value = "$Default")]
+        consumer_group: String,
+
+        /// Timeout: how long to listen for. Ex: 30s, 5m.
+        #[structopt(long, default_value = "5m")]
+        timeout: HumanDuration,
+    },
+
+    /// Drift: compares the config.toml and certs installed on each device with the generated ones
+    Drift {
+        /// JUnit: also writes the results to this JUnit XML file, one test case per device and check, for CI test tabs.
+        #[structopt(long)]
+        junit: Option<PathBuf>,
+
+        #[structopt(flatten)]
+        remote: RemoteOptions,
+    },
+
+    /// Logs: collects a module's logs from every device into <device_id>/logs in the output folder
+    Logs {
+        /// Module: name of the module, ex: edgeHub.
+        module: String,
+
+        /// Since: only logs newer than this. Ex: 30m, 1h, or an RFC 3339 timestamp.
+        #[structopt(long, default_value = "1h")]
+        since: String,
+
+        #[structopt(flatten)]
+        remote: RemoteOptions,
+    },
+
+    /// Push: copies each device's zip to the home directory on the device
+    Push {
+        #[structopt(flatten)]
+        remote: RemoteOptions,
+    },
+
+    /// Enable: enables devices created with --disabled, so they can connect
+    Enable {
+        /// Device Id: enables this device and the devices under it. Enables the whole hierarchy if not given.
+        device_id: Option<String>,
+    },
+
+    /// Wait Online: waits for devices to connect to the hub, reporting each one as it first connects
+    WaitOnline {
+        /// Device Id: waits for this device and the devices under it. Waits for the whole hierarchy if not given.
+        device_id: Option<String>,
+
+        /// Timeout: how long to wait for. Ex: 30m, 2h.
+        #[structopt(long, default_value = "30m")]
+        timeout: HumanDuration,
+
+        /// Interval: time between polls of the hub. Ex: 30s, 1m.
+        #[structopt(long, default_value = "30s")]
+        interval: HumanDuration,
+    },
+
+    /// Visualize: draws the device hierarchy with device ids, hostnames and deployments to visualization.svg and visualization.dot, for Graphviz, in the output folder
+    Visualize,
+
+    /// Versions: prints the edgeAgent, edgeHub and module versions each device reports, and warns about edgeHub versions that differ from the parent's
+    Versions,
+
+    /// Gc: deletes devices this tool created under a namespace, or longer ago than --older-than
+    Gc {
+        /// Namespace: only devices created with this --namespace.
+        #[structopt(long)]
+        namespace: Option<String>,
+
+        /// Older Than: only devices created longer ago than this. Ex: `7d`.
+        #[structopt(long)]
+        older_than: Option<HumanDuration>,
+
+        /// Dry Run: only lists the devices that would be deleted.
+        #[structopt(long)]
+        dry_run: bool,
+    },
+
+    /// Migrate: copies the hierarchy's devices to another hub, keeping their keys, and regenerates their configs
+    Migrate {
+        /// From Hub: name of the hub the devices are in.
+        #[structopt(long)]
+        from_hub: String,
+
+        /// To Hub: name of the hub to copy the devices to.
+        #[structopt(long)]
+        to_hub: String,
+
+        /// To Hub Hostname: host name of the new hub. Defaults to <to-hub>.azure-devices.net.
+        #[structopt(long)]
+        to_hub_hostname: Option<String>,
+
+        /// Delete Source: deletes the copied devices from the old hub afterwards.
+        #[structopt(long)]
+        delete_source: bool,
+    },
+
+    /// Simulate: writes a docker-compose.yml running one container per created device
+    Simulate {
+        /// Local: simulate the hierarchy on this machine with docker compose.
+        #[structopt(long)]
+        local: bool,
+
+        /// Image: container image to run for each device. It is given the device folder at /iotedge_config.
+        #[structopt(long)]
+        image: String,
+    },
+
+    /// Upgrade: rewrites the generated config.toml and deployment.json of every device for another IoT Edge runtime version
+    Upgrade {
+        /// To: runtime version to rewrite the files for: 1.2, 1.3 or 1.4.
+        #[structopt(long)]
+        to: RuntimeVersion,
+    },
+
+    /// Bench: creates and deletes synthetic devices under a bench-<time> namespace and reports the hub's throughput and throttling with the config's auth method, to pick --hub-tier and --hub-units for a real run
+    Bench {
+        /// Devices: number of synthetic devices to create and delete.
+        #[structopt(long, default_value = "100")]
+        devices: usize,
+    },
+
+    /// Estimate: prints the expected monthly cost of the hub tier, DPS registrations and bundle storage of the hierarchy
+    Estimate {
+        /// Message Size: average size in bytes of the devices' messages, which are metered in 4 KB blocks.
+        #[structopt(long, default_value = "1024")]
+        message_size: u64,
+    },
+
+    /// Scrub: overwrites and deletes the private keys and device keys in the output folder, keeping the files without secrets
+    Scrub {
+        /// Dry Run: only lists the files that would be redacted or deleted.
+        #[structopt(long)]
+        dry_run: bool,
+    },
+}
+
+/// How the drift, logs and push subcommands reach the devices.
+#[derive(StructOpt, Debug)]
+struct RemoteOptions {
+    /// Transport: ssh (to each device's hostname), arc (az ssh arc to Arc-enabled servers named after the device id) or direct-method (IoT Hub direct methods, logs only).
+    #[structopt(long, default_value = "ssh")]
+    transport: Transport,
+
+    /// SSH User: user to log in to the devices as, with ssh or arc. Defaults to the ssh config.
+    #[structopt(long)]
+    ssh_user: Option<String>,
+
+    /// SSH Identity: private key to log in with over ssh. Defaults to the ssh config.
+    #[structopt(long)]
+    ssh_identity: Option<PathBuf>,
+
+    /// Arc Resource Group: resource group of the Arc-enabled servers, for --transport arc.
+    #[structopt(long)]
+    arc_resource_group: Option<String>,
+
+    /// Parallel: number of devices worked on at the same time.
+    #[structopt(long, default_value = "8")]
+    parallel: usize,
+
+    /// Host Timeout: time after which a device is reported as failed and the others carry on. Ex: 90s, 5m.
+    #[structopt(long, default_value = "2m")]
+    host_timeout: HumanDuration,
+}
+
+impl RemoteOptions {
+    fn limits(&self) -> Result<FleetLimits> {
+        Ok(FleetLimits {
+            parallel: self.parallel,
+            timeout: self.host_timeout.0.to_std()?,
+        })
+    }
+
+    fn executor<'a>(
+        &self,
+        config: &'a config::Config,
+        runner: &'a CommandRunner,
+    ) -> Result<Box<dyn RemoteExecutor + 'a>> {
+        Ok(match self.transport {
+            Transport::Ssh => Box::new(SshExecutor {
+                config,
+                user: self.ssh_user.clone(),
+                identity: self.ssh_identity.clone(),
+            }),
+            Transport::Arc => Box::new(ArcExecutor {
+                resource_group: self.arc_resource_group.clone().ok_or_else(|| {
+                    anyhow::Error::msg("--transport arc needs --arc-resource-group")
+                })?,
+                user: self.ssh_user.clone(),
+            }),
+            Transport::DirectMethod => Box::new(DirectMethodExecutor { config, runner }),
+        })
+    }
+}
+
+#[derive(StructOpt, Debug)]
+enum ArtifactsCommand {
+    /// Info: prints the stamp of each generated file in the output folder, and warns about files from another tool version or config
+    Info,
+}
+
+#[derive(StructOpt, Debug)]
+enum BundleCommand {
+    /// Diff: summarizes the files that changed per device between two output folders. Certs are compared by fingerprint and keys are not shown
+    Diff {
+        /// Old: output folder of the earlier run.
+        old: PathBuf,
+
+        /// New: output folder of the later run.
+        new: PathBuf,
+
+        /// Patch: also prints the unified diff of each changed text file.
+        #[structopt(long)]
+        patch: bool,
+    },
+}
+
+#[derive(StructOpt, Debug)]
+enum CertsCommand {
+    /// List: prints the certs in certificates/issued_certs.jsonl
+    List {
+        /// Device Id: only show certs of this device.
+        device_id: Option<String>,
+
+        /// Revoked: also show revoked certs and their revocations.
+        #[structopt(long)]
+        revoked: bool,
+    },
+    /// Revoke: records the device's certs as revoked in certificates/issued_certs.jsonl, so they are no longer listed or renewed
+    Revoke {
+        /// Device Id: device whose certs to revoke.
+        device_id: String,
+
+        /// Serial: only revoke the cert with this serial.
+        #[structopt(long)]
+        serial: Option<String>,
+    },
+    /// Renewal Plan: schedules the devices whose certs expire soon into weekly batches, soonest expiry first
+    RenewalPlan {
+        /// Within: plans the certs expiring within this long. Ex: `90d`.
+        #[structopt(long, default_value = "90d")]
+        within: HumanDuration,
+
+        /// Per Week: most devices to renew in one week.
+        #[structopt(long, default_value = "10")]
+        per_week: usize,
+
+        /// Ics: also writes the plan to this iCalendar file, one reminder per week.
+        #[structopt(long)]
+        ics: Option<PathBuf>,
+    },
+    /// Split Root: splits the root CA key into shares, any --threshold of which rebuild it
+    SplitRoot {
+        /// Threshold: number of shares needed to rebuild the key.
+        #[structopt(long)]
+        threshold: u8,
+
+        /// Share: path to write a share to, once per share. Ex: --share /media/usb1/root.share.json
+        #[structopt(long = "share", required = true)]
+        shares: Vec<PathBuf>,
+    },
+    /// Combine Root: rebuilds the root CA key from shares made by split-root
+    CombineRoot {
+        /// Share: path of a share, once per share.
+        #[structopt(long = "share", required = true)]
+        shares: Vec<PathBuf>,
+
+        /// Output: path to write the rebuilt key to.
+        #[structopt(long)]
+        output: PathBuf,
+    },
+    /// Escrow Root: encrypts the root CA key to an escrow holder's cert
+    EscrowRoot {
+        /// Recipient: PEM cert of the escrow holder, whose key decrypts the output.
+        #[structopt(long)]
+        recipient: PathBuf,
+
+        /// Output: path to write the encrypted key to, as PEM CMS.
+        #[structopt(long)]
+        output: PathBuf,
+    },
+}
+
+#[derive(StructOpt, Debug)]
+enum ConfigCommand {
+    /// Resolve: prints the config as YAML after defaults, environment variables, --namespace and flat device lists are applied
+    Resolve {
+        /// Show Secrets: prints connection strings and registry passwords instead of <redacted>.
+        #[structopt(long)]
+        show_secrets: bool,
+    },
+    /// Check Hostnames: fails if two devices share a hostname or a hostname does not resolve from this machine
+    CheckHostnames {
+        /// Hosts: writes /etc/hosts entries of the devices with an ip_address to this file.
+        #[structopt(long)]
+        hosts: Option<PathBuf>,
+    },
+    /// Dns: writes hosts file fragments and a zone file for the devices with an ip_address to dns/ in the output folder
+    Dns {
+        /// Zone: also writes dns/<zone>.zone with the hostnames in this zone. Ex: lab.contoso.com.
+        #[structopt(long)]
+        zone: Option<String>,
+
+        /// TTL: time to live of the zone's records, in seconds.
+        #[structopt(long, default_value = "3600")]
+        ttl: u32,
+
+        /// Name server: hostname of the zone's NS record. ns.<zone> if not given.
+        #[structopt(long)]
+        name_server: Option<String>,
+
+        /// Name server address: address of the glue record of a name server in the zone.
+        #[structopt(long)]
+        name_server_address: Option<std::net::IpAddr>,
+    },
+    /// Anonymize: prints the resolved config with device ids, hostnames, addresses and hub names replaced with placeholders, for bug reports
+    Anonymize,
+    /// Lint: runs the checks of a normal run without creating anything, and estimates the message load of each gateway
+    Lint,
+}
+
+#[derive(StructOpt, Debug)]
+enum TagsCommand {
+    /// Set: merges name=value tags into the twins of the selected devices. Ex: tags set site=paris --select subtree:gw-paris
+    Set {
+        /// Tags: name=value pairs. JSON values such as numbers are set as such, and null removes a tag.
+        #[structopt(required = true)]
+        tags: Vec<TagAssignment>,
+
+        /// Select: devices to tag: all, device:<device_id>, subtree:<device_id>, layer:<n>,
+        /// site:<site>, region:<region> or environment:<environment>.
+        #[structopt(long, default_value = "all")]
+        select: Selection,
+
+        /// Dry Run: only lists the devices that would be tagged.
+        #[structopt(long)]
+        dry_run: bool,
+    },
+}
+
+#[derive(StructOpt, Debug)]
+enum DeployCommand {
+    /// Apply: records the current deployment of the selected devices, then sets the one of the config
+    Apply {
+        /// Select: devices to deploy to: all, device:<device_id>, subtree:<device_id>, layer:<n>,
+        /// site:<site>, region:<region> or environment:<environment>.
+        #[structopt(long, default_value = "all")]
+        select: Selection,
+    },
+    /// Rollback: sets back the deployments the selected devices had before the last apply
+    Rollback {
+        /// Select: devices to roll back: all, device:<device_id>, subtree:<device_id>, layer:<n>,
+        /// site:<site>, region:<region> or environment:<environment>.
+        #[structopt(long, default_value = "all")]
+        select: Selection,
+    },
+}
+
+#[derive(StructOpt, Debug)]
+enum SecretsCommand {
+    /// Show: prints the connection string stored for a device
+    Show {
+        device_id: String,
+
+        /// Key: prints only the device key, to write to the device where its config.toml refers to it
+        #[structopt(long)]
+        key: bool,
+    },
+}
+
+/// Validity and naming of the certs made by this tool.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum CertProfile {
+    Production,
+    /// Short lived certs that can't be mistaken for production ones.
+    Test,
+}
+
+impl CertProfile {
+    fn days(self) -> &'static str {
+        match self {
+            Self::Production => "365",
+            Self::Test => "1",
+        }
+    }
+
+    fn validity(self) -> chrono::Duration {
+        match self {
+            Self::Production => chrono::Duration::days(365),
+            Self::Test => chrono::Duration::days(1),
+        }
+    }
+
+    fn root_subject(self) -> String {
+        format!("/CN={}", self.root_common_name())
+    }
+
+    fn root_common_name(self) -> &'static str {
+        match self {
+            Self::Production => "Azure_IoT_Config_Cli_Cert",
+            Self::Test => TEST_ROOT_COMMON_NAME,
+        }
+    }
+}
+
+impl std::str::FromStr for CertProfile {
+    type Err = anyhow::Error;
+    fn from_str(string: &str) -> Result<Self> {
+        match string.to_lowercase().as_str() {
+            "production" => Ok(Self::Production),
+            "test" => Ok(Self::Test),
+            _ => Err(anyhow::Error::msg(format!(
+                "Did not recognize cert profile: {}. Use production or test.",
+                string
+            ))),
+        }
+    }
+}
+
+/// What creates and deletes the devices of the hub and sets their parents.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum HubApi {
+    Az,
+    Rest,
+}
+
+impl std::str::FromStr for HubApi {
+    type Err = anyhow::Error;
+    fn from_str(string: &str) -> Result<Self> {
+        match string.to_lowercase().as_str() {
+            "az" => Ok(Self::Az),
+            "rest" => Ok(Self::Rest),
+            _ => Err(anyhow::Error::msg(format!(
+                "Did not recognize hub api: {}. Use az or rest.",
+                string
+            ))),
+        }
+    }
+}
+
+/// What makes the certs: the openssl executable, or the openssl library in-process.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum CertBackend {
+    OpensslCli,
+    Native,
+}
+
+impl std::str::FromStr for CertBackend {
+    type Err = anyhow::Error;
+    fn from_str(string: &str) -> Result<Self> {
+        match string.to_lowercase().as_str() {
+            "openssl-cli" => Ok(Self::OpensslCli),
+            "native" => Ok(Self::Native),
+            _ => Err(anyhow::Error::msg(format!(
+                "Did not recognize cert backend: {}. Use native or openssl-cli.",
+                string
+            ))),
+        }
+    }
+}
+
+#[derive(StructOpt, Debug, PartialEq)]
+enum ZipOptions {
+    None,
+    Devices,
+    All,
+}
+
+impl std::str::FromStr for ZipOptions {
+    type Err = anyhow::Error;
+    fn from_str(string: &str) -> Result<Self> {
+        let result = match string.to_lowercase().as_str() {
+            "all" => Self::All,
+            "devices" => Self::Devices,
+            "none" => Self::None,
+            _ => {
+                return Err(anyhow::Error::msg(format!(
+                    "Did not recognize zip argument: {}",
+                    string
+                )))
+            }
+        };
+
+        Ok(result)
+    }
+}
+
+/// A duration given as a number followed by a unit: s, m, h or d. Ex: `90s`, `30m`, `7d`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct HumanDuration(chrono::Duration);
+
+impl std::str::FromStr for HumanDuration {
+    type Err = anyhow::Error;
+    fn from_str(string: &str) -> Result<Self> {
+        let string = string.trim();
+        let split = string
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(string.len());
+        let (amount, unit) = string.split_at(split);
+        let amount: i64 = amount
+            .parse()
+            .with_context(|| format!("Did not recognize duration: {}", string))?;
+
+        let duration = match unit {
+            "s" => chrono::Duration::seconds(amount),
+            "m" => chrono::Duration::minutes(amount),
+            "h" => chrono::Duration::hours(amount),
+            "d" => chrono::Duration::days(amount),
+            _ => {
+                return Err(anyhow::Error::msg(format!(
+                    "Did not recognize duration unit: {:?}. Use s, m, h or d.",
+                    unit
+                )))
+            }
+        };
+
+        Ok(Self(duration))
+    }
+}
+
+/// Format of the config file, told from its contents so `.yml`, `.toml` and files without an
+/// extension all work.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ConfigFormat {
+    Yaml,
+    Toml,
+}
+
+impl ConfigFormat {
+    /// TOML if the first line that isn't blank or a comment is a `[table]` or a `key = value`,
+    /// YAML otherwise.
+    fn sniff(data: &[u8]) -> Self {
+        let data = String::from_utf8_lossy(data);
+        let first = data
+            .lines()
+            .map(str::trim)
+            .find(|l| !l.is_empty() && !l.starts_with('#') && l != &"---");
+        match first {
+            Some(line) if line.starts_with('[') && !line.starts_with("[ ") => Self::Toml,
+            Some(line) => match (line.find('='), line.find(':')) {
+                (Some(equals), Some(colon)) if equals < colon => Self::Toml,
+                (Some(_), None) => Self::Toml,
+                _ => Self::Yaml,
+            },
+            None => Self::Yaml,
+        }
+    }
+
+    fn parse<T>(self, file_path: &Path, data: &[u8]) -> Result<T>
+    where
+        T: serde::de::DeserializeOwned,
+    {
+        match self {
+            Self::Yaml => serde_yaml::from_slice(data).map_err(|e| yaml_error(file_path, data, &e)),
+            Self::Toml => toml::from_slice(data)
+                .map_err(|e| anyhow::Error::msg(format!("{:?}: {}", file_path, e))),
+        }
+    }
+}
+
+/// Adds the file, line and the line's text to a YAML error. serde_yaml already gives the path of
+/// the field, ex: `root_device.children[3].device_id: invalid type: integer `5`, expected a string`.
+fn yaml_error(file_path: &Path, data: &[u8], error: &serde_yaml::Error) -> anyhow::Error {
+    let location = match error.location() {
+        Some(location) => location,
+        None => return anyhow::Error::msg(format!("{:?}: {}", file_path, error)),
+    };
+    let line = String::from_utf8_lossy(data)
+        .lines()
+        .nth(location.line().saturating_sub(1))
+        .unwrap_or_default()
+        .to_owned();
+    let number = location.line().to_string();
+
+    anyhow::Error::msg(format!(
+        "{:?}:{}:{}: {}\n{} | {}\n{} | {}^",
+        file_path,
+        location.line(),
+        location.column(),
+        error,
+        number,
+        line,
+        " ".repeat(number.len()),
+        " ".repeat(location.column().saturating_sub(1))
+    ))
+}
+
+impl config::Config {
+    pub async fn read_config<P>(file_path: P) -> Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        let file_path = file_path.as_ref();
+        println!("Reading {:?}", file_path);
+        let data = fs::read(file_path).await.context("Error reading file")?;
+        let format = ConfigFormat::sniff(&data);
+
+        let version: config::ConfigVersion = format
+            .parse(file_path, &data)
+            .context("Error parsing config version")?;
+        match version.config_version.as_str() {
+            "1.0" => (),
+            _ => {
+                return Err(anyhow::Error::msg(
+                    "Invalid api_version. Accepted values are: 1.0",
+                ))
+            }
+        }
+
+        let mut config: Self = format
+            .parse(file_path, &data)
+            .context("Error parsing data")?;
+        if !config.devices.is_empty() {
+            if !config.root_devices.is_empty() {
+                return Err(anyhow::Error::msg(
+                    "The config has both edgedevices and devices. Use one of them.",
+                ));
+            }
+            config.root_devices =
+                config::FlatDevice::build_hierarchies(std::mem::take(&mut config.devices))
+                    .map_err(anyhow::Error::msg)?;
+        }
+        if config.root_devices.is_empty() {
+            return Err(anyhow::Error::msg(
+                "The config has no devices. Add them under edgedevices or devices.",
+            ));
+        }
+        for root in &mut config.root_devices {
+            root.inherit_groups();
+        }
+        if let Some(device) = FlatenedDevice::iter(&config.root_devices)
+            .find(|d| d.parent.is_some() && d.device.external_parent.is_some())
+        {
+            return Err(anyhow::Error::msg(format!(
+                "{} has an external_parent but is not a top device. Only top devices can have one.",
+                device.device.device_id
+            )));
+        }
+        config.az_arguments.validate().map_err(anyhow::Error::msg)?;
+        config.apply_environment()?;
+
+        Ok(config)
+    }
+
+    /// Connection strings from the environment take precedence over the config, so secrets
+    /// can be injected by CI without templating the config file. Hub and DPS names left out of
+    /// the config are taken from the connection string's host name.
+    fn apply_environment(&mut self) -> Result<()> {
+        if let Ok(connection_string) = std::env::var("IOTHUB_CONNECTION_STRING") {
+            self.iothub.connection_string = Some(connection_string);
+        }
+        if let Some(connection_string) = &self.iothub.connection_string {
+            let connection_string: ConnectionString = connection_string
+                .parse()
+                .context("Error parsing iothub connection string")?;
+            if self.iothub.iothub_hostname.is_empty() {
+                self.iothub.iothub_hostname = connection_string.host_name.clone();
+            }
+            if self.iothub.iothub_name.is_empty() {
+                self.iothub.iothub_name = host_name_prefix(&connection_string.host_name);
+            }
+        }
+
+        if let Ok(connection_string) = std::env::var("IOTHUB_DPS_CONNECTION_STRING") {
+            self.dps
+                .get_or_insert_with(Default::default)
+                .connection_string = Some(connection_string);
+        }
+        if let Some(dps) = &mut self.dps {
+            if let Some(connection_string) = &dps.connection_string {
+                let connection_string: ConnectionString = connection_string
+                    .parse()
+                    .context("Error parsing dps connection string")?;
+                if dps.dps_name.is_empty() {
+                    dps.dps_name = host_name_prefix(&connection_string.host_name);
+                }
+            }
+        }
+
+        if self.iothub.iothub_hostname.is_empty() || self.iothub.iothub_name.is_empty() {
+            return Err(anyhow::Error::msg(
+                "iothub_hostname and iothub_name must be set, either in the config or through an iothub connection string",
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Copy of the config with its secrets replaced by `<redacted>`, for printing.
+    fn redacted(&self) -> Self {
+        fn redact_devices(device: &mut config::DeviceConfig) {
+            if let Some(auth) = &mut device.container_auth {
+                auth.password = REDACTED.to_owned();
+            }
+            device.children.iter_mut().for_each(redact_devices);
+        }
+        const REDACTED: &str = "<redacted>";
+
+        let mut config = self.clone();
+        if let Some(connection_string) = &mut config.iothub.connection_string {
+            *connection_string = REDACTED.to_owned();
+        }
+        if let Some(dps) = &mut config.dps {
+            if let Some(connection_string) = &mut dps.connection_string {
+                *connection_string = REDACTED.to_owned();
+            }
+        }
+        if let Some(routing) = &mut config.hub_routing {
+            for endpoint in &mut routing.endpoints {
+                endpoint.connection_string = REDACTED.to_owned();
+            }
+        }
+        config.root_devices.iter_mut().for_each(redact_devices);
+
+        config
+    }
+
+    /// Prefixes everything this tool names in the shared hub with `<namespace>-`: device ids, DPS
+    /// registration ids, the Event Grid subscription and hub routing names. Deployments are set per device, so they
+    /// follow the device ids.
+    fn apply_namespace(&mut self, namespace: &str) -> Result<()> {
+        if namespace.is_empty()
+            || !namespace
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+        {
+            return Err(anyhow::Error::msg(format!(
+                "Namespace {:?} can only contain letters, digits, '-', '_' and '.'",
+                namespace
+            )));
+        }
+
+        fn prefix(device: &mut config::DeviceConfig, namespace: &str) {
+            device.device_id = format!("{}-{}", namespace, device.device_id);
+            if let Some(registration_id) = &mut device.registration_id {
+                *registration_id = format!("{}-{}", namespace, registration_id);
+            }
+            for child in &mut device.children {
+                prefix(child, namespace);
+            }
+        }
+        for root in &mut self.root_devices {
+            prefix(root, namespace);
+        }
+        self.namespace = Some(namespace.to_owned());
+
+        if let Some(event_grid) = &mut self.iothub.event_grid {
+            event_grid.subscription_name =
+                format!("{}-{}", namespace, event_grid.subscription_name);
+        }
+        if let Some(routing) = &mut self.hub_routing {
+            for group in &mut routing.consumer_groups {
+                *group = format!("{}-{}", namespace, group);
+            }
+            for endpoint in &mut routing.endpoints {
+                endpoint.name = format!("{}-{}", namespace, endpoint.name);
+            }
+            for route in &mut routing.routes {
+                route.name = format!("{}-{}", namespace, route.name);
+                if let Some(endpoint) = &mut route.endpoint {
+                    if routing
+                        .endpoints
+                        .iter()
+                        .any(|e| e.name == format!("{}-{}", namespace, endpoint))
+                    {
+                        *endpoint = format!("{}-{}", namespace, endpoint);
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn check_device_ids(&self) -> Result<()> {
+        let devices = FlatenedDevice::flatten_devices(&self.root_devices);
+        let mut map = HashSet::new();
+
+        for device in devices {
+            if !map.insert(&device.device.device_id) {
+                let error = format!(r#"device id "{}" is used twice!"#, device.device.device_id);
+
+                return Err(anyhow::Error::msg(error));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Only IoT Edge devices can be parents. Fails if az_arguments makes leaf devices and one of
+    /// them has children.
+    async fn check_edge_parents(&self) -> Result<()> {
+        if self.az_arguments.edge_enabled() {
+            return Ok(());
+        }
+
+        let parents: Vec<&str> = FlatenedDevice::flatten_devices(&self.root_devices)
+            .iter()
+            .filter(|d| {
+                d.device.provisioning == config::DeviceProvisioning::Hub
+                    && !d.device.children.is_empty()
+            })
+            .map(|d| d.device.device_id.as_str())
+            .collect();
+        if parents.is_empty() {
+            Ok(())
+        } else {
+            Err(anyhow::Error::msg(format!(
+                "az_arguments.create turns off --edge-enabled, but {} have child devices. Only IoT Edge devices can be parents.",
+                parents.join(", ")
+            )))
+        }
+    }
+
+    /// Validates every deployment manifest before anything is pushed to the hub.
+    async fn check_deployments(&self) -> Result<()> {
+        let mut problems = Vec::new();
+        for device in FlatenedDevice::flatten_devices(&self.root_devices) {
+            if let Some(deployment) = &device.device.deployment {
+                let manifest = fs::read_to_string(deployment)
+                    .await
+                    .with_context(|| format!("Error reading deployment {}", deployment))?;
+                problems.extend(
+                    deployment::validate_manifest(&manifest)
+                        .into_iter()
+                        .map(|p| format!("{} ({}): {}", deployment, device.device.device_id, p)),
+                );
+            }
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(anyhow::Error::msg(format!(
+                "Invalid deployment manifests:\n{}",
+                problems.join("\n")
+            )))
+        }
+    }
+
+    async fn check_key_algorithms(&self, file_manager: &FileManager) -> Result<()> {
+        for warning in self.key_algorithms.warnings(self.certificates.is_some()) {
+            file_manager
+                .log(LogLevel::Warn, "config", None, warning)
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    async fn check_hostnames(&self, file_manager: &FileManager) -> Result<()> {
+        let devices = FlatenedDevice::flatten_devices(&self.root_devices);
+        for warning in hostnames::shared_hostnames(&devices) {
+            file_manager
+                .log(LogLevel::Warn, "config", None, warning)
+                .await?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Attempts at setting a parent before giving up.
+const PARENT_SET_ATTEMPTS: u64 = 5;
+
+/// Devices the hub and cert streams work on at the same time. Only these have a future in
+/// memory, so hierarchies of tens of thousands of devices are not all in flight at once.
+const MAX_CONCURRENT_DEVICES: usize = 64;
+
+/// Whether a failed registry update lost a race with another update of the same device, rather
+/// than failing for good.
+fn is_conflict(error: &str) -> bool {
+    [
+        "PreconditionFailed",
+        "Precondition Failed",
+        "(412)",
+        "(409)",
+    ]
+    .iter()
+    .any(|c| error.contains(c))
+}
+
+/// Created devices with children in the config that the hub did not make edge devices. Devices
+/// provisioned through DPS are left out, their capabilities are only known once they register.
+fn non_edge_parents<'a>(created_devices: &'a [CreatedDevice<'_>]) -> Vec<&'a str> {
+    created_devices
+        .iter()
+        .filter(|d| {
+            d.device.provisioning == config::DeviceProvisioning::Hub
+                && !d.device.children.is_empty()
+                && !d.create_response.capabilities.iot_edge
+        })
+        .map(|d| d.device.device_id.as_str())
+        .collect()
+}
+
+fn host_name_prefix(host_name: &str) -> String {
+    host_name.split('.').next().unwrap_or(host_name).to_owned()
+}
+
+#[derive(Clone, Copy)]
+struct FlatenedDevice<'a> {
+    device: &'a config::DeviceConfig,
+    parent: Option<&'a config::DeviceConfig>,
+    /// Depth in the hierarchy, 0 being the top layer.
+    layer: usize,
+}
+
+impl<'a> FlatenedDevice<'a> {
+    /// The devices of every hierarchy, each top device followed by the devices under it.
+    pub fn flatten_devices(roots: &'a [config::DeviceConfig]) -> Vec<Self> {
+        Self::iter(roots).collect()
+    }
+
+    /// The devices in the order of `flatten_devices`, walked one at a time without collecting
+    /// them. Only the devices still to visit next to the current branch are kept.
+    pub fn iter(roots: &'a [config::DeviceConfig]) -> FlatenedDevices<'a> {
+        FlatenedDevices {
+            stack: roots
+                .iter()
+                .rev()
+                .map(|device| FlatenedDevice {
+                    device,
+                    parent: None,
+                    layer: 0,
+                })
+                .collect(),
+        }
+    }
+
+    /// The devices at one depth of the hierarchies, in the order of `iter`.
+    pub fn layer(roots: &'a [config::DeviceConfig], layer: usize) -> impl Iterator<Item = Self> {
+        Self::iter(roots).filter(move |d| d.layer == layer)
+    }
+
+    /// Number of layers of the deepest hierarchy.
+    pub fn layer_count(roots: &[config::DeviceConfig]) -> usize {
+        FlatenedDevice::iter(roots)
+            .map(|d| d.layer + 1)
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+/// Depth-first walk of the hierarchies, see `FlatenedDevice::iter`.
+struct FlatenedDevices<'a> {
+    stack: Vec<FlatenedDevice<'a>>,
+}
+
+impl<'a> Iterator for FlatenedDevices<'a> {
+    type Item = FlatenedDevice<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let next = self.stack.pop()?;
+        self.stack.extend(
+            next.device
+                .children
+                .iter()
+                .rev()
+                .map(|child| FlatenedDevice {
+                    device: child,
+                    parent: Some(next.device),
+                    layer: next.layer + 1,
+                }),
+        );
+
+        Some(next)
+    }
+}
+
+/// Outcome of one device's operation, as yielded by the device streams.
+struct DeviceResult<T> {
+    device_id: String,
+    result: Result<T>,
+}
+
+/// Orders the results like the hierarchy's devices, failing with the first device's error.
+fn in_hierarchy_order<T>(
+    devices: &[FlatenedDevice<'_>],
+    mut results: Vec<DeviceResult<T>>,
+) -> Result<Vec<T>> {
+    let position: HashMap<&str, usize> = devices
+        .iter()
+        .enumerate()
+        .map(|(i, d)| (d.device.device_id.as_str(), i))
+        .collect();
+    results.sort_by_key(|r| position.get(r.device_id.as_str()).copied());
+
+    results.into_iter().map(|r| r.result).collect()
+}
+
+struct CreatedDevice<'a> {
+    device: &'a config::DeviceConfig,
+    parent: Option<&'a config::DeviceConfig>,
+    /// Depth in the hierarchy, 0 being the top layer.
+    layer: usize,
+    create_response: hub_responses::CreateResponse,
+    /// Set for devices that provision through DPS.
+    dps_id_scope: Option<String>,
+}
+
+struct IoTHubDeviceManager<'a> {
+    config: &'a config::Config,
+    file_manager: &'a FileManager,
+    cert_manager: &'a CertManager<'a>,
+    throttle: &'a HubThrottle,
+    runner: &'a CommandRunner,
+    dps: DpsManager<'a>,
+    cancel: CancellationToken,
+}
+
+impl<'a> IoTHubDeviceManager<'a> {
+    pub fn new(
+        config: &'a config::Config,
+        file_manager: &'a FileManager,
+        cert_manager: &'a CertManager,
+        throttle: &'a HubThrottle,
+        runner: &'a CommandRunner,
+        cancel: CancellationToken,
+    ) -> Self {
+        Self {
+            config,
+            file_manager,
+            cert_manager,
+            throttle,
+            runner,
+            dps: DpsManager::new(config, file_manager, runner),
+            cancel,
+        }
+    }
+
+    // Consider running "az extension update --name azure-iot"
+
+    /// Runs an az command against the hub within the tier's rate, retrying it with exponential
+    /// backoff while the hub throttles it. The output of the last attempt is returned.
+    async fn hub_output(
+        &self,
+        operation: HubOperation,
+        args: &[&str],
+    ) -> Result<std::process::Output> {
+        let device_id = args
+            .iter()
+            .position(|a| *a == "--device-id")
+            .and_then(|i| args.get(i + 1))
+            .copied();
+        let mut attempt = 1;
+        loop {
+            self.throttle.wait(operation).await;
+            let command = self.runner.output(args).await?;
+            let stderr = String::from_utf8_lossy(&command.stderr);
+            if command.status.success()
+                || !throttle::is_throttled(&stderr)
+                || attempt == throttle::THROTTLED_ATTEMPTS
+            {
+                return Ok(command);
+            }
+
+            let delay = throttle::backoff(attempt);
+            self.file_manager
+                .log(
+                    LogLevel::Debug,
+                    "hub",
+                    device_id,
+                    format!(
+                        "The hub throttled {}, retrying in {:.1}s.\n{}",
+                        args[0],
+                        delay.as_secs_f64(),
+                        stderr
+                    ),
+                )
+                .await?;
+            tokio::time::sleep(delay).await;
+            self.cancel.check()?;
+            attempt += 1;
+        }
+    }
+
+    pub async fn create_devices(&self) -> Result<Vec<CreatedDevice<'_>>> {
+        // Create devices
+        let devices_to_create = FlatenedDevice::flatten_devices(&self.config.root_devices);
+        self.file_manager
+            .log(
+                LogLevel::Info,
+                "hub",
+                None,
+                &format!(
+                    "Creating {} devices in hub {}",
+                    devices_to_create.len(),
+                    self.config.iothub.iothub_name
+                ),
+            )
+            .await?;
+
+        let results: Vec<DeviceResult<CreatedDevice<'_>>> =
+            self.create_device_stream().collect().await;
+        let created_devices = in_hierarchy_order(&devices_to_create, results)?;
+
+        // Add parent-child relationships. Devices provisioned through DPS are not in the hub until
+        // they first connect, so their relationships can only be set afterwards.
+        let mut relationships_to_add = Vec::new();
+        for child in &created_devices {
+            if let (None, Some(external)) = (child.parent, &child.device.external_parent) {
+                if child.device.provisioning == config::DeviceProvisioning::Hub {
+                    relationships_to_add.push((&external.device_id, &child.device.device_id));
+                } else {
+                    let message = format!(
+                        "{} provisions through DPS. Once it is registered, set its external parent with: az iot hub device-identity parent set --device-id {} --parent-device-id {} --hub-name {}",
+                        child.device.device_id,
+                        child.device.device_id,
+                        external.device_id,
+                        self.config.iothub.iothub_name
+                    );
+                    self.file_manager
+                        .log(
+                            LogLevel::Warn,
+                            "hub",
+                            Some(child.device.device_id.as_str()),
+                            message,
+                        )
+                        .await?;
+                }
+            }
+            if let Some(parent) = child.parent {
+                if parent.provisioning == config::DeviceProvisioning::Hub
+                    && child.device.provisioning == config::DeviceProvisioning::Hub
+                {
+                    relationships_to_add.push((&parent.device_id, &child.device.device_id));
+                } else {
+                    let message = format!(
+                        "{} or its parent {} provisions through DPS. Once both are registered, set the parent with: az iot hub device-identity parent set --device-id {} --parent-device-id {} --hub-name {}",
+                        child.device.device_id,
+                        parent.device_id,
+                        child.device.device_id,
+                        parent.device_id,
+                        self.config.iothub.iothub_name
+                    );
+                    self.file_manager
+                        .log(
+                            LogLevel::Warn,
+                            "hub",
+                            Some(child.device.device_id.as_str()),
+                            message,
+                        )
+                        .await?;
+                }
+            }
+        }
+        let not_edge = non_edge_parents(&created_devices);
+        if !not_edge.is_empty() {
+            return Err(anyhow::Error::msg(format!(
+                "The hub did not create {} as IoT Edge devices, so they can't be parents. No parents were set.",
+                not_edge.join(", ")
+            )));
+        }
+        self.file_manager
+            .log(
+                LogLevel::Debug,
+                "hub",
+                None,
+                "Adding parent-child relationships.",
+            )
+            .await?;
+
+        self.cancel.check()?;
+        let futures = relationships_to_add
+            .into_iter()
+            .map(|(parent, child)| self.create_parent_child_relationship(parent, child));
+
+        futures::stream::iter(futures)
+            .buffer_unordered(MAX_CONCURRENT_DEVICES)
+            .collect::<Vec<Result<()>>>()
+            .await
+            .into_iter()
+            .collect::<Result<Vec<()>>>()?;
+        self.file_manager
+            .log(LogLevel::Debug, "hub", None, "Created all relationships.")
+            .await?;
+
+        Ok(created_devices)
+    }
+
+    /// Creates the devices of the hierarchy, a layer at a time from the top and
+    /// `MAX_CONCURRENT_DEVICES` at a time within a layer, yielding each one as soon as it is
+    /// done, so callers can react to devices one by one. A layer starts once the layer above is
+    /// done, so parents are always in the hub before their children. Parent-child relationships
+    /// are not set.
+    ///
+    /// The crate has no library target yet, so only this binary uses the stream, through
+    /// create_devices. It is the API to export once the library is split out.
+    pub fn create_device_stream(
+        &self,
+    ) -> impl futures::Stream<Item = DeviceResult<CreatedDevice<'a>>> + '_ {
+        let roots = &self.config.root_devices;
+        futures::stream::iter(0..FlatenedDevice::layer_count(roots)).flat_map(move |layer| {
+            futures::stream::iter(
+                FlatenedDevice::layer(roots, layer).map(move |d| async move {
+                    let result = self
+                        .file_manager
+                        .track("hub", Some(&d.device.device_id), async {
+                            self.cancel.check()?;
+                            if d.device.provisioning == config::DeviceProvisioning::Hub
+                                && self.config.was_done("hub", &d.device.device_id)
+                            {
+                                return self.existing_device_identity(&d).await;
+                            }
+                            self.create_device_identity(&d).await
+                        })
+                        .await;
+                    DeviceResult {
+                        device_id: d.device.device_id.clone(),
+                        result,
+                    }
+                }),
+            )
+            .buffer_unordered(MAX_CONCURRENT_DEVICES)
+        })
+    }
+
+    /// Deletes the config's devices, a layer at a time from the bottom, so no device is deleted
+    /// while its children are still in the hub. Devices in the hub without the creation tag are
+    /// left alone unless `include_untagged`, since they were not made by this tool.
+    pub async fn delete_devices(&self, include_untagged: bool) -> Result<()> {
+        let roots = &self.config.root_devices;
+        let num_devices = FlatenedDevice::iter(roots).count();
+        self.file_manager
+            .log(
+                LogLevel::Info,
+                "hub",
+                None,
+                &format!(
+                    "Deleting {} devices from hub {}",
+                    num_devices, self.config.iothub.iothub_name
+                ),
+            )
+            .await?;
+
+        let mut num_successes = 0;
+        for layer in (0..FlatenedDevice::layer_count(roots)).rev() {
+            let futures = FlatenedDevice::layer(roots, layer)
+                .map(|d| self.delete_managed_device(d.device, include_untagged));
+            let mut results =
+                futures::stream::iter(futures).buffer_unordered(MAX_CONCURRENT_DEVICES);
+            while let Some(result) = results.next().await {
+                if result? {
+                    num_successes += 1;
+                }
+            }
+        }
+
+        if num_successes == num_devices {
+            self.file_manager
+                .log(LogLevel::Debug, "hub", None, "Deleted all devices.")
+                .await?;
+        } else {
+            let message = format!(
+                "Successfully deleted {} devices, {} failed. For more information use the -v flag.",
+                num_successes,
+                num_devices - num_successes,
+            );
+            self.file_manager
+                .log(LogLevel::Warn, "hub", None, &message)
+                .await?;
+        }
+
+        Ok(())
     }
-}
 
-struct CreatedDevice<'a> {
-    device: &'a config::DeviceConfig,
-    parent: Option<&'a config::DeviceConfig>,
-    create_response: hub_responses::CreateResponse,
-}
+    /// Checks that every device of the config is in the hub, created by this tool, with the parent
+    /// of the config. Devices that provision through DPS are skipped, as they are only in the hub
+    /// once they register.
+    pub async fn verify_devices(&self) -> Result<()> {
+        let futures = FlatenedDevice::iter(&self.config.root_devices)
+            .filter(|d| d.device.provisioning == config::DeviceProvisioning::Hub)
+            .map(|d| async move {
+                let device_id = d.device.device_id.as_str();
+                let result = self
+                    .file_manager
+                    .track("verify", Some(device_id), self.verify_device(&d))
+                    .await;
+                (device_id, result)
+            });
+        let results: Vec<(&str, Result<()>)> = futures::stream::iter(futures)
+            .buffer_unordered(MAX_CONCURRENT_DEVICES)
+            .collect()
+            .await;
 
-struct IoTHubDeviceManager<'a> {
-    config: &'a config::Config,
-    file_manager: &'a FileManager,
-    cert_manager: &'a CertManager<'a>,
-}
+        let failed: Vec<String> = results
+            .iter()
+            .filter_map(|(device_id, r)| {
+                r.as_ref().err().map(|e| format!("{}: {:#}", device_id, e))
+            })
+            .collect();
+        if !failed.is_empty() {
+            return Err(anyhow::Error::msg(format!(
+                "{} of {} devices don't match the hub:\n{}",
+                failed.len(),
+                results.len(),
+                failed.join("\n")
+            )));
+        }
 
-impl<'a> IoTHubDeviceManager<'a> {
-    pub fn new(
-        config: &'a config::Config,
-        file_manager: &'a FileManager,
-        cert_manager: &'a CertManager,
-    ) -> Self {
-        Self {
-            config,
-            file_manager,
-            cert_manager,
+        self.file_manager
+            .print(format!(
+                "All {} devices are in hub {} with their parents.",
+                results.len(),
+                self.config.iothub.iothub_name
+            ))
+            .await
+    }
+
+    async fn verify_device(&self, device: &FlatenedDevice<'_>) -> Result<()> {
+        let device_id = device.device.device_id.as_str();
+        match self.creation_tag(device_id).await? {
+            None => return Err(anyhow::Error::msg("Not in the hub")),
+            Some(None) => {
+                return Err(anyhow::Error::msg(format!(
+                    "No {} twin tag, it was not created by this tool",
+                    CREATION_TAG
+                )))
+            }
+            Some(Some(_)) => (),
+        }
+
+        let expected = match (device.parent, &device.device.external_parent) {
+            (Some(parent), _) if parent.provisioning == config::DeviceProvisioning::Hub => {
+                Some(parent.device_id.as_str())
+            }
+            // Set once the parent registers through DPS
+            (Some(_), _) => return Ok(()),
+            (None, Some(external)) => Some(external.device_id.as_str()),
+            (None, None) => None,
+        };
+        let actual = self.parent_of(device_id).await?;
+        if actual.as_deref() != expected {
+            return Err(anyhow::Error::msg(format!(
+                "The hub has parent {} instead of {}",
+                actual.as_deref().unwrap_or("none"),
+                expected.unwrap_or("none")
+            )));
         }
+
+        Ok(())
     }
 
-    // Consider running "az extension update --name azure-iot"
+    /// Subscribes the endpoint to the hub's device lifecycle events. Done before devices are
+    /// created so that the endpoint also learns about this run's devices.
+    pub async fn create_event_subscription(&self, event_grid: &config::EventGrid) -> Result<()> {
+        self.file_manager
+            .log(
+                LogLevel::Info,
+                "hub",
+                None,
+                format!(
+                    "Creating Event Grid subscription {} for device lifecycle events of hub {}",
+                    event_grid.subscription_name, self.config.iothub.iothub_name
+                ),
+            )
+            .await?;
 
-    pub async fn create_devices(&self) -> Result<Vec<CreatedDevice<'_>>> {
-        // Create devices
-        let devices_to_create = FlatenedDevice::flatten_devices(&self.config.root_device);
+        let hub_id = self.hub_resource_id().await?;
+        let endpoint = quote_arg(&event_grid.endpoint);
+        let args = &[
+            "az eventgrid event-subscription create",
+            "--name",
+            &event_grid.subscription_name,
+            "--source-resource-id",
+            &hub_id,
+            "--endpoint",
+            &endpoint,
+            "--endpoint-type",
+            event_grid.endpoint_type.as_deref().unwrap_or("webhook"),
+            "--included-event-types",
+            "Microsoft.Devices.DeviceCreated",
+            "Microsoft.Devices.DeviceDeleted",
+        ];
+        let command = self.runner.output(args).await?;
+        if command.status.success() {
+            self.file_manager
+                .log(
+                    LogLevel::Debug,
+                    "hub",
+                    None,
+                    format!(
+                        "Successfully created Event Grid subscription {}.\n{}",
+                        event_grid.subscription_name,
+                        String::from_utf8_lossy(&command.stdout)
+                    ),
+                )
+                .await?;
+
+            Ok(())
+        } else {
+            let error = format!(
+                "Failed to create Event Grid subscription {}:\n{}\n{}\n",
+                event_grid.subscription_name,
+                String::from_utf8_lossy(&command.stdout),
+                String::from_utf8_lossy(&command.stderr)
+            );
+            self.file_manager
+                .log(LogLevel::Debug, "hub", None, &error)
+                .await?;
+
+            Err(anyhow::Error::msg(error))
+        }
+    }
+
+    pub async fn hub_resource_id(&self) -> Result<String> {
+        let args = &[
+            "az iot hub show",
+            "--name",
+            &self.config.iothub.iothub_name,
+            "--query",
+            "id",
+            "-o",
+            "tsv",
+        ];
+        let command = self.runner.output(args).await?;
+        let hub_id = String::from_utf8_lossy(&command.stdout).trim().to_owned();
+        if command.status.success() && !hub_id.is_empty() {
+            Ok(hub_id)
+        } else {
+            Err(anyhow::Error::msg(format!(
+                "Failed to get resource id of hub {}:\n{}",
+                self.config.iothub.iothub_name,
+                String::from_utf8_lossy(&command.stderr)
+            )))
+        }
+    }
+
+    /// Reads the identity of a device the run --resume picks up created, with its deployment,
+    /// twin template and tags already set.
+    async fn existing_device_identity<'b>(
+        &self,
+        device: &FlatenedDevice<'b>,
+    ) -> Result<CreatedDevice<'b>> {
+        let device_id = device.device.device_id.as_str();
+        let command = self
+            .hub_output(
+                HubOperation::Registry,
+                &[
+                    "az iot hub device-identity show",
+                    "--device-id",
+                    device_id,
+                    "--hub-name",
+                    &self.config.iothub.iothub_name,
+                ],
+            )
+            .await?;
+        if !command.status.success() {
+            return Err(anyhow::Error::msg(format!(
+                "{} was created by the run being resumed but can't be read from hub {}:\n{}",
+                device_id,
+                self.config.iothub.iothub_name,
+                String::from_utf8_lossy(&command.stderr)
+            )));
+        }
         self.file_manager
-            .print(&format!(
-                "Creating {} devices in hub {}",
-                devices_to_create.len(),
-                self.config.iothub.iothub_name
-            ))
+            .log(
+                LogLevel::Debug,
+                "hub",
+                Some(device_id),
+                format!("Resuming with {} as created by the earlier run", device_id),
+            )
             .await?;
 
-        let futures = devices_to_create
-            .iter()
-            .map(|d| self.create_device_identity(d));
+        Ok(CreatedDevice {
+            device: device.device,
+            parent: device.parent,
+            layer: device.layer,
+            create_response: serde_json::from_slice(&command.stdout)?,
+            dps_id_scope: None,
+        })
+    }
 
-        let created_devices = futures::future::join_all(futures)
-            .await
+    async fn create_device_identity<'b>(
+        &self,
+        device: &FlatenedDevice<'b>,
+    ) -> Result<CreatedDevice<'b>> {
+        if device.device.provisioning != config::DeviceProvisioning::Hub {
+            let created = match device.device.provisioning {
+                config::DeviceProvisioning::Tpm => self.dps.create_tpm_enrollment(device).await?,
+                _ => self.dps.create_group_device(device).await?,
+            };
+            if let Some(deployment) = &device.device.deployment {
+                self.file_manager
+                    .log(
+                        LogLevel::Warn,
+                        "hub",
+                        Some(device.device.device_id.as_str()),
+                        format!(
+                            "Deployment {} of {} is not set because the device is not in the hub until it registers through DPS.",
+                            deployment, device.device.device_id
+                        ),
+                    )
+                    .await?;
+            }
+            if let Some(template) = self.config.configuration.twin_template(device.layer) {
+                self.file_manager
+                    .log(
+                        LogLevel::Warn,
+                        "hub",
+                        Some(device.device.device_id.as_str()),
+                        format!(
+                            "Twin template {} is not applied to {} because the device is not in the hub until it registers through DPS.",
+                            template, device.device.device_id
+                        ),
+                    )
+                    .await?;
+            }
+            return Ok(created);
+        }
+
+        self.file_manager
+            .log(
+                LogLevel::Debug,
+                "hub",
+                Some(device.device.device_id.as_str()),
+                format!(
+                    "Creating device {} on hub {}",
+                    device.device.device_id, self.config.iothub.iothub_name
+                ),
+            )
+            .await?;
+
+        let mut args = vec![
+            "az iot hub device-identity create",
+            "--device-id",
+            &device.device.device_id,
+            "--hub-name",
+            &self.config.iothub.iothub_name,
+            "--edge-enabled",
+        ];
+
+        let primary_thumbprint: String;
+        let secondary_thumbprint: String;
+        if self.config.iothub.authentication_method == config::IoTHubAuthMethod::X509Cert {
+            let auth_cert = self
+                .cert_manager
+                .make_hub_auth_cert(&device.device.device_id)
+                .await?;
+
+            primary_thumbprint = self.cert_manager.get_thumbprint(&auth_cert).await?;
+            secondary_thumbprint = self
+                .cert_manager
+                .get_thumbprint(
+                    &self
+                        .cert_manager
+                        .device_ca_path(&device.device.device_id)
+                        .await?,
+                )
+                .await?;
+
+            args.extend(&["--auth-method", "x509_thumbprint"]);
+            args.extend(&["--primary-thumbprint", &primary_thumbprint]);
+            args.extend(&["--secondary-thumbprint", &secondary_thumbprint]);
+        }
+        if self.config.create_disabled {
+            args.extend(&["--status", "disabled"]);
+        }
+        let extra = quote_args(&self.config.az_arguments.create);
+        args.extend(extra.iter().map(String::as_str));
+
+        let command = self.hub_output(HubOperation::Registry, &args).await?;
+        if command.status.success() {
+            self.file_manager
+                .log(
+                    LogLevel::Debug,
+                    "hub",
+                    Some(device.device.device_id.as_str()),
+                    format!(
+                        "Successfully created {}.\n{}",
+                        device.device.device_id,
+                        String::from_utf8_lossy(&command.stdout)
+                    ),
+                )
+                .await?;
+
+            let created_device: hub_responses::CreateResponse =
+                serde_json::from_slice(&command.stdout)?;
+
+            self.deploy(device).await?;
+            if let Some(template) = self.config.configuration.twin_template(device.layer) {
+                self.apply_twin_template(device, template).await?;
+            }
+            self.tag_device(device.device).await?;
+            Ok(CreatedDevice {
+                device: device.device,
+                parent: device.parent,
+                layer: device.layer,
+                create_response: created_device,
+                dps_id_scope: None,
+            })
+        } else {
+            let error = format!(
+                "Failed to create {}:\n{}\n{}\nMake sure you are running as sudo and try using the -f flag to delete existing devices before creation.",
+                device.device.device_id,
+                String::from_utf8_lossy(&command.stdout),
+                String::from_utf8_lossy(&command.stderr)
+            );
+            self.file_manager
+                .log(
+                    LogLevel::Debug,
+                    "hub",
+                    Some(device.device.device_id.as_str()),
+                    &error,
+                )
+                .await?;
+
+            Err(anyhow::Error::msg(error))
+        }
+    }
+
+    /// Enables the device and the devices under it, parents first. Devices provisioned through DPS
+    /// are skipped, they are not in the hub until they register.
+    pub async fn enable_devices(&self, roots: &[config::DeviceConfig]) -> Result<()> {
+        let devices: Vec<_> = FlatenedDevice::flatten_devices(roots)
             .into_iter()
-            .collect::<Result<Vec<CreatedDevice<'_>>>>()?;
+            .filter(|d| d.device.provisioning == config::DeviceProvisioning::Hub)
+            .collect();
+        self.file_manager
+            .log(
+                LogLevel::Info,
+                "hub",
+                None,
+                format!(
+                    "Enabling {} devices in hub {}",
+                    devices.len(),
+                    self.config.iothub.iothub_name
+                ),
+            )
+            .await?;
 
-        // Add parent-child relationships
-        let relationships_to_add = created_devices.iter().filter_map(|child| {
-            child
-                .parent
-                .map(|parent| (&parent.device_id, &child.device.device_id))
-        });
+        let mut failed = Vec::new();
+        for device in &devices {
+            self.cancel.check()?;
+            let device_id = device.device.device_id.as_str();
+            let command = self
+                .hub_output(
+                    HubOperation::Registry,
+                    &[
+                        "az iot hub device-identity update",
+                        "--device-id",
+                        device_id,
+                        "--hub-name",
+                        &self.config.iothub.iothub_name,
+                        "--status",
+                        "enabled",
+                    ],
+                )
+                .await?;
+            if command.status.success() {
+                self.file_manager
+                    .log(
+                        LogLevel::Info,
+                        "hub",
+                        Some(device_id),
+                        format!("Enabled {}.", device_id),
+                    )
+                    .await?;
+            } else {
+                self.file_manager
+                    .log(
+                        LogLevel::Error,
+                        "hub",
+                        Some(device_id),
+                        format!(
+                            "Failed to enable {}:\n{}",
+                            device_id,
+                            String::from_utf8_lossy(&command.stderr)
+                        ),
+                    )
+                    .await?;
+                failed.push(device_id);
+            }
+        }
+
+        if failed.is_empty() {
+            Ok(())
+        } else {
+            Err(anyhow::Error::msg(format!(
+                "Failed to enable {}. See the log for details.",
+                failed.join(", ")
+            )))
+        }
+    }
+
+    /// Sets the parent, then reads it back. Retries with backoff when the hub reports a conflicting
+    /// update or another write replaced the parent, which happens at high parallelism.
+    async fn create_parent_child_relationship(&self, parent: &str, child: &str) -> Result<()> {
         self.file_manager
-            .print_verbose("Adding parent-child relationships.")
+            .log(
+                LogLevel::Debug,
+                "hub",
+                Some(child),
+                format!("Adding {} as child of parent {}.", child, parent,),
+            )
+            .await?;
+
+        let mut attempt = 1;
+        loop {
+            let error = match self.set_parent(parent, child).await? {
+                // Not set in a dry run, so there is nothing to read back
+                Ok(()) if self.runner.plan().is_some() => return Ok(()),
+                Ok(()) => match self.parent_of(child).await? {
+                    Some(actual) if actual == parent => {
+                        self.file_manager
+                            .log(
+                                LogLevel::Debug,
+                                "hub",
+                                Some(child),
+                                format!(
+                                    "Successfully added {} as child of parent {}.",
+                                    child, parent
+                                ),
+                            )
+                            .await?;
+                        return Ok(());
+                    }
+                    actual => format!(
+                        "The hub reports {} as parent of {} after it was set to {}",
+                        actual.as_deref().unwrap_or("no device"),
+                        child,
+                        parent
+                    ),
+                },
+                Err(error) if is_conflict(&error) => error,
+                Err(error) => {
+                    self.file_manager
+                        .log(LogLevel::Debug, "hub", Some(child), &error)
+                        .await?;
+                    return Err(anyhow::Error::msg(error));
+                }
+            };
+
+            if attempt == PARENT_SET_ATTEMPTS {
+                self.file_manager
+                    .log(LogLevel::Debug, "hub", Some(child), &error)
+                    .await?;
+                return Err(anyhow::Error::msg(format!(
+                    "Gave up adding {} as child of parent {} after {} attempts:\n{}",
+                    child, parent, attempt, error
+                )));
+            }
+            let delay = 1 << attempt;
+            self.file_manager
+                .log(
+                    LogLevel::Debug,
+                    "hub",
+                    Some(child),
+                    format!("{}\nRetrying in {}s.", error, delay),
+                )
+                .await?;
+            tokio::time::sleep(std::time::Duration::from_secs(delay)).await;
+            self.cancel.check()?;
+            attempt += 1;
+        }
+    }
+
+    /// Runs `parent set` once. The inner error is the command's output when it failed.
+    async fn set_parent(&self, parent: &str, child: &str) -> Result<Result<(), String>> {
+        let extra = quote_args(&self.config.az_arguments.parent);
+        let mut args = vec![
+            "az iot hub device-identity parent set",
+            "--device-id",
+            child,
+            "--parent-device-id",
+            parent,
+            "--hub-name",
+            &self.config.iothub.iothub_name,
+        ];
+        args.extend(extra.iter().map(String::as_str));
+        let command = self.hub_output(HubOperation::Registry, &args).await?;
+        if command.status.success() {
+            Ok(Ok(()))
+        } else {
+            Ok(Err(format!(
+                "Failed to add {} as child of parent {}:\n{}\n{}\n",
+                child,
+                parent,
+                String::from_utf8_lossy(&command.stdout),
+                String::from_utf8_lossy(&command.stderr)
+            )))
+        }
+    }
+
+    /// Device id of the device's parent in the hub, None if it has none.
+    async fn parent_of(&self, child: &str) -> Result<Option<String>> {
+        let command = self
+            .hub_output(
+                HubOperation::Registry,
+                &[
+                    "az iot hub device-identity parent show",
+                    "--device-id",
+                    child,
+                    "--hub-name",
+                    &self.config.iothub.iothub_name,
+                ],
+            )
+            .await?;
+        if !command.status.success() {
+            let stderr = String::from_utf8_lossy(&command.stderr);
+            if stderr.contains("does not have a parent") {
+                return Ok(None);
+            }
+            return Err(anyhow::Error::msg(format!(
+                "Failed to read the parent of {}:\n{}",
+                child, stderr
+            )));
+        }
+
+        let parent: hub_responses::CreateResponse = serde_json::from_slice(&command.stdout)
+            .with_context(|| format!("Error parsing the parent of {}", child))?;
+
+        Ok(Some(parent.device_id))
+    }
+
+    /// Deletes the device from the hub, and its enrollment if it provisions through DPS.
+    async fn delete_managed_device(
+        &self,
+        device: &config::DeviceConfig,
+        include_untagged: bool,
+    ) -> Result<bool> {
+        let device_id = device.device_id.as_str();
+        if !include_untagged && self.creation_tag(device_id).await? == Some(None) {
+            self.file_manager
+                .log(
+                    LogLevel::Warn,
+                    "hub",
+                    Some(device_id),
+                    format!(
+                        "Not deleting {}, it has no {} twin tag so it was not created by this tool. Use --include-untagged to delete it anyway.",
+                        device_id, CREATION_TAG
+                    ),
+                )
+                .await?;
+            return Ok(false);
+        }
+
+        self.delete_device(device).await
+    }
+
+    /// The device's creation tag, Some(None) if it has none, or None if the device is not in
+    /// the hub.
+    async fn creation_tag(&self, device_id: &str) -> Result<Option<Option<CreationTag>>> {
+        let query = format!("tags.{}", CREATION_TAG);
+        let command = self
+            .hub_output(
+                HubOperation::Twin,
+                &[
+                    "az iot hub device-twin show",
+                    "--device-id",
+                    device_id,
+                    "--hub-name",
+                    &self.config.iothub.iothub_name,
+                    "--query",
+                    &query,
+                    "-o",
+                    "json",
+                ],
+            )
             .await?;
+        if !command.status.success() {
+            let stderr = String::from_utf8_lossy(&command.stderr);
+            if stderr.contains("DeviceNotFound") {
+                return Ok(None);
+            }
+            return Err(anyhow::Error::msg(format!(
+                "Failed to read the twin of {}:\n{}",
+                device_id, stderr
+            )));
+        }
 
-        let futures = relationships_to_add
-            .map(|(parent, child)| self.create_parent_child_relationship(parent, child));
+        let stdout = String::from_utf8_lossy(&command.stdout);
+        if stdout.trim().is_empty() {
+            return Ok(Some(None));
+        }
+        let tag: Option<CreationTag> = serde_json::from_str(&stdout)
+            .with_context(|| format!("Error parsing the {} tag of {}", CREATION_TAG, device_id))?;
 
-        futures::future::join_all(futures)
-            .await
-            .into_iter()
-            .collect::<Result<Vec<()>>>()?;
-        self.file_manager
-            .print_verbose("Created all relationships.")
-            .await?;
+        Ok(Some(tag))
+    }
 
-        Ok(created_devices)
+    async fn delete_device(&self, device: &config::DeviceConfig) -> Result<bool> {
+        let deleted = self.delete_device_identity(&device.device_id).await?;
+        if device.provisioning == config::DeviceProvisioning::Hub {
+            Ok(deleted)
+        } else {
+            Ok(self.dps.delete_enrollment(device).await? && deleted)
+        }
     }
 
-    pub async fn delete_devices(&self) -> Result<()> {
-        let devices_to_delete = FlatenedDevice::flatten_devices(&self.config.root_device);
+    async fn delete_device_identity(&self, device_id: &str) -> Result<bool> {
         self.file_manager
-            .print(&format!(
-                "Deleting {} devices from hub {}",
-                devices_to_delete.len(),
-                self.config.iothub.iothub_name
-            ))
+            .log(
+                LogLevel::Debug,
+                "hub",
+                Some(device_id),
+                format!(
+                    "Deleting device {} on hub {}",
+                    device_id, self.config.iothub.iothub_name
+                ),
+            )
             .await?;
 
-        let futures = devices_to_delete
-            .iter()
-            .map(|d| self.delete_device_identity(&d.device.device_id));
+        let extra = quote_args(&self.config.az_arguments.delete);
+        let mut args = vec![
+            "az iot hub device-identity delete",
+            "--device-id",
+            device_id,
+            "--hub-name",
+            &self.config.iothub.iothub_name,
+        ];
+        args.extend(extra.iter().map(String::as_str));
 
-        let num_successes = futures::future::join_all(futures)
-            .await
-            .into_iter()
-            .collect::<Result<Vec<bool>>>()?
-            .into_iter()
-            .filter(|s| *s)
-            .count();
+        let command = self.hub_output(HubOperation::Registry, &args).await?;
 
-        if num_successes == devices_to_delete.len() {
+        if command.status.success()
+            || String::from_utf8_lossy(&command.stderr).contains("ErrorCode:DeviceNotFound;")
+        {
             self.file_manager
-                .print_verbose("Deleted all devices.")
+                .log(
+                    LogLevel::Debug,
+                    "hub",
+                    Some(device_id),
+                    format!(
+                        "Successfully deleted {}.\n{}",
+                        device_id,
+                        String::from_utf8_lossy(&command.stdout)
+                    ),
+                )
                 .await?;
+            Ok(true)
         } else {
             self.file_manager
-                .print(&format!(
-                "Successfully deleted {} devices, {} failed. For more information use the -v flag.",
-                num_successes,
-                devices_to_delete.len() - num_successes,
-            ))
+                .log(
+                    LogLevel::Debug,
+                    "hub",
+                    Some(device_id),
+                    format!(
+                        "Failed to delete {}:\n{}\n{}\n",
+                        device_id,
+                        String::from_utf8_lossy(&command.stdout),
+                        String::from_utf8_lossy(&command.stderr)
+                    ),
+                )
                 .await?;
+
+            Ok(false)
         }
+    }
 
-        Ok(())
+    /// Sets the device's deployment, with the routes, edgeHub and upstream settings of its layer
+    /// applied.
+    async fn deploy(&self, device: &FlatenedDevice<'_>) -> Result<()> {
+        let deployment = match &device.device.deployment {
+            Some(deployment) => deployment,
+            None => return Ok(()),
+        };
+        let device_id = device.device.device_id.as_str();
+        let layer = match self.config.configuration.layers.get(device.layer) {
+            Some(layer)
+                if layer.routes.is_some()
+                    || layer.edge_hub.is_some()
+                    || layer.upstream_protocol.is_some()
+                    || layer.parent_port.is_some() =>
+            {
+                layer
+            }
+            _ => return self.set_deployment(device_id, deployment).await,
+        };
+
+        let manifest = fs::read_to_string(deployment)
+            .await
+            .with_context(|| format!("Error reading deployment {}", deployment))?;
+        let mut manifest: serde_json::Value = serde_json::from_str(&manifest)?;
+        if let Some(routes) = &layer.routes {
+            routes::apply_routes(&mut manifest, routes, device.parent.is_none())
+                .with_context(|| format!("Error generating routes for {}", device_id))?;
+        }
+        if let Some(edge_hub) = &layer.edge_hub {
+            deployment::apply_edge_hub_settings(&mut manifest, edge_hub)
+                .with_context(|| format!("Error applying edgeHub settings for {}", device_id))?;
+        }
+        deployment::apply_upstream(&mut manifest, layer.upstream_protocol, layer.parent_port)
+            .with_context(|| format!("Error applying upstream settings for {}", device_id))?;
+        let manifest = serde_json::to_string_pretty(&manifest)?;
+        let problems = deployment::validate_manifest(&manifest);
+        if !problems.is_empty() {
+            return Err(anyhow::Error::msg(format!(
+                "Generated deployment of {} is invalid:\n{}",
+                device_id,
+                problems.join("\n")
+            )));
+        }
+
+        let path = self
+            .file_manager
+            .get_folder(device_id)
+            .await?
+            .join("deployment.json");
+        self.file_manager
+            .log(
+                LogLevel::Debug,
+                "hub",
+                Some(device_id),
+                format!("Writing generated deployment to {:?}", path),
+            )
+            .await?;
+        self.file_manager
+            .write_generated(&path, &manifest, Some(device_id))
+            .await?;
+
+        self.set_deployment(device_id, &path.to_string_lossy())
+            .await
     }
 
-    async fn create_device_identity<'b>(
-        &self,
-        device: &FlatenedDevice<'b>,
-    ) -> Result<CreatedDevice<'b>> {
+    async fn set_deployment(&self, device_id: &str, path: &str) -> Result<()> {
         self.file_manager
-            .print_verbose(format!(
-                "Creating device {} on hub {}",
-                device.device.device_id, self.config.iothub.iothub_name
-            ))
+            .log(
+                LogLevel::Debug,
+                "hub",
+                Some(device_id),
+                format!("Setting {}'s deployment to {}", device_id, path),
+            )
             .await?;
 
+        let extra = quote_args(&self.config.az_arguments.deployment);
         let mut args = vec![
-            "az iot hub device-identity create",
+            "az iot edge set-modules",
             "--device-id",
-            &device.device.device_id,
+            device_id,
             "--hub-name",
             &self.config.iothub.iothub_name,
-            "--edge-enabled",
+            "--content",
+            path,
         ];
-
-        let primary_thumbprint: String;
-        let secondary_thumbprint: String;
-        if self.config.iothub.authentication_method == config::IoTHubAuthMethod::X509Cert {
-            let auth_cert = self
-                .cert_manager
-                .make_hub_auth_cert(&device.device.device_id)
-                .await?;
-
-            primary_thumbprint = self.cert_manager.get_thumbprint(&auth_cert).await?;
-            secondary_thumbprint = self
-                .cert_manager
-                .get_thumbprint(
-                    &self
-                        .cert_manager
-                        .device_ca_path(&device.device.device_id)
-                        .await?,
-                )
-                .await?;
-
-            args.extend(&["--auth-method", "x509_thumbprint"]);
-            args.extend(&["--primary-thumbprint", &primary_thumbprint]);
-            args.extend(&["--secondary-thumbprint", &secondary_thumbprint]);
-        }
-
-        let command = run_command(&args).output().await?;
+        args.extend(extra.iter().map(String::as_str));
+        let command = self.hub_output(HubOperation::Twin, &args).await?;
         if command.status.success() {
             self.file_manager
-                .print_verbose(format!(
-                    "Successfully created {}.\n{}",
-                    device.device.device_id,
-                    String::from_utf8_lossy(&command.stdout)
-                ))
+                .log(
+                    LogLevel::Debug,
+                    "hub",
+                    Some(device_id),
+                    format!(
+                        "Successfully set deployment for {}.\n{}",
+                        device_id,
+                        String::from_utf8_lossy(&command.stdout)
+                    ),
+                )
                 .await?;
 
-            let created_device: hub_responses::CreateResponse =
-                serde_json::from_slice(&command.stdout)?;
-
-            if let Some(deployment) = &device.device.deployment {
-                self.set_deployment(&device.device.device_id, &deployment)
-                    .await?;
-            }
-            Ok(CreatedDevice {
-                device: device.device,
-                parent: device.parent,
-                create_response: created_device,
-            })
+            Ok(())
         } else {
             let error = format!(
-                "Failed to create {}:\n{}\n{}\nMake sure you are running as sudo and try using the -f flag to delete existing devices before creation.",
-                device.device.device_id,
+                "Failed to set deployment for {}:\n{}\n{}\n",
+                device_id,
                 String::from_utf8_lossy(&command.stdout),
                 String::from_utf8_lossy(&command.stderr)
             );
-            self.file_manager.print_verbose(&error).await?;
+            self.file_manager
+                .log(LogLevel::Debug, "hub", Some(device_id), &error)
+                .await?;
 
             Err(anyhow::Error::msg(error))
         }
     }
 
-    async fn create_parent_child_relationship(&self, parent: &str, child: &str) -> Result<()> {
+    /// Writes the creation tag that gc finds the devices made by this tool with, and the
+    /// device's site, region and environment.
+    async fn tag_device(&self, device: &config::DeviceConfig) -> Result<()> {
+        let device_id = device.device_id.as_str();
+        let tag = CreationTag::new(self.config, self.file_manager.now());
+        let mut tags = serde_json::json!({ CREATION_TAG: tag });
+        for (name, value) in device.groups() {
+            tags[name] = serde_json::json!(value);
+        }
+        let tags = quote_arg(&tags.to_string());
+        let extra = quote_args(&self.config.az_arguments.twin);
+        let mut args = vec![
+            "az iot hub device-twin update",
+            "--device-id",
+            device_id,
+            "--hub-name",
+            &self.config.iothub.iothub_name,
+            "--tags",
+            &tags,
+        ];
+        args.extend(extra.iter().map(String::as_str));
+
+        let command = self.hub_output(HubOperation::Twin, &args).await?;
+        if !command.status.success() {
+            return Err(anyhow::Error::msg(format!(
+                "Failed to tag the twin of {}:\n{}",
+                device_id,
+                String::from_utf8_lossy(&command.stderr)
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Merges the layer's twin template into the device's twin.
+    async fn apply_twin_template(&self, device: &FlatenedDevice<'_>, path: &str) -> Result<()> {
+        let device_id = device.device.device_id.as_str();
         self.file_manager
-            .print_verbose(format!("Adding {} as child of parent {}.", child, parent,))
+            .log(
+                LogLevel::Debug,
+                "hub",
+                Some(device_id),
+                format!("Applying twin template {} to {}", path, device_id),
+            )
             .await?;
 
-        let args = &[
-            "az iot hub device-identity parent set",
+        let template = fs::read_to_string(path)
+            .await
+            .with_context(|| format!("Error reading twin template {}", path))?;
+        let twin = render_twin_template(
+            &template,
+            device_id,
+            device.parent.map(|p| p.device_id.as_str()),
+        )
+        .with_context(|| format!("Error parsing twin template {}", path))?;
+
+        let desired = twin
+            .pointer("/properties/desired")
+            .map(|d| quote_arg(&d.to_string()));
+        let tags = twin.get("tags").map(|t| quote_arg(&t.to_string()));
+        if desired.is_none() && tags.is_none() {
+            return Err(anyhow::Error::msg(format!(
+                "Twin template {} has neither tags nor properties.desired",
+                path
+            )));
+        }
+
+        let mut args = vec![
+            "az iot hub device-twin update",
             "--device-id",
-            child,
-            "--parent-device-id",
-            parent,
+            device_id,
             "--hub-name",
             &self.config.iothub.iothub_name,
         ];
-        let command = run_command(args).output().await?;
+        if let Some(desired) = &desired {
+            args.extend(&["--desired", desired.as_str()]);
+        }
+        if let Some(tags) = &tags {
+            args.extend(&["--tags", tags.as_str()]);
+        }
+        let extra = quote_args(&self.config.az_arguments.twin);
+        args.extend(extra.iter().map(String::as_str));
+
+        let command = self.hub_output(HubOperation::Twin, &args).await?;
         if command.status.success() {
             self.file_manager
-                .print_verbose(format!(
-                    "Successfully added {} as child of parent {}.\n{}",
-                    child,
-                    parent,
-                    String::from_utf8_lossy(&command.stdout)
-                ))
+                .log(
+                    LogLevel::Debug,
+                    "hub",
+                    Some(device_id),
+                    format!("Successfully updated the twin of {}.", device_id),
+                )
                 .await?;
 
             Ok(())
         } else {
             let error = format!(
-                "Failed to add {} as child of parent {}:\n{}\n{}\n",
-                child,
-                parent,
+                "Failed to update the twin of {}:\n{}\n{}\n",
+                device_id,
                 String::from_utf8_lossy(&command.stdout),
                 String::from_utf8_lossy(&command.stderr)
             );
-            self.file_manager.print_verbose(&error).await?;
+            self.file_manager
+                .log(LogLevel::Debug, "hub", Some(device_id), &error)
+                .await?;
 
             Err(anyhow::Error::msg(error))
         }
     }
+}
 
-    async fn delete_device_identity(&self, device_id: &str) -> Result<bool> {
-        self.file_manager
-            .print_verbose(format!(
-                "Deleting device {} on hub {}",
-                device_id, self.config.iothub.iothub_name
-            ))
-            .await?;
+/// Fills in `{device_id}` and `{parent_id}` in a twin template. The top layer has no parent, so
+/// its `{parent_id}` is empty.
+fn render_twin_template(
+    template: &str,
+    device_id: &str,
+    parent_id: Option<&str>,
+) -> Result<serde_json::Value> {
+    let twin = template
+        .replace("{device_id}", device_id)
+        .replace("{parent_id}", parent_id.unwrap_or_default());
+
+    Ok(serde_json::from_str(&twin)?)
+}
 
-        let args = &[
-            "az iot hub device-identity delete",
-            "--device-id",
-            device_id,
-            "--hub-name",
-            &self.config.iothub.iothub_name,
-        ];
+/// Environment variable with the passphrase of an encrypted root key. Also used to hand the
+/// passphrase to openssl, so it never shows up in the process list.
+const ROOT_CA_PASSPHRASE_ENV: &str = "IOTEDGE_CONFIG_ROOT_CA_PASSPHRASE";
 
-        let command = run_command(args)
-            // .spawn()?;
-            .output()
-            .await?;
+/// Key of the root CA generated when the config has none, in the certificates folder.
+const ROOT_KEY_FILE: &str = "iotedge_config_cli_root.key.pem";
 
-        if command.status.success()
-            || String::from_utf8_lossy(&command.stderr).contains("ErrorCode:DeviceNotFound;")
+/// Common name of the self-signed root made with --cert-profile test.
+const TEST_ROOT_COMMON_NAME: &str = "TEST_ONLY_Azure_IoT_Config_Cli_Cert";
+const TEST_CERTS_WARNING: &str = "WARNING: these certs were made with --cert-profile test. They expire after 1 day and must not be used in production.\n\n";
+
+struct CertManager<'a> {
+    config: &'a config::Config,
+    file_manager: &'a FileManager,
+    openssl_path: Option<&'a Path>,
+    profile: CertProfile,
+    backend: CertBackend,
+    rng: Box<dyn Rng>,
+    /// Where a prompted root key passphrase is kept for later runs.
+    secret_store: SecretStore,
+    /// With --dry-run, where openssl commands go instead of being run.
+    plan: Option<&'a Plan>,
+    cancel: CancellationToken,
+    issuance_lock: Mutex<()>,
+    /// The serials in the issuance log, read for the first new serial, and those handed out
+    /// since, which are not in the log until their cert is made.
+    reserved_serials: Mutex<Option<HashSet<String>>>,
+}
+
+impl<'a> CertManager<'a> {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        config: &'a config::Config,
+        file_manager: &'a FileManager,
+        openssl_path: Option<&'a Path>,
+        profile: CertProfile,
+        backend: CertBackend,
+        rng: Box<dyn Rng>,
+        secret_store: SecretStore,
+        plan: Option<&'a Plan>,
+        cancel: CancellationToken,
+    ) -> Self {
+        Self {
+            config,
+            file_manager,
+            openssl_path,
+            profile,
+            backend,
+            rng,
+            secret_store,
+            plan,
+            cancel,
+            issuance_lock: Mutex::new(()),
+            reserved_serials: Mutex::new(None),
+        }
+    }
+
+    /// The root CA key: the configured one, or the one generated in the output folder.
+    pub fn root_key_path(&self) -> PathBuf {
+        match &self.config.certificates {
+            Some(certificates) => PathBuf::from(&certificates.root_ca_cert_key_path),
+            None => self
+                .file_manager
+                .base_path()
+                .join("certificates")
+                .join(ROOT_KEY_FILE),
+        }
+    }
+
+    pub async fn issuance_log_path(&self) -> Result<PathBuf> {
+        Ok(self
+            .file_manager
+            .get_folder("certificates")
+            .await?
+            .join("issued_certs.jsonl"))
+    }
+
+    /// Prints the certs recorded in the issuance log, optionally only those of one device. Revoked
+    /// certs are left out unless `revoked` is set.
+    pub async fn list_issued(&self, device_id: Option<&str>, revoked: bool) -> Result<()> {
+        let mut certs = issuance::read(&self.issuance_log_path().await?).await?;
+        if !revoked {
+            let revoked_serials = issuance::revoked_serials(&certs);
+            certs.retain(|c| !revoked_serials.contains(&issuance::normalize_serial(&c.serial)));
+        }
+        // Device certs are logged as they are made, in no set order. List them like the
+        // hierarchy, the root CA first and devices no longer in the config last.
+        let position: HashMap<&str, usize> =
+            FlatenedDevice::flatten_devices(&self.config.root_devices)
+                .iter()
+                .enumerate()
+                .map(|(i, d)| (d.device.device_id.as_str(), i))
+                .collect();
+        certs.sort_by_key(|c| {
+            c.device_id
+                .as_deref()
+                .map(|d| position.get(d).copied().unwrap_or(usize::MAX))
+        });
+        println!(
+            "{:<8} {:<20} {:<40} {:<26} {}",
+            "EVENT", "DEVICE", "SERIAL", "NOT AFTER", "SUBJECT"
+        );
+        for cert in certs
+            .iter()
+            .filter(|c| device_id.is_none() || c.device_id.as_deref() == device_id)
         {
+            println!(
+                "{:<8} {:<20} {:<40} {:<26} {}",
+                format!("{:?}", cert.event).to_lowercase(),
+                cert.device_id.as_deref().unwrap_or("(root)"),
+                cert.serial,
+                cert.not_after,
+                cert.subject
+            );
+        }
+        if certs
+            .iter()
+            .any(|c| c.issuer.contains(TEST_ROOT_COMMON_NAME))
+        {
+            println!("WARNING: some certs were made with --cert-profile test and must not be used in production.");
+        }
+
+        Ok(())
+    }
+
+    /// Appends a `revoked` line to the issuance log for each current cert of the device, or only
+    /// the one with `serial`.
+    pub async fn revoke(&self, device_id: &str, serial: Option<&str>) -> Result<()> {
+        let log = self.issuance_log_path().await?;
+        let _lock = self.issuance_lock.lock().await;
+        let certs = issuance::read(&log).await?;
+        let serial = serial.map(issuance::normalize_serial);
+        let revoking: Vec<&IssuedCert> = issuance::current(&certs)
+            .into_iter()
+            .filter(|c| c.device_id.as_deref() == Some(device_id))
+            .filter(|c| {
+                serial
+                    .as_ref()
+                    .map_or(true, |s| issuance::normalize_serial(&c.serial) == *s)
+            })
+            .collect();
+        if revoking.is_empty() {
+            return Err(anyhow::Error::msg(format!(
+                "{} has no {} in {:?} that is not already revoked",
+                device_id,
+                serial.map_or("certs".to_owned(), |s| format!("cert with serial {}", s)),
+                log
+            )));
+        }
+
+        for cert in revoking {
+            let revoked = IssuedCert {
+                event: IssuanceEvent::Revoked,
+                time: self.file_manager.now().to_rfc3339(),
+                ..cert.clone()
+            };
+            issuance::append(&log, &revoked).await?;
             self.file_manager
-                .print_verbose(format!(
-                    "Successfully deleted {}.\n{}",
-                    device_id,
-                    String::from_utf8_lossy(&command.stdout)
-                ))
-                .await?;
-            Ok(true)
-        } else {
-            self.file_manager
-                .print_verbose(format!(
-                    "Failed to delete {}:\n{}\n{}\n",
-                    device_id,
-                    String::from_utf8_lossy(&command.stdout),
-                    String::from_utf8_lossy(&command.stderr)
-                ))
+                .log(
+                    LogLevel::Info,
+                    "certs",
+                    Some(device_id),
+                    format!("Revoked {} serial {}", cert.subject, cert.serial),
+                )
                 .await?;
-
-            Ok(false)
         }
+
+        Ok(())
     }
 
-    async fn set_deployment(&self, device_id: &str, path: &str) -> Result<()> {
-        self.file_manager
-            .print_verbose(format!("Setting {}'s deployment to {}", device_id, path))
-            .await?;
+    /// Prints which devices to renew in which week so expiries are spread out, and optionally
+    /// writes the weeks to an iCalendar file.
+    pub async fn renewal_plan(
+        &self,
+        within: chrono::Duration,
+        per_week: usize,
+        ics: Option<&Path>,
+    ) -> Result<()> {
+        let certs = issuance::read(&self.issuance_log_path().await?).await?;
+        let now = chrono::Utc::now();
+        let plan = issuance::renewal_plan(&certs, now, within, per_week)?;
+        if plan.is_empty() {
+            println!("No certs expire within {} days.", within.num_days());
+            return Ok(());
+        }
 
-        let args = &[
-            "az iot edge set-modules",
-            "--device-id",
-            device_id,
-            "--hub-name",
-            &self.config.iothub.iothub_name,
-            "--content",
-            path,
-        ];
-        let command = run_command(args).output().await?;
-        if command.status.success() {
+        println!("{:<12} {:<20} {:<26}", "WEEK OF", "DEVICE", "FIRST EXPIRY");
+        for renewal in &plan {
+            println!(
+                "{:<12} {:<20} {:<26}{}",
+                renewal.week.to_string(),
+                renewal.device_id.as_deref().unwrap_or("(root)"),
+                renewal.expiry.to_rfc2822(),
+                if renewal.late {
+                    " expires before its week, raise --per-week"
+                } else {
+                    ""
+                }
+            );
+        }
+
+        if let Some(ics) = ics {
+            fs::write(ics, issuance::renewal_calendar(&plan, now)).await?;
             self.file_manager
-                .print_verbose(format!(
-                    "Successfully set deployment for {}.\n{}",
-                    device_id,
-                    String::from_utf8_lossy(&command.stdout)
-                ))
+                .log(
+                    LogLevel::Info,
+                    "certs",
+                    None,
+                    format!("Wrote renewal reminders to {:?}.", ics),
+                )
                 .await?;
+        }
 
-            Ok(())
-        } else {
-            let error = format!(
-                "Failed to set deployment for {}:\n{}\n{}\n",
-                device_id,
-                String::from_utf8_lossy(&command.stdout),
-                String::from_utf8_lossy(&command.stderr)
-            );
-            self.file_manager.print_verbose(&error).await?;
+        Ok(())
+    }
 
-            Err(anyhow::Error::msg(error))
+    /// How long a new cert is valid, as openssl arguments. With --fixed-time the dates are given
+    /// from it, so certs made in different runs have the same validity.
+    fn validity_args(&self) -> Vec<String> {
+        match self.file_manager.fixed_time() {
+            Some(now) => {
+                let date =
+                    |time: chrono::DateTime<chrono::Utc>| time.format("%Y%m%d%H%M%SZ").to_string();
+                vec![
+                    "-not_before".to_owned(),
+                    date(now),
+                    "-not_after".to_owned(),
+                    date(now + self.profile.validity()),
+                ]
+            }
+            None => vec!["-days".to_owned(), self.profile.days().to_owned()],
         }
     }
-}
 
-struct CertManager<'a> {
-    config: &'a config::Config,
-    file_manager: &'a FileManager,
-    openssl_path: Option<&'a Path>,
-}
+    /// Makes a random 128 bit serial number that is not in the issuance log or used earlier in the
+    /// run, in the `0x` hex form taken by openssl's -set_serial.
+    async fn new_serial(&self) -> Result<String> {
+        let mut reserved = self.reserved_serials.lock().await;
+        if reserved.is_none() {
+            let log = self.issuance_log_path().await?;
+            let logged = issuance::read(&log)
+                .await?
+                .iter()
+                .map(|c| issuance::normalize_serial(&c.serial))
+                .collect();
+            *reserved = Some(logged);
+        }
+        let reserved = reserved.get_or_insert_with(HashSet::new);
 
-impl<'a> CertManager<'a> {
-    pub fn new(
-        config: &'a config::Config,
-        file_manager: &'a FileManager,
-        openssl_path: Option<&'a Path>,
-    ) -> Self {
-        Self {
-            config,
-            file_manager,
-            openssl_path,
+        loop {
+            // Clear the top bit, serials must be positive
+            let random = self.rng.random_hex(16).await?;
+            if random.len() != 32 || !random.chars().all(|c| c.is_ascii_hexdigit()) {
+                return Err(anyhow::Error::msg(format!(
+                    "Unexpected random serial: {:?}",
+                    random
+                )));
+            }
+            let first = u8::from_str_radix(&random[..1], 16)? & 0x7;
+            let serial = issuance::normalize_serial(&format!("{:x}{}", first, &random[1..]));
+            if reserved.insert(serial.clone()) {
+                return Ok(format!("0x{}", serial));
+            }
+        }
+    }
+
+    /// Appends a cert to the issuance log, `certificates/issued_certs.jsonl`.
+    async fn record_issuance(&self, device_id: Option<&str>, cert: &Path) -> Result<()> {
+        // A dry run makes no certs
+        if self.plan.is_some() {
+            return Ok(());
+        }
+        let text = match self.backend {
+            CertBackend::Native => native_certs::issuance_text(&fs::read(cert).await?)
+                .with_context(|| format!("Error reading {:?} for the issuance log", cert))?,
+            CertBackend::OpensslCli => {
+                let command = self
+                    .openssl_command()
+                    .args(&[
+                        "x509",
+                        "-noout",
+                        "-serial",
+                        "-subject",
+                        "-issuer",
+                        "-dates",
+                        "-fingerprint",
+                        "-sha256",
+                    ])
+                    .args(&[OsStr::new("-in"), cert.as_os_str()])
+                    .bounded_output()
+                    .await?;
+                if !command.status.success() {
+                    return Err(anyhow::Error::msg(format!(
+                        "Error reading {:?} for the issuance log:\n{}",
+                        cert,
+                        String::from_utf8_lossy(&command.stderr)
+                    )));
+                }
+                String::from_utf8_lossy(&command.stdout).into_owned()
+            }
+        };
+
+        let log = self.issuance_log_path().await?;
+        let _lock = self.issuance_lock.lock().await;
+        let mut issued = IssuedCert::from_openssl_text(
+            &text,
+            IssuanceEvent::Issued,
+            device_id,
+            self.file_manager.now(),
+        )?;
+        if issuance::read(&log)
+            .await?
+            .iter()
+            .any(|c| c.device_id == issued.device_id && c.subject == issued.subject)
+        {
+            issued.event = IssuanceEvent::Rotated;
+        }
+        // A cert signed elsewhere, for example by an external CA, has a serial new_serial did not
+        // hand out
+        if let Some(reserved) = self.reserved_serials.lock().await.as_mut() {
+            reserved.insert(issuance::normalize_serial(&issued.serial));
         }
+
+        self.file_manager
+            .log(
+                LogLevel::Debug,
+                "certs",
+                device_id,
+                format!(
+                    "Recording {:?} of {} serial {}",
+                    issued.event, issued.subject, issued.serial
+                ),
+            )
+            .await?;
+
+        issuance::append(&log, &issued).await
     }
 
     pub async fn device_ca_path(&self, device_id: &str) -> Result<PathBuf> {
@@ -584,72 +3742,284 @@ impl<'a> CertManager<'a> {
             .file_manager
             .get_folder(device_id)
             .await?
-            .join(format!("{}.full-chain.cert.pem", device_id));
+            .join(self.config.cert_names.device_ca_chain(device_id));
 
         Ok(path)
     }
 
     pub async fn make_all_device_ca_certs(&self) -> Result<()> {
-        let device_ids: Vec<&str> = FlatenedDevice::flatten_devices(&self.config.root_device)
-            .iter()
-            .map(|d| d.device.device_id.as_str())
-            .collect();
+        let results: Vec<DeviceResult<()>> = self.device_ca_cert_stream().await?.collect().await;
+        results
+            .into_iter()
+            .map(|r| r.result)
+            .collect::<Result<Vec<()>>>()?;
 
-        let config = self
-            .file_manager
-            .get_folder("certificates")
-            .await?
-            .join("v3_ca_extensions.cnf");
-        fs::write(config, include_str!(r#"scripts/v3_ca_extensions.cnf"#)).await?;
+        self.file_manager
+            .log(LogLevel::Debug, "certs", None, "Created all device certs.")
+            .await?;
+
+        Ok(())
+    }
+
+    /// Makes the root CA, or loads the configured one, then makes the devices' CA certs,
+    /// `MAX_CONCURRENT_DEVICES` at a time, yielding each device as soon as its cert is done.
+    /// Like create_device_stream, only used by this binary, through make_all_device_ca_certs.
+    pub async fn device_ca_cert_stream(
+        &self,
+    ) -> Result<impl futures::Stream<Item = DeviceResult<()>> + '_> {
+        self.write_ca_extensions().await?;
+        if self.profile == CertProfile::Test {
+            self.file_manager
+                .log(
+                    LogLevel::Warn,
+                    "certs",
+                    None,
+                    format!(
+                        "Making test certs that expire in {} day. Do not use them in production.",
+                        self.profile.days()
+                    ),
+                )
+                .await?;
+        }
 
+        let mut passphrase = None;
         let (cert_path, key_path) = if let Some(certificates) = &self.config.certificates {
             self.file_manager
-                .print(format!(
-                    "Using root CA {:?} with key {:?}.",
-                    certificates.root_ca_cert_path, certificates.root_ca_cert_key_path
-                ))
+                .log(
+                    LogLevel::Info,
+                    "certs",
+                    None,
+                    format!(
+                        "Using root CA {:?} with key {:?}.",
+                        certificates.root_ca_cert_path, certificates.root_ca_cert_key_path
+                    ),
+                )
                 .await?;
 
+            let key_path = PathBuf::from_str(&certificates.root_ca_cert_key_path)?;
+            passphrase = self.root_key_passphrase(certificates, &key_path).await?;
+
             (
                 PathBuf::from_str(&certificates.root_ca_cert_path)?,
-                PathBuf::from_str(&certificates.root_ca_cert_key_path)?,
+                key_path,
             )
+        } else if let Some(paths) = self.resumed_root_cert() {
+            paths
         } else {
             self.make_root_cert().await?
         };
 
         self.file_manager
-            .print(format!(
-                "Creating certificates for {} devices",
-                device_ids.len(),
-            ))
+            .log(
+                LogLevel::Info,
+                "certs",
+                None,
+                format!(
+                    "Creating certificates for {} devices",
+                    FlatenedDevice::iter(&self.config.root_devices).count()
+                ),
+            )
             .await?;
 
-        let futures = device_ids
-            .iter()
-            .map(|d| self.make_device_ca_cert(d, &cert_path, &key_path));
+        let devices = FlatenedDevice::iter(&self.config.root_devices).map(|d| d.device);
+        Ok(futures::stream::iter(devices.map(move |d| {
+            let (cert_path, key_path, passphrase) =
+                (cert_path.clone(), key_path.clone(), passphrase.clone());
+            async move {
+                let result = self
+                    .file_manager
+                    .track("certs", Some(&d.device_id), async {
+                        self.cancel.check()?;
+                        if self.config.was_done("certs", &d.device_id) && self.has_device_ca(d) {
+                            return Ok(());
+                        }
+                        self.make_device_ca_cert(d, &cert_path, &key_path, passphrase.as_deref())
+                            .await
+                    })
+                    .await;
+                DeviceResult {
+                    device_id: d.device_id.clone(),
+                    result,
+                }
+            }
+        }))
+        .buffer_unordered(MAX_CONCURRENT_DEVICES))
+    }
 
-        futures::future::join_all(futures)
-            .await
-            .into_iter()
-            .collect::<Result<Vec<()>>>()?;
+    /// First half of the offline CA workflow: makes each device's CA key and writes its CSR to
+    /// `dir` as `<device_id>.csr`, with the extensions file to sign it with as `<device_id>.cnf`.
+    pub async fn export_device_csrs(&self, dir: &Path) -> Result<()> {
+        self.write_ca_extensions().await?;
+        fs::create_dir_all(dir).await?;
+
+        let devices = FlatenedDevice::flatten_devices(&self.config.root_devices);
+        for device in &devices {
+            let device_id = device.device.device_id.as_str();
+            let csr = self.make_device_csr(device_id).await?;
+            fs::copy(&csr, dir.join(format!("{}.csr", device_id))).await?;
+            fs::copy(
+                self.extensions_config(device.device).await?,
+                dir.join(format!("{}.cnf", device_id)),
+            )
+            .await?;
+        }
+
+        self.file_manager
+            .log(
+                LogLevel::Info,
+                "certs",
+                None,
+                format!(
+                    "Wrote {} device CSRs to {:?}. Sign each <device_id>.csr with the v3_ca extensions of <device_id>.cnf, save the certs as <device_id>.cert.pem and rerun with --import-certs.",
+                    devices.len(),
+                    dir
+                ),
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    /// Second half of the offline CA workflow: takes the certs signed from the exported CSRs,
+    /// `<device_id>.cert.pem` in `dir`, in place of making device CA certs. The cert of the CA that
+    /// signed them is certificates.root_ca_cert_path or, without it, the root CA cert name in `dir`.
+    pub async fn import_device_certs(&self, dir: &Path) -> Result<()> {
+        let ca_cert_path = match &self.config.certificates {
+            Some(certificates) => PathBuf::from_str(&certificates.root_ca_cert_path)?,
+            None => dir.join(&self.config.cert_names.root_ca_cert),
+        };
+        if !ca_cert_path.exists() {
+            return Err(anyhow::Error::msg(format!(
+                "CA cert {:?} of the imported certs does not exist",
+                ca_cert_path
+            )));
+        }
+
+        let devices = FlatenedDevice::flatten_devices(&self.config.root_devices);
+        for device in &devices {
+            let device_id = device.device.device_id.as_str();
+            let device_folder = self.file_manager.get_folder(device_id).await?;
+            let signed = dir.join(format!("{}.cert.pem", device_id));
+            let device_key = device_folder.join(self.config.cert_names.device_ca_key(device_id));
+            if !signed.exists() || !device_key.exists() {
+                return Err(anyhow::Error::msg(format!(
+                    "Missing {:?} or {:?}. Export the CSRs with --export-csrs to the same output folder first.",
+                    signed, device_key
+                )));
+            }
+
+            let cert_key = self.public_key(&["x509", "-pubkey", "-noout"], &signed);
+            let own_key = self.public_key(&["pkey", "-pubout"], &device_key);
+            if cert_key.await? != own_key.await? {
+                return Err(anyhow::Error::msg(format!(
+                    "{:?} was not signed from the CSR exported for {}",
+                    signed, device_id
+                )));
+            }
+
+            self.file_manager
+                .log(
+                    LogLevel::Debug,
+                    "certs",
+                    Some(device_id),
+                    format!("Importing signed cert {:?}.", signed),
+                )
+                .await?;
+            let device_cert = device_folder.join(self.config.cert_names.device_ca_cert(device_id));
+            if self.plan.is_none() {
+                fs::copy(&signed, &device_cert).await?;
+                let _ = fs::remove_file(device_folder.join("device-id.csr")).await;
+            }
+
+            self.install_device_ca_cert(device_id, &device_cert, &ca_cert_path)
+                .await?;
+        }
 
         self.file_manager
-            .print_verbose("Created all device certs.")
+            .log(
+                LogLevel::Info,
+                "certs",
+                None,
+                format!("Imported {} signed device certs.", devices.len()),
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    async fn public_key(&self, args: &[&str], file: &Path) -> Result<String> {
+        let command = self
+            .openssl_output(
+                None,
+                self.openssl_command()
+                    .args(args)
+                    .args(&[OsStr::new("-in"), file.as_os_str()]),
+            )
             .await?;
+        if !command.status.success() {
+            return Err(anyhow::Error::msg(format!(
+                "Error reading public key of {:?}:\n{}",
+                file,
+                String::from_utf8_lossy(&command.stderr)
+            )));
+        }
+
+        Ok(String::from_utf8_lossy(&command.stdout).trim().to_owned())
+    }
+
+    async fn write_ca_extensions(&self) -> Result<()> {
+        let config = self
+            .file_manager
+            .get_folder("certificates")
+            .await?
+            .join("v3_ca_extensions.cnf");
+        fs::write(config, include_str!(r#"scripts/v3_ca_extensions.cnf"#)).await?;
 
         Ok(())
     }
 
+    /// The generated root CA of the run --resume picks up, if it made device certs with it.
+    fn resumed_root_cert(&self) -> Option<(PathBuf, PathBuf)> {
+        if !self.config.resumed.contains_key("certs") {
+            return None;
+        }
+        let cert_folder = self.file_manager.base_path().join("certificates");
+        let cert_path = cert_folder.join(&self.config.cert_names.root_ca_cert);
+        let key_path = cert_folder.join(ROOT_KEY_FILE);
+
+        if cert_path.exists() && key_path.exists() {
+            Some((cert_path, key_path))
+        } else {
+            None
+        }
+    }
+
+    /// Whether the device's CA cert chain and key are in its folder.
+    fn has_device_ca(&self, device: &config::DeviceConfig) -> bool {
+        let device_id = device.device_id.as_str();
+        let device_folder = self.file_manager.base_path().join(device_id);
+        device_folder
+            .join(self.config.cert_names.device_ca_chain(device_id))
+            .exists()
+            && device_folder
+                .join(self.config.cert_names.device_ca_key(device_id))
+                .exists()
+    }
+
     async fn make_root_cert(&self) -> Result<(PathBuf, PathBuf)> {
         let cert_folder = self.file_manager.get_folder("certificates").await?;
-        let cert_path = cert_folder.join("iotedge_config_cli_root.pem");
-        let key_path = cert_folder.join("iotedge_config_cli_root.key.pem");
+        let cert_path = cert_folder.join(&self.config.cert_names.root_ca_cert);
+        let key_path = cert_folder.join(ROOT_KEY_FILE);
         self.file_manager
-            .print(format!(
-                "No Root CA specified. Generating self-signed root at {:?}.",
-                cert_path
-            ))
+            .log(
+                LogLevel::Info,
+                "certs",
+                None,
+                format!(
+                    "No Root CA specified. Generating self-signed root at {:?}.",
+                    cert_path
+                ),
+            )
             .await?;
 
         let config = self
@@ -658,146 +4028,331 @@ impl<'a> CertManager<'a> {
             .await?
             .join("v3_ca_extensions.cnf");
 
+        let serial = self.new_serial().await?;
+        if self.backend == CertBackend::Native {
+            let request = self.native_request(
+                self.profile.root_subject(),
+                serial,
+                self.config.key_algorithms.root_ca,
+            );
+            self.write_native(None, &cert_path, &key_path, move || {
+                native_certs::root_ca(&request)
+            })
+            .await?;
+            self.record_issuance(None, &cert_path).await?;
+            return Ok((cert_path, key_path));
+        }
         let command = self
-            .openssl_command()
-            .arg("req")
-            .args(&[
-                "-x509",
-                "-new",
-                "-newkey",
-                "rsa:4096",
-                "-days",
-                "365",
-                "-nodes",
-                // "-addext",
-                // "keyUsage=critical, digitalSignature, cRLSign, keyCertSign",
-                "-extensions",
-                "v3_ca",
-            ])
-            .args(&[OsStr::new("-keyout"), key_path.as_os_str()])
-            .args(&[OsStr::new("-out"), cert_path.as_os_str()])
-            .args(&[OsStr::new("-config"), config.as_os_str()])
-            .args(&["-subj", "/CN=Azure_IoT_Config_Cli_Cert"])
-            .output()
+            .openssl_output(
+                None,
+                self.openssl_command()
+                    .arg("req")
+                    .args(self.config.key_algorithms.root_ca.newkey_args())
+                    .args(&["-set_serial", &serial])
+                    .args(self.validity_args())
+                    .args(&[
+                        "-x509",
+                        "-new",
+                        "-nodes",
+                        // "-addext",
+                        // "keyUsage=critical, digitalSignature, cRLSign, keyCertSign",
+                        "-extensions",
+                        "v3_ca",
+                    ])
+                    .args(&[OsStr::new("-keyout"), key_path.as_os_str()])
+                    .args(&[OsStr::new("-out"), cert_path.as_os_str()])
+                    .args(&[OsStr::new("-config"), config.as_os_str()])
+                    .args(&["-subj", &self.profile.root_subject()]),
+            )
             .await?;
 
         self.file_manager
-            .print_verbose(format!(
-                "{}{}",
-                String::from_utf8_lossy(&command.stdout),
-                String::from_utf8_lossy(&command.stderr)
-            ))
+            .log(
+                LogLevel::Debug,
+                "certs",
+                None,
+                format!(
+                    "{}{}",
+                    String::from_utf8_lossy(&command.stdout),
+                    String::from_utf8_lossy(&command.stderr)
+                ),
+            )
             .await?;
 
+        if !command.status.success() {
+            return Err(anyhow::Error::msg("Error making self-signed root CA"));
+        }
+        self.record_issuance(None, &cert_path).await?;
+
         Ok((cert_path, key_path))
     }
 
     async fn make_device_ca_cert(
         &self,
-        device_id: &str,
+        device: &config::DeviceConfig,
         ca_cert_path: &Path,
         ca_key_path: &Path,
+        ca_key_passphrase: Option<&str>,
     ) -> Result<()> {
+        if self.backend == CertBackend::Native {
+            return self
+                .make_native_device_ca_cert(device, ca_cert_path, ca_key_path, ca_key_passphrase)
+                .await;
+        }
+        let device_id = device.device_id.as_str();
         let device_folder = self.file_manager.get_folder(device_id).await?;
-        let csr = device_folder.join("device-id.csr");
-        let device_key = device_folder.join(format!("{}.key.pem", device_id));
-        let device_cert = device_folder.join(format!("{}.cert.pem", device_id));
-        let config = self
-            .file_manager
-            .get_folder("certificates")
-            .await?
-            .join("v3_ca_extensions.cnf");
+        let device_cert = device_folder.join(self.config.cert_names.device_ca_cert(device_id));
+        let config = self.extensions_config(device).await?;
+        let csr = self.make_device_csr(device_id).await?;
 
-        // CSR
+        // Sign Cert
         self.file_manager
-            .print_verbose(format!("Making device csr for {}.", device_id))
-            .await?;
-        let command = self
-            .openssl_command()
-            .arg("req")
-            .args(&["-newkey", "rsa:4096", "-nodes"])
-            .args(&[OsStr::new("-keyout"), device_key.as_os_str()])
-            .args(&[OsStr::new("-out"), csr.as_os_str()])
-            .args(&["-subj", &format!("/CN={}.deviceca", device_id)])
-            .output()
+            .log(
+                LogLevel::Debug,
+                "certs",
+                Some(device_id),
+                format!(
+                    "Making device cert based on for {:?} using {:?}.",
+                    csr, ca_cert_path
+                ),
+            )
             .await?;
+        let serial = self.new_serial().await?;
+        let mut command = self.openssl_command();
+        command
+            .arg("x509")
+            .args(&["-req", "-extensions", "v3_ca"])
+            .args(self.validity_args())
+            .args(&["-set_serial", &serial])
+            .args(&[OsStr::new("-in"), csr.as_os_str()])
+            .args(&[OsStr::new("-out"), device_cert.as_os_str()])
+            .args(&[OsStr::new("-CA"), ca_cert_path.as_os_str()])
+            .args(&[OsStr::new("-CAkey"), ca_key_path.as_os_str()])
+            .args(&[OsStr::new("-extfile"), config.as_os_str()]);
+        if let Some(passphrase) = ca_key_passphrase {
+            command
+                .args(&["-passin", &format!("env:{}", ROOT_CA_PASSPHRASE_ENV)])
+                .env(ROOT_CA_PASSPHRASE_ENV, passphrase);
+        }
+        let command = self.openssl_output(Some(device_id), &mut command).await?;
 
         self.file_manager
-            .print_verbose(format!(
-                "{}{}",
-                String::from_utf8_lossy(&command.stdout),
-                String::from_utf8_lossy(&command.stderr)
-            ))
+            .log(
+                LogLevel::Debug,
+                "certs",
+                Some(device_id),
+                format!(
+                    "{}{}",
+                    String::from_utf8_lossy(&command.stdout),
+                    String::from_utf8_lossy(&command.stderr)
+                ),
+            )
             .await?;
 
         if !command.status.success() {
             return Err(anyhow::Error::msg(format!(
-                "Error making csr for {}",
+                "Error making cert for {}",
                 device_id
             )));
         }
+        if self.plan.is_none() {
+            fs::remove_file(csr).await?;
+        }
+
+        self.install_device_ca_cert(device_id, &device_cert, ca_cert_path)
+            .await
+    }
+
+    /// Makes the device CA key and cert in-process, with the extensions of v3_ca_extensions.cnf.
+    async fn make_native_device_ca_cert(
+        &self,
+        device: &config::DeviceConfig,
+        ca_cert_path: &Path,
+        ca_key_path: &Path,
+        ca_key_passphrase: Option<&str>,
+    ) -> Result<()> {
+        let device_id = device.device_id.as_str();
+        if let Some(extensions) = &device.openssl_extensions {
+            return Err(anyhow::Error::msg(format!(
+                "openssl_extensions {} of {} is an openssl config, it needs --cert-backend openssl-cli",
+                extensions, device_id
+            )));
+        }
+        let device_folder = self.file_manager.get_folder(device_id).await?;
+        let device_cert = device_folder.join(self.config.cert_names.device_ca_cert(device_id));
+        let device_key = device_folder.join(self.config.cert_names.device_ca_key(device_id));
+        self.file_manager
+            .log(
+                LogLevel::Debug,
+                "certs",
+                Some(device_id),
+                format!("Making device cert signed by {:?}.", ca_cert_path),
+            )
+            .await?;
+
+        let request = self.native_request(
+            format!("/CN={}.deviceca", device_id),
+            self.new_serial().await?,
+            self.config.key_algorithms.device_ca,
+        );
+        let ip_address = device.hostname.as_deref().and_then(hostnames::ip_literal);
+        let (ca_cert, ca_key) = (ca_cert_path.to_owned(), ca_key_path.to_owned());
+        let passphrase = ca_key_passphrase.map(str::to_owned);
+        self.write_native(Some(device_id), &device_cert, &device_key, move || {
+            let ca_cert = std::fs::read(&ca_cert)
+                .with_context(|| format!("Error reading root CA cert {:?}", ca_cert))?;
+            let ca_key = std::fs::read(&ca_key)
+                .with_context(|| format!("Error reading root CA key {:?}", ca_key))?;
+            native_certs::device_ca(
+                &request,
+                &ca_cert,
+                &ca_key,
+                passphrase.as_deref(),
+                ip_address,
+            )
+        })
+        .await?;
+
+        self.install_device_ca_cert(device_id, &device_cert, ca_cert_path)
+            .await
+    }
+
+    /// A cert for the native backend, valid for the profile from now.
+    fn native_request(
+        &self,
+        subject: String,
+        serial: String,
+        key_algorithm: config::KeyAlgorithm,
+    ) -> native_certs::CertRequest {
+        let now = self.file_manager.now();
+        native_certs::CertRequest {
+            subject,
+            serial,
+            not_before: now,
+            not_after: now + self.profile.validity(),
+            key_algorithm,
+        }
+    }
+
+    /// Writes the cert and key `make` makes with the native backend, or with --dry-run adds them to
+    /// the plan instead.
+    async fn write_native(
+        &self,
+        device_id: Option<&str>,
+        cert_path: &Path,
+        key_path: &Path,
+        make: impl FnOnce() -> Result<native_certs::Issued> + Send + 'static,
+    ) -> Result<()> {
+        if let Some(plan) = self.plan {
+            plan.add(
+                device_id,
+                format!("make {:?} and key {:?} in-process", cert_path, key_path),
+            )
+            .await;
+            return Ok(());
+        }
+        // Key generation is CPU bound, RSA 4096 can take seconds
+        let issued = tokio::task::spawn_blocking(make).await??;
+        fs::write(cert_path, issued.cert)
+            .await
+            .with_context(|| format!("Error writing {:?}", cert_path))?;
+
+        escrow::write_private(key_path, &issued.key).await
+    }
+
+    /// Makes the device CA key and a CSR for it in the device folder.
+    async fn make_device_csr(&self, device_id: &str) -> Result<PathBuf> {
+        let device_folder = self.file_manager.get_folder(device_id).await?;
+        let csr = device_folder.join("device-id.csr");
+        let device_key = device_folder.join(self.config.cert_names.device_ca_key(device_id));
 
-        // Sign Cert
         self.file_manager
-            .print_verbose(format!(
-                "Making device cert based on for {:?} using {:?}.",
-                csr, ca_cert_path
-            ))
+            .log(
+                LogLevel::Debug,
+                "certs",
+                Some(device_id),
+                format!("Making device csr for {}.", device_id),
+            )
             .await?;
         let command = self
-            .openssl_command()
-            .arg("x509")
-            .args(&[
-                "-req",
-                "-days",
-                "365",
-                "-CAcreateserial",
-                "-extensions",
-                "v3_ca",
-            ])
-            .args(&[OsStr::new("-in"), csr.as_os_str()])
-            .args(&[OsStr::new("-out"), device_cert.as_os_str()])
-            .args(&[OsStr::new("-CA"), ca_cert_path.as_os_str()])
-            .args(&[OsStr::new("-CAkey"), ca_key_path.as_os_str()])
-            .args(&[OsStr::new("-extfile"), config.as_os_str()])
-            .output()
+            .openssl_output(
+                Some(device_id),
+                self.openssl_command()
+                    .arg("req")
+                    .args(self.config.key_algorithms.device_ca.newkey_args())
+                    .arg("-nodes")
+                    .args(&[OsStr::new("-keyout"), device_key.as_os_str()])
+                    .args(&[OsStr::new("-out"), csr.as_os_str()])
+                    .args(&["-subj", &format!("/CN={}.deviceca", device_id)]),
+            )
             .await?;
 
         self.file_manager
-            .print_verbose(format!(
-                "{}{}",
-                String::from_utf8_lossy(&command.stdout),
-                String::from_utf8_lossy(&command.stderr)
-            ))
+            .log(
+                LogLevel::Debug,
+                "certs",
+                Some(device_id),
+                format!(
+                    "{}{}",
+                    String::from_utf8_lossy(&command.stdout),
+                    String::from_utf8_lossy(&command.stderr)
+                ),
+            )
             .await?;
 
         if !command.status.success() {
             return Err(anyhow::Error::msg(format!(
-                "Error making cert for {}",
+                "Error making csr for {}",
                 device_id
             )));
         }
 
+        Ok(csr)
+    }
+
+    /// Records a signed device CA cert and makes its chain with the root cert.
+    async fn install_device_ca_cert(
+        &self,
+        device_id: &str,
+        device_cert: &Path,
+        ca_cert_path: &Path,
+    ) -> Result<()> {
+        // A dry run has no cert to chain
+        if self.plan.is_some() {
+            return Ok(());
+        }
+        let device_folder = self.file_manager.get_folder(device_id).await?;
+        self.record_issuance(Some(device_id), device_cert).await?;
+
         self.file_manager
-            .print_verbose(format!(
-                "Successfully made cert {:?}. Copying root cert to folder.",
-                device_cert
-            ))
+            .log(
+                LogLevel::Debug,
+                "certs",
+                Some(device_id),
+                format!(
+                    "Successfully made cert {:?}. Copying root cert to folder.",
+                    device_cert
+                ),
+            )
             .await?;
 
-        fs::remove_file(csr).await?;
         fs::copy(
             ca_cert_path,
-            device_folder.join(ca_cert_path.file_name().unwrap()),
+            device_folder.join(&self.config.cert_names.root_ca_cert),
         )
         .await?;
 
         self.file_manager
-            .print_verbose("Copied Root. Making cert chain.")
+            .log(
+                LogLevel::Debug,
+                "certs",
+                Some(device_id),
+                "Copied Root. Making cert chain.",
+            )
             .await?;
 
         Self::make_cert_chain(
-            &[&device_cert, ca_cert_path],
+            &[device_cert, ca_cert_path],
             &self.device_ca_path(device_id).await?,
         )
         .await?;
@@ -805,58 +4360,157 @@ impl<'a> CertManager<'a> {
         Ok(())
     }
 
+    /// The extensions file used to sign a device's CA cert: the shared v3_ca_extensions.cnf, or a
+    /// copy of it with the device's openssl_extensions merged in. Devices whose hostname is an IP
+    /// address get it as an IP subjectAltName, unless their openssl_extensions set one.
+    async fn extensions_config(&self, device: &config::DeviceConfig) -> Result<PathBuf> {
+        let cert_folder = self.file_manager.get_folder("certificates").await?;
+        let ip_address = device.hostname.as_deref().and_then(hostnames::ip_literal);
+        let mut snippet = match &device.openssl_extensions {
+            Some(extensions) => fs::read_to_string(extensions).await.with_context(|| {
+                format!(
+                    "Error reading openssl extensions {} of {}",
+                    extensions, device.device_id
+                )
+            })?,
+            None if ip_address.is_some() => String::new(),
+            None => return Ok(cert_folder.join("v3_ca_extensions.cnf")),
+        };
+        if let Some(ip_address) = ip_address {
+            if !snippet
+                .lines()
+                .any(|l| l.trim_start().starts_with("subjectAltName"))
+            {
+                snippet.insert_str(0, &format!("subjectAltName = IP:{}\n", ip_address));
+            }
+        }
+        let merged = merge_extensions(include_str!(r#"scripts/v3_ca_extensions.cnf"#), &snippet);
+
+        let path = cert_folder.join(format!("{}_extensions.cnf", device.device_id));
+        self.file_manager
+            .log(
+                LogLevel::Debug,
+                "certs",
+                Some(device.device_id.as_str()),
+                format!("Merged extensions into {:?}:\n{}", path, merged),
+            )
+            .await?;
+        fs::write(&path, merged).await?;
+
+        Ok(path)
+    }
+
     pub async fn make_hub_auth_cert(&self, device_id: &str) -> Result<PathBuf> {
         let device_folder = self.file_manager.get_folder(device_id).await?;
-        let device_cert = device_folder.join(format!("{}.hub-auth.cert.pem", device_id));
-        let device_key = device_folder.join(format!("{}.hub-auth.key.pem", device_id));
+        let device_cert = device_folder.join(self.config.cert_names.hub_auth_cert(device_id));
+        let device_key = device_folder.join(self.config.cert_names.hub_auth_key(device_id));
         self.file_manager
-            .print_verbose(format!(
-                "Generating self-signed hub cert for {} at {:?}.",
-                device_id, device_cert
-            ))
+            .log(
+                LogLevel::Debug,
+                "certs",
+                Some(device_id),
+                format!(
+                    "Generating self-signed hub cert for {} at {:?}.",
+                    device_id, device_cert
+                ),
+            )
             .await?;
 
+        let serial = self.new_serial().await?;
+        if self.backend == CertBackend::Native {
+            let request = self.native_request(
+                format!("/CN={}", device_id),
+                serial,
+                self.config.key_algorithms.hub_auth,
+            );
+            self.write_native(Some(device_id), &device_cert, &device_key, move || {
+                native_certs::hub_auth(&request)
+            })
+            .await?;
+            self.record_issuance(Some(device_id), &device_cert).await?;
+            return Ok(device_cert);
+        }
         let command = self
-            .openssl_command()
-            .arg("req")
-            .args(&[
-                "-x509", "-new", "-newkey", "rsa:4096", "-days", "365", "-nodes",
-            ])
-            .args(&[OsStr::new("-keyout"), device_key.as_os_str()])
-            .args(&[OsStr::new("-out"), device_cert.as_os_str()])
-            .args(&["-subj", &format!("/CN={}", device_id)])
-            .output()
+            .openssl_output(
+                Some(device_id),
+                self.openssl_command()
+                    .arg("req")
+                    .args(self.config.key_algorithms.hub_auth.newkey_args())
+                    .args(&["-x509", "-new", "-nodes"])
+                    .args(self.validity_args())
+                    .args(&["-set_serial", &serial])
+                    .args(&[OsStr::new("-keyout"), device_key.as_os_str()])
+                    .args(&[OsStr::new("-out"), device_cert.as_os_str()])
+                    .args(&["-subj", &format!("/CN={}", device_id)]),
+            )
             .await?;
 
         self.file_manager
-            .print_verbose(format!(
-                "{}{}",
-                String::from_utf8_lossy(&command.stdout),
-                String::from_utf8_lossy(&command.stderr)
-            ))
+            .log(
+                LogLevel::Debug,
+                "certs",
+                Some(device_id),
+                format!(
+                    "{}{}",
+                    String::from_utf8_lossy(&command.stdout),
+                    String::from_utf8_lossy(&command.stderr)
+                ),
+            )
             .await?;
 
+        if !command.status.success() {
+            return Err(anyhow::Error::msg(format!(
+                "Error making hub auth cert for {}",
+                device_id
+            )));
+        }
+        self.record_issuance(Some(device_id), &device_cert).await?;
+
         Ok(device_cert)
     }
 
     pub async fn get_thumbprint(&self, cert: &Path) -> Result<String> {
         self.file_manager
-            .print_verbose(format!("Getting thumbprint for {:?}", cert))
+            .log(
+                LogLevel::Debug,
+                "certs",
+                None,
+                format!("Getting thumbprint for {:?}", cert),
+            )
             .await?;
+        if self.backend == CertBackend::Native {
+            // Only known once the cert is made
+            if self.plan.is_some() {
+                return Ok("<thumbprint>".to_owned());
+            }
+            return native_certs::thumbprint(&fs::read(cert).await?)
+                .with_context(|| format!("Error getting fingerprint for {:?}", cert));
+        }
 
         let command = self
-            .openssl_command()
-            .args(&["x509", "--noout", "-fingerprint"])
-            .args(&[OsStr::new("-in"), cert.as_os_str()])
-            .output()
+            .openssl_output(
+                None,
+                self.openssl_command()
+                    .args(&["x509", "--noout", "-fingerprint"])
+                    .args(&[OsStr::new("-in"), cert.as_os_str()]),
+            )
             .await?;
+        // Only known once the cert is made
+        if self.plan.is_some() {
+            return Ok("<thumbprint>".to_owned());
+        }
 
         self.file_manager
-            .print_verbose(format!(
-                "{}{}",
-                String::from_utf8_lossy(&command.stdout),
-                String::from_utf8_lossy(&command.stderr)
-            ))
+            .log(
+                LogLevel::Debug,
+                "certs",
+                None,
+                format!(
+                    "{}{}",
+                    String::from_utf8_lossy(&command.stdout),
+                    String::from_utf8_lossy(&command.stderr)
+                ),
+            )
             .await?;
 
         if !command.status.success() {
@@ -884,6 +4538,121 @@ impl<'a> CertManager<'a> {
         Ok(thumbprint)
     }
 
+    /// Gets the passphrase of an encrypted root key once per run, from the environment, Key Vault,
+    /// or by prompting. Returns None if the key is not encrypted.
+    async fn root_key_passphrase(
+        &self,
+        certificates: &config::Certificates,
+        key_path: &Path,
+    ) -> Result<Option<String>> {
+        let key = fs::read_to_string(key_path)
+            .await
+            .with_context(|| format!("Error reading root key {:?}", key_path))?;
+        if !key.contains("ENCRYPTED") {
+            return Ok(None);
+        }
+
+        if let Ok(passphrase) = std::env::var(ROOT_CA_PASSPHRASE_ENV) {
+            self.file_manager
+                .log(
+                    LogLevel::Debug,
+                    "certs",
+                    None,
+                    format!("Using root key passphrase from {}.", ROOT_CA_PASSPHRASE_ENV),
+                )
+                .await?;
+            return Ok(Some(passphrase));
+        }
+
+        if let Some(secret) = &certificates.root_ca_cert_key_passphrase_secret {
+            self.file_manager
+                .log(
+                    LogLevel::Info,
+                    "certs",
+                    None,
+                    format!(
+                        "Reading root key passphrase from Key Vault secret {}.",
+                        secret
+                    ),
+                )
+                .await?;
+
+            // Run directly instead of through the command runner so the passphrase is never
+            // saved to a recording
+            let command = run_command(&[
+                "az keyvault secret show",
+                "--id",
+                secret,
+                "--query",
+                "value",
+                "-o",
+                "tsv",
+            ])
+            .bounded_output()
+            .await?;
+            if !command.status.success() {
+                return Err(anyhow::Error::msg(format!(
+                    "Failed to read Key Vault secret {}:\n{}",
+                    secret,
+                    String::from_utf8_lossy(&command.stderr)
+                )));
+            }
+            return Ok(Some(
+                String::from_utf8_lossy(&command.stdout)
+                    .trim_end_matches(&['\r', '\n'][..])
+                    .to_owned(),
+            ));
+        }
+
+        if let Some(passphrase) = self.secret_store.passphrase(key_path).await {
+            self.file_manager
+                .log(
+                    LogLevel::Debug,
+                    "certs",
+                    None,
+                    "Using root key passphrase from the OS credential store.",
+                )
+                .await?;
+            return Ok(Some(passphrase));
+        }
+
+        let passphrase =
+            prompt_secret(&format!("Enter pass phrase for root key {:?}: ", key_path))?;
+        if self.secret_store != SecretStore::None {
+            self.check_key_passphrase(key_path, &passphrase).await?;
+            self.secret_store
+                .keep_passphrase(key_path, &passphrase)
+                .await?;
+        }
+        Ok(Some(passphrase))
+    }
+
+    /// Fails if the passphrase does not unlock the key, so a mistyped one is not kept.
+    async fn check_key_passphrase(&self, key_path: &Path, passphrase: &str) -> Result<()> {
+        if self.backend == CertBackend::Native {
+            return native_certs::check_key_passphrase(&fs::read(key_path).await?, passphrase)
+                .with_context(|| {
+                    format!("The pass phrase does not unlock root key {:?}", key_path)
+                });
+        }
+        let command = self
+            .openssl_command()
+            .args(&["pkey", "-noout", "-in"])
+            .arg(key_path)
+            .args(&["-passin", &format!("env:{}", ROOT_CA_PASSPHRASE_ENV)])
+            .env(ROOT_CA_PASSPHRASE_ENV, passphrase)
+            .bounded_output()
+            .await?;
+        if !command.status.success() {
+            return Err(anyhow::Error::msg(format!(
+                "The pass phrase does not unlock root key {:?}",
+                key_path
+            )));
+        }
+
+        Ok(())
+    }
+
     async fn make_cert_chain(certs: &[&Path], out: &Path) -> Result<()> {
         let mut file = fs::File::create(out).await?;
         for cert in certs {
@@ -897,18 +4666,144 @@ impl<'a> CertManager<'a> {
         self.openssl_path
             .map_or_else(|| Command::new("openssl"), Command::new)
     }
+
+    /// Runs an openssl command, or with --dry-run adds it to the plan and answers it with no
+    /// output instead.
+    async fn openssl_output(
+        &self,
+        device_id: Option<&str>,
+        command: &mut Command,
+    ) -> Result<std::process::Output> {
+        let dry_run = match self.plan {
+            Some(plan) => plan,
+            None => return command.bounded_output().await,
+        };
+        let command = command.as_std();
+        let args: Vec<String> = std::iter::once(command.get_program())
+            .chain(command.get_args())
+            .map(|a| a.to_string_lossy().into_owned())
+            .collect();
+        dry_run
+            .add(
+                device_id,
+                plan::command_line(args.iter().map(String::as_str)),
+            )
+            .await;
+
+        Ok(commands::placeholder_output(String::new()))
+    }
+}
+
+/// Merges an openssl extensions snippet into the `[ v3_ca ]` section of the base config. Lines
+/// before the snippet's first section header replace or add v3_ca values. Sections in the snippet,
+/// such as an `[ alt_names ]` referenced from v3_ca, are appended as is.
+fn merge_extensions(base: &str, snippet: &str) -> String {
+    fn key(line: &str) -> Option<&str> {
+        let line = line.trim();
+        if line.starts_with('#') {
+            return None;
+        }
+        line.split_once('=').map(|(key, _)| key.trim())
+    }
+    fn section(line: &str) -> Option<&str> {
+        let line = line.trim();
+        if line.starts_with('[') {
+            Some(line.trim_matches(|c| c == '[' || c == ']').trim())
+        } else {
+            None
+        }
+    }
+
+    let split = snippet
+        .lines()
+        .position(|l| section(l).is_some())
+        .unwrap_or_else(|| snippet.lines().count());
+    let overrides: Vec<&str> = snippet
+        .lines()
+        .take(split)
+        .map(str::trim_end)
+        .filter(|l| key(l).is_some())
+        .collect();
+    let overridden: HashSet<&str> = overrides.iter().filter_map(|l| key(l)).collect();
+
+    let mut result: Vec<&str> = Vec::new();
+    let mut in_v3_ca = false;
+    for line in base.lines().map(str::trim_end) {
+        if let Some(section) = section(line) {
+            if in_v3_ca {
+                result.extend(&overrides);
+            }
+            in_v3_ca = section == "v3_ca";
+        }
+
+        if in_v3_ca && key(line).map_or(false, |k| overridden.contains(k)) {
+            continue;
+        }
+        result.push(line);
+    }
+    if in_v3_ca {
+        result.extend(&overrides);
+    }
+
+    result.extend(snippet.lines().skip(split).map(str::trim_end));
+    result.push("");
+    result.join("\n")
+}
+
+/// Adds variables to the `[agent.env]` table of a config.toml, replacing ones of the same name
+/// from the template.
+fn add_agent_env(config: &str, env: &BTreeMap<String, String>) -> Result<String> {
+    let mut config: toml::Value = toml::from_str(config)?;
+    let agent = config
+        .as_table_mut()
+        .and_then(|c| c.get_mut("agent"))
+        .and_then(toml::Value::as_table_mut)
+        .ok_or_else(|| anyhow::Error::msg("config.toml has no [agent] table"))?;
+    let agent_env = agent
+        .entry("env")
+        .or_insert_with(|| toml::Value::Table(Default::default()))
+        .as_table_mut()
+        .ok_or_else(|| anyhow::Error::msg("agent.env of config.toml is not a table"))?;
+    for (name, value) in env {
+        agent_env.insert(name.clone(), toml::Value::String(value.clone()));
+    }
+
+    Ok(toml::to_string(&config)?)
+}
+
+/// Ports a child device of the layer needs open to its parent, for the upstream protocol and the
+/// image pulls. None if the layer does not change the defaults.
+fn parent_ports(layer: &config::Layer) -> Option<Vec<u16>> {
+    if layer.upstream_protocol.is_none() && layer.parent_port.is_none() {
+        return None;
+    }
+
+    let mut ports = vec![layer.parent_port.unwrap_or(443)];
+    if let Some(protocol) = layer.upstream_protocol {
+        ports.push(protocol.port());
+    }
+    ports.dedup();
+
+    Some(ports)
 }
 
 struct DeviceConfigManager<'a> {
     config: &'a config::Config,
     file_manager: &'a FileManager,
+    /// With a store, config.toml refers to the device key instead of containing it.
+    secret_store: SecretStore,
 }
 
 impl<'a> DeviceConfigManager<'a> {
-    pub fn new(config: &'a config::Config, file_manager: &'a FileManager) -> Self {
+    pub fn new(
+        config: &'a config::Config,
+        file_manager: &'a FileManager,
+        secret_store: SecretStore,
+    ) -> Self {
         Self {
             config,
             file_manager,
+            secret_store,
         }
     }
 
@@ -921,83 +4816,157 @@ impl<'a> DeviceConfigManager<'a> {
 
     pub async fn make_all_device_configs(&self, devices: &[CreatedDevice<'_>]) -> Result<()> {
         self.file_manager
-            .print(&format!(
-                "Creating configuration files based on {:?} for {} devices.",
-                self.config.configuration.template_config_path,
-                devices.len(),
-            ))
+            .log(
+                LogLevel::Info,
+                "configs",
+                None,
+                &format!(
+                    "Creating configuration files based on {:?} for {} devices.",
+                    self.config.configuration.template_config_path,
+                    devices.len(),
+                ),
+            )
             .await?;
 
         let base_config = fs::read(&self.config.configuration.template_config_path).await?;
         let mut base_config: iotedge_config::Config = toml::from_slice(&base_config)?;
 
         self.file_manager
-            .print_verbose(format!("Base Config File: {:#?}", base_config))
+            .log(
+                LogLevel::Debug,
+                "configs",
+                None,
+                format!("Base Config File: {:#?}", base_config),
+            )
             .await?;
 
         for device in devices {
-            self.make_device_config(&device, &mut base_config).await?;
+            self.file_manager
+                .track(
+                    "configs",
+                    Some(&device.device.device_id),
+                    self.make_device_config(&device, &mut base_config),
+                )
+                .await?;
         }
 
         self.file_manager
-            .print_verbose("Created config files.")
+            .log(LogLevel::Debug, "configs", None, "Created config files.")
             .await?;
 
         Ok(())
     }
 
-    async fn make_device_config(
+    /// The device key for config.toml. With --secret-store it is kept in the credential store
+    /// instead, and config.toml refers to where it goes on the device.
+    fn device_key(
         &self,
         device: &CreatedDevice<'_>,
-        config: &mut iotedge_config::Config,
-    ) -> Result<()> {
-        self.file_manager
-            .print_verbose(format!("Generating config for {}", device.device.device_id))
-            .await?;
+        missing: &str,
+    ) -> Result<aziot_config::SymmetricKey> {
+        if self.secret_store != SecretStore::None {
+            return Ok(aziot_config::SymmetricKey::Preloaded {
+                uri: Url::parse(secrets::DEVICE_KEY_URI)?,
+            });
+        }
+
+        let key = device
+            .create_response
+            .authentication
+            .symmetric_key
+            .primary_key
+            .clone()
+            .ok_or_else(|| anyhow::Error::msg(missing.to_owned()))?;
+        Ok(aziot_config::SymmetricKey::Inline {
+            value: base64::decode(key)?,
+        })
+    }
 
+    fn manual_provisioning(
+        &self,
+        device: &CreatedDevice<'_>,
+    ) -> Result<aziot_config::ProvisioningType> {
+        let device_id = device.device.device_id.as_str();
+        let names = &self.config.cert_names;
         let authentication = match self.config.iothub.authentication_method {
             config::IoTHubAuthMethod::SymmetricKey => {
                 aziot_config::ManualAuthMethod::SharedPrivateKey {
-                    device_id_pk: aziot_config::SymmetricKey::Inline {
-                        value: base64::decode(
-                            device
-                                .create_response
-                                .authentication
-                                .symmetric_key
-                                .primary_key
-                                .clone()
-                                .ok_or_else(|| {
-                                    anyhow::Error::msg("Hub response did not contain symmetric key")
-                                })?,
-                        )?,
-                    },
+                    device_id_pk: self
+                        .device_key(device, "Hub response did not contain symmetric key")?,
                 }
             }
             config::IoTHubAuthMethod::X509Cert => aziot_config::ManualAuthMethod::X509 {
                 identity: aziot_config::X509Identity::Preloaded {
                     identity_cert: Url::parse(&format!(
-                        "file:///etc/aziot/certificates/{}.hub-auth.cert.pem",
-                        device.device.device_id
+                        "file:///etc/aziot/certificates/{}",
+                        names.hub_auth_cert(device_id)
                     ))?,
                     identity_pk: aziot_keys_common::PreloadedKeyLocation::Filesystem {
                         // Leave off the file://, it is automatically added by the serializer
-                        path: format!(
-                            "/etc/aziot/certificates/{}.hub-auth.key.pem",
-                            device.device.device_id
-                        )
-                        .into(),
+                        path: format!("/etc/aziot/certificates/{}", names.hub_auth_key(device_id))
+                            .into(),
                     },
                 },
             },
         };
 
+        Ok(aziot_config::ProvisioningType::Manual {
+            inner: aziot_config::ManualProvisioning::Explicit {
+                device_id: device.device.device_id.clone(),
+                iothub_hostname: self.config.iothub.iothub_hostname.clone(),
+                authentication,
+            },
+        })
+    }
+
+    fn dps_provisioning(
+        &self,
+        device: &CreatedDevice<'_>,
+        attestation: aziot_config::DpsAttestationMethod,
+    ) -> Result<aziot_config::ProvisioningType> {
+        Ok(aziot_config::ProvisioningType::Dps {
+            global_endpoint: Url::parse(dps::GLOBAL_ENDPOINT)?,
+            id_scope: device
+                .dps_id_scope
+                .clone()
+                .ok_or_else(|| anyhow::Error::msg("DPS ID scope is missing"))?,
+            attestation,
+        })
+    }
+
+    async fn make_device_config(
+        &self,
+        device: &CreatedDevice<'_>,
+        config: &mut iotedge_config::Config,
+    ) -> Result<()> {
+        self.file_manager
+            .log(
+                LogLevel::Debug,
+                "configs",
+                Some(device.device.device_id.as_str()),
+                format!("Generating config for {}", device.device.device_id),
+            )
+            .await?;
+
+        let device_id = device.device.device_id.as_str();
+        let names = &self.config.cert_names;
         config.aziot.provisioning = aziot_config::Provisioning {
-            provisioning: aziot_config::ProvisioningType::Manual {
-                inner: aziot_config::ManualProvisioning::Explicit {
-                    device_id: device.device.device_id.clone(),
-                    iothub_hostname: self.config.iothub.iothub_hostname.clone(),
-                    authentication,
-                },
+            provisioning: match device.device.provisioning {
+                config::DeviceProvisioning::Hub => self.manual_provisioning(device)?,
+                config::DeviceProvisioning::Tpm => self.dps_provisioning(
+                    device,
+                    aziot_config::DpsAttestationMethod::Tpm {
+                        registration_id: device.device.registration_id().to_owned(),
+                    },
+                )?,
+                config::DeviceProvisioning::DpsSymmetricKey => self.dps_provisioning(
+                    device,
+                    aziot_config::DpsAttestationMethod::SymmetricKey {
+                        registration_id: device.device.registration_id().to_owned(),
+                        symmetric_key: self
+                            .device_key(device, "No key was derived for the device")?,
+                    },
+                )?,
             },
         };
 
@@ -1006,38 +4975,45 @@ impl<'a> DeviceConfigManager<'a> {
                 .device
                 .hostname
                 .as_deref()
-                .unwrap_or("{{HOSTNAME}}")
-                .to_owned(),
+                .map_or_else(|| "{{HOSTNAME}}".to_owned(), hostnames::bare_host),
         );
 
-        config.aziot.parent_hostname = device.parent.map(|p| {
-            p.hostname
-                .as_deref()
-                .unwrap_or("{{PARENT_HOSTNAME}}")
-                .to_owned()
+        // The parent hostname is put in front of ports, as in $upstream:443 images
+        let parent_hostname = device
+            .parent
+            .map(|p| &p.hostname)
+            .or_else(|| device.device.external_parent.as_ref().map(|p| &p.hostname));
+        config.aziot.parent_hostname = parent_hostname.map(|h| {
+            h.as_deref()
+                .map_or_else(|| "{{PARENT_HOSTNAME}}".to_owned(), hostnames::url_host)
         });
 
-        config.trust_bundle_cert = Some(Url::parse(
-            "file:///etc/aziot/certificates/iotedge_config_cli_root.pem",
-        )?);
+        config.trust_bundle_cert = Some(Url::parse(&format!(
+            "file:///etc/aziot/certificates/{}",
+            names.root_ca_cert
+        ))?);
 
         config.edge_ca = Some(iotedge_config::EdgeCa::Explicit {
             cert: Url::parse(&format!(
-                "file:///etc/aziot/certificates/{}.full-chain.cert.pem",
-                device.device.device_id
+                "file:///etc/aziot/certificates/{}",
+                names.device_ca_chain(device_id)
             ))?,
             pk: Url::parse(&format!(
-                "file:///etc/aziot/certificates/{}.key.pem",
-                device.device.device_id
+                "file:///etc/aziot/certificates/{}",
+                names.device_ca_key(device_id)
             ))?,
         });
 
-        config.agent.config.image = device
+        let layer = self.config.configuration.layers.get(device.layer);
+        let image = device
             .device
             .edge_agent
             .as_ref()
-            .unwrap_or(&self.config.configuration.default_edge_agent)
-            .to_owned();
+            .unwrap_or(&self.config.configuration.default_edge_agent);
+        config.agent.config.image = match layer.and_then(|l| l.parent_port) {
+            Some(port) => deployment::with_parent_port(image, port),
+            None => image.to_owned(),
+        };
 
         config.agent.config.auth = device.device.container_auth.as_ref().and_then(|auth| {
             serde_json::from_value(serde_json::json! {{
@@ -1048,20 +5024,36 @@ impl<'a> DeviceConfigManager<'a> {
             .unwrap()
         });
 
-        let config = toml::to_string(&config)?;
+        let mut env = BTreeMap::new();
+        if let Some(protocol) = layer.and_then(|l| l.upstream_protocol) {
+            env.insert("UpstreamProtocol".to_owned(), format!("{:?}", protocol));
+        }
+        env.extend(device.device.env.clone());
+        let mut config = toml::to_string(&config)?;
+        if !env.is_empty() {
+            config = add_agent_env(&config, &env)?;
+        }
+        let config = artifacts::stamp(self.config, self.file_manager, &config);
         let file = self
             .file_manager
             .get_folder(&device.device.device_id)
             .await?
             .join("config.toml");
         self.file_manager
-            .print_verbose(format!(
-                "Writing config for {} to {:?}\n{}",
-                device.device.device_id, file, config
-            ))
+            .log(
+                LogLevel::Debug,
+                "configs",
+                Some(device.device.device_id.as_str()),
+                format!(
+                    "Writing config for {} to {:?}\n{}",
+                    device.device.device_id, file, config
+                ),
+            )
             .await?;
 
-        fs::write(file, config).await?;
+        self.file_manager
+            .write_generated(&file, &config, Some(device.device.device_id.as_str()))
+            .await?;
         Ok(())
     }
 }
@@ -1077,18 +5069,38 @@ use zip::write::FileOptions;
 struct FileManager {
     base_path: PathBuf,
     log_file: Arc<Mutex<fs::File>>,
+    /// logs/<device_id>.txt of each device logged about so far.
+    device_logs: Mutex<HashMap<String, Arc<Mutex<fs::File>>>>,
     verbose: bool,
+    log_filter: LogFilter,
+    overwrite: bool,
+    progress: ProgressFormat,
+    clock: Box<dyn Clock>,
+    /// Outcome of each tracked operation, by device id then phase.
+    outcomes: std::sync::Mutex<BTreeMap<String, BTreeMap<String, run::PhaseOutcome>>>,
+    /// Each tracked operation, for --otlp-file.
+    spans: std::sync::Mutex<Vec<telemetry::Span>>,
 }
 
 impl FileManager {
-    async fn new<P>(base_path: P, verbose: bool) -> Result<Self>
+    async fn new<P>(
+        base_path: P,
+        verbose: bool,
+        log_filter: LogFilter,
+        overwrite: bool,
+        progress: ProgressFormat,
+        clock: Box<dyn Clock>,
+    ) -> Result<Self>
     where
         P: Into<PathBuf>,
     {
         let base_path: PathBuf = base_path.into();
         fs::create_dir_all(&base_path).await?;
 
-        let time = Local::now().format("%Y-%m-%d_%H-%M-%S");
+        let time = clock
+            .now()
+            .with_timezone(&Local)
+            .format("%Y-%m-%d_%H-%M-%S");
         let log_file = base_path.join(format!("log_{}.txt", time));
         let message = format!("Writing logs to {:?}", log_file);
         let log_file = fs::File::create(log_file).await?;
@@ -1097,7 +5109,14 @@ impl FileManager {
         let this = Self {
             base_path,
             log_file,
+            device_logs: Default::default(),
             verbose,
+            log_filter,
+            overwrite,
+            progress,
+            clock,
+            outcomes: Default::default(),
+            spans: Default::default(),
         };
         this.print(message).await?;
         Ok(this)
@@ -1107,6 +5126,20 @@ impl FileManager {
         &self.base_path
     }
 
+    /// The run's time, which is fixed with --fixed-time.
+    pub fn now(&self) -> chrono::DateTime<chrono::Utc> {
+        self.clock.now()
+    }
+
+    /// The time given with --fixed-time, if any.
+    pub fn fixed_time(&self) -> Option<chrono::DateTime<chrono::Utc>> {
+        if self.clock.is_real() {
+            None
+        } else {
+            Some(self.clock.now())
+        }
+    }
+
     pub async fn get_folder(&self, path: &str) -> Result<PathBuf> {
         let mut folder = self.base_path.clone();
         folder.push(path);
@@ -1116,6 +5149,64 @@ impl FileManager {
         Ok(folder)
     }
 
+    /// Writes a generated file. If a different version already exists, for example one edited by
+    /// hand, the diff is shown and the file is only replaced with --overwrite. Otherwise the new
+    /// version is written next to it as `<file>.new`.
+    pub async fn write_generated(
+        &self,
+        file: &Path,
+        contents: &str,
+        device: Option<&str>,
+    ) -> Result<()> {
+        let existing = match fs::read_to_string(file).await {
+            Ok(existing) => existing,
+            Err(_) => return Ok(fs::write(file, contents).await?),
+        };
+
+        let mut new_file = file.as_os_str().to_owned();
+        new_file.push(".new");
+        let new_file = PathBuf::from(new_file);
+
+        let diff = diff::unified_diff(
+            artifacts::unstamped(&existing),
+            artifacts::unstamped(contents),
+            &file.to_string_lossy(),
+            &new_file.to_string_lossy(),
+        );
+        if diff.is_empty() {
+            // Only the stamp changed, if anything
+            if existing != contents {
+                fs::write(file, contents).await?;
+            }
+            return Ok(());
+        }
+
+        if self.overwrite {
+            self.log(
+                LogLevel::Info,
+                "files",
+                device,
+                format!("Overwriting {:?}:\n{}", file, diff),
+            )
+            .await?;
+            fs::write(file, contents).await?;
+        } else {
+            self.log(
+                LogLevel::Warn,
+                "files",
+                device,
+                format!(
+                    "{:?} differs from the generated version, keeping it and writing {:?}. Use --overwrite to replace it.\n{}",
+                    file, new_file, diff
+                ),
+            )
+            .await?;
+            fs::write(new_file, contents).await?;
+        }
+
+        Ok(())
+    }
+
     pub fn path_to_zip<P>(path: P) -> PathBuf
     where
         P: AsRef<Path>,
@@ -1135,8 +5226,13 @@ impl FileManager {
         P: AsRef<Path> + Clone,
     {
         let dest = Self::path_to_zip(&dir);
-        self.print_verbose(format!("Zipping {:?} into {:?}", dir.as_ref(), dest))
-            .await?;
+        self.log(
+            LogLevel::Debug,
+            "files",
+            None,
+            format!("Zipping {:?} into {:?}", dir.as_ref(), dest),
+        )
+        .await?;
 
         // Note zipping is done synchronously since the zip lib is sync
         let file = std::fs::File::create(&dest)?;
@@ -1191,35 +5287,142 @@ impl FileManager {
         Result::Ok(())
     }
 
-    async fn print<S>(&self, text: S) -> Result<()>
+    async fn print<S>(&self, text: S) -> Result<()>
+    where
+        S: AsRef<str>,
+    {
+        self.log(LogLevel::Info, "main", None, text).await
+    }
+
+    async fn print_verbose<S>(&self, text: S) -> Result<()>
+    where
+        S: AsRef<str>,
+    {
+        self.log(LogLevel::Debug, "main", None, text).await
+    }
+
+    /// Prints the text to the console if it meets the console level (info, or debug when verbose)
+    /// and writes it to the log file with its level, target and device if it passes the log filter.
+    async fn log<S>(
+        &self,
+        level: LogLevel,
+        target: &str,
+        device: Option<&str>,
+        text: S,
+    ) -> Result<()>
     where
         S: AsRef<str>,
     {
-        println!("{}", text.as_ref());
+        let console_level = if self.verbose {
+            LogLevel::Debug
+        } else {
+            LogLevel::Info
+        };
+        if level <= console_level {
+            if self.progress == ProgressFormat::Ndjson {
+                eprintln!("{}", text.as_ref());
+            } else {
+                println!("{}", text.as_ref());
+            }
+        }
+
+        if self.log_filter.enabled(target, level) {
+            let line = format!(
+                "{} {:<5} {}{} {}\n",
+                self.now().with_timezone(&Local).format("%Y-%m-%d %H:%M:%S"),
+                level,
+                target,
+                device.map(|d| format!(" [{}]", d)).unwrap_or_default(),
+                text.as_ref()
+            );
+            self.write_log(&line).await?;
+            if let Some(device) = device {
+                self.write_device_log(device, &line).await?;
+            }
+        }
 
-        self.write_log(&format!(
-            "{} {}\n",
-            Local::now().format("%Y-%m-%d %H:%M:%S"),
-            text.as_ref()
-        ))
-        .await?;
         Ok(())
     }
 
-    async fn print_verbose<S>(&self, text: S) -> Result<()>
+    /// Runs one operation of a phase, reporting its start and end with --progress-format ndjson
+    /// and keeping its span for --otlp-file.
+    async fn track<T, F>(&self, phase: &str, device_id: Option<&str>, operation: F) -> Result<T>
     where
-        S: AsRef<str>,
+        F: std::future::Future<Output = Result<T>>,
     {
-        if self.verbose {
-            println!("{}", text.as_ref());
+        self.report(ProgressEvent::Start, phase, device_id, None)?;
+        let start = std::time::SystemTime::now();
+        let (result, hub_calls, hub_time) = telemetry::measure(operation).await;
+        let error = result.as_ref().err().map(|e| format!("{:#}", e));
+        if let Ok(mut spans) = self.spans.lock() {
+            spans.push(telemetry::Span {
+                phase: phase.to_owned(),
+                device_id: device_id.map(str::to_owned),
+                start,
+                end: std::time::SystemTime::now(),
+                hub_calls,
+                hub_time,
+                error: error.clone(),
+            });
+        }
+        if let (Some(device_id), Ok(mut outcomes)) = (device_id, self.outcomes.lock()) {
+            outcomes.entry(device_id.to_owned()).or_default().insert(
+                phase.to_owned(),
+                run::PhaseOutcome {
+                    succeeded: error.is_none(),
+                    error: error.clone(),
+                },
+            );
+        }
+        match error {
+            None => self.report(ProgressEvent::Success, phase, device_id, None)?,
+            Some(error) => self.report(ProgressEvent::Failure, phase, device_id, Some(error))?,
+        }
+
+        result
+    }
+
+    /// Outcome of each tracked operation so far, by device id then phase.
+    fn outcomes(&self) -> BTreeMap<String, BTreeMap<String, run::PhaseOutcome>> {
+        self.outcomes.lock().map(|o| o.clone()).unwrap_or_default()
+    }
+
+    fn spans(&self) -> Vec<telemetry::Span> {
+        self.spans.lock().map(|s| s.clone()).unwrap_or_default()
+    }
+
+    /// Devices each phase succeeded for so far, by phase.
+    fn completed(&self) -> BTreeMap<String, Vec<String>> {
+        let mut completed: BTreeMap<String, Vec<String>> = BTreeMap::new();
+        for (device_id, phases) in self.outcomes() {
+            for (phase, outcome) in phases {
+                if outcome.succeeded {
+                    completed.entry(phase).or_default().push(device_id.clone());
+                }
+            }
+        }
+
+        completed
+    }
+
+    fn report(
+        &self,
+        event: ProgressEvent,
+        phase: &str,
+        device_id: Option<&str>,
+        error: Option<String>,
+    ) -> Result<()> {
+        if self.progress == ProgressFormat::Ndjson {
+            let progress = Progress {
+                time: self.now().to_rfc3339(),
+                event,
+                phase,
+                device_id,
+                error,
+            };
+            println!("{}", serde_json::to_string(&progress)?);
         }
 
-        self.write_log(&format!(
-            "{} {}\n",
-            Local::now().format("%Y-%m-%d %H:%M:%S"),
-            text.as_ref()
-        ))
-        .await?;
         Ok(())
     }
 
@@ -1229,6 +5432,35 @@ impl FileManager {
         log_file.write_all(text.as_bytes()).await?;
         Ok(())
     }
+
+    /// Appends to the device's own log, kept in logs/ rather than the device folder so it is not
+    /// zipped with the device's bundle.
+    async fn write_device_log(&self, device_id: &str, text: &str) -> Result<()> {
+        let log_file = {
+            let mut device_logs = self.device_logs.lock().await;
+            match device_logs.get(device_id) {
+                Some(log_file) => log_file.clone(),
+                None => {
+                    let path = self
+                        .get_folder("logs")
+                        .await?
+                        .join(format!("{}.txt", device_id));
+                    let log_file = fs::OpenOptions::new()
+                        .create(true)
+                        .append(true)
+                        .open(path)
+                        .await?;
+                    let log_file = Arc::new(Mutex::new(log_file));
+                    device_logs.insert(device_id.to_owned(), log_file.clone());
+                    log_file
+                }
+            }
+        };
+
+        let mut log_file = log_file.lock().await;
+        log_file.write_all(text.as_bytes()).await?;
+        Ok(())
+    }
 }
 
 struct ScriptManager<'a> {
@@ -1246,12 +5478,21 @@ impl<'a> ScriptManager<'a> {
 
     pub async fn add_install_scripts(&self, devices: &[CreatedDevice<'_>]) -> Result<()> {
         self.file_manager
-            .print_verbose("Adding install scripts for all devices")
+            .log(
+                LogLevel::Debug,
+                "scripts",
+                None,
+                "Adding install scripts for all devices",
+            )
             .await?;
 
         for device in devices {
-            self.add_install_scripts_internal(&device).await?;
-            self.copy_device_readme(device).await?;
+            self.file_manager
+                .track("scripts", Some(&device.device.device_id), async {
+                    self.add_install_scripts_internal(&device).await?;
+                    self.copy_device_readme(device).await
+                })
+                .await?;
         }
 
         Ok(())
@@ -1259,9 +5500,13 @@ impl<'a> ScriptManager<'a> {
 
     async fn add_install_scripts_internal(&self, device: &CreatedDevice<'_>) -> Result<()> {
         let hostname = device.device.hostname.as_deref();
-        let parent_hostname = device.parent.and_then(|p| p.hostname.as_deref());
+        let external_parent = device.device.external_parent.as_ref();
+        let parent_hostname = device
+            .parent
+            .and_then(|p| p.hostname.as_deref())
+            .or_else(|| external_parent.and_then(|p| p.hostname.as_deref()));
         self.file_manager
-            .print_verbose(format!(
+            .log(LogLevel::Debug, "scripts", Some(device.device.device_id.as_str()), format!(
                 "Adding install script for {} with hostname {:?} and parent hostname {:?}. (If values are none, install script will prompt user for values).",
                 device.device.device_id,
                 hostname,
@@ -1270,17 +5515,34 @@ impl<'a> ScriptManager<'a> {
             .await?;
 
         let mut script: Vec<&str> = Vec::new();
+        let device_id = device.device.device_id.as_str();
+        let names = &self.config.cert_names;
         let headers = format!(
             include_str!(r#"scripts/headers.sh"#),
-            device_id = device.device.device_id
+            device_id = device_id,
+            root_ca_cert = names.root_ca_cert,
+            device_ca_chain = names.device_ca_chain(device_id),
+            device_ca_key = names.device_ca_key(device_id),
+            hub_auth_cert = names.hub_auth_cert(device_id),
+            hub_auth_key = names.hub_auth_key(device_id),
         );
         script.push(&headers);
 
+        // Stop before installing files that don't match their signatures
+        let verify_signatures = match &self.config.signing {
+            Some(signing) if signing.verify_on_install => Some(signing::verify_script(signing)),
+            _ => None,
+        };
+        if let Some(verify_signatures) = &verify_signatures {
+            script.push(verify_signatures);
+        }
+        script.push(include_str!(r#"scripts/copy_config.sh"#));
+
         // Add user prompts if no hostname provided
         if hostname.is_none() {
             script.push(include_str!(r#"scripts/set_hostname.sh"#));
         }
-        if device.parent.is_some() && parent_hostname.is_none() {
+        if (device.parent.is_some() || external_parent.is_some()) && parent_hostname.is_none() {
             script.push(include_str!(r#"scripts/set_parent_hostname.sh"#));
         }
 
@@ -1290,6 +5552,24 @@ impl<'a> ScriptManager<'a> {
             script.push(include_str!(r#"scripts/install_hub_auth_certs.sh"#));
         }
 
+        // Check the ports of the layer's upstream settings are open to the parent
+        let check_parent_ports = match self.config.configuration.layers.get(device.layer) {
+            Some(layer) if device.parent.is_some() => parent_ports(layer).map(|ports| {
+                format!(
+                    include_str!(r#"scripts/check_parent_ports.sh"#),
+                    ports = ports
+                        .iter()
+                        .map(u16::to_string)
+                        .collect::<Vec<_>>()
+                        .join(" ")
+                )
+            }),
+            _ => None,
+        };
+        if let Some(check_parent_ports) = &check_parent_ports {
+            script.push(check_parent_ports);
+        }
+
         // Run iotedge config apply
         script.push(include_str!(r#"scripts/apply.sh"#));
 
@@ -1299,7 +5579,37 @@ impl<'a> ScriptManager<'a> {
             .get_folder(&device.device.device_id)
             .await?
             .join("install.sh");
-        fs::write(file, script).await?;
+        self.file_manager
+            .write_generated(
+                &file,
+                &artifacts::stamp(self.config, self.file_manager, &script),
+                Some(device_id),
+            )
+            .await?;
+
+        if device.device.os == config::DeviceOs::Eflow {
+            let script = format!(
+                include_str!(r#"scripts/install_eflow.ps1"#),
+                device_id = device_id,
+                prompt_hostname = powershell_bool(hostname.is_none()),
+                prompt_parent_hostname = powershell_bool(
+                    (device.parent.is_some() || external_parent.is_some())
+                        && parent_hostname.is_none()
+                ),
+            );
+            let file = self
+                .file_manager
+                .get_folder(device_id)
+                .await?
+                .join("install.ps1");
+            self.file_manager
+                .write_generated(
+                    &file,
+                    &artifacts::stamp(self.config, self.file_manager, &script),
+                    Some(device_id),
+                )
+                .await?;
+        }
 
         Ok(())
     }
@@ -1316,8 +5626,23 @@ impl<'a> ScriptManager<'a> {
     }
 }
 
-async fn visualize_terminal(root: &config::DeviceConfig, file_manager: &FileManager) -> Result<()> {
-    let result = make_tree(root, "")?;
+fn powershell_bool(value: bool) -> &'static str {
+    if value {
+        "$true"
+    } else {
+        "$false"
+    }
+}
+
+async fn visualize_terminal(
+    roots: &[config::DeviceConfig],
+    file_manager: &FileManager,
+) -> Result<()> {
+    let result = roots
+        .iter()
+        .map(|root| make_tree(root, ""))
+        .collect::<Result<Vec<_>>>()?
+        .join("\n");
     file_manager.print(&result).await?;
     fs::write(file_manager.base_path().join("visualization.txt"), result).await?;
 
@@ -1342,6 +5667,136 @@ fn make_tree(device: &config::DeviceConfig, prefix: &str) -> Result<String> {
     Ok(result.concat())
 }
 
+/// Reads a line from the terminal without echoing it.
+fn prompt_secret(prompt: &str) -> Result<String> {
+    #[cfg(any(unix))]
+    {
+        eprint!("{}", prompt);
+        let _ = std::process::Command::new("stty").arg("-echo").status();
+        let mut secret = String::new();
+        let result = std::io::stdin().read_line(&mut secret);
+        let _ = std::process::Command::new("stty").arg("echo").status();
+        eprintln!();
+        result?;
+
+        Ok(secret.trim_end_matches(&['\r', '\n'][..]).to_owned())
+    }
+
+    #[cfg(any(windows))]
+    {
+        let output = std::process::Command::new("powershell.exe")
+            .arg("-NoProfile")
+            .arg("-Command")
+            .arg(format!(
+                "$secret = Read-Host -AsSecureString -Prompt {}; [Runtime.InteropServices.Marshal]::PtrToStringAuto([Runtime.InteropServices.Marshal]::SecureStringToBSTR($secret))",
+                quote_arg(prompt.trim_end_matches(&[':', ' '][..]))
+            ))
+            .stdin(Stdio::inherit())
+            .stderr(Stdio::inherit())
+            .output()?;
+
+        Ok(String::from_utf8_lossy(&output.stdout)
+            .trim_end_matches(&['\r', '\n'][..])
+            .to_owned())
+    }
+}
+
+/// Writes what was done to the state file if the result is from a run that failed or was
+/// cancelled, for --resume.
+async fn record_unfinished<T>(
+    result: &Result<T>,
+    cancel: &CancellationToken,
+    state_path: &Path,
+    config: &config::Config,
+    file_manager: &FileManager,
+) -> Result<()> {
+    if result.is_ok() {
+        return Ok(());
+    }
+
+    let mut state = state::for_run(state_path, config, file_manager.now()).await?;
+    state.cancelled = cancel.is_cancelled();
+    state.completed = file_manager.completed();
+    state::write(state_path, &state).await?;
+    file_manager
+        .log(
+            LogLevel::Warn,
+            "main",
+            None,
+            format!(
+                "{}. The devices each phase was done for are recorded in {:?}, rerun with --resume to carry on from there.",
+                if state.cancelled { "Cancelled" } else { "Failed" },
+                state_path
+            ),
+        )
+        .await
+}
+
+/// `openssl version`, as in `OpenSSL 3.5.6 7 Apr 2026 (Library: OpenSSL 3.5.6 7 Apr 2026)`.
+async fn openssl_version(openssl_path: Option<&Path>) -> Result<String> {
+    let command = openssl_path
+        .map_or_else(|| Command::new("openssl"), Command::new)
+        .arg("version")
+        .bounded_output()
+        .await
+        .context("Error running openssl")?;
+
+    Ok(String::from_utf8_lossy(&command.stdout).trim().to_owned())
+}
+
+/// Whether the openssl of `openssl version` takes -not_before and -not_after, which came in
+/// OpenSSL 3.4.
+fn sets_validity_dates(version: &str) -> bool {
+    let mut words = version.split_whitespace();
+    if words.next() != Some("OpenSSL") {
+        return false;
+    }
+    let mut numbers = words
+        .next()
+        .unwrap_or_default()
+        .split('.')
+        .map(|n| n.parse::<u32>().unwrap_or(0));
+    let major = numbers.next().unwrap_or(0);
+    let minor = numbers.next().unwrap_or(0);
+
+    (major, minor) >= (3, 4)
+}
+
+/// Hex SHA-256 of the data.
+fn sha256_hex(data: &[u8]) -> String {
+    openssl::sha::sha256(data)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// HMAC-SHA256 (RFC 2104) of the data.
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Result<Vec<u8>> {
+    let key = openssl::pkey::PKey::hmac(key)?;
+    let mut signer = openssl::sign::Signer::new(openssl::hash::MessageDigest::sha256(), &key)?;
+    signer.update(data)?;
+
+    Ok(signer.sign_to_vec()?)
+}
+
+/// Quotes an argument so it survives being joined into the shell command built by `run_command`.
+fn quote_arg(arg: &str) -> String {
+    #[cfg(any(unix))]
+    {
+        format!("'{}'", arg.replace('\'', r#"'\''"#))
+    }
+
+    #[cfg(any(windows))]
+    {
+        format!("'{}'", arg.replace('\'', "''"))
+    }
+}
+
+/// Quotes config-supplied arguments so they reach az as given.
+fn quote_args(args: &[String]) -> Vec<String> {
+    args.iter().map(|a| quote_arg(a)).collect()
+}
+
 fn run_command(args: &[&str]) -> Command {
     #[cfg(any(unix))]
     {
@@ -1366,22 +5821,46 @@ mod tests {
 
     #[tokio::test]
     async fn test_cert_creation() {
+        for backend in &[CertBackend::OpensslCli, CertBackend::Native] {
+            cert_creation(*backend).await;
+        }
+    }
+
+    async fn cert_creation(backend: CertBackend) {
+        println!("Making certs with {:?}", backend);
         let config = config::Config::read_config("src/test_files/cert_test.yaml")
             .await
             .unwrap();
         let dir = tempdir().unwrap();
-        let file_manager = FileManager::new(dir.path(), true)
-            .await
-            .expect("Could not make file manager");
-
-        let cert_manager = CertManager::new(&config, &file_manager, None);
+        let file_manager = FileManager::new(
+            dir.path(),
+            true,
+            LogFilter::default(),
+            true,
+            ProgressFormat::Text,
+            Box::new(SystemClock),
+        )
+        .await
+        .expect("Could not make file manager");
+
+        let cert_manager = CertManager::new(
+            &config,
+            &file_manager,
+            None,
+            CertProfile::Production,
+            backend,
+            Box::new(OpensslRng { openssl_path: None }),
+            SecretStore::None,
+            None,
+            CancellationToken::new(),
+        );
 
         cert_manager
             .make_all_device_ca_certs()
             .await
             .expect("Could not make all certs");
 
-        let make_auth_certs = FlatenedDevice::flatten_devices(&config.root_device)
+        let make_auth_certs = FlatenedDevice::flatten_devices(&config.root_devices)
             .into_iter()
             .map(|device| cert_manager.make_hub_auth_cert(&device.device.device_id));
         futures::future::join_all(make_auth_certs)
@@ -1390,7 +5869,7 @@ mod tests {
             .collect::<Result<Vec<PathBuf>>>()
             .expect("Could not make all hub auth certs");
 
-        let validate_certs = FlatenedDevice::flatten_devices(&config.root_device)
+        let validate_certs = FlatenedDevice::flatten_devices(&config.root_devices)
             .into_iter()
             .map(|device| {
                 validate_created_certs(&file_manager, &cert_manager, &device.device.device_id)
@@ -1398,6 +5877,133 @@ mod tests {
         futures::future::join_all(validate_certs).await;
     }
 
+    #[test]
+    fn test_sets_validity_dates() {
+        assert!(sets_validity_dates(
+            "OpenSSL 3.5.6 7 Apr 2026 (Library: OpenSSL 3.5.6 7 Apr 2026)"
+        ));
+        assert!(sets_validity_dates("OpenSSL 3.4.0 22 Oct 2024"));
+        assert!(!sets_validity_dates("OpenSSL 3.0.2 15 Mar 2022"));
+        assert!(!sets_validity_dates("OpenSSL 1.1.1w  11 Sep 2023"));
+        assert!(!sets_validity_dates("LibreSSL 3.3.6"));
+    }
+
+    #[tokio::test]
+    async fn test_golden_certs() {
+        // --fixed-time needs an openssl that takes the validity dates
+        let version = openssl_version(None).await.unwrap();
+        if !sets_validity_dates(&version) {
+            println!("Skipping golden certs with {}", version);
+            return;
+        }
+
+        let mut config = config::Config::read_config("src/test_files/cert_test.yaml")
+            .await
+            .unwrap();
+        config.root_devices[0].children.clear();
+        let dir = tempdir().unwrap();
+        let file_manager = FileManager::new(
+            dir.path(),
+            true,
+            LogFilter::default(),
+            true,
+            ProgressFormat::Text,
+            Box::new(FixedClock("2021-06-01T12:00:00Z".parse().unwrap())),
+        )
+        .await
+        .unwrap();
+        let cert_manager = CertManager::new(
+            &config,
+            &file_manager,
+            None,
+            CertProfile::Test,
+            CertBackend::OpensslCli,
+            Box::new(SeededRng::new(1)),
+            SecretStore::None,
+            None,
+            CancellationToken::new(),
+        );
+
+        cert_manager.make_all_device_ca_certs().await.unwrap();
+        let hub_auth_cert = cert_manager.make_hub_auth_cert("A").await.unwrap();
+
+        // The keys are random, everything else about the certs comes from the clock and the seed
+        let device_folder = dir.path().join("A");
+        let mut certs = String::new();
+        for cert in &[
+            device_folder.join("iotedge_config_cli_root.pem"),
+            device_folder.join("A.cert.pem"),
+            hub_auth_cert,
+        ] {
+            let output = cert_manager
+                .openssl_command()
+                .args(&["x509", "-noout", "-serial", "-subject", "-issuer", "-dates"])
+                .arg("-in")
+                .arg(cert)
+                .output()
+                .await
+                .unwrap();
+            certs.push_str(&String::from_utf8_lossy(&output.stdout));
+        }
+        assert_eq!(
+            certs,
+            fs::read_to_string("src/test_files/golden_certs.txt")
+                .await
+                .unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_dry_run_certs() {
+        let config = config::Config::read_config("src/test_files/cert_test.yaml")
+            .await
+            .unwrap();
+        let dir = tempdir().unwrap();
+        let file_manager = FileManager::new(
+            dir.path(),
+            true,
+            LogFilter::default(),
+            true,
+            ProgressFormat::Text,
+            Box::new(SystemClock),
+        )
+        .await
+        .unwrap();
+        let plan = Plan::default();
+        let cert_manager = CertManager::new(
+            &config,
+            &file_manager,
+            None,
+            CertProfile::Production,
+            CertBackend::OpensslCli,
+            Box::new(SeededRng::new(1)),
+            SecretStore::None,
+            Some(&plan),
+            CancellationToken::new(),
+        );
+
+        cert_manager.make_all_device_ca_certs().await.unwrap();
+        let auth_cert = cert_manager.make_hub_auth_cert("A").await.unwrap();
+        assert_eq!(
+            cert_manager.get_thumbprint(&auth_cert).await.unwrap(),
+            "<thumbprint>"
+        );
+
+        assert!(!cert_manager.root_key_path().exists());
+        assert!(!auth_cert.exists());
+        let steps = plan.steps().await;
+        assert!(steps[0].operation.starts_with("openssl req -newkey"));
+        assert!(steps[0].operation.contains("-set_serial 0x"));
+        let device_steps: Vec<&str> = steps
+            .iter()
+            .filter(|s| s.device_id.as_deref() == Some("AA"))
+            .map(|s| s.operation.as_str())
+            .collect();
+        assert_eq!(device_steps.len(), 2);
+        assert!(device_steps[0].contains("-subj /CN=AA.deviceca"));
+        assert!(device_steps[1].starts_with("openssl x509 -req"));
+    }
+
     async fn validate_created_certs(
         file_manager: &FileManager,
         cert_manager: &CertManager<'_>,
@@ -1428,6 +6034,47 @@ mod tests {
         assert!(verify.success());
     }
 
+    #[tokio::test]
+    async fn test_device_key() {
+        let config = config::Config::read_config("src/test_files/cert_test.yaml")
+            .await
+            .unwrap();
+        let dir = tempdir().unwrap();
+        let file_manager = FileManager::new(
+            dir.path(),
+            true,
+            LogFilter::default(),
+            true,
+            ProgressFormat::Text,
+            Box::new(SystemClock),
+        )
+        .await
+        .unwrap();
+        let mut create_response = hub_responses::CreateResponse::default();
+        create_response.authentication.symmetric_key.primary_key = Some("a2V5".to_owned());
+        let device = CreatedDevice {
+            device: &config.root_devices[0],
+            parent: None,
+            layer: 0,
+            create_response,
+            dps_id_scope: None,
+        };
+
+        let inline = DeviceConfigManager::new(&config, &file_manager, SecretStore::None)
+            .device_key(&device, "missing")
+            .unwrap();
+        assert!(matches!(inline, aziot_config::SymmetricKey::Inline { value } if value == b"key"));
+
+        // With a store, config.toml only refers to the key
+        let stored = DeviceConfigManager::new(&config, &file_manager, SecretStore::Keychain)
+            .device_key(&device, "missing")
+            .unwrap();
+        assert!(matches!(
+            stored,
+            aziot_config::SymmetricKey::Preloaded { uri } if uri.as_str() == secrets::DEVICE_KEY_URI
+        ));
+    }
+
     #[tokio::test]
     async fn test_configs() {
         let configs = WalkDir::new("templates").into_iter().filter_map(|path| {
@@ -1460,6 +6107,306 @@ mod tests {
             ));
     }
 
+    #[test]
+    fn test_add_agent_env() {
+        let mut env = BTreeMap::new();
+        env.insert("https_proxy".to_owned(), "http://proxy:3128".to_owned());
+        env.insert("UpstreamProtocol".to_owned(), "AmqpWs".to_owned());
+
+        let config = add_agent_env(
+            "hostname = \"a\"\n\n[agent]\nname = \"edgeAgent\"\n\n[agent.env]\nUpstreamProtocol = \"Mqtt\"\nRuntimeLogLevel = \"debug\"\n",
+            &env,
+        )
+        .unwrap();
+        let config: toml::Value = toml::from_str(&config).unwrap();
+        let agent_env = &config["agent"]["env"];
+        assert_eq!(agent_env["https_proxy"].as_str(), Some("http://proxy:3128"));
+        assert_eq!(agent_env["UpstreamProtocol"].as_str(), Some("AmqpWs"));
+        assert_eq!(agent_env["RuntimeLogLevel"].as_str(), Some("debug"));
+        assert!(add_agent_env("hostname = \"a\"\n", &env).is_err());
+    }
+
+    #[test]
+    fn test_hmac_sha256() {
+        // RFC 4231 test cases 2 and 6
+        let hex =
+            |bytes: Vec<u8>| -> String { bytes.iter().map(|b| format!("{:02x}", b)).collect() };
+        assert_eq!(
+            hex(hmac_sha256(b"Jefe", b"what do ya want for nothing?").unwrap()),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+        assert_eq!(
+            hex(hmac_sha256(
+                &[0xaa; 131],
+                b"Test Using Larger Than Block-Size Key - Hash Key First"
+            )
+            .unwrap()),
+            "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54"
+        );
+        assert_eq!(
+            sha256_hex(b"abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    #[test]
+    fn test_is_conflict() {
+        assert!(is_conflict(
+            "Failed to add AA as child of parent A:\n\n(PreconditionFailed) Precondition failed: device etag mismatch\n"
+        ));
+        assert!(!is_conflict(
+            "Failed to add AA as child of parent A:\n\nDevice A is not an edge device\n"
+        ));
+        assert!(!is_conflict("Device AA has a ConflictingParent setting\n"));
+    }
+
+    #[test]
+    fn test_merge_extensions() {
+        let snippet = "# Make the device CA a path length restricted CA\nbasicConstraints = critical, CA:true, pathlen:0\nsubjectAltName = @alt_names\n\n[ alt_names ]\nDNS.1 = gateway.local\n";
+        let merged = merge_extensions(include_str!(r#"scripts/v3_ca_extensions.cnf"#), snippet);
+        let lines: Vec<&str> = merged.lines().collect();
+
+        assert!(lines.contains(&"basicConstraints = critical, CA:true, pathlen:0"));
+        assert!(!lines.contains(&"basicConstraints = critical, CA:true"));
+        assert!(lines.contains(&"keyUsage = critical, digitalSignature, cRLSign, keyCertSign"));
+
+        let alt_names = lines.iter().position(|l| *l == "[ alt_names ]").unwrap();
+        let san = lines
+            .iter()
+            .position(|l| l.starts_with("subjectAltName"))
+            .unwrap();
+        assert!(san < alt_names);
+        assert_eq!(lines[alt_names + 1], "DNS.1 = gateway.local");
+    }
+
+    #[test]
+    fn test_render_twin_template() {
+        let template = r#"{"tags": {"parent": "{parent_id}"}, "properties": {"desired": {"deviceName": "{device_id}"}}}"#;
+
+        let twin = render_twin_template(template, "lower-layer", Some("top-layer")).unwrap();
+        assert_eq!(twin["tags"]["parent"], "top-layer");
+        assert_eq!(twin["properties"]["desired"]["deviceName"], "lower-layer");
+
+        let twin = render_twin_template(template, "top-layer", None).unwrap();
+        assert_eq!(twin["tags"]["parent"], "");
+        assert!(render_twin_template("{", "top-layer", None).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_apply_namespace() {
+        let mut config = config::Config::read_config("src/test_files/cert_test.yaml")
+            .await
+            .unwrap();
+        let ids: Vec<String> = FlatenedDevice::flatten_devices(&config.root_devices)
+            .iter()
+            .map(|d| format!("alice-{}", d.device.device_id))
+            .collect();
+
+        config.apply_namespace("alice").unwrap();
+        let namespaced: Vec<String> = FlatenedDevice::flatten_devices(&config.root_devices)
+            .iter()
+            .map(|d| d.device.device_id.clone())
+            .collect();
+        assert_eq!(namespaced, ids);
+        assert!(config.apply_namespace("a b").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_yaml_error() {
+        let data = std::fs::read_to_string("src/test_files/cert_test.yaml").unwrap();
+        let dir = tempdir().unwrap();
+        let file = dir.path().join("bad.yaml");
+        std::fs::write(
+            &file,
+            data.replacen("device_id: AA\n", "device_id: [AA]\n", 1),
+        )
+        .unwrap();
+
+        let error = format!(
+            "{:?}",
+            config::Config::read_config(&file).await.unwrap_err()
+        );
+        assert!(error.contains("bad.yaml\":15:"), "{}", error);
+        assert!(error.contains("15 |     - device_id: [AA]"), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn test_external_parent() {
+        let data = std::fs::read_to_string("src/test_files/cert_test.yaml").unwrap();
+        let dir = tempdir().unwrap();
+        let file = dir.path().join("external.yaml");
+        let external = |device_id: &str| {
+            format!(
+                "device_id: {}\n{:indent$}external_parent: {{device_id: factory-gw, hostname: 10.0.0.1}}\n",
+                device_id,
+                "",
+                indent = if device_id == "A" { 2 } else { 6 }
+            )
+        };
+
+        std::fs::write(&file, data.replacen("device_id: A\n", &external("A"), 1)).unwrap();
+        let config = config::Config::read_config(&file).await.unwrap();
+        let parent = config.root_devices[0].external_parent.as_ref().unwrap();
+        assert_eq!(parent.device_id, "factory-gw");
+        assert_eq!(parent.hostname.as_deref(), Some("10.0.0.1"));
+
+        std::fs::write(&file, data.replacen("device_id: AA\n", &external("AA"), 1)).unwrap();
+        let error = config::Config::read_config(&file).await.unwrap_err();
+        assert!(error.to_string().starts_with("AA has an external_parent"));
+    }
+
+    #[test]
+    fn test_build_hierarchies() {
+        let devices =
+            |yaml: &str| -> Vec<config::FlatDevice> { serde_yaml::from_str(yaml).unwrap() };
+
+        let root = config::FlatDevice::build_hierarchies(devices(
+            "[{device_id: top}, {device_id: leaf, parent: lower}, {device_id: lower, parent: top}, {device_id: other, parent: top}, {device_id: site2}]",
+        ))
+        .unwrap();
+        let ids: Vec<(&str, Option<&str>)> = FlatenedDevice::flatten_devices(&root)
+            .iter()
+            .map(|d| {
+                (
+                    d.device.device_id.as_str(),
+                    d.parent.map(|p| p.device_id.as_str()),
+                )
+            })
+            .collect();
+        assert_eq!(
+            ids,
+            vec![
+                ("top", None),
+                ("lower", Some("top")),
+                ("leaf", Some("lower")),
+                ("other", Some("top")),
+                ("site2", None)
+            ]
+        );
+        assert_eq!(FlatenedDevice::layer_count(&root), 3);
+        let layer: Vec<&str> = FlatenedDevice::layer(&root, 1)
+            .map(|d| d.device.device_id.as_str())
+            .collect();
+        assert_eq!(layer, vec!["lower", "other"]);
+
+        let error = |yaml: &str| config::FlatDevice::build_hierarchies(devices(yaml)).unwrap_err();
+        assert!(error("[{device_id: top}, {device_id: a, parent: b}]").contains("a (parent b)"));
+        assert!(error("[{device_id: top}, {device_id: top, parent: top}]").contains("used twice"));
+        assert!(
+            error("[{device_id: a, parent: b}, {device_id: b, parent: a}]")
+                .contains("at least one top device")
+        );
+        assert!(
+            error("[{device_id: top}, {device_id: a, parent: b}, {device_id: b, parent: a}]")
+                .contains("cycle of parents, or under one: a, b")
+        );
+    }
+
+    #[test]
+    fn test_edge_enabled() {
+        let edge_enabled = |args: &[&str]| {
+            config::AzArguments {
+                create: args.iter().map(|a| a.to_string()).collect(),
+                ..Default::default()
+            }
+            .edge_enabled()
+        };
+
+        assert!(edge_enabled(&[]));
+        assert!(edge_enabled(&["--edge-enabled", "--status", "disabled"]));
+        assert!(!edge_enabled(&["--edge-enabled", "false"]));
+        assert!(!edge_enabled(&["--ee=false"]));
+    }
+
+    #[test]
+    fn test_command_name() {
+        let name = |args: &[&str]| Arguments::from_iter(args).command_name();
+
+        assert_eq!(name(&["iotedge_config"]), "create");
+        assert_eq!(name(&["iotedge_config", "-d"]), "delete");
+        assert_eq!(
+            name(&["iotedge_config", "certs", "renewal-plan"]),
+            "certs renewal-plan"
+        );
+        assert_eq!(
+            name(&["iotedge_config", "wait-online", "top-layer"]),
+            "wait-online"
+        );
+        assert_eq!(
+            name(&["iotedge_config", "identities-only"]),
+            "identities-only"
+        );
+
+        let phases = |args: &[&str]| Arguments::from_iter(args).run_phases();
+        assert_eq!(phases(&["iotedge_config", "-d"]), Some(RunPhases::Delete));
+        assert_eq!(
+            phases(&["iotedge_config", "delete"]),
+            Some(RunPhases::Delete)
+        );
+        assert_eq!(
+            phases(&["iotedge_config", "certs-only"]),
+            Some(RunPhases::CertsOnly)
+        );
+        assert_eq!(phases(&["iotedge_config", "certs", "list"]), None);
+        assert_eq!(phases(&["iotedge_config", "deploy", "apply"]), None);
+
+        let reads_only = |args: &[&str]| Arguments::from_iter(args).reads_only();
+        assert!(reads_only(&["iotedge_config", "verify"]));
+        assert!(reads_only(&["iotedge_config", "--visualize"]));
+        assert!(!reads_only(&["iotedge_config"]));
+        assert!(!reads_only(&[
+            "iotedge_config",
+            "tags",
+            "set",
+            "site=paris"
+        ]));
+    }
+
+    #[test]
+    fn test_config_format() {
+        let sniff = |s: &str| ConfigFormat::sniff(s.as_bytes());
+
+        assert_eq!(sniff("config_version: \"1.0\"\n"), ConfigFormat::Yaml);
+        assert_eq!(sniff("---\n# comment\niothub:\n"), ConfigFormat::Yaml);
+        assert_eq!(sniff("- device_id: a=b\n"), ConfigFormat::Yaml);
+        assert_eq!(
+            sniff("# comment\nconfig_version = \"1.0\"\n"),
+            ConfigFormat::Toml
+        );
+        assert_eq!(sniff("[iothub]\n"), ConfigFormat::Toml);
+        assert_eq!(sniff(""), ConfigFormat::Yaml);
+    }
+
+    #[test]
+    fn test_key_algorithm_warnings() {
+        let mixed = config::KeyAlgorithms {
+            root_ca: config::KeyAlgorithm::EcdsaP384,
+            device_ca: config::KeyAlgorithm::Rsa2048,
+            hub_auth: config::KeyAlgorithm::Rsa2048,
+        };
+        assert!(mixed.warnings(false).is_empty());
+
+        let weak_root = config::KeyAlgorithms {
+            root_ca: config::KeyAlgorithm::Rsa2048,
+            device_ca: config::KeyAlgorithm::EcdsaP256,
+            hub_auth: config::KeyAlgorithm::EcdsaP256,
+        };
+        assert_eq!(weak_root.warnings(false).len(), 1);
+        assert!(weak_root.warnings(true).is_empty());
+    }
+
+    #[test]
+    fn test_human_duration() {
+        let parse = |s: &str| s.parse::<HumanDuration>().map(|d| d.0);
+
+        assert_eq!(parse("90s").unwrap(), chrono::Duration::seconds(90));
+        assert_eq!(parse("30m").unwrap(), chrono::Duration::minutes(30));
+        assert_eq!(parse("1h").unwrap(), chrono::Duration::hours(1));
+        assert_eq!(parse("7d").unwrap(), chrono::Duration::days(7));
+        assert!(parse("1w").is_err());
+        assert!(parse("h").is_err());
+    }
+
     #[tokio::test]
     async fn test_iotedge_config() {
         let files = &[
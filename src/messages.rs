@@ -0,0 +1,150 @@
+use anyhow::Result;
+
+use crate::commands::CommandRunner;
+use crate::config;
+use crate::log::LogLevel;
+use crate::{quote_arg, FileManager, FlatenedDevice};
+
+/// Sends and reads device-to-cloud messages so message flow can be checked per device.
+pub struct MessageManager<'a> {
+    config: &'a config::Config,
+    file_manager: &'a FileManager,
+    runner: &'a CommandRunner,
+}
+
+impl<'a> MessageManager<'a> {
+    pub fn new(
+        config: &'a config::Config,
+        file_manager: &'a FileManager,
+        runner: &'a CommandRunner,
+    ) -> Self {
+        Self {
+            config,
+            file_manager,
+            runner,
+        }
+    }
+
+    pub async fn send_d2c(&self, device_id: &str, data: &str, count: u32) -> Result<()> {
+        self.check_device(device_id)?;
+        self.file_manager
+            .log(
+                LogLevel::Info,
+                "messages",
+                Some(device_id),
+                format!(
+                    "Sending {} message(s) from {} to hub {}.",
+                    count, device_id, self.config.iothub.iothub_name
+                ),
+            )
+            .await?;
+
+        let data = quote_arg(data);
+        let count = count.to_string();
+        let args = &[
+            "az iot device send-d2c-message",
+            "--device-id",
+            device_id,
+            "--hub-name",
+            &self.config.iothub.iothub_name,
+            "--data",
+            &data,
+            "--msg-count",
+            &count,
+        ];
+
+        let command = self.runner.output(args).await?;
+        if command.status.success() {
+            self.file_manager
+                .log(
+                    LogLevel::Info,
+                    "messages",
+                    Some(device_id),
+                    format!("Sent {} message(s) from {}.", count, device_id),
+                )
+                .await?;
+
+            Ok(())
+        } else {
+            let error = format!(
+                "Failed to send messages from {}:\n{}\n{}",
+                device_id,
+                String::from_utf8_lossy(&command.stdout),
+                String::from_utf8_lossy(&command.stderr)
+            );
+            self.file_manager
+                .log(LogLevel::Debug, "messages", Some(device_id), &error)
+                .await?;
+
+            Err(anyhow::Error::msg(error))
+        }
+    }
+
+    /// Streams events received on the hub's built-in event hub-compatible endpoint to the console.
+    pub async fn monitor_events(
+        &self,
+        device_id: Option<&str>,
+        consumer_group: &str,
+        timeout: chrono::Duration,
+    ) -> Result<()> {
+        if let Some(device_id) = device_id {
+            self.check_device(device_id)?;
+        }
+        self.file_manager
+            .log(
+                LogLevel::Info,
+                "messages",
+                device_id,
+                format!(
+                    "Monitoring events from {} on hub {} for {} seconds.",
+                    device_id.unwrap_or("all devices"),
+                    self.config.iothub.iothub_name,
+                    timeout.num_seconds()
+                ),
+            )
+            .await?;
+
+        let timeout = timeout.num_seconds().to_string();
+        // Quoted so the shell doesn't expand the default $Default
+        let consumer_group = quote_arg(consumer_group);
+        let mut args = vec![
+            "az iot hub monitor-events",
+            "--hub-name",
+            &self.config.iothub.iothub_name,
+            "--consumer-group",
+            &consumer_group,
+            "--timeout",
+            &timeout,
+            "--properties",
+            "all",
+        ];
+        if let Some(device_id) = device_id {
+            args.extend(&["--device-id", device_id]);
+        }
+
+        // Events are printed by az as they arrive, so stdout is not captured
+        let status = self.runner.status(&args).await?;
+        if status.success() {
+            Ok(())
+        } else {
+            Err(anyhow::Error::msg(format!(
+                "Failed to monitor events for hub {}",
+                self.config.iothub.iothub_name
+            )))
+        }
+    }
+
+    fn check_device(&self, device_id: &str) -> Result<()> {
+        if FlatenedDevice::flatten_devices(&self.config.root_devices)
+            .iter()
+            .any(|d| d.device.device_id == device_id)
+        {
+            Ok(())
+        } else {
+            Err(anyhow::Error::msg(format!(
+                r#"device id "{}" is not in the config"#,
+                device_id
+            )))
+        }
+    }
+}
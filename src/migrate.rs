@@ -0,0 +1,431 @@
+use anyhow::Result;
+use futures::StreamExt;
+
+use crate::cancel::CancellationToken;
+use crate::commands::CommandRunner;
+use crate::config;
+use crate::hub_responses;
+use crate::log::LogLevel;
+use crate::throttle::{HubOperation, HubThrottle};
+use crate::{
+    CertManager, CreatedDevice, FileManager, FlatenedDevice, IoTHubDeviceManager,
+    MAX_CONCURRENT_DEVICES,
+};
+
+/// Copies the hierarchy's device identities from one hub to another, keeping their keys or
+/// thumbprints so devices that are already installed only need their hub hostname changed.
+pub struct MigrationManager<'a> {
+    file_manager: &'a FileManager,
+    throttle: &'a HubThrottle,
+    runner: &'a CommandRunner,
+    source: IoTHubDeviceManager<'a>,
+    target: IoTHubDeviceManager<'a>,
+}
+
+impl<'a> MigrationManager<'a> {
+    /// The configs are the same hierarchy, with `iothub` pointing at the source and target hubs.
+    pub fn new(
+        source: &'a config::Config,
+        target: &'a config::Config,
+        file_manager: &'a FileManager,
+        cert_manager: &'a CertManager,
+        throttle: &'a HubThrottle,
+        runner: &'a CommandRunner,
+        cancel: &CancellationToken,
+    ) -> Self {
+        Self {
+            file_manager,
+            throttle,
+            runner,
+            source: IoTHubDeviceManager::new(
+                source,
+                file_manager,
+                cert_manager,
+                throttle,
+                runner,
+                cancel.clone(),
+            ),
+            target: IoTHubDeviceManager::new(
+                target,
+                file_manager,
+                cert_manager,
+                throttle,
+                runner,
+                cancel.clone(),
+            ),
+        }
+    }
+
+    /// Creates the devices and their parent relationships in the target hub. Devices that
+    /// provision through DPS are skipped, since the hub they join is set by their enrollment.
+    pub async fn copy_devices(&self) -> Result<Vec<CreatedDevice<'a>>> {
+        let (devices, dps_devices): (Vec<FlatenedDevice<'a>>, _) =
+            FlatenedDevice::flatten_devices(&self.target.config.root_devices)
+                .into_iter()
+                .partition(|d| d.device.provisioning == config::DeviceProvisioning::Hub);
+        self.file_manager
+            .log(
+                LogLevel::Info,
+                "migrate",
+                None,
+                format!(
+                    "Copying {} devices from hub {} to hub {}",
+                    devices.len(),
+                    self.source.config.iothub.iothub_name,
+                    self.target.config.iothub.iothub_name
+                ),
+            )
+            .await?;
+        for device in dps_devices {
+            self.file_manager
+                .log(
+                    LogLevel::Warn,
+                    "migrate",
+                    Some(device.device.device_id.as_str()),
+                    format!(
+                        "{} provisions through DPS and is not copied. Link its enrollment to hub {} instead.",
+                        device.device.device_id, self.target.config.iothub.iothub_name
+                    ),
+                )
+                .await?;
+        }
+
+        let futures = devices.iter().map(|d| self.copy_device(d));
+        let created_devices = futures::stream::iter(futures)
+            .buffered(MAX_CONCURRENT_DEVICES)
+            .collect::<Vec<_>>()
+            .await
+            .into_iter()
+            .collect::<Result<Vec<CreatedDevice<'a>>>>()?;
+
+        let not_edge = crate::non_edge_parents(&created_devices);
+        if !not_edge.is_empty() {
+            return Err(anyhow::Error::msg(format!(
+                "{} are not IoT Edge devices in hub {}, so they can't be parents. No parents were set.",
+                not_edge.join(", "),
+                self.target.config.iothub.iothub_name
+            )));
+        }
+        let futures = created_devices
+            .iter()
+            .filter_map(|d| d.parent.map(|p| (&p.device_id, &d.device.device_id)))
+            .filter(|(parent, _)| {
+                created_devices
+                    .iter()
+                    .any(|d| &d.device.device_id == *parent)
+            })
+            .map(|(parent, child)| self.target.create_parent_child_relationship(parent, child));
+        futures::stream::iter(futures)
+            .buffer_unordered(MAX_CONCURRENT_DEVICES)
+            .collect::<Vec<_>>()
+            .await
+            .into_iter()
+            .collect::<Result<Vec<()>>>()?;
+
+        Ok(created_devices)
+    }
+
+    /// Deletes the copied devices from the source hub, a layer at a time from the bottom.
+    pub async fn delete_source(&self, devices: &[CreatedDevice<'_>]) -> Result<()> {
+        self.file_manager
+            .log(
+                LogLevel::Info,
+                "migrate",
+                None,
+                format!(
+                    "Deleting {} devices from hub {}",
+                    devices.len(),
+                    self.source.config.iothub.iothub_name
+                ),
+            )
+            .await?;
+
+        let mut failed = 0;
+        let layers = devices.iter().map(|d| d.layer + 1).max().unwrap_or(0);
+        for layer in (0..layers).rev() {
+            let futures = devices
+                .iter()
+                .filter(|d| d.layer == layer)
+                .map(|d| self.source.delete_device_identity(&d.device.device_id));
+            failed += futures::stream::iter(futures)
+                .buffer_unordered(MAX_CONCURRENT_DEVICES)
+                .collect::<Vec<_>>()
+                .await
+                .into_iter()
+                .collect::<Result<Vec<bool>>>()?
+                .into_iter()
+                .filter(|s| !s)
+                .count();
+        }
+        if failed > 0 {
+            let message = format!(
+                "Failed to delete {} devices from hub {}. For more information use the -v flag.",
+                failed, self.source.config.iothub.iothub_name
+            );
+            self.file_manager
+                .log(LogLevel::Warn, "migrate", None, &message)
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    async fn copy_device(&self, device: &FlatenedDevice<'a>) -> Result<CreatedDevice<'a>> {
+        let device_id = device.device.device_id.as_str();
+        let existing = match self.show_source_device(device_id).await? {
+            Some(existing) => existing,
+            None => {
+                self.file_manager
+                    .log(
+                        LogLevel::Warn,
+                        "migrate",
+                        Some(device_id),
+                        format!(
+                            "{} is not in hub {}, so it is created with new credentials.",
+                            device_id, self.source.config.iothub.iothub_name
+                        ),
+                    )
+                    .await?;
+                return self.target.create_device_identity(device).await;
+            }
+        };
+
+        let mut args = vec![
+            "az iot hub device-identity create",
+            "--device-id",
+            device_id,
+            "--hub-name",
+            &self.target.config.iothub.iothub_name,
+            "--edge-enabled",
+        ];
+        let authentication = &existing.authentication;
+        match authentication.type_field.as_str() {
+            "sas" => {
+                if let (Some(primary), Some(secondary)) = (
+                    &authentication.symmetric_key.primary_key,
+                    &authentication.symmetric_key.secondary_key,
+                ) {
+                    args.extend(&["--primary-key", primary.as_str()]);
+                    args.extend(&["--secondary-key", secondary.as_str()]);
+                }
+            }
+            "selfSigned" => {
+                if let (Some(primary), Some(secondary)) = (
+                    &authentication.x509_thumbprint.primary_thumbprint,
+                    &authentication.x509_thumbprint.secondary_thumbprint,
+                ) {
+                    args.extend(&["--auth-method", "x509_thumbprint"]);
+                    args.extend(&["--primary-thumbprint", primary.as_str()]);
+                    args.extend(&["--secondary-thumbprint", secondary.as_str()]);
+                }
+            }
+            "certificateAuthority" => args.extend(&["--auth-method", "x509_ca"]),
+            other => {
+                return Err(anyhow::Error::msg(format!(
+                    "{} uses authentication type {}, which cannot be copied",
+                    device_id, other
+                )))
+            }
+        }
+        let extra = crate::quote_args(&self.target.config.az_arguments.create);
+        args.extend(extra.iter().map(String::as_str));
+
+        self.file_manager
+            .log(
+                LogLevel::Debug,
+                "migrate",
+                Some(device_id),
+                format!(
+                    "Creating {} on hub {} with its existing credentials",
+                    device_id, self.target.config.iothub.iothub_name
+                ),
+            )
+            .await?;
+        self.throttle.wait(HubOperation::Registry).await;
+        let command = self.runner.output(&args).await?;
+        if !command.status.success() {
+            let error = format!(
+                "Failed to create {} on hub {}:\n{}\n{}",
+                device_id,
+                self.target.config.iothub.iothub_name,
+                String::from_utf8_lossy(&command.stdout),
+                String::from_utf8_lossy(&command.stderr)
+            );
+            self.file_manager
+                .log(LogLevel::Debug, "migrate", Some(device_id), &error)
+                .await?;
+
+            return Err(anyhow::Error::msg(error));
+        }
+
+        self.target.deploy(device).await?;
+        if let Some(template) = self.target.config.configuration.twin_template(device.layer) {
+            self.target.apply_twin_template(device, template).await?;
+        }
+        // Tags aren't copied with the identity, and verify, delete and gc look for this one
+        self.target.tag_device(device.device).await?;
+
+        Ok(CreatedDevice {
+            device: device.device,
+            parent: device.parent,
+            layer: device.layer,
+            create_response: serde_json::from_slice(&command.stdout)?,
+            dps_id_scope: None,
+        })
+    }
+
+    async fn show_source_device(
+        &self,
+        device_id: &str,
+    ) -> Result<Option<hub_responses::CreateResponse>> {
+        let args = &[
+            "az iot hub device-identity show",
+            "--device-id",
+            device_id,
+            "--hub-name",
+            &self.source.config.iothub.iothub_name,
+        ];
+        self.throttle.wait(HubOperation::Registry).await;
+        let command = self.runner.output(args).await?;
+        if command.status.success() {
+            Ok(Some(serde_json::from_slice(&command.stdout)?))
+        } else if String::from_utf8_lossy(&command.stderr).contains("DeviceNotFound") {
+            Ok(None)
+        } else {
+            Err(anyhow::Error::msg(format!(
+                "Failed to read {} from hub {}:\n{}",
+                device_id,
+                self.source.config.iothub.iothub_name,
+                String::from_utf8_lossy(&command.stderr)
+            )))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::{OpensslRng, SystemClock};
+    use crate::commands::{AzLogin, RunMode};
+    use crate::gc::CREATION_TAG;
+    use crate::log::{LogFilter, ProgressFormat};
+    use crate::secrets::SecretStore;
+    use crate::{CertBackend, CertProfile};
+    use tempfile::tempdir;
+
+    #[tokio::test]
+    async fn test_copy_devices_tags() {
+        let mut source = config::Config::read_config("src/test_files/cert_test.yaml")
+            .await
+            .unwrap();
+        source.root_devices[0].children.clear();
+        source.iothub.iothub_name = "source".to_owned();
+        let mut target = source.clone();
+        target.iothub.iothub_name = "target".to_owned();
+
+        let dir = tempdir().unwrap();
+        let identity = serde_json::json!({
+            "authentication": {
+                "symmetricKey": { "primaryKey": "cHJpbWFyeQ==", "secondaryKey": "c2Vjb25kYXJ5" },
+                "type": "sas",
+                "x509Thumbprint": {}
+            },
+            "capabilities": { "iotEdge": true },
+            "cloudToDeviceMessageCount": 0,
+            "connectionState": "Disconnected",
+            "connectionStateUpdatedTime": "",
+            "deviceId": "A",
+            "deviceScope": "",
+            "etag": "",
+            "generationId": "",
+            "lastActivityTime": "",
+            "status": "enabled",
+            "statusReason": null,
+            "statusUpdatedTime": ""
+        })
+        .to_string();
+        let recording = serde_json::json!([
+            {
+                "command": ["az iot hub device-identity show", "--device-id", "A", "--hub-name", "source"],
+                "status": 0,
+                "stdout": identity,
+                "stderr": ""
+            },
+            {
+                "command": [
+                    "az iot hub device-identity create", "--device-id", "A", "--hub-name", "target",
+                    "--edge-enabled", "--primary-key", "<redacted>", "--secondary-key", "<redacted>"
+                ],
+                "status": 0,
+                "stdout": identity,
+                "stderr": ""
+            },
+            {
+                "command": [
+                    "az iot hub device-twin update", "--device-id", "A", "--hub-name", "target",
+                    "--tags", "<tags>"
+                ],
+                "status": 0,
+                "stdout": "{}",
+                "stderr": ""
+            }
+        ]);
+        let replay = dir.path().join("recording.json");
+        std::fs::write(&replay, recording.to_string()).unwrap();
+        let trace = dir.path().join("trace.log");
+        let runner = CommandRunner::new(
+            RunMode::Replay(replay),
+            Some(&trace),
+            AzLogin::default(),
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+
+        let file_manager = FileManager::new(
+            dir.path().join("out"),
+            true,
+            LogFilter::default(),
+            true,
+            ProgressFormat::Text,
+            Box::new(SystemClock),
+        )
+        .await
+        .unwrap();
+        let cert_manager = CertManager::new(
+            &target,
+            &file_manager,
+            None,
+            CertProfile::Production,
+            CertBackend::OpensslCli,
+            Box::new(OpensslRng { openssl_path: None }),
+            SecretStore::None,
+            None,
+            CancellationToken::new(),
+        );
+        let throttle = HubThrottle::new(None, 1);
+        let migration = MigrationManager::new(
+            &source,
+            &target,
+            &file_manager,
+            &cert_manager,
+            &throttle,
+            &runner,
+            &CancellationToken::new(),
+        );
+
+        let created = migration.copy_devices().await.unwrap();
+        assert_eq!(created.len(), 1);
+        let trace = std::fs::read_to_string(trace).unwrap();
+        let tagged = trace
+            .lines()
+            .find(|l| {
+                l.starts_with(
+                    "$ az iot hub device-twin update --device-id A --hub-name target --tags",
+                )
+            })
+            .expect("No tag update in trace.log");
+        assert!(tagged.contains(CREATION_TAG));
+    }
+}
@@ -0,0 +1,163 @@
+use std::collections::HashMap;
+
+use anyhow::{Context, Result};
+use tokio::time::{Duration, Instant};
+
+use crate::cancel::CancellationToken;
+use crate::commands::CommandRunner;
+use crate::config;
+use crate::log::LogLevel;
+use crate::throttle::{HubOperation, HubThrottle};
+use crate::{quote_arg, FileManager, FlatenedDevice};
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ConnectionState {
+    device_id: String,
+    connection_state: String,
+}
+
+/// Polls the hub until devices connect, to confirm a rollout is landing after the bundles are
+/// installed.
+pub struct OnlineManager<'a> {
+    config: &'a config::Config,
+    file_manager: &'a FileManager,
+    throttle: &'a HubThrottle,
+    runner: &'a CommandRunner,
+    cancel: &'a CancellationToken,
+}
+
+impl<'a> OnlineManager<'a> {
+    pub fn new(
+        config: &'a config::Config,
+        file_manager: &'a FileManager,
+        throttle: &'a HubThrottle,
+        runner: &'a CommandRunner,
+        cancel: &'a CancellationToken,
+    ) -> Self {
+        Self {
+            config,
+            file_manager,
+            throttle,
+            runner,
+            cancel,
+        }
+    }
+
+    /// Reports each device of the subtree as it first connects. Fails with the devices still
+    /// offline if not all of them connected within `timeout`.
+    pub async fn wait(
+        &self,
+        roots: &[config::DeviceConfig],
+        timeout: Duration,
+        interval: Duration,
+    ) -> Result<()> {
+        let device_ids = FlatenedDevice::flatten_devices(roots)
+            .iter()
+            .map(|d| d.device.device_id.clone())
+            .collect();
+        let offline = self.offline_after(device_ids, timeout, interval).await?;
+        if offline.is_empty() {
+            Ok(())
+        } else {
+            Err(anyhow::Error::msg(format!(
+                "{} devices did not connect within {}s: {}",
+                offline.len(),
+                timeout.as_secs(),
+                offline.join(", ")
+            )))
+        }
+    }
+
+    /// Waits for the devices to connect, up to `timeout`. Returns the ones that did not.
+    pub async fn offline_after(
+        &self,
+        device_ids: Vec<String>,
+        timeout: Duration,
+        interval: Duration,
+    ) -> Result<Vec<String>> {
+        let mut offline = device_ids;
+        self.file_manager
+            .log(
+                LogLevel::Info,
+                "online",
+                None,
+                format!(
+                    "Waiting up to {}s for {} devices to connect to hub {}",
+                    timeout.as_secs(),
+                    offline.len(),
+                    self.config.iothub.iothub_name
+                ),
+            )
+            .await?;
+
+        let start = Instant::now();
+        loop {
+            self.cancel.check()?;
+            let states = self.connection_states(&offline).await?;
+            let (connected, still_offline): (Vec<String>, Vec<String>) = offline
+                .into_iter()
+                .partition(|d| states.get(d).map(String::as_str) == Some("Connected"));
+            for device_id in &connected {
+                self.file_manager
+                    .log(
+                        LogLevel::Info,
+                        "online",
+                        Some(device_id),
+                        format!(
+                            "{} connected after {}s",
+                            device_id,
+                            start.elapsed().as_secs()
+                        ),
+                    )
+                    .await?;
+            }
+            offline = still_offline;
+
+            if offline.is_empty() || start.elapsed() + interval > timeout {
+                return Ok(offline);
+            }
+            tokio::time::sleep(interval).await;
+        }
+    }
+
+    /// Connection state by device id. Devices not in the hub yet, such as DPS devices that did not
+    /// register, are left out.
+    async fn connection_states(&self, device_ids: &[String]) -> Result<HashMap<String, String>> {
+        let ids: Vec<String> = device_ids.iter().map(|d| format!("'{}'", d)).collect();
+        let query = format!(
+            "SELECT deviceId, connectionState FROM devices WHERE deviceId IN [{}]",
+            ids.join(", ")
+        );
+        self.throttle.wait(HubOperation::Twin).await;
+        let command = self
+            .runner
+            .output(&[
+                "az iot hub query",
+                "--hub-name",
+                &self.config.iothub.iothub_name,
+                "--query-command",
+                &quote_arg(&query),
+                "--top",
+                "-1",
+                "-o",
+                "json",
+            ])
+            .await?;
+        if !command.status.success() {
+            return Err(anyhow::Error::msg(format!(
+                "Failed to query the connection state of the devices in hub {}:\n{}",
+                self.config.iothub.iothub_name,
+                String::from_utf8_lossy(&command.stderr)
+            )));
+        }
+
+        let states: Vec<ConnectionState> =
+            serde_json::from_slice(&command.stdout).context("Error parsing hub query result")?;
+
+        Ok(states
+            .into_iter()
+            .map(|s| (s.device_id, s.connection_state))
+            .collect())
+    }
+}
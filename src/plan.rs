@@ -0,0 +1,112 @@
+use anyhow::{Context, Result};
+use tokio::fs;
+use tokio::sync::Mutex;
+
+use crate::log::LogLevel;
+use crate::{quote_arg, FileManager};
+
+/// One operation of a run, as --dry-run lists it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Step {
+    pub device_id: Option<String>,
+    pub operation: String,
+}
+
+/// The hub, DPS and openssl operations of a --dry-run, added by the command runner and the cert
+/// manager as the run reaches them instead of running them. As a real run, devices are done
+/// concurrently, so operations of devices of the same layer can come in any order.
+#[derive(Debug, Default)]
+pub struct Plan {
+    steps: Mutex<Vec<Step>>,
+}
+
+impl Plan {
+    pub async fn add(&self, device_id: Option<&str>, operation: String) {
+        self.steps.lock().await.push(Step {
+            device_id: device_id.map(str::to_owned),
+            operation,
+        });
+    }
+
+    pub async fn steps(&self) -> Vec<Step> {
+        self.steps.lock().await.clone()
+    }
+
+    /// Writes the plan to plan.txt in the output folder and prints it.
+    pub async fn write(&self, file_manager: &FileManager) -> Result<()> {
+        let steps = self.steps().await;
+        let text: String = steps.iter().map(|s| format!("{}\n", render(s))).collect();
+        let path = file_manager.base_path().join("plan.txt");
+        fs::write(&path, &text)
+            .await
+            .with_context(|| format!("Error writing plan {:?}", path))?;
+
+        print!("{}", text);
+        file_manager
+            .log(
+                LogLevel::Info,
+                "plan",
+                None,
+                format!(
+                    "Dry run: {} operations written to {:?}, only the az commands that read were run",
+                    steps.len(),
+                    path
+                ),
+            )
+            .await
+    }
+}
+
+/// A command line as the plan lists it, with the arguments the shell would split quoted.
+pub fn command_line<'s>(args: impl IntoIterator<Item = &'s str>) -> String {
+    args.into_iter()
+        .enumerate()
+        .map(|(i, arg)| {
+            let plain = arg
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "-_./:<>=@,".contains(c));
+            if i == 0 || plain {
+                arg.to_owned()
+            } else {
+                quote_arg(arg)
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn render(step: &Step) -> String {
+    match &step.device_id {
+        Some(device_id) => format!("{}: {}", device_id, step.operation),
+        None => step.operation.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_plan() {
+        let plan = Plan::default();
+        plan.add(
+            None,
+            command_line(vec!["openssl", "req", "-subj", "/CN=A B"]),
+        )
+        .await;
+        plan.add(
+            Some("A"),
+            "az iot hub device-identity create --device-id A --hub-name hub".to_owned(),
+        )
+        .await;
+
+        let rendered: Vec<String> = plan.steps().await.iter().map(render).collect();
+        assert_eq!(
+            rendered,
+            vec![
+                "openssl req -subj '/CN=A B'",
+                "A: az iot hub device-identity create --device-id A --hub-name hub"
+            ]
+        );
+    }
+}
@@ -0,0 +1,211 @@
+use anyhow::Result;
+
+use crate::commands::CommandRunner;
+use crate::config;
+use crate::log::LogLevel;
+use crate::{quote_arg, FileManager, IoTHubDeviceManager};
+
+/// Roles that allow managing device identities. Owner and Contributor also cover the
+/// `listkeys` call az makes when it authenticates to the hub with its shared access keys.
+const IDENTITY_ROLES: &[&str] = &[
+    "IoT Hub Registry Contributor",
+    "IoT Hub Data Contributor",
+    "Contributor",
+    "Owner",
+];
+const EVENT_GRID_ROLES: &[&str] = &[
+    "EventGrid EventSubscription Contributor",
+    "Contributor",
+    "Owner",
+];
+
+/// The role assigned by --assign-roles for each requirement that is not met.
+const IDENTITY_ROLE: &str = "IoT Hub Registry Contributor";
+const EVENT_GRID_ROLE: &str = "EventGrid EventSubscription Contributor";
+
+struct Principal {
+    object_id: String,
+    principal_type: &'static str,
+}
+
+/// Checks the signed in az principal has the roles a run needs on the hub before anything is
+/// changed, so runs don't fail halfway through on authorization errors.
+pub struct RoleManager<'a> {
+    config: &'a config::Config,
+    file_manager: &'a FileManager,
+    hub_manager: &'a IoTHubDeviceManager<'a>,
+    runner: &'a CommandRunner,
+}
+
+impl<'a> RoleManager<'a> {
+    pub fn new(
+        config: &'a config::Config,
+        file_manager: &'a FileManager,
+        hub_manager: &'a IoTHubDeviceManager,
+        runner: &'a CommandRunner,
+    ) -> Self {
+        Self {
+            config,
+            file_manager,
+            hub_manager,
+            runner,
+        }
+    }
+
+    pub async fn check_roles(&self, assign: bool) -> Result<()> {
+        let principal = self.signed_in_principal().await?;
+        let hub_id = self.hub_manager.hub_resource_id().await?;
+        let roles = self.assigned_roles(&principal, &hub_id).await?;
+
+        self.file_manager
+            .log(
+                LogLevel::Debug,
+                "rbac",
+                None,
+                format!(
+                    "Principal {} has roles {:?} on {}",
+                    principal.object_id, roles, hub_id
+                ),
+            )
+            .await?;
+
+        let mut requirements = vec![(IDENTITY_ROLES, IDENTITY_ROLE)];
+        if self.config.iothub.event_grid.is_some() {
+            requirements.push((EVENT_GRID_ROLES, EVENT_GRID_ROLE));
+        }
+
+        let missing: Vec<&str> = requirements
+            .into_iter()
+            .filter(|(accepted, _)| {
+                !accepted
+                    .iter()
+                    .any(|r| roles.iter().any(|h| h.as_str() == *r))
+            })
+            .map(|(_, role)| role)
+            .collect();
+
+        if missing.is_empty() {
+            return self
+                .file_manager
+                .log(
+                    LogLevel::Info,
+                    "rbac",
+                    None,
+                    format!(
+                        "Signed in principal has the required roles on hub {}.",
+                        self.config.iothub.iothub_name
+                    ),
+                )
+                .await;
+        }
+
+        if !assign {
+            return Err(anyhow::Error::msg(format!(
+                "The signed in principal {} is missing the roles {:?} on hub {}. Ask an owner of the hub to assign them, or rerun with --assign-roles if you are allowed to assign roles.",
+                principal.object_id, missing, self.config.iothub.iothub_name
+            )));
+        }
+
+        for role in missing {
+            self.assign_role(&principal, &hub_id, role).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn signed_in_principal(&self) -> Result<Principal> {
+        let query = quote_arg("user.[type,name]");
+        let account = az_tsv(self.runner, &["az account show", "--query", &query]).await?;
+        let mut account = account.lines();
+        let (user_type, name) = (
+            account.next().unwrap_or_default(),
+            account.next().unwrap_or_default(),
+        );
+
+        if user_type == "servicePrincipal" {
+            Ok(Principal {
+                object_id: az_tsv(
+                    self.runner,
+                    &["az ad sp show", "--id", name, "--query", "id"],
+                )
+                .await?,
+                principal_type: "ServicePrincipal",
+            })
+        } else {
+            Ok(Principal {
+                object_id: az_tsv(self.runner, &["az ad signed-in-user show", "--query", "id"])
+                    .await?,
+                principal_type: "User",
+            })
+        }
+    }
+
+    async fn assigned_roles(&self, principal: &Principal, hub_id: &str) -> Result<Vec<String>> {
+        let query = quote_arg("[].roleDefinitionName");
+        let roles = az_tsv(
+            self.runner,
+            &[
+                "az role assignment list",
+                "--assignee",
+                &principal.object_id,
+                "--scope",
+                hub_id,
+                "--include-inherited",
+                "--query",
+                &query,
+            ],
+        )
+        .await?;
+
+        Ok(roles.lines().map(str::to_owned).collect())
+    }
+
+    async fn assign_role(&self, principal: &Principal, hub_id: &str, role: &str) -> Result<()> {
+        self.file_manager
+            .log(
+                LogLevel::Info,
+                "rbac",
+                None,
+                format!(
+                    "Assigning role {:?} on hub {} to {}.",
+                    role, self.config.iothub.iothub_name, principal.object_id
+                ),
+            )
+            .await?;
+
+        let role = quote_arg(role);
+        az_tsv(
+            self.runner,
+            &[
+                "az role assignment create",
+                "--assignee-object-id",
+                &principal.object_id,
+                "--assignee-principal-type",
+                principal.principal_type,
+                "--role",
+                &role,
+                "--scope",
+                hub_id,
+            ],
+        )
+        .await?;
+
+        Ok(())
+    }
+}
+
+async fn az_tsv(runner: &CommandRunner, args: &[&str]) -> Result<String> {
+    let mut args = args.to_vec();
+    args.extend(&["-o", "tsv"]);
+
+    let command = runner.output(&args).await?;
+    if command.status.success() {
+        Ok(String::from_utf8_lossy(&command.stdout).trim().to_owned())
+    } else {
+        Err(anyhow::Error::msg(format!(
+            "Failed to run {}:\n{}",
+            args[0],
+            String::from_utf8_lossy(&command.stderr)
+        )))
+    }
+}
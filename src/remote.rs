@@ -0,0 +1,645 @@
+use std::future::Future;
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use futures::future::BoxFuture;
+use futures::{FutureExt, StreamExt};
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+
+use crate::commands::{self, CommandRunner};
+use crate::config;
+use crate::drift::DriftManager;
+use crate::hostnames;
+use crate::log::LogLevel;
+use crate::rollout::{self, RolloutManager};
+use crate::schedule::ScheduleManager;
+use crate::{quote_arg, run_command, FileManager, FlatenedDevice};
+
+/// How remote operations reach the devices.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Transport {
+    Ssh,
+    /// `az ssh arc` to Arc-enabled servers named after the device id.
+    Arc,
+    /// IoT Hub direct methods to edgeAgent. Only module logs are available this way.
+    DirectMethod,
+}
+
+impl std::str::FromStr for Transport {
+    type Err = anyhow::Error;
+    fn from_str(string: &str) -> Result<Self> {
+        let result = match string.to_lowercase().as_str() {
+            "ssh" => Self::Ssh,
+            "arc" => Self::Arc,
+            "direct-method" => Self::DirectMethod,
+            _ => {
+                return Err(anyhow::Error::msg(format!(
+                    "Did not recognize transport: {}. Accepted values are: ssh, arc, direct-method",
+                    string
+                )))
+            }
+        };
+
+        Ok(result)
+    }
+}
+
+/// Bounds on running an operation on the whole fleet.
+#[derive(Clone, Copy, Debug)]
+pub struct FleetLimits {
+    /// Devices worked on at the same time.
+    pub parallel: usize,
+    /// Time after which a device's operation is abandoned.
+    pub timeout: Duration,
+}
+
+/// Runs the operation on every device, at most `parallel` at a time. A device that fails or
+/// times out only fails its own result, so one unreachable box does not hold up or abort the
+/// others. Results are in the order of the devices.
+pub async fn run_on_devices<'d, T, F, Fut>(
+    devices: &[FlatenedDevice<'d>],
+    limits: FleetLimits,
+    operation: F,
+) -> Vec<Result<T>>
+where
+    F: Fn(&'d config::DeviceConfig) -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    let futures = devices.iter().map(|d| {
+        let device_id = d.device.device_id.as_str();
+        let future = operation(d.device);
+        async move {
+            match tokio::time::timeout(limits.timeout, future).await {
+                Ok(result) => result,
+                Err(_) => Err(anyhow::Error::msg(format!(
+                    "{} did not finish within {}s",
+                    device_id,
+                    limits.timeout.as_secs()
+                ))),
+            }
+        }
+    });
+
+    futures::stream::iter(futures)
+        .buffered(std::cmp::max(limits.parallel, 1))
+        .collect()
+        .await
+}
+
+/// Runs operations on a device, so remote checks, log collection and bundle pushes work in
+/// environments where raw SSH to devices is not allowed.
+pub trait RemoteExecutor: Send + Sync {
+    /// Runs a shell command on the device and returns its output.
+    fn run<'a>(
+        &'a self,
+        device: &'a config::DeviceConfig,
+        command: &'a str,
+    ) -> BoxFuture<'a, Result<String>>;
+
+    /// Writes a file on the device, relative to the login user's home directory.
+    fn write_file<'a>(
+        &'a self,
+        device: &'a config::DeviceConfig,
+        path: &'a str,
+        contents: &'a [u8],
+    ) -> BoxFuture<'a, Result<()>>;
+
+    /// Logs of a module since a time like `1h` or an RFC 3339 timestamp.
+    fn module_logs<'a>(
+        &'a self,
+        device: &'a config::DeviceConfig,
+        module: &'a str,
+        since: &'a str,
+    ) -> BoxFuture<'a, Result<String>> {
+        let command = format!(
+            "sudo iotedge logs {} --since {} 2>&1",
+            sh_quote(module),
+            sh_quote(since)
+        );
+        async move { self.run(device, &command).await }.boxed()
+    }
+}
+
+pub struct SshExecutor<'a> {
+    pub config: &'a config::Config,
+    pub user: Option<String>,
+    pub identity: Option<PathBuf>,
+}
+
+impl SshExecutor<'_> {
+    fn command(&self, device: &config::DeviceConfig, remote_command: &str) -> Result<Command> {
+        let host = device.hostname.as_deref().ok_or_else(|| {
+            anyhow::Error::msg(format!(
+                "{} has no hostname to connect to over SSH",
+                device.device_id
+            ))
+        })?;
+
+        let ssh = self.config.ssh.clone().unwrap_or_default();
+        let mut command = Command::new("ssh");
+        command.args(&["-o", "BatchMode=yes"]);
+        if let Some(config_file) = &ssh.config_file {
+            command.args(&["-F", config_file]);
+        }
+        if let Some(user) = &self.user {
+            command.args(&["-l", user]);
+        }
+        if let Some(identity) = &self.identity {
+            command.arg("-i").arg(identity);
+        }
+        let jumps = match &device.ssh_proxy_jump {
+            Some(jump) => vec![jump.clone()],
+            None => jump_hosts(
+                &self.config.root_devices,
+                &device.device_id,
+                &ssh,
+                self.user.as_deref(),
+            )?,
+        };
+        if !jumps.is_empty() {
+            command.args(&["-J", &jumps.join(",")]);
+        }
+        command.arg(hostnames::bare_host(host)).arg(remote_command);
+
+        Ok(command)
+    }
+}
+
+/// Hosts to jump through to reach the device: the bastion, then with `jump_through_parents` the
+/// hostnames of its parents from the top layer down.
+fn jump_hosts(
+    roots: &[config::DeviceConfig],
+    device_id: &str,
+    ssh: &config::Ssh,
+    user: Option<&str>,
+) -> Result<Vec<String>> {
+    let mut jumps: Vec<String> = ssh.proxy_jump.iter().cloned().collect();
+    if !ssh.jump_through_parents {
+        return Ok(jumps);
+    }
+
+    let ancestors = roots
+        .iter()
+        .find_map(|root| ancestors(root, device_id))
+        .ok_or_else(|| anyhow::Error::msg(format!("{} is not in the config", device_id)))?;
+    for parent in ancestors {
+        let host = parent.hostname.as_deref().ok_or_else(|| {
+            anyhow::Error::msg(format!(
+                "{} is reached through its parent {}, which has no hostname",
+                device_id, parent.device_id
+            ))
+        })?;
+        let host = hostnames::url_host(host);
+        jumps.push(match user {
+            Some(user) => format!("{}@{}", user, host),
+            None => host,
+        });
+    }
+
+    Ok(jumps)
+}
+
+/// The devices above the device, from the top layer down.
+fn ancestors<'a>(
+    device: &'a config::DeviceConfig,
+    device_id: &str,
+) -> Option<Vec<&'a config::DeviceConfig>> {
+    if device.device_id == device_id {
+        return Some(Vec::new());
+    }
+
+    device.children.iter().find_map(|child| {
+        ancestors(child, device_id).map(|mut result| {
+            result.insert(0, device);
+            result
+        })
+    })
+}
+
+impl RemoteExecutor for SshExecutor<'_> {
+    fn run<'a>(
+        &'a self,
+        device: &'a config::DeviceConfig,
+        command: &'a str,
+    ) -> BoxFuture<'a, Result<String>> {
+        async move { shell_output(self.command(device, command)?, command, None).await }.boxed()
+    }
+
+    fn write_file<'a>(
+        &'a self,
+        device: &'a config::DeviceConfig,
+        path: &'a str,
+        contents: &'a [u8],
+    ) -> BoxFuture<'a, Result<()>> {
+        async move {
+            let remote_command = format!("cat > {}", sh_quote(path));
+            let command = self.command(device, &remote_command)?;
+            shell_output(command, &remote_command, Some(contents)).await?;
+            Ok(())
+        }
+        .boxed()
+    }
+}
+
+pub struct ArcExecutor {
+    pub resource_group: String,
+    pub user: Option<String>,
+}
+
+impl ArcExecutor {
+    fn command(&self, device: &config::DeviceConfig, remote_command: &str) -> Command {
+        let remote_command = quote_arg(remote_command);
+        let mut args = vec![
+            "az ssh arc",
+            "--resource-group",
+            &self.resource_group,
+            "--name",
+            &device.device_id,
+        ];
+        if let Some(user) = &self.user {
+            args.extend(&["--local-user", user.as_str()]);
+        }
+        args.extend(&["--", "-o", "BatchMode=yes", &remote_command]);
+
+        run_command(&args)
+    }
+}
+
+impl RemoteExecutor for ArcExecutor {
+    fn run<'a>(
+        &'a self,
+        device: &'a config::DeviceConfig,
+        command: &'a str,
+    ) -> BoxFuture<'a, Result<String>> {
+        async move { shell_output(self.command(device, command), command, None).await }.boxed()
+    }
+
+    fn write_file<'a>(
+        &'a self,
+        device: &'a config::DeviceConfig,
+        path: &'a str,
+        contents: &'a [u8],
+    ) -> BoxFuture<'a, Result<()>> {
+        async move {
+            let remote_command = format!("cat > {}", sh_quote(path));
+            let command = self.command(device, &remote_command);
+            shell_output(command, &remote_command, Some(contents)).await?;
+            Ok(())
+        }
+        .boxed()
+    }
+}
+
+pub struct DirectMethodExecutor<'a> {
+    pub config: &'a config::Config,
+    pub runner: &'a CommandRunner,
+}
+
+impl RemoteExecutor for DirectMethodExecutor<'_> {
+    fn run<'a>(
+        &'a self,
+        device: &'a config::DeviceConfig,
+        _command: &'a str,
+    ) -> BoxFuture<'a, Result<String>> {
+        let error = format!(
+            "Direct methods cannot run commands on {}. Use --transport ssh or arc.",
+            device.device_id
+        );
+        async move { Err(anyhow::Error::msg(error)) }.boxed()
+    }
+
+    fn write_file<'a>(
+        &'a self,
+        device: &'a config::DeviceConfig,
+        _path: &'a str,
+        _contents: &'a [u8],
+    ) -> BoxFuture<'a, Result<()>> {
+        let error = format!(
+            "Direct methods cannot copy files to {}. Use --transport ssh or arc.",
+            device.device_id
+        );
+        async move { Err(anyhow::Error::msg(error)) }.boxed()
+    }
+
+    fn module_logs<'a>(
+        &'a self,
+        device: &'a config::DeviceConfig,
+        module: &'a str,
+        since: &'a str,
+    ) -> BoxFuture<'a, Result<String>> {
+        async move {
+            let payload = serde_json::json!({
+                "schemaVersion": "1.0",
+                "items": [{ "id": module, "filter": { "since": since } }],
+                "encoding": "none",
+                "contentType": "text",
+            });
+            let payload = quote_arg(&payload.to_string());
+            let module_id = quote_arg("$edgeAgent");
+            let args = &[
+                "az iot hub invoke-module-method",
+                "--hub-name",
+                &self.config.iothub.iothub_name,
+                "--device-id",
+                &device.device_id,
+                "--module-id",
+                &module_id,
+                "--method-name",
+                "GetModuleLogs",
+                "--method-payload",
+                &payload,
+            ];
+            let command = self.runner.output(args).await?;
+            if !command.status.success() {
+                return Err(anyhow::Error::msg(format!(
+                    "GetModuleLogs on {} failed:\n{}",
+                    device.device_id,
+                    String::from_utf8_lossy(&command.stderr)
+                )));
+            }
+
+            let response: serde_json::Value = serde_json::from_slice(&command.stdout)?;
+            response
+                .pointer("/payload/0/payload")
+                .and_then(serde_json::Value::as_str)
+                .map(str::to_owned)
+                .ok_or_else(|| {
+                    anyhow::Error::msg(format!(
+                        "GetModuleLogs on {} returned no logs:\n{}",
+                        device.device_id, response
+                    ))
+                })
+        }
+        .boxed()
+    }
+}
+
+/// Collects logs from and pushes bundles to every device through a `RemoteExecutor`.
+pub struct RemoteManager<'a> {
+    config: &'a config::Config,
+    file_manager: &'a FileManager,
+    executor: &'a dyn RemoteExecutor,
+    limits: FleetLimits,
+}
+
+impl<'a> RemoteManager<'a> {
+    pub fn new(
+        config: &'a config::Config,
+        file_manager: &'a FileManager,
+        executor: &'a dyn RemoteExecutor,
+        limits: FleetLimits,
+    ) -> Self {
+        Self {
+            config,
+            file_manager,
+            executor,
+            limits,
+        }
+    }
+
+    /// Writes the module's logs to `<device_id>/logs/<module>.log` in the output folder.
+    pub async fn collect_logs(&self, module: &str, since: &str) -> Result<()> {
+        let devices = FlatenedDevice::flatten_devices(&self.config.root_devices);
+        let results = run_on_devices(&devices, self.limits, |d| {
+            self.collect_device_logs(d, module, since)
+        })
+        .await;
+
+        self.report(&devices, results, "logs").await
+    }
+
+    /// Copies each device's zip made by the main command to the home directory on the device,
+    /// in the waves of `rollout`. A wave with more failed devices than `max_failure_percent`
+    /// stops the rollout. Devices outside their maintenance window are left for a later push.
+    pub async fn push_bundles(
+        &self,
+        rollout: &RolloutManager<'_>,
+        drift: &DriftManager<'_>,
+        schedule: &ScheduleManager<'_>,
+    ) -> Result<()> {
+        let devices = schedule
+            .due(
+                "push",
+                FlatenedDevice::flatten_devices(&self.config.root_devices),
+            )
+            .await?;
+        let waves = rollout::waves(self.config.rollout.as_ref(), devices, |d| d.layer);
+        let mut result = Ok(());
+        for (i, wave) in waves.iter().enumerate() {
+            rollout.start_wave(i, waves.len(), wave).await?;
+            let results = run_on_devices(wave, self.limits, |d| self.push_bundle(d)).await;
+            let failed: Vec<&str> = wave
+                .iter()
+                .zip(&results)
+                .filter(|(_, r)| r.is_err())
+                .map(|(d, _)| d.device.device_id.as_str())
+                .collect();
+            if let Err(e) = self.report(wave, results, "push").await {
+                result = Err(e);
+            }
+            let succeeded: Vec<&str> = wave
+                .iter()
+                .map(|d| d.device.device_id.as_str())
+                .filter(|d| !failed.contains(d))
+                .collect();
+            schedule.done("push", &succeeded).await?;
+            rollout
+                .check_wave(i, waves.len(), wave, &failed, Some(drift))
+                .await?;
+        }
+
+        result
+    }
+
+    async fn collect_device_logs(
+        &self,
+        device: &config::DeviceConfig,
+        module: &str,
+        since: &str,
+    ) -> Result<()> {
+        let logs = self.executor.module_logs(device, module, since).await?;
+        let folder = self
+            .file_manager
+            .get_folder(&format!("{}/logs", device.device_id))
+            .await?;
+        fs::write(folder.join(format!("{}.log", module)), logs).await?;
+
+        Ok(())
+    }
+
+    async fn push_bundle(&self, device: &config::DeviceConfig) -> Result<()> {
+        let bundle =
+            FileManager::path_to_zip(self.file_manager.base_path().join(&device.device_id));
+        let contents = fs::read(&bundle).await.with_context(|| {
+            format!(
+                "Error reading {:?}. Run without a subcommand and with --zip-options devices first.",
+                bundle
+            )
+        })?;
+
+        self.executor
+            .write_file(device, &format!("{}.zip", device.device_id), &contents)
+            .await
+    }
+
+    async fn report(
+        &self,
+        devices: &[FlatenedDevice<'_>],
+        results: Vec<Result<()>>,
+        operation: &str,
+    ) -> Result<()> {
+        let mut failed = 0;
+        for (device, result) in devices.iter().zip(results) {
+            let device_id = device.device.device_id.as_str();
+            let (level, message) = match result {
+                Ok(()) => (
+                    LogLevel::Info,
+                    format!("{} {} succeeded.", device_id, operation),
+                ),
+                Err(e) => {
+                    failed += 1;
+                    (
+                        LogLevel::Warn,
+                        format!("{} {} failed: {:#}", device_id, operation, e),
+                    )
+                }
+            };
+            self.file_manager
+                .log(level, "remote", Some(device_id), message)
+                .await?;
+        }
+
+        if failed == 0 {
+            Ok(())
+        } else {
+            Err(anyhow::Error::msg(format!(
+                "{} failed on {} devices",
+                operation, failed
+            )))
+        }
+    }
+}
+
+async fn shell_output(
+    mut command: Command,
+    remote_command: &str,
+    stdin: Option<&[u8]>,
+) -> Result<String> {
+    // Killed if the device's operation times out
+    command
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true);
+    if stdin.is_some() {
+        command.stdin(Stdio::piped());
+    }
+
+    let mut child = command.spawn()?;
+    if let (Some(contents), Some(mut child_stdin)) = (stdin, child.stdin.take()) {
+        child_stdin.write_all(contents).await?;
+    }
+    let output = commands::wait_bounded(child, remote_command).await?;
+    if !output.status.success() {
+        return Err(anyhow::Error::msg(format!(
+            "Remote command {} failed:\n{}",
+            remote_command,
+            String::from_utf8_lossy(&output.stderr)
+        )));
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Quotes an argument for the device's shell.
+pub fn sh_quote(arg: &str) -> String {
+    format!("'{}'", arg.replace('\'', r#"'\''"#))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_run_on_devices() {
+        let root: config::DeviceConfig = serde_yaml::from_str(
+            "device_id: top\nchild:\n  - device_id: slow\n  - device_id: failing\n",
+        )
+        .unwrap();
+        let devices = FlatenedDevice::flatten_devices(std::slice::from_ref(&root));
+        let limits = FleetLimits {
+            parallel: 2,
+            timeout: Duration::from_millis(100),
+        };
+
+        let results = run_on_devices(&devices, limits, |d| async move {
+            match d.device_id.as_str() {
+                "slow" => {
+                    tokio::time::sleep(Duration::from_secs(10)).await;
+                    Ok(())
+                }
+                "failing" => Err(anyhow::Error::msg("unreachable")),
+                _ => Ok(()),
+            }
+        })
+        .await;
+
+        assert!(results[0].is_ok());
+        assert!(results[1]
+            .as_ref()
+            .unwrap_err()
+            .to_string()
+            .contains("did not finish"));
+        assert_eq!(results[2].as_ref().unwrap_err().to_string(), "unreachable");
+    }
+
+    #[test]
+    fn test_jump_hosts() {
+        let root: config::DeviceConfig = serde_yaml::from_str(
+            "device_id: top\nhostname: 10.0.0.1\nchild:\n  - device_id: middle\n    hostname: 10.0.1.1\n    child:\n      - device_id: bottom\n        hostname: 10.0.2.1\n",
+        )
+        .unwrap();
+        let root = std::slice::from_ref(&root);
+        let mut ssh = config::Ssh {
+            proxy_jump: Some("bastion.example.com".to_owned()),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            jump_hosts(&root, "bottom", &ssh, None).unwrap(),
+            vec!["bastion.example.com"]
+        );
+
+        ssh.jump_through_parents = true;
+        assert_eq!(
+            jump_hosts(&root, "bottom", &ssh, Some("admin")).unwrap(),
+            vec!["bastion.example.com", "admin@10.0.0.1", "admin@10.0.1.1"]
+        );
+        assert_eq!(
+            jump_hosts(&root, "top", &ssh, None).unwrap(),
+            vec!["bastion.example.com"]
+        );
+        assert!(jump_hosts(&root, "missing", &ssh, None).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_ssh_destination() {
+        let mut config = config::Config::read_config("src/test_files/cert_test.yaml")
+            .await
+            .unwrap();
+        config.root_devices =
+            vec![serde_yaml::from_str("device_id: top\nhostname: \"[fd00::1]\"\n").unwrap()];
+        let ssh = SshExecutor {
+            config: &config,
+            user: None,
+            identity: None,
+        };
+
+        let command = ssh.command(&config.root_devices[0], "true").unwrap();
+        let args: Vec<_> = command.as_std().get_args().collect();
+        assert_eq!(args[args.len() - 2], "fd00::1");
+    }
+}
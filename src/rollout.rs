@@ -0,0 +1,301 @@
+use std::collections::HashSet;
+
+use anyhow::Result;
+use tokio::time::Duration;
+
+use crate::cancel::CancellationToken;
+use crate::commands::{CommandExt, CommandRunner};
+use crate::config::{self, RolloutCheck, RolloutStrategy};
+use crate::drift::DriftManager;
+use crate::log::LogLevel;
+use crate::online::OnlineManager;
+use crate::run_command;
+use crate::throttle::HubThrottle;
+use crate::{FileManager, FlatenedDevice};
+
+/// Interval the hub is polled at while waiting for a wave's devices to connect.
+const HEALTH_POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Splits the devices, in hierarchy order, into the waves of the strategy. Without a rollout
+/// every device is in one wave.
+pub fn waves<T>(
+    rollout: Option<&config::Rollout>,
+    devices: Vec<T>,
+    layer: impl Fn(&T) -> usize,
+) -> Vec<Vec<T>> {
+    let rollout = match rollout {
+        Some(rollout) if !devices.is_empty() => rollout,
+        _ => return vec![devices],
+    };
+
+    match rollout.strategy {
+        RolloutStrategy::All => vec![devices],
+        RolloutStrategy::Layers => {
+            let mut waves: Vec<Vec<T>> = Vec::new();
+            let mut devices = devices;
+            devices.sort_by_key(&layer);
+            for device in devices {
+                match waves.last_mut() {
+                    Some(wave) if layer(&wave[0]) == layer(&device) => wave.push(device),
+                    _ => waves.push(vec![device]),
+                }
+            }
+            waves
+        }
+        RolloutStrategy::Canary => {
+            let total = devices.len();
+            let mut remaining = devices.into_iter();
+            let mut waves = Vec::new();
+            let mut done = 0;
+            for percentage in &rollout.percentages {
+                // At least one device per wave, so a small fleet still gets a canary
+                let end = ((total * usize::from(*percentage) + 99) / 100)
+                    .max(done + 1)
+                    .min(total);
+                if end > done {
+                    waves.push(remaining.by_ref().take(end - done).collect());
+                    done = end;
+                }
+            }
+            let rest: Vec<T> = remaining.collect();
+            if !rest.is_empty() {
+                waves.push(rest);
+            }
+            waves
+        }
+    }
+}
+
+/// Checks that a wave of a rollout landed before the next one starts.
+pub struct RolloutManager<'a> {
+    config: &'a config::Config,
+    file_manager: &'a FileManager,
+    online: OnlineManager<'a>,
+}
+
+impl<'a> RolloutManager<'a> {
+    pub fn new(
+        config: &'a config::Config,
+        file_manager: &'a FileManager,
+        throttle: &'a HubThrottle,
+        runner: &'a CommandRunner,
+        cancel: &'a CancellationToken,
+    ) -> Self {
+        Self {
+            config,
+            file_manager,
+            online: OnlineManager::new(config, file_manager, throttle, runner, cancel),
+        }
+    }
+
+    pub async fn start_wave(
+        &self,
+        wave: usize,
+        waves: usize,
+        devices: &[FlatenedDevice<'_>],
+    ) -> Result<()> {
+        if waves < 2 {
+            return Ok(());
+        }
+
+        self.file_manager
+            .log(
+                LogLevel::Info,
+                "rollout",
+                None,
+                format!(
+                    "Wave {} of {}: {} devices: {}",
+                    wave + 1,
+                    waves,
+                    devices.len(),
+                    device_ids(devices).join(", ")
+                ),
+            )
+            .await
+    }
+
+    /// Waits `pause_seconds`, then runs the checks on the wave's devices. Fails if more than
+    /// `max_failure_percent` of them failed the operation or a check, or if the health check
+    /// command failed, so the next waves are not started. `drift` is only there for operations
+    /// that reach the devices.
+    pub async fn check_wave(
+        &self,
+        wave: usize,
+        waves: usize,
+        devices: &[FlatenedDevice<'_>],
+        failed: &[&str],
+        drift: Option<&DriftManager<'_>>,
+    ) -> Result<()> {
+        let rollout = match &self.config.rollout {
+            Some(rollout) if wave + 1 < waves => rollout,
+            _ => return Ok(()),
+        };
+
+        let pause = rollout.pause_seconds.unwrap_or(60);
+        self.file_manager
+            .log(
+                LogLevel::Info,
+                "rollout",
+                None,
+                format!(
+                    "Waiting {}s before checking the {} devices of wave {}",
+                    pause,
+                    devices.len(),
+                    wave + 1
+                ),
+            )
+            .await?;
+        tokio::time::sleep(Duration::from_secs(pause)).await;
+
+        let mut failed: HashSet<String> = failed.iter().map(|d| (*d).to_owned()).collect();
+        for check in &rollout.checks {
+            match (check, drift) {
+                (RolloutCheck::Online, _) => failed.extend(
+                    self.online
+                        .offline_after(
+                            device_ids(devices).iter().map(|d| (*d).to_owned()).collect(),
+                            Duration::from_secs(rollout.health_timeout_seconds.unwrap_or(300)),
+                            HEALTH_POLL_INTERVAL,
+                        )
+                        .await?,
+                ),
+                (RolloutCheck::Drift, Some(drift)) => failed.extend(
+                    drift
+                        .drifted(devices)
+                        .await?
+                        .into_iter()
+                        .map(str::to_owned),
+                ),
+                (RolloutCheck::Drift, None) => {
+                    self.file_manager
+                        .log(
+                            LogLevel::Warn,
+                            "rollout",
+                            None,
+                            "The drift check only runs with push, which reaches the devices. Skipping it.",
+                        )
+                        .await?
+                }
+            }
+        }
+
+        if let Some(health_check) = &rollout.health_check {
+            let command = run_command(&[health_check.as_str()])
+                .env("IOTEDGE_ROLLOUT_DEVICES", device_ids(devices).join(","))
+                .bounded_output()
+                .await?;
+            if !command.status.success() {
+                return Err(anyhow::Error::msg(format!(
+                    "Wave {} failed its health check {}, the remaining waves were not started:\n{}{}",
+                    wave + 1,
+                    health_check,
+                    String::from_utf8_lossy(&command.stdout),
+                    String::from_utf8_lossy(&command.stderr)
+                )));
+            }
+        }
+
+        let failed: Vec<&str> = device_ids(devices)
+            .into_iter()
+            .filter(|d| failed.contains(*d))
+            .collect();
+        let failure_percent = failure_percent(failed.len(), devices.len());
+        if failure_percent > rollout.max_failure_percent {
+            return Err(anyhow::Error::msg(format!(
+                "{} of the {} devices of wave {} failed ({:.0}%, more than max_failure_percent {}), the remaining waves were not started: {}",
+                failed.len(),
+                devices.len(),
+                wave + 1,
+                failure_percent,
+                rollout.max_failure_percent,
+                failed.join(", ")
+            )));
+        }
+
+        let (level, message) = if failed.is_empty() {
+            (LogLevel::Info, format!("Wave {} is healthy.", wave + 1))
+        } else {
+            (
+                LogLevel::Warn,
+                format!(
+                    "{} of the {} devices of wave {} failed, within max_failure_percent {}, continuing: {}",
+                    failed.len(),
+                    devices.len(),
+                    wave + 1,
+                    rollout.max_failure_percent,
+                    failed.join(", ")
+                ),
+            )
+        };
+        self.file_manager.log(level, "rollout", None, message).await
+    }
+}
+
+fn device_ids<'d>(devices: &'d [FlatenedDevice<'_>]) -> Vec<&'d str> {
+    devices
+        .iter()
+        .map(|d| d.device.device_id.as_str())
+        .collect()
+}
+
+fn failure_percent(failed: usize, devices: usize) -> f64 {
+    if devices == 0 {
+        0.0
+    } else {
+        failed as f64 * 100.0 / devices as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_waves() {
+        let rollout = |strategy, percentages| config::Rollout {
+            strategy,
+            percentages,
+            pause_seconds: None,
+            health_timeout_seconds: None,
+            health_check: None,
+            checks: vec![RolloutCheck::Online],
+            max_failure_percent: 0.0,
+        };
+        let devices: Vec<(usize, usize)> = (0..20).map(|i| (i, i % 3)).collect();
+        let sizes = |waves: Vec<Vec<(usize, usize)>>| -> Vec<usize> {
+            waves.iter().map(Vec::len).collect()
+        };
+
+        assert_eq!(sizes(waves(None, devices.clone(), |d| d.1)), vec![20]);
+        assert_eq!(
+            sizes(waves(
+                Some(&rollout(RolloutStrategy::Canary, vec![10, 50])),
+                devices.clone(),
+                |d| d.1
+            )),
+            vec![2, 8, 10]
+        );
+        // Small fleets still start with one device
+        assert_eq!(
+            sizes(waves(
+                Some(&rollout(RolloutStrategy::Canary, vec![1, 100])),
+                devices[..3].to_vec(),
+                |d| d.1
+            )),
+            vec![1, 2]
+        );
+
+        let layers = waves(
+            Some(&rollout(RolloutStrategy::Layers, vec![])),
+            devices,
+            |d| d.1,
+        );
+        assert_eq!(sizes(layers.clone()), vec![7, 7, 6]);
+        assert!(layers[1].iter().all(|d| d.1 == 1));
+        // Hierarchy order is kept within a layer
+        assert_eq!(layers[0][1].0, 3);
+
+        assert_eq!(failure_percent(1, 8), 12.5);
+        assert_eq!(failure_percent(0, 0), 0.0);
+    }
+}
@@ -0,0 +1,105 @@
+use anyhow::Result;
+use serde_json::{json, Map, Value};
+
+use crate::config;
+
+/// Replaces the edgeHub routes of a deployment manifest with the ones generated from a layer's
+/// routing config. Bridged topics also set up the MQTT broker bridge to the parent device.
+pub fn apply_routes(
+    manifest: &mut Value,
+    routes: &config::LayerRoutes,
+    top_layer: bool,
+) -> Result<()> {
+    if top_layer && !routes.bridge.is_empty() {
+        return Err(anyhow::Error::msg(
+            "The top layer has no parent device to bridge MQTT topics with",
+        ));
+    }
+
+    let hub = manifest
+        .pointer_mut("/modulesContent/$edgeHub/properties.desired")
+        .and_then(Value::as_object_mut)
+        .ok_or_else(|| anyhow::Error::msg("Deployment has no $edgeHub properties.desired"))?;
+    hub.insert("routes".to_owned(), Value::Object(make_routes(routes)));
+
+    if !routes.bridge.is_empty() {
+        let settings: Vec<Value> = routes
+            .bridge
+            .iter()
+            .map(|b| {
+                json!({
+                    "direction": b.direction,
+                    "topic": b.topic,
+                    "inPrefix": b.in_prefix.as_deref().unwrap_or_default(),
+                    "outPrefix": b.out_prefix.as_deref().unwrap_or_default(),
+                })
+            })
+            .collect();
+        hub.insert(
+            "mqttBroker".to_owned(),
+            json!({ "bridges": [{ "endpoint": "$upstream", "settings": settings }] }),
+        );
+        // Broker bridges need edgeHub schema 1.2
+        hub.insert("schemaVersion".to_owned(), json!("1.2"));
+    }
+
+    Ok(())
+}
+
+fn make_routes(routes: &config::LayerRoutes) -> Map<String, Value> {
+    let mut result = Map::new();
+    for route in &routes.local {
+        let name = format!("{}To{}", route.from, route.to);
+        let route = format!(
+            r#"FROM /messages/modules/{}/outputs/{} INTO BrokeredEndpoint("/modules/{}/inputs/{}")"#,
+            route.from,
+            route.output.as_deref().unwrap_or("*"),
+            route.to,
+            route.input
+        );
+        result.insert(name, Value::String(route));
+    }
+    if routes.upstream {
+        result.insert(
+            "upstream".to_owned(),
+            Value::String("FROM /messages/* INTO $upstream".to_owned()),
+        );
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deployment::validate_manifest;
+
+    #[test]
+    fn test_apply_routes() {
+        let routes: config::LayerRoutes = serde_yaml::from_str(
+            "upstream: true\nlocal:\n  - from: sensor\n    output: temperature\n    to: filter\n    input: input1\nbridge:\n  - topic: alerts/#\n    direction: out\n",
+        )
+        .unwrap();
+        let mut manifest: Value = serde_json::from_str(include_str!(
+            "../templates/tutorial/deploymentLowerLayer.json"
+        ))
+        .unwrap();
+
+        assert!(apply_routes(&mut manifest.clone(), &routes, true).is_err());
+        apply_routes(&mut manifest, &routes, false).unwrap();
+
+        let hub = &manifest["modulesContent"]["$edgeHub"]["properties.desired"];
+        assert_eq!(hub["routes"]["upstream"], "FROM /messages/* INTO $upstream");
+        assert_eq!(
+            hub["routes"]["sensorTofilter"],
+            r#"FROM /messages/modules/sensor/outputs/temperature INTO BrokeredEndpoint("/modules/filter/inputs/input1")"#
+        );
+        assert!(hub["routes"].get("route").is_none());
+        assert_eq!(
+            hub["mqttBroker"]["bridges"][0]["settings"][0]["direction"],
+            "out"
+        );
+        assert_eq!(hub["schemaVersion"], "1.2");
+        assert!(validate_manifest(&manifest.to_string()).is_empty());
+    }
+}
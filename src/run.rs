@@ -0,0 +1,142 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use tokio::fs;
+use walkdir::WalkDir;
+
+use crate::config;
+
+/// How one phase went for a device.
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize, PartialEq)]
+pub struct PhaseOutcome {
+    pub succeeded: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Devices of one site, region or environment, and those with a failed phase.
+#[derive(Clone, Debug, Default, serde::Deserialize, serde::Serialize, PartialEq)]
+pub struct GroupSummary {
+    pub devices: Vec<String>,
+    pub failed: Vec<String>,
+}
+
+/// `run.json` in the output folder: what the last command did, for scripts wrapping the tool.
+/// Fields are only ever added to it.
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize, PartialEq)]
+pub struct RunManifest {
+    pub tool_version: String,
+    /// Subcommand that was run, or `create` or `delete` without one.
+    pub command: String,
+    /// Command line arguments, without the program name.
+    pub arguments: Vec<String>,
+    /// SHA-256 of the config file.
+    pub config_hash: Option<String>,
+    /// In RFC 3339.
+    pub started: String,
+    pub finished: String,
+    pub succeeded: bool,
+    pub error: Option<String>,
+    /// Outcome of each phase of each device, by device id then phase.
+    pub devices: BTreeMap<String, BTreeMap<String, PhaseOutcome>>,
+    /// Devices by grouping, as `site=<site>`, `region=<region>` and `environment=<environment>`.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub groups: BTreeMap<String, GroupSummary>,
+    /// Files in the output folder after the run, relative to it.
+    pub artifacts: Vec<String>,
+}
+
+impl RunManifest {
+    pub fn new(
+        command: String,
+        config: &config::Config,
+        started: DateTime<Utc>,
+        finished: DateTime<Utc>,
+        result: &Result<()>,
+    ) -> Self {
+        Self {
+            tool_version: env!("CARGO_PKG_VERSION").to_owned(),
+            command,
+            arguments: std::env::args().skip(1).collect(),
+            config_hash: config.source_hash.clone(),
+            started: started.to_rfc3339(),
+            finished: finished.to_rfc3339(),
+            succeeded: result.is_ok(),
+            error: result.as_ref().err().map(|e| format!("{:#}", e)),
+            devices: BTreeMap::new(),
+            groups: BTreeMap::new(),
+            artifacts: Vec::new(),
+        }
+    }
+
+    /// Groups the devices of the config by site, region and environment, with the ones `devices`
+    /// has a failed phase of.
+    pub fn group_devices(&mut self, config: &config::Config) {
+        for device in crate::FlatenedDevice::iter(&config.root_devices) {
+            let device_id = &device.device.device_id;
+            let failed = self
+                .devices
+                .get(device_id)
+                .map_or(false, |phases| phases.values().any(|p| !p.succeeded));
+            for (name, value) in device.device.groups() {
+                let group = self
+                    .groups
+                    .entry(format!("{}={}", name, value))
+                    .or_default();
+                group.devices.push(device_id.clone());
+                if failed {
+                    group.failed.push(device_id.clone());
+                }
+            }
+        }
+    }
+}
+
+/// Files under `dir`, relative to it with `/` separators, sorted.
+pub fn artifacts(dir: &Path) -> Vec<String> {
+    let mut artifacts: Vec<String> = WalkDir::new(dir)
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_type().is_file())
+        .filter_map(|e| {
+            let path = e.path().strip_prefix(dir).ok()?;
+            Some(
+                path.components()
+                    .map(|c| c.as_os_str().to_string_lossy())
+                    .collect::<Vec<_>>()
+                    .join("/"),
+            )
+        })
+        .filter(|p| p != "run.json")
+        .collect();
+    artifacts.sort();
+
+    artifacts
+}
+
+pub async fn write(path: &Path, manifest: &RunManifest) -> Result<()> {
+    fs::write(path, serde_json::to_string_pretty(manifest)?).await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_artifacts() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(dir.path().join("top-layer")).unwrap();
+        std::fs::write(dir.path().join("top-layer").join("config.toml"), "").unwrap();
+        std::fs::write(dir.path().join("state.json"), "").unwrap();
+        std::fs::write(dir.path().join("run.json"), "").unwrap();
+
+        assert_eq!(
+            artifacts(dir.path()),
+            vec!["state.json", "top-layer/config.toml"]
+        );
+    }
+}
@@ -0,0 +1,257 @@
+use anyhow::{Context, Result};
+use chrono::{Duration, Utc};
+
+use crate::commands::CommandRunner;
+use crate::config;
+use crate::log::LogLevel;
+use crate::{hmac_sha256, FileManager, SecretManager};
+
+/// The `key=value;key=value` connection strings handed out by IoT Hub.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConnectionString {
+    pub host_name: String,
+    pub device_id: Option<String>,
+    pub shared_access_key_name: Option<String>,
+    pub shared_access_key: Option<String>,
+}
+
+impl std::str::FromStr for ConnectionString {
+    type Err = anyhow::Error;
+    fn from_str(string: &str) -> Result<Self> {
+        let mut host_name = None;
+        let mut device_id = None;
+        let mut shared_access_key_name = None;
+        let mut shared_access_key = None;
+
+        for part in string.trim().split(';').filter(|p| !p.is_empty()) {
+            // Keys are base64, so only split on the first '='
+            let (key, value) = part.split_once('=').ok_or_else(|| {
+                anyhow::Error::msg(format!("Invalid connection string segment {:?}", part))
+            })?;
+            let value = Some(value.to_owned());
+            match key {
+                "HostName" => host_name = value,
+                "DeviceId" => device_id = value,
+                "SharedAccessKeyName" => shared_access_key_name = value,
+                "SharedAccessKey" => shared_access_key = value,
+                _ => (),
+            }
+        }
+
+        Ok(Self {
+            host_name: host_name
+                .ok_or_else(|| anyhow::Error::msg("Connection string is missing HostName"))?,
+            device_id,
+            shared_access_key_name,
+            shared_access_key,
+        })
+    }
+}
+
+pub struct TokenManager<'a> {
+    config: &'a config::Config,
+    file_manager: &'a FileManager,
+    secret_manager: &'a SecretManager<'a>,
+    runner: &'a CommandRunner,
+}
+
+impl<'a> TokenManager<'a> {
+    pub fn new(
+        config: &'a config::Config,
+        file_manager: &'a FileManager,
+        secret_manager: &'a SecretManager,
+        runner: &'a CommandRunner,
+    ) -> Self {
+        Self {
+            config,
+            file_manager,
+            secret_manager,
+            runner,
+        }
+    }
+
+    /// Makes a SAS token scoped to the device. Signed with the device's own key, or with the
+    /// hub shared access policy when one is given.
+    pub async fn make_token(
+        &self,
+        device_id: &str,
+        policy: Option<&str>,
+        ttl: Duration,
+    ) -> Result<String> {
+        let key = match policy {
+            Some(policy) => self.policy_key(policy).await?,
+            None => self.device_key(device_id).await?,
+        };
+        let resource_uri = format!(
+            "{}/devices/{}",
+            self.config.iothub.iothub_hostname, device_id
+        );
+
+        self.file_manager
+            .log(
+                LogLevel::Debug,
+                "sas",
+                Some(device_id),
+                format!(
+                    "Making SAS token for {} valid for {} minutes.",
+                    resource_uri,
+                    ttl.num_minutes()
+                ),
+            )
+            .await?;
+
+        self.sign(&resource_uri, &key, policy, ttl).await
+    }
+
+    pub async fn sign(
+        &self,
+        resource_uri: &str,
+        key: &str,
+        policy: Option<&str>,
+        ttl: Duration,
+    ) -> Result<String> {
+        let expiry = (Utc::now() + ttl).timestamp();
+
+        signed_token(resource_uri, key, policy, expiry)
+    }
+
+    async fn device_key(&self, device_id: &str) -> Result<String> {
+        if let Some(connection_string) = self.secret_manager.read(device_id).await? {
+            let connection_string: ConnectionString = connection_string.parse()?;
+            if let Some(key) = connection_string.shared_access_key {
+                return Ok(key);
+            }
+        }
+
+        let key = self
+            .az_query(
+                &[
+                    "az iot hub device-identity show",
+                    "--device-id",
+                    device_id,
+                    "--hub-name",
+                    &self.config.iothub.iothub_name,
+                    "--query",
+                    "authentication.symmetricKey.primaryKey",
+                ],
+                device_id,
+            )
+            .await?;
+        self.secret_manager.cache_key(device_id, &key).await?;
+
+        Ok(key)
+    }
+
+    async fn policy_key(&self, policy: &str) -> Result<String> {
+        if let Some(connection_string) = &self.config.iothub.connection_string {
+            let connection_string: ConnectionString = connection_string.parse()?;
+            if connection_string.shared_access_key_name.as_deref() == Some(policy) {
+                if let Some(key) = connection_string.shared_access_key {
+                    return Ok(key);
+                }
+            }
+        }
+
+        self.az_query(
+            &[
+                "az iot hub policy show",
+                "--name",
+                policy,
+                "--hub-name",
+                &self.config.iothub.iothub_name,
+                "--query",
+                "primaryKey",
+            ],
+            policy,
+        )
+        .await
+    }
+
+    async fn az_query(&self, args: &[&str], name: &str) -> Result<String> {
+        let mut args = args.to_vec();
+        args.extend(&["-o", "tsv"]);
+
+        let command = self.runner.output(&args).await?;
+        let key = String::from_utf8_lossy(&command.stdout).trim().to_owned();
+        if command.status.success() && !key.is_empty() {
+            Ok(key)
+        } else {
+            Err(anyhow::Error::msg(format!(
+                "Failed to get shared access key for {}:\n{}",
+                name,
+                String::from_utf8_lossy(&command.stderr)
+            )))
+        }
+    }
+}
+
+/// A SAS token for the resource, valid until `expiry` in seconds since the epoch.
+pub fn signed_token(
+    resource_uri: &str,
+    key: &str,
+    policy: Option<&str>,
+    expiry: i64,
+) -> Result<String> {
+    let resource_uri = url_encode(resource_uri);
+
+    let key = base64::decode(key).context("Shared access key is not valid base64")?;
+    let to_sign = format!("{}\n{}", resource_uri, expiry);
+    let signature = hmac_sha256(&key, to_sign.as_bytes())?;
+
+    let mut token = format!(
+        "SharedAccessSignature sr={}&sig={}&se={}",
+        resource_uri,
+        url_encode(&base64::encode(signature)),
+        expiry
+    );
+    if let Some(policy) = policy {
+        token.push_str(&format!("&skn={}", url_encode(policy)));
+    }
+
+    Ok(token)
+}
+
+fn url_encode(value: &str) -> String {
+    url::form_urlencoded::byte_serialize(value.as_bytes()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_connection_string() {
+        let connection_string: ConnectionString =
+            "HostName=hub.azure-devices.net;SharedAccessKeyName=iothubowner;SharedAccessKey=a2V5=="
+                .parse()
+                .unwrap();
+
+        assert_eq!(connection_string.host_name, "hub.azure-devices.net");
+        assert_eq!(connection_string.device_id, None);
+        assert_eq!(
+            connection_string.shared_access_key_name.as_deref(),
+            Some("iothubowner")
+        );
+        assert_eq!(
+            connection_string.shared_access_key.as_deref(),
+            Some("a2V5==")
+        );
+
+        assert!("DeviceId=device".parse::<ConnectionString>().is_err());
+    }
+
+    #[test]
+    fn test_signed_token() {
+        assert_eq!(
+            signed_token(
+                "hub.azure-devices.net/devices/A",
+                "a2V5",
+                Some("iothubowner"),
+                1600000000
+            )
+            .unwrap(),
+            "SharedAccessSignature sr=hub.azure-devices.net%2Fdevices%2FA&sig=Ih%2BhzusJ55s8IfgqcmcMA0wbDJhUzGEzaGLCL7aiUL0%3D&se=1600000000&skn=iothubowner"
+        );
+        assert!(signed_token("hub", "not base64!", None, 0).is_err());
+    }
+}
@@ -0,0 +1,264 @@
+use std::collections::HashSet;
+use std::path::Path;
+
+use anyhow::Result;
+use chrono::{DateTime, Datelike, FixedOffset, NaiveTime, Utc, Weekday};
+
+use crate::config;
+use crate::log::LogLevel;
+use crate::state;
+use crate::tags::Selection;
+use crate::{FileManager, FlatenedDevice};
+
+/// A maintenance window of the config, parsed.
+#[derive(Debug)]
+struct Window {
+    name: String,
+    selection: Selection,
+    days: Vec<Weekday>,
+    start: NaiveTime,
+    end: NaiveTime,
+    offset: FixedOffset,
+}
+
+impl Window {
+    fn parse(window: &config::MaintenanceWindow) -> Result<Self> {
+        let error = |field: &str, value: &str, expected: &str| {
+            anyhow::Error::msg(format!(
+                "Did not recognize {} of maintenance window {}: {}. Use {}.",
+                field, window.name, value, expected
+            ))
+        };
+        let time = |field: &str, value: &str| {
+            NaiveTime::parse_from_str(value, "%H:%M").map_err(|_| error(field, value, "HH:MM"))
+        };
+
+        Ok(Self {
+            name: window.name.clone(),
+            selection: window.devices.as_deref().unwrap_or("all").parse()?,
+            days: window
+                .days
+                .iter()
+                .map(|d| d.parse().map_err(|_| error("day", d, "mon to sun")))
+                .collect::<Result<_>>()?,
+            start: time("start", &window.start)?,
+            end: time("end", &window.end)?,
+            offset: match &window.utc_offset {
+                Some(offset) => parse_offset(offset)
+                    .ok_or_else(|| error("utc_offset", offset, "+HH:MM or -HH:MM"))?,
+                None => FixedOffset::east(0),
+            },
+        })
+    }
+
+    fn is_open(&self, now: DateTime<Utc>) -> bool {
+        let local = now.with_timezone(&self.offset);
+        let time = local.time();
+        // The day the window that is open now started on
+        let day = if self.start <= self.end {
+            if time < self.start || time >= self.end {
+                return false;
+            }
+            local.weekday()
+        } else if time >= self.start {
+            local.weekday()
+        } else if time < self.end {
+            local.weekday().pred()
+        } else {
+            return false;
+        };
+
+        self.days.is_empty() || self.days.contains(&day)
+    }
+}
+
+fn parse_offset(offset: &str) -> Option<FixedOffset> {
+    let (sign, rest) = match offset.as_bytes().first() {
+        Some(b'+') => (1, &offset[1..]),
+        Some(b'-') => (-1, &offset[1..]),
+        _ => return None,
+    };
+    let (hours, minutes) = rest.split_once(':')?;
+    let seconds = hours.parse::<i32>().ok()? * 3600 + minutes.parse::<i32>().ok()? * 60;
+
+    FixedOffset::east_opt(sign * seconds)
+}
+
+/// Holds `push` and `deploy apply` to the maintenance windows of `schedule`, queueing the
+/// devices of an operation in the state file until they are done.
+pub struct ScheduleManager<'a> {
+    config: &'a config::Config,
+    file_manager: &'a FileManager,
+    state_path: &'a Path,
+}
+
+impl<'a> ScheduleManager<'a> {
+    pub fn new(
+        config: &'a config::Config,
+        file_manager: &'a FileManager,
+        state_path: &'a Path,
+    ) -> Self {
+        Self {
+            config,
+            file_manager,
+            state_path,
+        }
+    }
+
+    /// The devices the operation can change now: those in an open window or in none. If an
+    /// earlier run queued devices for the operation, only those are considered. They all stay
+    /// queued until `done`, so the next run resumes with the ones outside their windows and the
+    /// ones that failed.
+    pub async fn due<'d>(
+        &self,
+        operation: &str,
+        devices: Vec<FlatenedDevice<'d>>,
+    ) -> Result<Vec<FlatenedDevice<'d>>> {
+        if self.config.schedule.is_empty() {
+            return Ok(devices);
+        }
+        let windows = self
+            .config
+            .schedule
+            .iter()
+            .map(Window::parse)
+            .collect::<Result<Vec<_>>>()?;
+
+        let mut state = self.read_state().await?;
+        let devices: Vec<FlatenedDevice> = match state.queued.get(operation) {
+            Some(queued) => {
+                self.log(format!(
+                    "Resuming {} with the {} devices queued by an earlier run",
+                    operation,
+                    queued.len()
+                ))
+                .await?;
+                devices
+                    .into_iter()
+                    .filter(|d| queued.contains(&d.device.device_id))
+                    .collect()
+            }
+            None => devices,
+        };
+
+        let all = FlatenedDevice::flatten_devices(&self.config.root_devices);
+        let members = windows
+            .iter()
+            .map(|w| {
+                let members: HashSet<&str> = w
+                    .selection
+                    .select(&all)?
+                    .into_iter()
+                    .map(|d| d.device.device_id.as_str())
+                    .collect();
+                Ok((w, members))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let now = self.file_manager.now();
+        let mut closed = Vec::new();
+        let (due, waiting): (Vec<_>, Vec<_>) = devices.iter().partition(|d| {
+            let windows: Vec<&Window> = members
+                .iter()
+                .filter(|(_, m)| m.contains(d.device.device_id.as_str()))
+                .map(|(w, _)| *w)
+                .collect();
+            let open = windows.is_empty() || windows.iter().any(|w| w.is_open(now));
+            if !open {
+                closed.extend(windows.iter().map(|w| w.name.as_str()));
+            }
+            open
+        });
+
+        if !waiting.is_empty() {
+            closed.sort_unstable();
+            closed.dedup();
+            self.log(format!(
+                "{} devices are outside maintenance windows {} and queued for a later {}: {}",
+                waiting.len(),
+                closed.join(", "),
+                operation,
+                waiting
+                    .iter()
+                    .map(|d| d.device.device_id.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ))
+            .await?;
+        }
+        if devices.is_empty() {
+            state.queued.remove(operation);
+        } else {
+            state.queued.insert(
+                operation.to_owned(),
+                devices.iter().map(|d| d.device.device_id.clone()).collect(),
+            );
+        }
+        state::write(self.state_path, &state).await?;
+
+        Ok(due.into_iter().copied().collect())
+    }
+
+    /// Takes devices the operation succeeded on off its queue.
+    pub async fn done(&self, operation: &str, device_ids: &[&str]) -> Result<()> {
+        if self.config.schedule.is_empty() || device_ids.is_empty() {
+            return Ok(());
+        }
+
+        let mut state = self.read_state().await?;
+        if let Some(queued) = state.queued.get_mut(operation) {
+            queued.retain(|d| !device_ids.contains(&d.as_str()));
+            if queued.is_empty() {
+                state.queued.remove(operation);
+                self.log(format!("Every queued device of {} is done", operation))
+                    .await?;
+            }
+        }
+
+        state::write(self.state_path, &state).await
+    }
+
+    async fn read_state(&self) -> Result<state::State> {
+        Ok(state::read(self.state_path)
+            .await?
+            .unwrap_or_else(|| state::State::new(self.config, self.file_manager.now())))
+    }
+
+    async fn log(&self, message: String) -> Result<()> {
+        self.file_manager
+            .log(LogLevel::Info, "schedule", None, message)
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_window_is_open() {
+        let config = |start: &str, utc_offset: &str| config::MaintenanceWindow {
+            name: "site-a".to_owned(),
+            devices: Some("subtree:site-a-gateway".to_owned()),
+            days: vec!["sat".to_owned()],
+            start: start.to_owned(),
+            end: "04:00".to_owned(),
+            utc_offset: Some(utc_offset.to_owned()),
+        };
+        let window = Window::parse(&config("22:00", "+02:00")).unwrap();
+        assert_eq!(
+            window.selection,
+            Selection::Subtree("site-a-gateway".to_owned())
+        );
+
+        // 2024-06-01 is a Saturday
+        assert!(window.is_open(Utc.ymd(2024, 6, 1).and_hms(20, 30, 0)));
+        // Past midnight, the window still started on Saturday
+        assert!(window.is_open(Utc.ymd(2024, 6, 1).and_hms(23, 30, 0)));
+        assert!(!window.is_open(Utc.ymd(2024, 6, 1).and_hms(19, 0, 0)));
+        assert!(!window.is_open(Utc.ymd(2024, 6, 2).and_hms(20, 30, 0)));
+
+        assert!(Window::parse(&config("25:00", "+02:00")).is_err());
+        assert!(Window::parse(&config("22:00", "2")).is_err());
+    }
+}
@@ -0,0 +1,10 @@
+# ======================= Check Parent Ports =======================================
+
+parent_hostname=$(sed -n 's/^parent_hostname = "\(.*\)"$/\1/p' /etc/aziot/config.toml | tr -d '[]')
+for port in {ports}
+do
+    if ! timeout 5 bash -c "</dev/tcp/$parent_hostname/$port" 2>/dev/null
+    then
+        echo "WARNING: $parent_hostname is not reachable on port $port. Open it in the firewall between this device and its parent."
+    fi
+done
@@ -0,0 +1,2 @@
+# ======================= Copy Config =======================================
+cp config.toml /etc/aziot/config.toml
\ No newline at end of file
@@ -2,4 +2,8 @@
 # It must be run as sudo, and will modify the ca
 
 device_id={device_id:?}
-cp config.toml /etc/aziot/config.toml
\ No newline at end of file
+root_ca_cert={root_ca_cert:?}
+device_ca_chain={device_ca_chain:?}
+device_ca_key={device_ca_key:?}
+hub_auth_cert={hub_auth_cert:?}
+hub_auth_key={hub_auth_key:?}
\ No newline at end of file
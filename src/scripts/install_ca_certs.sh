@@ -4,14 +4,14 @@ then
         . /etc/os-release
         if [[ "$NAME" == "Common Base Linux Mariner"* ]];
         then
-                cp iotedge_config_cli_root.pem /etc/pki/ca-trust/source/anchors/iotedge_config_cli_root.pem.crt
+                cp "$root_ca_cert" /etc/pki/ca-trust/source/anchors/iotedge_config_cli_root.pem.crt
                 update-ca-trust
         else
-                cp iotedge_config_cli_root.pem /usr/local/share/ca-certificates/iotedge_config_cli_root.pem.crt
+                cp "$root_ca_cert" /usr/local/share/ca-certificates/iotedge_config_cli_root.pem.crt
                 update-ca-certificates
         fi
 else
-        cp iotedge_config_cli_root.pem /usr/local/share/ca-certificates/iotedge_config_cli_root.pem.crt
+        cp "$root_ca_cert" /usr/local/share/ca-certificates/iotedge_config_cli_root.pem.crt
         update-ca-certificates
 fi
 
@@ -20,6 +20,6 @@ systemctl restart docker
 # ======================= Copy device certs  =======================================
 cert_dir="/etc/aziot/certificates"
 mkdir -p $cert_dir
-cp "iotedge_config_cli_root.pem" "$cert_dir/iotedge_config_cli_root.pem"
-cp "$device_id.full-chain.cert.pem" "$cert_dir/$device_id.full-chain.cert.pem"
-cp "$device_id.key.pem" "$cert_dir/$device_id.key.pem"
\ No newline at end of file
+cp "$root_ca_cert" "$cert_dir/$root_ca_cert"
+cp "$device_ca_chain" "$cert_dir/$device_ca_chain"
+cp "$device_ca_key" "$cert_dir/$device_ca_key"
\ No newline at end of file
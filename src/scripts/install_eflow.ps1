@@ -0,0 +1,39 @@
+# This script will configure IoT Edge for Linux on Windows as a nested node, deploying its VM
+# first if there is none. Run it from an elevated PowerShell prompt in this folder.
+
+$ErrorActionPreference = "Stop"
+$deviceId = {device_id:?}
+$vmFolder = "/home/iotedge-user/$deviceId"
+
+if (-not (Get-Module -ListAvailable -Name AzureEFLOW)) {{
+    Write-Error "The AzureEFLOW module is missing. Install IoT Edge for Linux on Windows first: https://aka.ms/AzEFLOWMSI"
+}}
+Import-Module AzureEFLOW
+
+# ======================= Read User Input =======================================
+$hostname = ""
+if ({prompt_hostname}) {{
+    $hostname = Read-Host "Enter the hostname to use"
+}}
+$parentHostname = ""
+if ({prompt_parent_hostname}) {{
+    $parentHostname = Read-Host "Enter the parent hostname to use"
+}}
+
+# ======================= Deploy the EFLOW VM =======================================
+try {{
+    Get-EflowVm | Out-Null
+}} catch {{
+    Write-Host "Deploying the EFLOW VM"
+    Deploy-Eflow -acceptEula Yes -acceptOptionalTelemetry No
+}}
+
+# ======================= Copy the device files =======================================
+Write-Host "Copying the files of $deviceId to $vmFolder in the EFLOW VM"
+Invoke-EflowVmCommand "mkdir -p '$vmFolder'"
+Get-ChildItem -File -Path $PSScriptRoot | Where-Object {{ $_.Extension -ne ".ps1" }} | ForEach-Object {{
+    Copy-EflowVmFile -fromFile $_.FullName -toFile "$vmFolder/$($_.Name)" -pushFile
+}}
+
+# ======================= Apply the config in the VM =======================================
+Invoke-EflowVmCommand "cd '$vmFolder' && sudo IOTEDGE_HOSTNAME='$hostname' IOTEDGE_PARENT_HOSTNAME='$parentHostname' bash ./install.sh"
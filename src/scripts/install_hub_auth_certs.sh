@@ -1,5 +1,5 @@
 # ======================= Copy hub auth certs  =======================================
 cert_dir="/etc/aziot/certificates"
 mkdir -p $cert_dir
-cp "$device_id.hub-auth.cert.pem" "$cert_dir/$device_id.hub-auth.cert.pem"
-cp "$device_id.hub-auth.key.pem" "$cert_dir/$device_id.hub-auth.key.pem"
\ No newline at end of file
+cp "$hub_auth_cert" "$cert_dir/$hub_auth_cert"
+cp "$hub_auth_key" "$cert_dir/$hub_auth_key"
\ No newline at end of file
@@ -1,6 +1,11 @@
 # ======================= Set Hostname =======================================
 
-read -p "Enter the hostname to use: " hostname
+# Set by install.ps1 on EFLOW hosts, which can't answer prompts in the VM
+hostname="$IOTEDGE_HOSTNAME"
+if [ -z "$hostname" ]
+then
+    read -p "Enter the hostname to use: " hostname
+fi
 if [ -z "$hostname" ]
 then
     echo "Invalid hostname $hostname"
@@ -1,10 +1,21 @@
 # ======================= Set Parent Hostname =======================================
 
-read -p "Enter the parent hostname to use: " parent_hostname
+# Set by install.ps1 on EFLOW hosts, which can't answer prompts in the VM
+parent_hostname="$IOTEDGE_PARENT_HOSTNAME"
+if [ -z "$parent_hostname" ]
+then
+    read -p "Enter the parent hostname to use: " parent_hostname
+fi
 if [ -z "$parent_hostname" ]
 then
     echo "Invalid parent hostname $parent_hostname"
     exit 1
 fi
 
+# IPv6 addresses go in brackets, since the parent hostname is put in front of ports
+case "$parent_hostname" in
+    \[*) ;;
+    *:*) parent_hostname="[$parent_hostname]" ;;
+esac
+
 sed -i "s/{{PARENT_HOSTNAME}}/$parent_hostname/" /etc/aziot/config.toml
\ No newline at end of file
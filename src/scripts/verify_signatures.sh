@@ -0,0 +1,10 @@
+# ======================= Verify Signatures =======================================
+
+for file in config.toml deployment.json "$root_ca_cert" "$device_ca_chain" "$device_ca_key" "$hub_auth_cert" "$hub_auth_key"
+do
+    if [ -f "$file" ] && ! {verify}
+    then
+        echo "ERROR: $file does not have a valid signature. Not applying it."
+        exit 1
+    fi
+done
\ No newline at end of file
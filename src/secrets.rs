@@ -0,0 +1,410 @@
+use std::path::Path;
+
+use anyhow::Result;
+use tokio::process::Command;
+
+use crate::commands::CommandExt;
+use crate::config;
+use crate::key_cache::KeyCache;
+use crate::log::LogLevel;
+use crate::{ConnectionString, CreatedDevice, FileManager};
+
+const SERVICE: &str = "iotedge_config_cli";
+
+/// Where config.toml expects the device key when it is kept in the credential store instead of
+/// written into config.toml.
+pub const DEVICE_KEY_URI: &str = "file:///var/secrets/aziot/device-id.key";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SecretStore {
+    None,
+    Keychain,
+}
+
+impl std::str::FromStr for SecretStore {
+    type Err = anyhow::Error;
+    fn from_str(string: &str) -> Result<Self> {
+        let result = match string.to_lowercase().as_str() {
+            "none" => Self::None,
+            "keychain" => Self::Keychain,
+            _ => {
+                return Err(anyhow::Error::msg(format!(
+                    "Did not recognize secret store: {}",
+                    string
+                )))
+            }
+        };
+
+        Ok(result)
+    }
+}
+
+impl SecretStore {
+    /// The passphrase of a key file kept in the store, if there is one.
+    pub async fn passphrase(self, key_path: &Path) -> Option<String> {
+        match self {
+            Self::None => None,
+            Self::Keychain => read_secret(&passphrase_account(key_path)).await.ok(),
+        }
+    }
+
+    /// Keeps the passphrase of a key file in the store, so later runs don't prompt for it.
+    pub async fn keep_passphrase(self, key_path: &Path, passphrase: &str) -> Result<()> {
+        match self {
+            Self::None => Ok(()),
+            Self::Keychain => store_secret(&passphrase_account(key_path), passphrase).await,
+        }
+    }
+}
+
+/// Keeps device connection strings in the OS credential store (Windows Credential Manager,
+/// macOS Keychain or Secret Service) so they can be retrieved later with `secrets show`, and
+/// device keys in the `--key-cache`.
+pub struct SecretManager<'a> {
+    config: &'a config::Config,
+    file_manager: &'a FileManager,
+    store: SecretStore,
+    key_cache: Option<&'a KeyCache>,
+}
+
+impl<'a> SecretManager<'a> {
+    pub fn new(
+        config: &'a config::Config,
+        file_manager: &'a FileManager,
+        store: SecretStore,
+        key_cache: Option<&'a KeyCache>,
+    ) -> Self {
+        Self {
+            config,
+            file_manager,
+            store,
+            key_cache,
+        }
+    }
+
+    pub async fn store_device_secrets(&self, devices: &[CreatedDevice<'_>]) -> Result<()> {
+        if let Some(key_cache) = self.key_cache {
+            for device in devices {
+                if let Some(key) = &device
+                    .create_response
+                    .authentication
+                    .symmetric_key
+                    .primary_key
+                {
+                    key_cache
+                        .insert(
+                            &self.config.iothub.iothub_name,
+                            &device.device.device_id,
+                            key,
+                        )
+                        .await;
+                }
+            }
+            key_cache.save().await?;
+        }
+        if self.store == SecretStore::None {
+            return Ok(());
+        }
+
+        let secrets: Vec<(&str, String)> = devices
+            .iter()
+            .filter_map(|device| {
+                self.connection_string(device)
+                    .map(|secret| (device.device.device_id.as_str(), secret))
+            })
+            .collect();
+
+        self.file_manager
+            .log(
+                LogLevel::Info,
+                "secrets",
+                None,
+                format!(
+                    "Storing {} connection strings in the OS credential store.",
+                    secrets.len()
+                ),
+            )
+            .await?;
+
+        for (device_id, secret) in secrets {
+            self.file_manager
+                .log(
+                    LogLevel::Debug,
+                    "secrets",
+                    Some(device_id),
+                    format!("Storing connection string for {}.", device_id),
+                )
+                .await?;
+            store_secret(&self.account(device_id), &secret).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Prints the stored connection string of the device, or with `key_only` just its key, to
+    /// write to the device where config.toml refers to it.
+    pub async fn show(&self, device_id: &str, key_only: bool) -> Result<()> {
+        // Printed directly so the secret does not end up in the log file
+        let secret = read_secret(&self.account(device_id)).await?;
+        if key_only {
+            let connection_string: ConnectionString = secret.parse()?;
+            println!(
+                "{}",
+                connection_string.shared_access_key.unwrap_or_default()
+            );
+        } else {
+            println!("{}", secret);
+        }
+
+        Ok(())
+    }
+
+    /// Reads the stored connection string of a device, from the key cache or the credential store.
+    pub async fn read(&self, device_id: &str) -> Result<Option<String>> {
+        if let Some(key_cache) = self.key_cache {
+            if let Some(key) = key_cache
+                .get(&self.config.iothub.iothub_name, device_id)
+                .await
+            {
+                self.file_manager
+                    .log(
+                        LogLevel::Debug,
+                        "secrets",
+                        Some(device_id),
+                        format!("Using cached key for {}.", device_id),
+                    )
+                    .await?;
+                return Ok(Some(self.format_connection_string(device_id, &key)));
+            }
+        }
+        if self.store == SecretStore::None {
+            return Ok(None);
+        }
+
+        self.file_manager
+            .log(
+                LogLevel::Debug,
+                "secrets",
+                Some(device_id),
+                format!("Reading connection string for {}.", device_id),
+            )
+            .await?;
+
+        read_secret(&self.account(device_id)).await.map(Some)
+    }
+
+    /// Adds a key read from the hub to the key cache, if there is one.
+    pub async fn cache_key(&self, device_id: &str, key: &str) -> Result<()> {
+        if let Some(key_cache) = self.key_cache {
+            key_cache
+                .insert(&self.config.iothub.iothub_name, device_id, key)
+                .await;
+            key_cache.save().await?;
+        }
+
+        Ok(())
+    }
+
+    fn account(&self, device_id: &str) -> String {
+        format!("{}/{}", self.config.iothub.iothub_name, device_id)
+    }
+
+    fn connection_string(&self, device: &CreatedDevice<'_>) -> Option<String> {
+        device
+            .create_response
+            .authentication
+            .symmetric_key
+            .primary_key
+            .as_ref()
+            .map(|key| self.format_connection_string(&device.device.device_id, key))
+    }
+
+    fn format_connection_string(&self, device_id: &str, key: &str) -> String {
+        format!(
+            "HostName={};DeviceId={};SharedAccessKey={}",
+            self.config.iothub.iothub_hostname, device_id, key
+        )
+    }
+}
+
+/// The account a key passphrase is kept under, by the key it unlocks.
+fn passphrase_account(key_path: &Path) -> String {
+    let key_path = std::env::current_dir()
+        .map(|dir| dir.join(key_path))
+        .unwrap_or_else(|_| key_path.to_owned());
+    format!("passphrase/{}", key_path.display())
+}
+
+fn secret_output(output: std::process::Output, action: &str, account: &str) -> Result<String> {
+    if output.status.success() {
+        Ok(String::from_utf8_lossy(&output.stdout).trim().to_owned())
+    } else {
+        Err(anyhow::Error::msg(format!(
+            "Failed to {} secret {} in the OS credential store:\n{}",
+            action,
+            account,
+            String::from_utf8_lossy(&output.stderr)
+        )))
+    }
+}
+
+#[cfg(target_os = "macos")]
+async fn store_secret(account: &str, secret: &str) -> Result<()> {
+    use std::process::Stdio;
+    use tokio::io::AsyncWriteExt;
+
+    // `security -w` only takes the secret as an argument, so the command is fed to its
+    // interactive mode through stdin instead to keep the secret out of the process list
+    let mut child = Command::new("security")
+        .arg("-i")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin
+            .write_all(keychain_command(account, secret).as_bytes())
+            .await?;
+    }
+    let output = crate::commands::wait_bounded(child, "security").await?;
+
+    secret_output(output, "store", account).map(|_| ())
+}
+
+/// The `security -i` line that stores the secret. Its arguments are split like a shell's, with
+/// double quotes.
+#[cfg(any(target_os = "macos", test))]
+fn keychain_command(account: &str, secret: &str) -> String {
+    let quote = |arg: &str| format!("\"{}\"", arg.replace('\\', "\\\\").replace('"', "\\\""));
+    format!(
+        "add-generic-password -U -s {} -a {} -w {}\n",
+        quote(SERVICE),
+        quote(account),
+        quote(secret)
+    )
+}
+
+#[cfg(target_os = "macos")]
+async fn read_secret(account: &str) -> Result<String> {
+    let output = Command::new("security")
+        .args(&["find-generic-password", "-s", SERVICE, "-a", account, "-w"])
+        .bounded_output()
+        .await?;
+
+    secret_output(output, "read", account)
+}
+
+#[cfg(all(unix, not(target_os = "macos")))]
+async fn store_secret(account: &str, secret: &str) -> Result<()> {
+    use std::process::Stdio;
+    use tokio::io::AsyncWriteExt;
+
+    // secret-tool reads the secret from stdin so it never shows up in the process list
+    let mut child = Command::new("secret-tool")
+        .arg("store")
+        .arg(format!("--label={} {}", SERVICE, account))
+        .args(&["service", SERVICE, "account", account])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(secret.as_bytes()).await?;
+    }
+    let output = crate::commands::wait_bounded(child, "secret-tool").await?;
+
+    secret_output(output, "store", account).map(|_| ())
+}
+
+#[cfg(all(unix, not(target_os = "macos")))]
+async fn read_secret(account: &str) -> Result<String> {
+    let output = Command::new("secret-tool")
+        .args(&["lookup", "service", SERVICE, "account", account])
+        .bounded_output()
+        .await?;
+
+    secret_output(output, "read", account)
+}
+
+#[cfg(windows)]
+const PASSWORD_VAULT: &str = "[void][Windows.Security.Credentials.PasswordVault,Windows.Security.Credentials,ContentType=WindowsRuntime]; $vault = New-Object Windows.Security.Credentials.PasswordVault;";
+
+#[cfg(windows)]
+async fn store_secret(account: &str, secret: &str) -> Result<()> {
+    // Values are passed through the environment to avoid quoting them into the script
+    let output = Command::new("powershell.exe")
+        .arg("-NoProfile")
+        .arg("-Command")
+        .arg(format!(
+            "{} $vault.Add((New-Object Windows.Security.Credentials.PasswordCredential($env:IOTEDGE_CONFIG_SERVICE, $env:IOTEDGE_CONFIG_ACCOUNT, $env:IOTEDGE_CONFIG_SECRET)))",
+            PASSWORD_VAULT
+        ))
+        .env("IOTEDGE_CONFIG_SERVICE", SERVICE)
+        .env("IOTEDGE_CONFIG_ACCOUNT", account)
+        .env("IOTEDGE_CONFIG_SECRET", secret)
+        .bounded_output()
+        .await?;
+
+    secret_output(output, "store", account).map(|_| ())
+}
+
+#[cfg(windows)]
+async fn read_secret(account: &str) -> Result<String> {
+    let output = Command::new("powershell.exe")
+        .arg("-NoProfile")
+        .arg("-Command")
+        .arg(format!(
+            "{} $credential = $vault.Retrieve($env:IOTEDGE_CONFIG_SERVICE, $env:IOTEDGE_CONFIG_ACCOUNT); $credential.RetrievePassword(); $credential.Password",
+            PASSWORD_VAULT
+        ))
+        .env("IOTEDGE_CONFIG_SERVICE", SERVICE)
+        .env("IOTEDGE_CONFIG_ACCOUNT", account)
+        .bounded_output()
+        .await?;
+
+    secret_output(output, "read", account)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_secret_store() {
+        assert_eq!(
+            "Keychain".parse::<SecretStore>().unwrap(),
+            SecretStore::Keychain
+        );
+        assert_eq!("none".parse::<SecretStore>().unwrap(), SecretStore::None);
+        assert!("vault".parse::<SecretStore>().is_err());
+    }
+
+    #[test]
+    fn test_keychain_command() {
+        assert_eq!(
+            keychain_command("hub/A", r#"Key="a\b""#),
+            "add-generic-password -U -s \"iotedge_config_cli\" -a \"hub/A\" -w \"Key=\\\"a\\\\b\\\"\"\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_passphrases() {
+        let key_path = Path::new("root.key.pem");
+        assert_eq!(
+            passphrase_account(key_path),
+            format!(
+                "passphrase/{}",
+                std::env::current_dir().unwrap().join(key_path).display()
+            )
+        );
+
+        // Nothing is kept without a store
+        SecretStore::None
+            .keep_passphrase(key_path, "secret")
+            .await
+            .unwrap();
+        assert_eq!(SecretStore::None.passphrase(key_path).await, None);
+    }
+}
@@ -0,0 +1,215 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use tokio::fs;
+use tokio::process::Command;
+
+use crate::commands::CommandExt;
+use crate::config::{self, SigningTool};
+use crate::log::LogLevel;
+use crate::{CreatedDevice, FileManager};
+
+/// Name of the cosign public key in the device folders.
+const PUBLIC_KEY_FILE: &str = "signing.pub";
+
+/// Signs the generated bundles with the key of `signing`, each signature next to its file.
+pub struct SigningManager<'a> {
+    config: &'a config::Config,
+    file_manager: &'a FileManager,
+}
+
+impl<'a> SigningManager<'a> {
+    pub fn new(config: &'a config::Config, file_manager: &'a FileManager) -> Self {
+        Self {
+            config,
+            file_manager,
+        }
+    }
+
+    pub fn check(&self) -> Result<()> {
+        match &self.config.signing {
+            Some(signing)
+                if signing.tool == SigningTool::Cosign
+                    && signing.verify_on_install
+                    && signing.public_key.is_none() =>
+            {
+                Err(anyhow::Error::msg(
+                    "signing.verify_on_install with cosign needs signing.public_key",
+                ))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Signs the config, deployment, certs and install script of every device, and copies the cosign
+    /// public key into the folders. Done before zipping so the signatures are in the zips.
+    pub async fn sign_device_files(&self, devices: &[CreatedDevice<'_>]) -> Result<()> {
+        let signing = match &self.config.signing {
+            Some(signing) => signing,
+            None => return Ok(()),
+        };
+
+        for device in devices {
+            let device_id = device.device.device_id.as_str();
+            let folder = self.file_manager.get_folder(device_id).await?;
+            for file in signed_files(&self.config.cert_names, device_id) {
+                let path = folder.join(file);
+                if path.exists() {
+                    self.sign(&path).await?;
+                }
+            }
+            if let Some(public_key) = &signing.public_key {
+                fs::copy(public_key, folder.join(PUBLIC_KEY_FILE))
+                    .await
+                    .with_context(|| format!("Error copying public key {:?}", public_key))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Signs the zip of every device.
+    pub async fn sign_zips(&self, devices: &[CreatedDevice<'_>]) -> Result<()> {
+        if self.config.signing.is_none() {
+            return Ok(());
+        }
+
+        for device in devices {
+            let folder = self
+                .file_manager
+                .get_folder(&device.device.device_id)
+                .await?;
+            self.sign(&FileManager::path_to_zip(folder)).await?;
+        }
+
+        Ok(())
+    }
+
+    pub async fn sign(&self, path: &Path) -> Result<()> {
+        let signing = match &self.config.signing {
+            Some(signing) => signing,
+            None => return Ok(()),
+        };
+        let signature = signature_path(signing.tool, path);
+        self.file_manager
+            .log(
+                LogLevel::Debug,
+                "signing",
+                None,
+                format!("Signing {:?} to {:?}", path, signature),
+            )
+            .await?;
+
+        let mut command = match signing.tool {
+            SigningTool::Cosign => {
+                let mut command = Command::new("cosign");
+                command
+                    .args(&["sign-blob", "--yes", "--key", &signing.key])
+                    .arg("--output-signature")
+                    .arg(&signature);
+                command
+            }
+            SigningTool::Notation => {
+                let mut command = Command::new("notation");
+                command
+                    .args(&["blob", "sign", "--key", &signing.key])
+                    .arg("--signature-directory")
+                    .arg(path.parent().unwrap_or_else(|| Path::new(".")));
+                command
+            }
+        };
+        let output = command
+            .arg(path)
+            .bounded_output()
+            .await
+            .with_context(|| format!("Error running {:?}", signing.tool))?;
+        if !output.status.success() {
+            return Err(anyhow::Error::msg(format!(
+                "Failed to sign {:?}:\n{}",
+                path,
+                String::from_utf8_lossy(&output.stderr)
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+/// Where the tool writes the signature of a file.
+pub fn signature_path(tool: SigningTool, path: &Path) -> PathBuf {
+    let mut signature = path.as_os_str().to_owned();
+    signature.push(match tool {
+        SigningTool::Cosign => ".sig",
+        SigningTool::Notation => ".jws.sig",
+    });
+
+    PathBuf::from(signature)
+}
+
+/// install.sh step that stops before applying files without a valid signature.
+/// Files of a device folder that are signed before it is zipped: install.sh and the files it
+/// installs.
+fn signed_files(names: &config::CertNames, device_id: &str) -> Vec<String> {
+    vec![
+        "config.toml".to_owned(),
+        "deployment.json".to_owned(),
+        "install.sh".to_owned(),
+        names.root_ca_cert.clone(),
+        names.device_ca_chain(device_id),
+        names.device_ca_key(device_id),
+        names.hub_auth_cert(device_id),
+        names.hub_auth_key(device_id),
+    ]
+}
+
+pub fn verify_script(signing: &config::Signing) -> String {
+    let verify = match signing.tool {
+        SigningTool::Cosign => format!(
+            r#"cosign verify-blob --key {} --signature "$file.sig" "$file""#,
+            PUBLIC_KEY_FILE
+        ),
+        SigningTool::Notation => {
+            r#"notation blob verify --signature "$file.jws.sig" "$file""#.to_owned()
+        }
+    };
+
+    format!(
+        include_str!(r#"scripts/verify_signatures.sh"#),
+        verify = verify
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_signature_path() {
+        assert_eq!(
+            signature_path(SigningTool::Cosign, Path::new("out/top/config.toml")),
+            Path::new("out/top/config.toml.sig")
+        );
+        assert_eq!(
+            signature_path(SigningTool::Notation, Path::new("out/top.zip")),
+            Path::new("out/top.zip.jws.sig")
+        );
+
+        let script = verify_script(&config::Signing {
+            tool: SigningTool::Cosign,
+            key: "cosign.key".to_owned(),
+            public_key: Some("cosign.pub".to_owned()),
+            verify_on_install: true,
+        });
+        assert!(script
+            .contains(r#"cosign verify-blob --key signing.pub --signature "$file.sig" "$file""#));
+        assert!(script.contains(r#""$root_ca_cert" "$device_ca_chain""#));
+    }
+
+    #[test]
+    fn test_signed_files() {
+        let files = signed_files(&config::CertNames::default(), "top");
+        assert!(files.contains(&"iotedge_config_cli_root.pem".to_owned()));
+        assert!(files.contains(&"top.full-chain.cert.pem".to_owned()));
+        assert!(files.contains(&"top.hub-auth.cert.pem".to_owned()));
+    }
+}
@@ -0,0 +1,160 @@
+use anyhow::Result;
+use tokio::fs;
+
+use crate::artifacts;
+use crate::config;
+use crate::k8s::resource_name;
+use crate::log::LogLevel;
+use crate::{FileManager, FlatenedDevice};
+
+/// Generates a docker compose file that runs a container per created device, for testing a
+/// topology on one machine. Each device shares a network with its parent and one with its
+/// children, so devices can only reach the layers directly above and below them.
+pub struct SimulationManager<'a> {
+    config: &'a config::Config,
+    file_manager: &'a FileManager,
+}
+
+impl<'a> SimulationManager<'a> {
+    pub fn new(config: &'a config::Config, file_manager: &'a FileManager) -> Self {
+        Self {
+            config,
+            file_manager,
+        }
+    }
+
+    pub async fn make_compose_file(&self, image: &str) -> Result<()> {
+        let devices = FlatenedDevice::flatten_devices(&self.config.root_devices);
+        for device in &devices {
+            let config = self
+                .file_manager
+                .base_path()
+                .join(&device.device.device_id)
+                .join("config.toml");
+            if fs::metadata(&config).await.is_err() {
+                return Err(anyhow::Error::msg(format!(
+                    "{:?} does not exist. Run iotedge_config without a subcommand to create the devices first.",
+                    config
+                )));
+            }
+        }
+
+        self.file_manager
+            .log(
+                LogLevel::Info,
+                "simulate",
+                None,
+                format!(
+                    "Creating docker compose file for {} devices using image {}.",
+                    devices.len(),
+                    image
+                ),
+            )
+            .await?;
+
+        let mut services = serde_json::Map::new();
+        let mut networks = serde_json::Map::new();
+        for device in &devices {
+            let service = resource_name(&device.device.device_id);
+            let device_hostname = hostname(device.device);
+
+            // Leaf devices only need their parent's network
+            let mut device_networks = serde_json::Map::new();
+            if !device.device.children.is_empty() {
+                networks.insert(
+                    network(device.device),
+                    serde_json::json!({ "internal": true }),
+                );
+                device_networks.insert(
+                    network(device.device),
+                    serde_json::json!({ "aliases": [device_hostname] }),
+                );
+            }
+            match device.parent {
+                Some(parent) => {
+                    device_networks.insert(
+                        network(parent),
+                        serde_json::json!({ "aliases": [device_hostname] }),
+                    );
+                }
+                // Only the top layer can reach the hub
+                None => {
+                    device_networks.insert("default".to_owned(), serde_json::json!({}));
+                }
+            }
+
+            let mut environment = serde_json::Map::new();
+            environment.insert(
+                "IOTEDGE_DEVICE_ID".to_owned(),
+                device.device.device_id.as_str().into(),
+            );
+            environment.insert("IOTEDGE_HOSTNAME".to_owned(), device_hostname.into());
+            if let Some(parent) = device.parent {
+                environment.insert(
+                    "IOTEDGE_PARENT_HOSTNAME".to_owned(),
+                    hostname(parent).into(),
+                );
+            }
+
+            let mut service_config = serde_json::json!({
+                "image": image,
+                "hostname": device_hostname,
+                "privileged": true,
+                "volumes": [format!("./{}:/iotedge_config:ro", device.device.device_id)],
+                "environment": environment,
+                "networks": device_networks,
+            });
+            if let Some(parent) = device.parent {
+                service_config["depends_on"] =
+                    serde_json::json!([resource_name(&parent.device_id)]);
+            }
+
+            self.file_manager
+                .log(
+                    LogLevel::Debug,
+                    "simulate",
+                    Some(device.device.device_id.as_str()),
+                    format!(
+                        "Service {} with hostname {} on networks {:?}",
+                        service, device_hostname, service_config["networks"]
+                    ),
+                )
+                .await?;
+            services.insert(service, service_config);
+        }
+
+        let compose = serde_json::json!({
+            "services": services,
+            "networks": networks,
+        });
+        let file = self.file_manager.base_path().join("docker-compose.yml");
+        let compose = artifacts::stamp(
+            self.config,
+            self.file_manager,
+            &serde_yaml::to_string(&compose)?,
+        );
+        self.file_manager
+            .write_generated(&file, &compose, None)
+            .await?;
+
+        let file = std::fs::canonicalize(&file).unwrap_or(file);
+        self.file_manager
+            .print(format!(
+                "Done! Start the simulation with `docker compose -f {:?} up`.",
+                file
+            ))
+            .await?;
+
+        Ok(())
+    }
+}
+
+/// The hostname the device is reachable at, which is also what its children use as parent hostname.
+fn hostname(device: &config::DeviceConfig) -> &str {
+    device.hostname.as_deref().unwrap_or(&device.device_id)
+}
+
+/// The network a device shares with its children.
+fn network(device: &config::DeviceConfig) -> String {
+    format!("{}-children", resource_name(&device.device_id))
+}
@@ -0,0 +1,144 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use tokio::fs;
+
+use crate::config;
+
+/// `state.json` in the output folder: what the files in it were generated from.
+#[derive(Clone, Debug, Default, serde::Deserialize, serde::Serialize, PartialEq)]
+pub struct State {
+    pub tool_version: String,
+    /// SHA-256 of the config file the output was generated from.
+    pub config_hash: Option<String>,
+    /// When the output was last generated, in RFC 3339.
+    pub updated: String,
+    /// The run was cancelled before it finished.
+    #[serde(default)]
+    pub cancelled: bool,
+    /// Devices each phase was done for, in a run that failed or was cancelled. `--resume` picks it
+    /// up from there.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub completed: BTreeMap<String, Vec<String>>,
+    /// Devices each scheduled operation still has to do, left by a run outside their
+    /// maintenance windows.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub queued: BTreeMap<String, Vec<String>>,
+}
+
+impl State {
+    pub fn new(config: &config::Config, now: DateTime<Utc>) -> Self {
+        Self {
+            tool_version: env!("CARGO_PKG_VERSION").to_owned(),
+            config_hash: config.source_hash.clone(),
+            updated: now.to_rfc3339(),
+            cancelled: false,
+            completed: BTreeMap::new(),
+            queued: BTreeMap::new(),
+        }
+    }
+
+    /// Warning to show when the output was generated from a different config than the current one.
+    pub fn staleness(&self, config: &config::Config) -> Option<String> {
+        match (&self.config_hash, &config.source_hash) {
+            (Some(generated), Some(current)) if generated != current => Some(format!(
+                "The output folder was generated on {} from a different config (sha256 {}) than the current one (sha256 {}). Its files may not match this config, rerun without a subcommand to regenerate them.",
+                self.updated, generated, current
+            )),
+            _ => None,
+        }
+    }
+}
+
+/// The state of a run that generated the output now, keeping the devices queued by scheduled
+/// operations in the state file so a run between two of them doesn't drop the queue.
+pub async fn for_run(path: &Path, config: &config::Config, now: DateTime<Utc>) -> Result<State> {
+    let queued = read(path).await?.map(|s| s.queued).unwrap_or_default();
+
+    Ok(State {
+        queued,
+        ..State::new(config, now)
+    })
+}
+
+/// Devices each phase was done for by the run to pick up with --resume, by phase. Fails if there
+/// is no state file or it is from another config, since the files it lists may not match.
+pub async fn resume(path: &Path, config: &config::Config) -> Result<BTreeMap<String, Vec<String>>> {
+    let state = read(path).await?.ok_or_else(|| {
+        anyhow::Error::msg(format!(
+            "Found no {:?} to resume from. Rerun without --resume.",
+            path
+        ))
+    })?;
+    if state.staleness(config).is_some() {
+        return Err(anyhow::Error::msg(format!(
+            "{:?} is from a different config than the current one, so its run can't be resumed. Rerun without --resume.",
+            path
+        )));
+    }
+
+    Ok(state.completed)
+}
+
+/// The state of the output folder, or None if it was not generated yet.
+pub async fn read(path: &Path) -> Result<Option<State>> {
+    let contents = match fs::read(path).await {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+
+    serde_json::from_slice(&contents)
+        .with_context(|| format!("Error parsing {:?}", path))
+        .map(Some)
+}
+
+pub async fn write(path: &Path, state: &State) -> Result<()> {
+    fs::write(path, serde_json::to_string_pretty(state)?).await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_staleness() {
+        let mut config = config::Config::read_config("src/test_files/cert_test.yaml")
+            .await
+            .unwrap();
+        config.source_hash = Some("abc".to_owned());
+        let state = State::new(&config, Utc::now());
+        assert_eq!(state.staleness(&config), None);
+
+        config.source_hash = Some("def".to_owned());
+        assert!(state.staleness(&config).unwrap().contains("sha256 abc"));
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state.json");
+        assert_eq!(read(&path).await.unwrap(), None);
+        assert!(resume(&path, &config).await.is_err());
+        write(&path, &state).await.unwrap();
+        assert_eq!(read(&path).await.unwrap(), Some(state.clone()));
+        assert!(resume(&path, &config).await.is_err());
+
+        let mut state = state;
+        state
+            .completed
+            .insert("certs".to_owned(), vec!["A".to_owned()]);
+        config.source_hash = Some("abc".to_owned());
+        write(&path, &state).await.unwrap();
+        assert_eq!(resume(&path, &config).await.unwrap(), state.completed);
+
+        state
+            .queued
+            .insert("push".to_owned(), vec!["AA".to_owned()]);
+        write(&path, &state).await.unwrap();
+        let next = for_run(&path, &config, Utc::now()).await.unwrap();
+        assert_eq!(next.queued, state.queued);
+        assert!(next.completed.is_empty());
+    }
+}
@@ -0,0 +1,261 @@
+use anyhow::Result;
+use serde_json::{Map, Value};
+
+use crate::commands::CommandRunner;
+use crate::config;
+use crate::log::LogLevel;
+use crate::throttle::{HubOperation, HubThrottle};
+use crate::{quote_arg, quote_args, FileManager, FlatenedDevice};
+
+/// Devices of the config a bulk command acts on.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Selection {
+    All,
+    /// One device.
+    Device(String),
+    /// A device and every device under it.
+    Subtree(String),
+    /// The devices at a depth of the hierarchy, 0 being the top layer.
+    Layer(usize),
+    /// The devices of a site, region or environment of the config.
+    Site(String),
+    Region(String),
+    Environment(String),
+}
+
+impl std::str::FromStr for Selection {
+    type Err = anyhow::Error;
+    fn from_str(string: &str) -> Result<Self> {
+        let error = || {
+            anyhow::Error::msg(format!(
+                "Did not recognize selection: {}. Use all, device:<device_id>, subtree:<device_id>, layer:<n>, site:<site>, region:<region> or environment:<environment>.",
+                string
+            ))
+        };
+        match string.split_once(':') {
+            None if string == "all" => Ok(Self::All),
+            Some(("device", device_id)) if !device_id.is_empty() => {
+                Ok(Self::Device(device_id.to_owned()))
+            }
+            Some(("subtree", device_id)) if !device_id.is_empty() => {
+                Ok(Self::Subtree(device_id.to_owned()))
+            }
+            Some(("layer", layer)) => layer.parse().map(Self::Layer).map_err(|_| error()),
+            Some(("site", site)) if !site.is_empty() => Ok(Self::Site(site.to_owned())),
+            Some(("region", region)) if !region.is_empty() => Ok(Self::Region(region.to_owned())),
+            Some(("environment", environment)) if !environment.is_empty() => {
+                Ok(Self::Environment(environment.to_owned()))
+            }
+            _ => Err(error()),
+        }
+    }
+}
+
+impl Selection {
+    /// The selected devices, in the order of the hierarchy. Fails if a named device is not in the
+    /// config.
+    pub fn select<'a, 'd>(
+        &self,
+        devices: &'d [FlatenedDevice<'a>],
+    ) -> Result<Vec<&'d FlatenedDevice<'a>>> {
+        let named = match self {
+            Self::Device(device_id) | Self::Subtree(device_id) => Some(
+                devices
+                    .iter()
+                    .find(|d| d.device.device_id == *device_id)
+                    .ok_or_else(|| {
+                        anyhow::Error::msg(format!("{} is not in the config", device_id))
+                    })?,
+            ),
+            _ => None,
+        };
+
+        Ok(devices
+            .iter()
+            .filter(|d| match (self, named) {
+                (Self::All, _) => true,
+                (Self::Device(device_id), _) => d.device.device_id == *device_id,
+                (Self::Subtree(_), Some(named)) => named.device.find(&d.device.device_id).is_some(),
+                (Self::Layer(layer), _) => d.layer == *layer,
+                (Self::Site(site), _) => d.device.site.as_ref() == Some(site),
+                (Self::Region(region), _) => d.device.region.as_ref() == Some(region),
+                (Self::Environment(environment), _) => {
+                    d.device.environment.as_ref() == Some(environment)
+                }
+                _ => false,
+            })
+            .collect())
+    }
+}
+
+/// A `name=value` twin tag. Values that are JSON, such as numbers, true or null, are set as
+/// such, and null removes the tag.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TagAssignment {
+    pub name: String,
+    pub value: Value,
+}
+
+impl std::str::FromStr for TagAssignment {
+    type Err = anyhow::Error;
+    fn from_str(string: &str) -> Result<Self> {
+        match string.split_once('=') {
+            Some((name, value)) if !name.is_empty() => Ok(Self {
+                name: name.to_owned(),
+                value: serde_json::from_str(value)
+                    .unwrap_or_else(|_| Value::String(value.to_owned())),
+            }),
+            _ => Err(anyhow::Error::msg(format!(
+                "Did not recognize tag: {}. Use name=value.",
+                string
+            ))),
+        }
+    }
+}
+
+/// Patches twin tags of devices after they are created, for targeting data that changes.
+pub struct TagManager<'a> {
+    config: &'a config::Config,
+    file_manager: &'a FileManager,
+    throttle: &'a HubThrottle,
+    runner: &'a CommandRunner,
+}
+
+impl<'a> TagManager<'a> {
+    pub fn new(
+        config: &'a config::Config,
+        file_manager: &'a FileManager,
+        throttle: &'a HubThrottle,
+        runner: &'a CommandRunner,
+    ) -> Self {
+        Self {
+            config,
+            file_manager,
+            throttle,
+            runner,
+        }
+    }
+
+    /// Merges the tags into the twin of every selected device. Other tags are kept.
+    pub async fn set(
+        &self,
+        tags: &[TagAssignment],
+        selection: &Selection,
+        dry_run: bool,
+    ) -> Result<()> {
+        let devices = FlatenedDevice::flatten_devices(&self.config.root_devices);
+        let selected = selection.select(&devices)?;
+        let patch: Map<String, Value> = tags
+            .iter()
+            .map(|t| (t.name.clone(), t.value.clone()))
+            .collect();
+        let patch = Value::Object(patch).to_string();
+        let device_ids: Vec<&str> = selected
+            .iter()
+            .map(|d| d.device.device_id.as_str())
+            .collect();
+        self.file_manager
+            .log(
+                LogLevel::Info,
+                "tags",
+                None,
+                format!(
+                    "{} tags {} on {} devices: {}",
+                    if dry_run { "Would set" } else { "Setting" },
+                    patch,
+                    device_ids.len(),
+                    device_ids.join(", ")
+                ),
+            )
+            .await?;
+        if dry_run {
+            return Ok(());
+        }
+
+        let tags = quote_arg(&patch);
+        let extra = quote_args(&self.config.az_arguments.twin);
+        let mut failed = Vec::new();
+        for device_id in device_ids {
+            let mut args = vec![
+                "az iot hub device-twin update",
+                "--device-id",
+                device_id,
+                "--hub-name",
+                &self.config.iothub.iothub_name,
+                "--tags",
+                &tags,
+            ];
+            args.extend(extra.iter().map(String::as_str));
+
+            self.throttle.wait(HubOperation::Twin).await;
+            let command = self.runner.output(&args).await?;
+            if !command.status.success() {
+                self.file_manager
+                    .log(
+                        LogLevel::Error,
+                        "tags",
+                        Some(device_id),
+                        format!(
+                            "Failed to set the tags of {}:\n{}",
+                            device_id,
+                            String::from_utf8_lossy(&command.stderr)
+                        ),
+                    )
+                    .await?;
+                failed.push(device_id);
+            }
+        }
+
+        if failed.is_empty() {
+            Ok(())
+        } else {
+            Err(anyhow::Error::msg(format!(
+                "Failed to set the tags of {}. See the log for details.",
+                failed.join(", ")
+            )))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_selection() {
+        let mut root: config::DeviceConfig = serde_yaml::from_str(
+            "device_id: top\nregion: eu\nchild:\n  - device_id: gw-paris\n    site: paris\n    child:\n      - device_id: leaf\n  - device_id: gw-lyon\n",
+        )
+        .unwrap();
+        root.inherit_groups();
+        let devices = FlatenedDevice::flatten_devices(std::slice::from_ref(&root));
+        let ids = |selection: &str| -> Vec<String> {
+            selection
+                .parse::<Selection>()
+                .unwrap()
+                .select(&devices)
+                .unwrap()
+                .iter()
+                .map(|d| d.device.device_id.clone())
+                .collect()
+        };
+
+        assert_eq!(ids("subtree:gw-paris"), vec!["gw-paris", "leaf"]);
+        assert_eq!(ids("layer:1"), vec!["gw-paris", "gw-lyon"]);
+        assert_eq!(ids("device:leaf"), vec!["leaf"]);
+        assert_eq!(ids("all").len(), 4);
+        assert_eq!(ids("site:paris"), vec!["gw-paris", "leaf"]);
+        assert_eq!(ids("region:eu").len(), 4);
+        assert!(ids("environment:prod").is_empty());
+        assert!(Selection::Subtree("missing".to_owned())
+            .select(&devices)
+            .is_err());
+        assert!("subtree:".parse::<Selection>().is_err());
+
+        let tag: TagAssignment = "site=paris".parse().unwrap();
+        assert_eq!(tag.value, Value::String("paris".to_owned()));
+        let tag: TagAssignment = "floor=3".parse().unwrap();
+        assert_eq!(tag.value, serde_json::json!(3));
+        assert!("=paris".parse::<TagAssignment>().is_err());
+    }
+}
@@ -0,0 +1,302 @@
+use std::collections::hash_map::RandomState;
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::hash::{BuildHasher, Hasher};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use serde_json::{json, Value};
+use tokio::fs;
+
+/// OTLP span status codes.
+const STATUS_OK: u8 = 1;
+const STATUS_ERROR: u8 = 2;
+
+tokio::task_local! {
+    /// Hub commands of the operation being tracked.
+    static HUB_LATENCY: Arc<HubLatency>;
+}
+
+#[derive(Default)]
+struct HubLatency {
+    calls: AtomicU64,
+    micros: AtomicU64,
+}
+
+/// One tracked operation of a phase, exported as a span.
+#[derive(Clone, Debug)]
+pub struct Span {
+    pub phase: String,
+    pub device_id: Option<String>,
+    pub start: SystemTime,
+    pub end: SystemTime,
+    /// az commands run by the operation, and the time they took.
+    pub hub_calls: u64,
+    pub hub_time: Duration,
+    pub error: Option<String>,
+}
+
+/// Counts an az command towards the operation being tracked, if any.
+pub fn record_hub_call(elapsed: Duration) {
+    let _ = HUB_LATENCY.try_with(|latency| {
+        latency.calls.fetch_add(1, Ordering::Relaxed);
+        latency
+            .micros
+            .fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+    });
+}
+
+/// Runs the operation, measuring the az commands it runs. Operations run concurrently on the
+/// same task, as with `buffer_unordered`, are measured each on their own.
+pub async fn measure<F: Future>(operation: F) -> (F::Output, u64, Duration) {
+    let latency = Arc::new(HubLatency::default());
+    let output = HUB_LATENCY.scope(latency.clone(), operation).await;
+
+    (
+        output,
+        latency.calls.load(Ordering::Relaxed),
+        Duration::from_micros(latency.micros.load(Ordering::Relaxed)),
+    )
+}
+
+/// OTLP JSON export of the run: a span for the run, one for each phase covering its operations,
+/// and one for each operation under its phase.
+pub fn export(
+    command: &str,
+    started: SystemTime,
+    finished: SystemTime,
+    spans: &[Span],
+    result: &Result<()>,
+) -> Value {
+    let trace_id = format!("{:016x}{:016x}", random_id(), random_id());
+    let run_id = format!("{:016x}", random_id());
+
+    let mut phases: BTreeMap<&str, Vec<&Span>> = BTreeMap::new();
+    for span in spans {
+        phases.entry(&span.phase).or_default().push(span);
+    }
+
+    let mut exported = vec![span(
+        &trace_id,
+        &run_id,
+        None,
+        command,
+        (started, finished),
+        vec![attribute(
+            "iotedge_config.command",
+            json!({ "stringValue": command }),
+        )],
+        result.as_ref().err().map(|e| format!("{:#}", e)).as_deref(),
+    )];
+    for (phase, operations) in phases {
+        let phase_id = format!("{:016x}", random_id());
+        let failures = operations.iter().filter(|o| o.error.is_some()).count();
+        exported.push(span(
+            &trace_id,
+            &phase_id,
+            Some(&run_id),
+            phase,
+            (
+                operations.iter().map(|o| o.start).min().unwrap_or(started),
+                operations.iter().map(|o| o.end).max().unwrap_or(finished),
+            ),
+            vec![
+                int_attribute("iotedge_config.operations", operations.len() as u64),
+                int_attribute("iotedge_config.failures", failures as u64),
+                int_attribute(
+                    "iotedge_config.hub.calls",
+                    operations.iter().map(|o| o.hub_calls).sum(),
+                ),
+                int_attribute(
+                    "iotedge_config.hub.latency_ms",
+                    operations
+                        .iter()
+                        .map(|o| o.hub_time.as_millis() as u64)
+                        .sum(),
+                ),
+            ],
+            if failures == 0 {
+                None
+            } else {
+                Some("Some operations failed")
+            },
+        ));
+
+        for operation in operations {
+            let mut attributes = vec![
+                int_attribute("iotedge_config.hub.calls", operation.hub_calls),
+                int_attribute(
+                    "iotedge_config.hub.latency_ms",
+                    operation.hub_time.as_millis() as u64,
+                ),
+            ];
+            if let Some(device_id) = &operation.device_id {
+                attributes.push(attribute(
+                    "iotedge_config.device_id",
+                    json!({ "stringValue": device_id }),
+                ));
+            }
+            exported.push(span(
+                &trace_id,
+                &format!("{:016x}", random_id()),
+                Some(&phase_id),
+                &match &operation.device_id {
+                    Some(device_id) => format!("{} {}", phase, device_id),
+                    None => phase.to_owned(),
+                },
+                (operation.start, operation.end),
+                attributes,
+                operation.error.as_deref(),
+            ));
+        }
+    }
+
+    json!({
+        "resourceSpans": [{
+            "resource": {
+                "attributes": [
+                    attribute("service.name", json!({ "stringValue": "iotedge_config" })),
+                    attribute("service.version", json!({ "stringValue": env!("CARGO_PKG_VERSION") })),
+                ]
+            },
+            "scopeSpans": [{
+                "scope": { "name": "iotedge_config" },
+                "spans": exported,
+            }]
+        }]
+    })
+}
+
+/// Writes the export as one line, the format of the OpenTelemetry collector's otlpjsonfile
+/// receiver.
+pub async fn write(
+    path: &Path,
+    command: &str,
+    started: SystemTime,
+    spans: &[Span],
+    result: &Result<()>,
+) -> Result<()> {
+    let export = export(command, started, SystemTime::now(), spans, result);
+    fs::write(path, format!("{}\n", serde_json::to_string(&export)?))
+        .await
+        .with_context(|| format!("Error writing OTLP file {:?}", path))
+}
+
+fn span(
+    trace_id: &str,
+    span_id: &str,
+    parent_id: Option<&str>,
+    name: &str,
+    (start, end): (SystemTime, SystemTime),
+    attributes: Vec<Value>,
+    error: Option<&str>,
+) -> Value {
+    let mut span = json!({
+        "traceId": trace_id,
+        "spanId": span_id,
+        "name": name,
+        // Internal
+        "kind": 1,
+        "startTimeUnixNano": unix_nanos(start),
+        "endTimeUnixNano": unix_nanos(end),
+        "attributes": attributes,
+        "status": match error {
+            None => json!({ "code": STATUS_OK }),
+            Some(error) => json!({ "code": STATUS_ERROR, "message": error }),
+        },
+    });
+    if let Some(parent_id) = parent_id {
+        span["parentSpanId"] = json!(parent_id);
+    }
+
+    span
+}
+
+fn attribute(key: &str, value: Value) -> Value {
+    json!({ "key": key, "value": value })
+}
+
+fn int_attribute(key: &str, value: u64) -> Value {
+    // OTLP JSON carries 64 bit integers as strings
+    attribute(key, json!({ "intValue": value.to_string() }))
+}
+
+fn unix_nanos(time: SystemTime) -> String {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos()
+        .to_string()
+}
+
+/// Random enough for trace and span ids, from the randomly seeded keys of the standard hasher.
+fn random_id() -> u64 {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos() as u64,
+    );
+
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_export() {
+        let (output, calls, time) = measure(async {
+            record_hub_call(Duration::from_millis(30));
+            record_hub_call(Duration::from_millis(20));
+            7
+        })
+        .await;
+        assert_eq!((output, calls, time), (7, 2, Duration::from_millis(50)));
+
+        let started = UNIX_EPOCH + Duration::from_secs(100);
+        let operation = |device_id: &str, start: u64, error: Option<&str>| Span {
+            phase: "hub".to_owned(),
+            device_id: Some(device_id.to_owned()),
+            start: UNIX_EPOCH + Duration::from_secs(start),
+            end: UNIX_EPOCH + Duration::from_secs(start + 2),
+            hub_calls: calls,
+            hub_time: time,
+            error: error.map(str::to_owned),
+        };
+        let export = export(
+            "create",
+            started,
+            started + Duration::from_secs(10),
+            &[
+                operation("top", 101, None),
+                operation("leaf", 104, Some("Throttled")),
+            ],
+            &Ok(()),
+        );
+
+        let spans = export["resourceSpans"][0]["scopeSpans"][0]["spans"]
+            .as_array()
+            .unwrap();
+        assert_eq!(spans.len(), 4);
+        let (run, phase, leaf) = (&spans[0], &spans[1], &spans[3]);
+        assert_eq!(run["name"], "create");
+        assert!(run.get("parentSpanId").is_none());
+        assert_eq!(phase["parentSpanId"], run["spanId"]);
+        assert_eq!(phase["startTimeUnixNano"], "101000000000");
+        assert_eq!(phase["endTimeUnixNano"], "106000000000");
+        assert_eq!(phase["status"]["code"], STATUS_ERROR);
+        assert_eq!(leaf["name"], "hub leaf");
+        assert_eq!(leaf["parentSpanId"], phase["spanId"]);
+        assert_eq!(leaf["traceId"], run["traceId"]);
+        assert_eq!(leaf["status"]["message"], "Throttled");
+        assert_eq!(
+            leaf["attributes"][1],
+            json!({ "key": "iotedge_config.hub.latency_ms", "value": { "intValue": "50" } })
+        );
+    }
+}
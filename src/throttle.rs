@@ -0,0 +1,177 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+
+use anyhow::Result;
+use tokio::sync::Mutex;
+use tokio::time::{Duration, Instant};
+
+/// Attempts at a hub request the hub keeps throttling before giving up.
+pub const THROTTLED_ATTEMPTS: u32 = 6;
+
+/// Parts of az errors that mean the hub throttled the request, and it can be retried later. 429
+/// only counts as a status, as in `IotHub(429002)` or `(429) Too Many Requests`, since device ids
+/// and etags in the error can contain it too.
+const THROTTLE_MARKERS: &[&str] = &["(429", "Too Many Requests", "Throttl", "TooManyRequests"];
+
+/// Whether the hub refused the request because too many were made. A used up daily quota is not
+/// throttling, it only resets the next day.
+pub fn is_throttled(error: &str) -> bool {
+    THROTTLE_MARKERS.iter().any(|m| error.contains(m))
+}
+
+/// Time to wait before the next attempt at a throttled request: 1s doubling up to 32s, each half
+/// of it random so requests throttled together don't come back together.
+pub fn backoff(attempt: u32) -> Duration {
+    let delay = Duration::from_secs(1 << std::cmp::min(attempt.saturating_sub(1), 5));
+    let jitter = RandomState::new().build_hasher().finish() as f64 / u64::MAX as f64;
+
+    delay / 2 + (delay / 2).mul_f64(jitter)
+}
+
+/// IoT Hub tiers, used to pick request rates within the hub's documented throttling limits.
+/// See https://docs.microsoft.com/azure/iot-hub/iot-hub-devguide-quotas-throttling
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HubTier {
+    Free,
+    S1,
+    S2,
+    S3,
+}
+
+impl std::str::FromStr for HubTier {
+    type Err = anyhow::Error;
+    fn from_str(string: &str) -> Result<Self> {
+        let result = match string.to_lowercase().as_str() {
+            "free" | "f1" => Self::Free,
+            "s1" => Self::S1,
+            "s2" => Self::S2,
+            "s3" => Self::S3,
+            _ => {
+                return Err(anyhow::Error::msg(format!(
+                    "Did not recognize hub tier: {}",
+                    string
+                )))
+            }
+        };
+
+        Ok(result)
+    }
+}
+
+impl HubTier {
+    /// Identity registry operations (create, get, update, delete) per minute.
+    fn registry_ops_per_minute(self, units: u32) -> u32 {
+        match self {
+            Self::Free => 100,
+            Self::S1 | Self::S2 => 100 * units,
+            Self::S3 => 5000 * units,
+        }
+    }
+
+    /// Twin and module configuration updates per minute.
+    fn twin_ops_per_minute(self, units: u32) -> u32 {
+        match self {
+            Self::Free => 10 * 60,
+            Self::S1 => std::cmp::max(10, units) * 60,
+            Self::S2 => 50 * units * 60,
+            Self::S3 => 500 * units * 60,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HubOperation {
+    Registry,
+    Twin,
+}
+
+/// Spaces out hub requests so a run stays within the tier's limits. Without a tier, requests
+/// are not delayed.
+pub struct HubThrottle {
+    registry: Option<Throttle>,
+    twin: Option<Throttle>,
+}
+
+impl HubThrottle {
+    pub fn new(tier: Option<HubTier>, units: u32) -> Self {
+        // The free tier only ever has one unit
+        let units = match tier {
+            Some(HubTier::Free) => 1,
+            _ => std::cmp::max(units, 1),
+        };
+
+        Self {
+            registry: tier.map(|t| Throttle::per_minute(t.registry_ops_per_minute(units))),
+            twin: tier.map(|t| Throttle::per_minute(t.twin_ops_per_minute(units))),
+        }
+    }
+
+    pub async fn wait(&self, operation: HubOperation) {
+        let throttle = match operation {
+            HubOperation::Registry => &self.registry,
+            HubOperation::Twin => &self.twin,
+        };
+
+        if let Some(throttle) = throttle {
+            throttle.wait().await;
+        }
+    }
+}
+
+struct Throttle {
+    interval: Duration,
+    next: Mutex<Instant>,
+}
+
+impl Throttle {
+    fn per_minute(operations: u32) -> Self {
+        Self {
+            interval: Duration::from_secs(60) / std::cmp::max(operations, 1),
+            next: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Waits for the next free slot. Slots are handed out in order, one interval apart.
+    async fn wait(&self) {
+        let slot = {
+            let mut next = self.next.lock().await;
+            let slot = std::cmp::max(*next, Instant::now());
+            *next = slot + self.interval;
+            slot
+        };
+
+        tokio::time::sleep_until(slot).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_throttled() {
+        assert!(is_throttled("ThrottlingException: IotHub(429002)"));
+        assert!(is_throttled(
+            "Operation returned an invalid status code 'Too Many Requests'"
+        ));
+        assert!(is_throttled("(429) Too Many Requests"));
+        assert!(!is_throttled("QuotaExceeded: IotHub(403002)"));
+        assert!(!is_throttled(
+            "DeviceNotFound: IotHub(404001) Device sensor-4291 with etag \"AAAA429\" not found"
+        ));
+    }
+
+    #[test]
+    fn test_backoff() {
+        for (attempt, seconds) in &[(1, 1), (2, 2), (3, 4), (6, 32), (9, 32)] {
+            let delay = backoff(*attempt);
+            let max = Duration::from_secs(*seconds);
+            assert!(
+                delay >= max / 2 && delay <= max,
+                "{:?} {:?}",
+                attempt,
+                delay
+            );
+        }
+    }
+}
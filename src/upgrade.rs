@@ -0,0 +1,331 @@
+use std::fmt;
+
+use anyhow::{Context, Result};
+use serde_json::Value;
+use tokio::fs;
+
+use crate::config;
+use crate::deployment;
+use crate::log::LogLevel;
+use crate::{FileManager, FlatenedDevice};
+
+/// IoT Edge runtime versions the generated files can be rewritten for.
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd)]
+pub enum RuntimeVersion {
+    V1_2,
+    V1_3,
+    V1_4,
+}
+
+impl std::str::FromStr for RuntimeVersion {
+    type Err = anyhow::Error;
+    fn from_str(string: &str) -> Result<Self> {
+        match string {
+            "1.2" => Ok(Self::V1_2),
+            "1.3" => Ok(Self::V1_3),
+            "1.4" => Ok(Self::V1_4),
+            _ => Err(anyhow::Error::msg(format!(
+                "Did not recognize runtime version: {}. Use 1.2, 1.3 or 1.4.",
+                string
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for RuntimeVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::V1_2 => "1.2",
+            Self::V1_3 => "1.3",
+            Self::V1_4 => "1.4",
+        })
+    }
+}
+
+/// edgeHub environment variables of the MQTT broker preview, which 1.4 dropped.
+const BROKER_ENV: &[&str] = &[
+    "experimentalFeatures__enabled",
+    "experimentalFeatures__mqttBrokerEnabled",
+];
+
+/// Rewrites the files generated for every device for another IoT Edge runtime version, so a fleet
+/// can move without being created again.
+pub struct UpgradeManager<'a> {
+    config: &'a config::Config,
+    file_manager: &'a FileManager,
+}
+
+impl<'a> UpgradeManager<'a> {
+    pub fn new(config: &'a config::Config, file_manager: &'a FileManager) -> Self {
+        Self {
+            config,
+            file_manager,
+        }
+    }
+
+    /// Rewrites each device's config.toml and deployment.json, and its zip if there is one.
+    /// Settings with no equivalent in `to` are logged as warnings, and removed from deployments.
+    pub async fn upgrade(&self, to: RuntimeVersion) -> Result<()> {
+        let devices = FlatenedDevice::flatten_devices(&self.config.root_devices);
+        let mut upgraded = 0;
+        let mut flagged = 0;
+        for device in &devices {
+            let device_id = device.device.device_id.as_str();
+            let dir = self.file_manager.base_path().join(device_id);
+            if !dir.is_dir() {
+                self.file_manager
+                    .log(
+                        LogLevel::Warn,
+                        "upgrade",
+                        Some(device_id),
+                        format!("No generated files for {}, skipped", device_id),
+                    )
+                    .await?;
+                continue;
+            }
+
+            let mut notes = Vec::new();
+            let mut changed = false;
+            let path = dir.join("config.toml");
+            if path.is_file() {
+                let old = fs::read_to_string(&path).await?;
+                let new = upgrade_config(&old, to, &mut notes)
+                    .with_context(|| format!("Error upgrading {:?}", path))?;
+                if new != old {
+                    fs::write(&path, new).await?;
+                    changed = true;
+                }
+            }
+            let path = dir.join("deployment.json");
+            if path.is_file() {
+                let old = fs::read_to_string(&path).await?;
+                let new = upgrade_manifest(&old, to, &mut notes)
+                    .with_context(|| format!("Error upgrading {:?}", path))?;
+                if new != old {
+                    fs::write(&path, new).await?;
+                    changed = true;
+                }
+            }
+
+            for note in &notes {
+                self.file_manager
+                    .log(LogLevel::Warn, "upgrade", Some(device_id), note)
+                    .await?;
+            }
+            flagged += notes.len();
+            if !changed {
+                continue;
+            }
+            upgraded += 1;
+            if FileManager::path_to_zip(&dir).exists() {
+                self.file_manager.zip_dir(&dir).await?;
+            }
+            self.file_manager
+                .log(
+                    LogLevel::Info,
+                    "upgrade",
+                    Some(device_id),
+                    format!("Rewrote the files of {} for {}", device_id, to),
+                )
+                .await?;
+        }
+
+        let configured = std::iter::once(&self.config.configuration.default_edge_agent)
+            .chain(devices.iter().filter_map(|d| d.device.edge_agent.as_ref()));
+        for image in configured {
+            if let Some(new) = retag(image, to).filter(|new| new != image) {
+                self.file_manager
+                    .log(
+                        LogLevel::Warn,
+                        "upgrade",
+                        None,
+                        format!(
+                            "The config still uses {}. Change it to {} so the next run generates files for {}.",
+                            image, new, to
+                        ),
+                    )
+                    .await?;
+            }
+        }
+
+        self.file_manager
+            .print(format!(
+                "Upgraded {} of {} devices to {}, {} settings with no equivalent were flagged. Apply the deployment.json files again to update the devices' modules.",
+                upgraded,
+                devices.len(),
+                to,
+                flagged
+            ))
+            .await?;
+
+        Ok(())
+    }
+}
+
+/// The image with its tag set to the version, for edgeAgent and edgeHub images tagged with a
+/// version. None for other images, whose versions are the user's to pick.
+fn retag(image: &str, to: RuntimeVersion) -> Option<String> {
+    let (name, tag) = image.rsplit_once(':')?;
+    if !(name.ends_with("/azureiotedge-agent") || name.ends_with("/azureiotedge-hub")) {
+        return None;
+    }
+    if !tag
+        .split('.')
+        .all(|p| !p.is_empty() && p.chars().all(|c| c.is_ascii_digit()))
+    {
+        return None;
+    }
+
+    Some(format!("{}:{}", name, to))
+}
+
+/// Rewrites the `image` lines of a config.toml, leaving the rest of it untouched.
+fn upgrade_config(config: &str, to: RuntimeVersion, notes: &mut Vec<String>) -> Result<String> {
+    let parsed: toml::Value = toml::from_str(config)?;
+    if to >= RuntimeVersion::V1_4 {
+        let env = parsed
+            .get("agent")
+            .and_then(|a| a.get("env"))
+            .and_then(toml::Value::as_table);
+        for name in BROKER_ENV {
+            if env.map_or(false, |e| e.contains_key(*name)) {
+                notes.push(format!(
+                    "agent.env.{} in config.toml is for the MQTT broker preview, which {} does not have. It was left in and is ignored.",
+                    name, to
+                ));
+            }
+        }
+    }
+
+    let mut result: Vec<String> = Vec::new();
+    for line in config.lines() {
+        let image = line
+            .trim_start()
+            .strip_prefix("image = \"")
+            .and_then(|i| i.strip_suffix('"'));
+        match image.and_then(|i| retag(i, to).map(|new| (i, new))) {
+            Some((old, new)) => result.push(line.replacen(old, &new, 1)),
+            None => result.push(line.to_owned()),
+        }
+    }
+    let mut result = result.join("\n");
+    if config.ends_with('\n') {
+        result.push('\n');
+    }
+
+    Ok(result)
+}
+
+/// Retags the system module images of a deployment manifest and, for 1.4, removes the MQTT
+/// broker settings.
+fn upgrade_manifest(manifest: &str, to: RuntimeVersion, notes: &mut Vec<String>) -> Result<String> {
+    let mut manifest: Value = serde_json::from_str(manifest)?;
+    for module in &["edgeAgent", "edgeHub"] {
+        let pointer = format!(
+            "/modulesContent/$edgeAgent/properties.desired/systemModules/{}/settings/image",
+            module
+        );
+        if let Some(image) = manifest.pointer_mut(&pointer) {
+            if let Some(new) = image.as_str().and_then(|i| retag(i, to)) {
+                *image = Value::String(new);
+            }
+        }
+    }
+
+    if to >= RuntimeVersion::V1_4 {
+        if let Some(env) = manifest
+            .pointer_mut("/modulesContent/$edgeAgent/properties.desired/systemModules/edgeHub/env")
+            .and_then(Value::as_object_mut)
+        {
+            for name in BROKER_ENV {
+                if env.remove(*name).is_some() {
+                    notes.push(format!(
+                        "Removed edgeHub env {}, the MQTT broker preview is not in {}",
+                        name, to
+                    ));
+                }
+            }
+        }
+        if let Some(hub) = manifest
+            .pointer_mut("/modulesContent/$edgeHub/properties.desired")
+            .and_then(Value::as_object_mut)
+        {
+            if hub.remove("mqttBroker").is_some() {
+                notes.push(format!(
+                    "Removed the MQTT broker bridge to the parent, {} has no equivalent. Bridged topics are no longer forwarded, route them through $upstream instead.",
+                    to
+                ));
+            }
+        }
+    }
+
+    let manifest = serde_json::to_string_pretty(&manifest)?;
+    let problems = deployment::validate_manifest(&manifest);
+    if !problems.is_empty() {
+        return Err(anyhow::Error::msg(format!(
+            "Upgraded deployment is invalid:\n{}",
+            problems.join("\n")
+        )));
+    }
+
+    Ok(manifest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_upgrade() {
+        assert_eq!(
+            retag("$upstream:443/azureiotedge-agent:1.2", RuntimeVersion::V1_4).as_deref(),
+            Some("$upstream:443/azureiotedge-agent:1.4")
+        );
+        assert_eq!(
+            retag(
+                "mcr.microsoft.com/azureiotedge-hub:1.2.7",
+                RuntimeVersion::V1_4
+            )
+            .as_deref(),
+            Some("mcr.microsoft.com/azureiotedge-hub:1.4")
+        );
+        assert_eq!(
+            retag("mcr.microsoft.com/azureiotedge-agent", RuntimeVersion::V1_4),
+            None
+        );
+        assert_eq!(
+            retag("contoso.azurecr.io/sensor:1.2", RuntimeVersion::V1_4),
+            None
+        );
+
+        let mut notes = Vec::new();
+        let config = "# Generated\n[agent.config]\nimage = \"mcr.microsoft.com/azureiotedge-agent:1.2\"\n\n[agent.env]\nexperimentalFeatures__enabled = \"true\"\n";
+        assert_eq!(
+            upgrade_config(config, RuntimeVersion::V1_4, &mut notes).unwrap(),
+            config.replace(":1.2", ":1.4")
+        );
+        assert_eq!(notes.len(), 1);
+
+        let mut manifest: Value = serde_json::from_str(include_str!(
+            "../templates/tutorial/deploymentLowerLayer.json"
+        ))
+        .unwrap();
+        manifest["modulesContent"]["$edgeHub"]["properties.desired"]["mqttBroker"] =
+            serde_json::json!({ "bridges": [] });
+        let mut notes = Vec::new();
+        let upgraded: Value = serde_json::from_str(
+            &upgrade_manifest(&manifest.to_string(), RuntimeVersion::V1_4, &mut notes).unwrap(),
+        )
+        .unwrap();
+        let system_modules =
+            &upgraded["modulesContent"]["$edgeAgent"]["properties.desired"]["systemModules"];
+        assert_eq!(
+            system_modules["edgeHub"]["settings"]["image"],
+            "$upstream:443/azureiotedge-hub:1.4"
+        );
+        assert!(upgraded["modulesContent"]["$edgeHub"]["properties.desired"]
+            .get("mqttBroker")
+            .is_none());
+        assert_eq!(notes.len(), 1);
+    }
+}
@@ -0,0 +1,149 @@
+use anyhow::Result;
+use id_tree::{InsertBehavior, Node, NodeId, Tree, TreeBuilder};
+use id_tree_layout::{Layouter, Visualize};
+use tokio::fs;
+
+use crate::config;
+use crate::log::LogLevel;
+use crate::FileManager;
+
+/// A box of the hierarchy drawing: the hub on top, then the devices.
+struct Label {
+    lines: Vec<String>,
+    hub: bool,
+}
+
+impl Label {
+    fn device(device: &config::DeviceConfig) -> Self {
+        let mut lines = vec![device.device_id.clone()];
+        if let Some(hostname) = &device.hostname {
+            lines.push(format!("hostname: {}", hostname));
+        }
+        if let Some(deployment) = &device.deployment {
+            lines.push(format!("deployment: {}", deployment));
+        }
+
+        Self { lines, hub: false }
+    }
+}
+
+impl Visualize for Label {
+    fn visualize(&self) -> String {
+        self.lines.join(" | ")
+    }
+
+    fn emphasize(&self) -> bool {
+        self.hub
+    }
+}
+
+/// Writes visualization.svg and visualization.dot, for Graphviz, to the output folder.
+pub async fn write_files(config: &config::Config, file_manager: &FileManager) -> Result<()> {
+    let svg = file_manager.base_path().join("visualization.svg");
+    let tree = tree(config)?;
+    Layouter::new(&tree)
+        .with_file_path(&svg)
+        .write()
+        .map_err(|e| anyhow::Error::msg(format!("Error writing {:?}: {:?}", svg, e)))?;
+
+    let dot = file_manager.base_path().join("visualization.dot");
+    fs::write(&dot, dot_graph(config)).await?;
+
+    file_manager
+        .log(
+            LogLevel::Info,
+            "main",
+            None,
+            format!("Wrote the hierarchy to {:?} and {:?}", svg, dot),
+        )
+        .await
+}
+
+fn tree(config: &config::Config) -> Result<Tree<Label>> {
+    let mut tree = TreeBuilder::new().build();
+    let hub = tree
+        .insert(
+            Node::new(Label {
+                lines: vec![config.iothub.iothub_name.clone()],
+                hub: true,
+            }),
+            InsertBehavior::AsRoot,
+        )
+        .map_err(node_error)?;
+    for root in &config.root_devices {
+        insert(&mut tree, &hub, root)?;
+    }
+
+    Ok(tree)
+}
+
+fn insert(tree: &mut Tree<Label>, parent: &NodeId, device: &config::DeviceConfig) -> Result<()> {
+    let id = tree
+        .insert(
+            Node::new(Label::device(device)),
+            InsertBehavior::UnderNode(parent),
+        )
+        .map_err(node_error)?;
+    for child in &device.children {
+        insert(tree, &id, child)?;
+    }
+
+    Ok(())
+}
+
+fn node_error(e: id_tree::NodeIdError) -> anyhow::Error {
+    anyhow::Error::msg(format!("Error building the hierarchy tree: {:?}", e))
+}
+
+/// Graphviz DOT of the hierarchy, top to bottom from the hub.
+fn dot_graph(config: &config::Config) -> String {
+    let hub = &config.iothub.iothub_name;
+    let mut lines = vec![
+        "digraph hierarchy {".to_owned(),
+        "    node [shape=box];".to_owned(),
+        format!("    {} [label={}, style=bold];", quote(hub), quote(hub)),
+    ];
+    for device in crate::FlatenedDevice::iter(&config.root_devices) {
+        let id = &device.device.device_id;
+        lines.push(format!(
+            "    {} [label={}];",
+            quote(id),
+            quote(&Label::device(device.device).lines.join("\n"))
+        ));
+        let parent = device.parent.map_or(hub, |p| &p.device_id);
+        lines.push(format!("    {} -> {};", quote(parent), quote(id)));
+    }
+    lines.push("}".to_owned());
+
+    lines.join("\n") + "\n"
+}
+
+fn quote(text: &str) -> String {
+    format!(
+        "\"{}\"",
+        text.replace('\\', "\\\\")
+            .replace('"', "\\\"")
+            .replace('\n', "\\n")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_dot_graph() {
+        let mut config = config::Config::read_config("src/test_files/cert_test.yaml")
+            .await
+            .unwrap();
+        config.iothub.iothub_name = "hub".to_owned();
+        config.root_devices[0].hostname = Some("10.0.0.1".to_owned());
+
+        let dot = dot_graph(&config);
+        assert!(dot.starts_with("digraph hierarchy {\n"));
+        assert!(dot.contains("    \"A\" [label=\"A\\nhostname: 10.0.0.1"));
+        assert!(dot.contains("    \"hub\" -> \"A\";\n"));
+        assert!(dot.contains("    \"AA\" -> \"AAA\";\n"));
+        assert!(dot.ends_with("}\n"));
+    }
+}